@@ -1,14 +1,21 @@
+use clap::Parser;
+use ethnum::U256;
 use ring::{
-    digest, rand,
-    signature::{Ed25519KeyPair, KeyPair, UnparsedPublicKey, ED25519},
+    rand,
+    signature::{Ed25519KeyPair, KeyPair},
+};
+use std::path::PathBuf;
+use tiny_blockchain::{
+    pub_key_hash, Block, BlockHeader, BlockStore, BlockTemplateBuilder, ConsensusEngine,
+    DifficultyAlgorithm, FeeEstimator, Hash, ProofOfWork, SystemClock, TinyBlockchain,
+    TinyBlockchainParams, DEFAULT_CONFIDENCE,
 };
-use tiny_blockchain::{hash_to_u256, Transaction};
 
-const LEAF_PREFIX: &[u8] = &[0];
-macro_rules! hash_leaf {
-    {$d:expr} => {
-        hash_to_u256!(&[LEAF_PREFIX, $d].concat())
-    }
+#[derive(Parser, Debug)]
+struct Opts {
+    /// Directory holding the block files.
+    #[clap(long, default_value = "data")]
+    data_dir: PathBuf,
 }
 
 fn generate_pk() -> Ed25519KeyPair {
@@ -17,23 +24,86 @@ fn generate_pk() -> Ed25519KeyPair {
     let pkcs8_bytes = Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
 
     // Create a key pair from the PKCS#8 document
-    let key_pair = Ed25519KeyPair::from_pkcs8(pkcs8_bytes.as_ref()).unwrap();
+    Ed25519KeyPair::from_pkcs8(pkcs8_bytes.as_ref()).unwrap()
+}
 
-    key_pair
+fn genesis_block(params: &TinyBlockchainParams) -> Block {
+    let header = BlockHeader {
+        version: 1,
+        timestamp: 1_700_000_000,
+        prev: U256::ZERO,
+        merkle_root: U256::ZERO,
+        bits: params.init_difficulty,
+        nonce: 0,
+        extra: Vec::new(),
+    };
+    Block::new(0, header.hash(), header, vec![])
+}
+
+fn mine_block(blockchain: &TinyBlockchain, keypair: &Ed25519KeyPair) -> Block {
+    let payout = pub_key_hash(keypair.public_key().as_ref());
+    let mut block = BlockTemplateBuilder::new(payout).build(blockchain).block;
+
+    blockchain
+        .engine()
+        .seal(&mut block, blockchain.clock())
+        .expect("Seal block");
+    block
 }
 
 fn main() {
-    // let to_keypair = generate_pk();
-    // let to_pk_bytes = to_keypair.public_key().as_ref();
-    // let to_pk_digest = digest::digest(&digest::SHA256, to_pk_bytes);
+    let opts = Opts::parse();
+    let params = TinyBlockchainParams {
+        blocks_in_epoch: 2016,
+        init_difficulty: 0x207fffff,
+        target_spacing: 600,
+        max_adjustment: 4,
+        pow_limit: 0x207fffff,
+        difficulty: DifficultyAlgorithm::Epoch,
+        max_future_drift: 2 * 60 * 60,
+    };
+
+    let mut store = BlockStore::open(&opts.data_dir).expect("Open block store");
+    let mut blockchain = TinyBlockchain::open(
+        &mut store,
+        genesis_block(&params),
+        params,
+        ProofOfWork,
+        SystemClock,
+    )
+    .expect("Load chain");
+    println!(
+        "Resumed at height {} tip {:#064x}",
+        blockchain.tip().height,
+        blockchain.tip().header_hash
+    );
 
-    // let from_keypair = generate_pk();
-    // let from_pk_bytes = from_keypair.public_key().as_ref();
-    // let from_pk = UnparsedPublicKey::new(&ED25519, from_pk_bytes);
+    let fee_estimates = opts.data_dir.join("fee_estimates.json");
+    let mut fee_estimator = FeeEstimator::load(&fee_estimates).expect("Load fee estimates");
 
-    // let tx = Transaction::new(&from_keypair, to_pk_digest, 10);
+    let block = mine_block(&blockchain, &generate_pk());
+    blockchain.add_block(block.clone()).expect("Valid block");
+    store.append(&block).expect("Store block");
 
-    // println!("Tx: {:?}", tx);
-    // println!("Verification: {:?}", tx.verify(from_pk, &from_keypair));
-    // println!("Tx hash: {:?}", hash_to_u256!(tx.as_bytes()));
+    fee_estimator.process_block(&block);
+    fee_estimator
+        .save(&fee_estimates)
+        .expect("Save fee estimates");
+
+    println!(
+        "Mined height {} tip {:#064x}",
+        blockchain.tip().height,
+        blockchain.tip().header_hash
+    );
+    for target in [1, 6, 24] {
+        match fee_estimator.estimate_fee_rate(target, DEFAULT_CONFIDENCE) {
+            Some(fee_rate) => {
+                println!("Fee rate to confirm within {} blocks: {}", target, fee_rate)
+            }
+            None => println!(
+                "Fee rate to confirm within {} blocks: not enough data",
+                target
+            ),
+        }
+    }
 }
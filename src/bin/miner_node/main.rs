@@ -1,65 +1,314 @@
-use ethnum::AsU256;
+use ethnum::U256;
+use ring::{
+    rand,
+    signature::{Ed25519KeyPair, KeyPair},
+};
+use std::time::Duration;
 use tiny_blockchain::{
-    is_epoch, pow, retarget, Block, BlockHeader, Chain, Hash, TinyBlockchainParams, Transaction,
+    compact::{CompactBlock, CompactBlockError, PartialBlock, PendingBlocks},
+    gossip::{Acceptance, GossipMessage, GossipValidator},
+    pub_key_hash,
+    relay::{RelayRequest, RelayResponse, TransactionRelay},
+    sync::{handle_request, InitialBlockDownload, SyncRequest, SyncResponse},
+    Block, BlockHeader, BlockTemplateBuilder, Chain, Clock, DifficultyAlgorithm, FeeEstimator,
+    Hash, Mempool, MempoolPolicy, Miner, NetworkTime, ProofOfWork, SystemClock, TinyBlockchain,
+    TinyBlockchainParams, DEFAULT_CONFIDENCE,
 };
 
-fn mine_block(
-    chain: &Chain,
-    transactions: Vec<Transaction>,
-    params: &TinyBlockchainParams,
-) -> Block {
-    let prev_block = chain.previous_block().expect("Previous block");
-    let mut bits = prev_block.header.bits;
-
-    if is_epoch(prev_block.height, chain, params) {
-        // TODO: create a constant for 2016 blocks
-        let epoch_start_block = chain
-            .get_block(chain.len() - params.blocks_in_epoch)
-            .unwrap();
-        bits = retarget(epoch_start_block.header.bits, chain, params);
+/// Block timestamps are checked against the time adjusted by the peers'.
+type Blockchain = TinyBlockchain<ProofOfWork, NetworkTime<SystemClock, PeerId>>;
+
+fn params() -> TinyBlockchainParams {
+    TinyBlockchainParams {
+        blocks_in_epoch: 2016,
+        init_difficulty: 0x207fffff,
+        target_spacing: 600,
+        max_adjustment: 4,
+        pow_limit: 0x207fffff,
+        difficulty: DifficultyAlgorithm::Epoch,
+        max_future_drift: 2 * 60 * 60,
     }
+}
 
-    let proof = pow(bits);
-
-    // TODO: add reward transaction
-    // let reward_tx = Transaction::new(1);
-    let block_header = BlockHeader {
+// Same genesis as the full node, so both share one chain.
+fn genesis_block(params: &TinyBlockchainParams) -> Block {
+    let header = BlockHeader {
         version: 1,
-        timestamp: proof.timestamp,
-        prev: prev_block.header_hash.clone(),
-        merkle_root: 0.as_u256(),
-        bits,
-        nonce: proof.nonce,
+        timestamp: 1_700_000_000,
+        prev: U256::ZERO,
+        merkle_root: U256::ZERO,
+        bits: params.init_difficulty,
+        nonce: 0,
+        extra: Vec::new(),
     };
+    Block::new(0, header.hash(), header, vec![])
+}
 
-    Block::new(
-        chain.len() + 1,
-        block_header.hash(),
-        block_header,
-        transactions,
-    )
+fn generate_pk() -> Ed25519KeyPair {
+    let rng = rand::SystemRandom::new();
+    let pkcs8_bytes = Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
+    Ed25519KeyPair::from_pkcs8(pkcs8_bytes.as_ref()).unwrap()
 }
 
-// fn main() {
-//     // init mempool
-//     let mempool = Vec::<Transaction>::new();
-//     // connect to node discovery or direclty to a node
+fn random_nonce() -> u64 {
+    let rng = rand::SystemRandom::new();
+    u64::from_le_bytes(rand::generate(&rng).unwrap().expose())
+}
 
-//     // get actual blockchain
+fn start_mining(blockchain: &Blockchain, mempool: &Mempool, payout: U256, miner: &mut Miner) {
+    let mut builder = BlockTemplateBuilder::new(payout);
+    builder.add_candidates(mempool.candidates());
 
-//     // listen new transaction
+    miner.start(builder.build(blockchain).block);
+}
 
-//     // take transactions from mempool
+/// Follows the chain over gossip, optionally mining on top of it and
+/// announcing the blocks found.
+async fn run_node(
+    mut network_client: network::Client,
+    mut network_events: impl Stream<Item = network::Event> + Unpin,
+    mine: bool,
+    threads: usize,
+    data_dir: PathBuf,
+) -> Result<(), Box<dyn Error>> {
+    let params = params();
+    let chain = Chain {
+        items: vec![genesis_block(&params)],
+        last_update: 0,
+    };
+    let time = NetworkTime::new(SystemClock);
+    let mut blockchain = TinyBlockchain::with_clock(chain, params, ProofOfWork, time);
+    let mut mempool = Mempool::new(MempoolPolicy::default());
+    let validator = GossipValidator::new(&mut blockchain);
+    let tip_changes = blockchain.subscribe();
+    let mempool_events = mempool.subscribe();
+    std::fs::create_dir_all(&data_dir)?;
+    let fee_estimates = data_dir.join("fee_estimates.json");
+    let mut fee_estimator = FeeEstimator::load(&fee_estimates)?;
+
+    let payout = pub_key_hash(generate_pk().public_key().as_ref());
+    let mut miner = Miner::new(threads);
+    if mine {
+        start_mining(&blockchain, &mempool, payout, &mut miner);
+    }
 
-//     // mine a new block
+    let mut ibd = InitialBlockDownload::new();
+    let mut pending_blocks = PendingBlocks::new();
+    let mut relay = TransactionRelay::new();
+
+    loop {
+        let tip = blockchain.tip().header_hash;
+
+        futures::select! {
+            event = network_events.next().fuse() => match event {
+                Some(network::Event::Gossip { message, message_id, source }) => {
+                    // A block we can't place means the peer knows headers we
+                    // don't, sync them.
+                    let orphan = match &*message {
+                        GossipMessage::Block(block) => {
+                            !blockchain.index().contains(&block.header.prev)
+                        }
+                        GossipMessage::CompactBlock(compact) => {
+                            !blockchain.index().contains(&compact.header.prev)
+                        }
+                        GossipMessage::Transaction(_) => false,
+                    };
+                    // Only a header that could extend our chain is worth asking
+                    // its peer for the missing transactions, anything else is
+                    // left to the validator.
+                    let partial = match &*message {
+                        GossipMessage::CompactBlock(compact)
+                            if blockchain.check_header(&compact.header).is_ok() =>
+                        {
+                            PartialBlock::new(compact, &mempool)
+                                .ok()
+                                .filter(|partial| !partial.is_complete())
+                        }
+                        _ => None,
+                    };
+
+                    if let Some(partial) = partial {
+                        let request = SyncRequest::GetBlockTransactions(partial.request());
+                        if pending_blocks.insert(partial, source, message_id.clone()) {
+                            network_client.send_sync_request(source, request).await;
+                        } else {
+                            network_client
+                                .report_validation(message_id, source, Acceptance::Ignore)
+                                .await;
+                        }
+                    } else {
+                        let acceptance =
+                            validator.validate(*message, &mut blockchain, &mut mempool);
+                        network_client
+                            .report_validation(message_id, source, acceptance)
+                            .await;
+                    }
+                    if orphan {
+                        ibd.request_headers(&source);
+                    }
+                }
+                Some(network::Event::PeerConnected(peer)) => {
+                    ibd.add_peer(peer);
+                    relay.add_peer(peer);
+                    network_client.send_sync_request(peer, SyncRequest::GetTime).await;
+                }
+                Some(network::Event::PeerDisconnected(peer)) => {
+                    ibd.remove_peer(&peer);
+                    relay.remove_peer(&peer);
+                    pending_blocks.remove_peer(&peer);
+                }
+                Some(network::Event::SyncRequest { request, channel, .. }) => {
+                    let response = handle_request(&blockchain, &request);
+                    network_client.respond_sync(response, channel).await;
+                }
+                Some(network::Event::SyncResponse { peer, response }) => {
+                    let result = match response {
+                        SyncResponse::Headers(headers) => {
+                            ibd.on_headers(&peer, headers, &mut blockchain)
+                        }
+                        SyncResponse::Blocks(blocks) => {
+                            ibd.on_blocks(&peer, blocks, &mut blockchain)
+                        }
+                        SyncResponse::BlockTransactions(transactions) => {
+                            if let Some((mut partial, message_id)) =
+                                pending_blocks.take(&peer, &transactions.block_hash)
+                            {
+                                let header = partial.header().clone();
+                                let acceptance = match partial
+                                    .fill(transactions)
+                                    .and_then(|()| partial.into_block(blockchain.index()))
+                                {
+                                    Ok(block) => validator.validate(
+                                        GossipMessage::Block(block),
+                                        &mut blockchain,
+                                        &mut mempool,
+                                    ),
+                                    // A mempool transaction collided with a short ID,
+                                    // indexing the header has the sync download the
+                                    // full block.
+                                    Err(CompactBlockError::BadMerkleRoot) => {
+                                        if let Err(err) = blockchain.add_header(header) {
+                                            eprintln!("Failed to add compact block header: {err}");
+                                        }
+                                        Acceptance::Ignore
+                                    }
+                                    Err(err) => {
+                                        eprintln!("Failed to rebuild compact block: {err}");
+                                        Acceptance::Ignore
+                                    }
+                                };
+                                network_client
+                                    .report_validation(message_id, peer, acceptance)
+                                    .await;
+                            }
+                            Ok(())
+                        }
+                        SyncResponse::Time(time) => {
+                            blockchain.clock_mut().add_sample(peer, time);
+                            Ok(())
+                        }
+                    };
+                    if let Err(err) = result {
+                        eprintln!("Disconnecting {peer}: {err}");
+                        ibd.remove_peer(&peer);
+                        network_client.disconnect(peer).await;
+                    }
+                }
+                Some(network::Event::SyncFailed { peer }) => {
+                    ibd.on_request_failed(&peer);
+                    pending_blocks.remove_peer(&peer);
+                }
+                Some(network::Event::RelayRequest { peer, request, channel }) => {
+                    let response = match request {
+                        RelayRequest::Inventory(txids) => {
+                            relay.on_inventory(&peer, txids, &mempool);
+                            RelayResponse::Ack
+                        }
+                        RelayRequest::GetData(txids) => relay.on_get_data(&peer, &txids, &mempool),
+                        RelayRequest::EstimateFee { target } => RelayResponse::FeeRate(
+                            fee_estimator.estimate_fee_rate(target as usize, DEFAULT_CONFIDENCE),
+                        ),
+                    };
+                    network_client.respond_relay(response, channel).await;
+                }
+                Some(network::Event::RelayResponse { peer, response }) => {
+                    if let RelayResponse::Transactions(transactions) = response {
+                        let now = SystemClock.now();
+                        relay.on_transactions(&peer, transactions, &blockchain, &mut mempool, now);
+                    }
+                }
+                Some(network::Event::RelayFailed { peer }) => relay.on_request_failed(&peer),
+                // Nothing is provided in node mode.
+                Some(network::Event::InboundRequest { .. }) => {}
+                None => return Ok(()),
+            },
+            _ = async_std::task::sleep(Duration::from_secs(1)).fuse() => {
+                for peer in ibd.check_stalls(SystemClock.now()) {
+                    eprintln!("Disconnecting stalled peer {peer}");
+                    network_client.disconnect(peer).await;
+                }
+
+                if let Some(block) = miner.try_block() {
+                    match blockchain.add_block(block.clone()) {
+                        Ok(()) => {
+                            println!(
+                                "Mined height {} {:#064x}",
+                                block.height, block.header_hash
+                            );
+                            let compact = CompactBlock::new(&block, random_nonce());
+                            network_client
+                                .publish(GossipMessage::CompactBlock(compact))
+                                .await;
+                        }
+                        // The template went stale, e.g. the tip moved while
+                        // it was being mined.
+                        Err(err) => {
+                            eprintln!("Mined block rejected: {err}");
+                            start_mining(&blockchain, &mempool, payout, &mut miner);
+                        }
+                    }
+                }
+            },
+        }
 
-//     // let new_bits = blockchain.retarget(0x1d00ffff);
-//     // let mined_block = blockchain.mine_block(vec![]);
+        for (peer, request) in ibd.poll(&blockchain, SystemClock.now()) {
+            network_client.send_sync_request(peer, request).await;
+        }
 
-//     // blockchain.add_block(mined_block);
+        validator.update_mempool(&blockchain, &mut mempool);
+        // Transactions are tracked before the blocks mining them are seen.
+        for event in mempool_events.try_iter() {
+            fee_estimator.process_mempool_event(&event);
+        }
+        for event in tip_changes.try_iter() {
+            for block in event
+                .connected
+                .iter()
+                .filter_map(|hash| blockchain.index().block(hash))
+            {
+                fee_estimator.process_block(block);
+            }
+            relay.on_tip_changed(&event, &blockchain, &mut mempool, SystemClock.now());
+            if let Err(err) = fee_estimator.save(&fee_estimates) {
+                eprintln!("Failed to save fee estimates: {err}");
+            }
+        }
+        for (peer, request) in relay.poll(&mempool, SystemClock.now()) {
+            network_client.send_relay_request(peer, request).await;
+        }
 
-//     println!("Miner node");
-// }
+        if blockchain.tip().header_hash != tip {
+            println!(
+                "New tip at height {} {:#064x}",
+                blockchain.tip().height,
+                blockchain.tip().header_hash
+            );
+            if mine {
+                start_mining(&blockchain, &mempool, payout, &mut miner);
+            }
+        }
+    }
+}
 
 mod network;
 
@@ -68,7 +317,7 @@ use clap::Parser;
 
 use futures::prelude::*;
 use futures::StreamExt;
-use libp2p::{core::Multiaddr, multiaddr::Protocol};
+use libp2p::{core::Multiaddr, multiaddr::Protocol, PeerId};
 use std::error::Error;
 use std::io::Write;
 use std::path::PathBuf;
@@ -113,6 +362,13 @@ async fn main() -> Result<(), Box<dyn Error>> {
     }
 
     match opt.argument {
+        CliArgument::Node {
+            mine,
+            threads,
+            data_dir,
+        } => {
+            run_node(network_client, network_events, mine, threads, data_dir).await?;
+        }
         // Providing a file.
         CliArgument::Provide { path, name } => {
             // Advertise oneself as a provider of the file on the DHT.
@@ -128,7 +384,8 @@ async fn main() -> Result<(), Box<dyn Error>> {
                                 .await;
                         }
                     }
-                    e => todo!("{:?}", e),
+                    Some(_) => {}
+                    None => break,
                 }
             }
         }
@@ -179,6 +436,17 @@ struct Opt {
 
 #[derive(Debug, Parser)]
 enum CliArgument {
+    /// Follow the chain over gossip.
+    Node {
+        /// Mine blocks on top of the best chain.
+        #[clap(long)]
+        mine: bool,
+        #[clap(long, default_value_t = 1)]
+        threads: usize,
+        /// Directory holding the fee estimates.
+        #[clap(long, default_value = "data")]
+        data_dir: PathBuf,
+    },
     Provide {
         #[clap(long)]
         path: PathBuf,
@@ -3,7 +3,7 @@ use futures::prelude::*;
 
 use libp2p::{
     core::Multiaddr,
-    identity, kad,
+    gossipsub, identity, kad,
     multiaddr::Protocol,
     noise,
     request_response::{self, OutboundRequestId, ProtocolSupport, ResponseChannel},
@@ -16,6 +16,12 @@ use serde::{Deserialize, Serialize};
 use std::collections::{hash_map, HashMap, HashSet};
 use std::error::Error;
 use std::time::Duration;
+use tiny_blockchain::gossip::{
+    gossipsub_config, Acceptance, GossipMessage, BLOCK_TOPIC, COMPACT_BLOCK_TOPIC,
+};
+use tiny_blockchain::relay::{RelayRequest, RelayResponse};
+use tiny_blockchain::sync::{SyncRequest, SyncResponse};
+use tiny_blockchain::{Decode, Encode};
 
 /// Creates the network components, namely:
 ///
@@ -46,18 +52,38 @@ pub(crate) async fn new(
             noise::Config::new,
             yamux::Config::default,
         )?
-        .with_behaviour(|key| Behaviour {
-            kademlia: kad::Behaviour::new(
-                peer_id,
-                kad::store::MemoryStore::new(key.public().to_peer_id()),
-            ),
-            request_response: request_response::cbor::Behaviour::new(
-                [(
-                    StreamProtocol::new("/file-exchange/1"),
-                    ProtocolSupport::Full,
-                )],
-                request_response::Config::default(),
-            ),
+        .with_behaviour(|key| {
+            Ok(Behaviour {
+                kademlia: kad::Behaviour::new(
+                    peer_id,
+                    kad::store::MemoryStore::new(key.public().to_peer_id()),
+                ),
+                request_response: request_response::cbor::Behaviour::new(
+                    [(
+                        StreamProtocol::new("/file-exchange/1"),
+                        ProtocolSupport::Full,
+                    )],
+                    request_response::Config::default(),
+                ),
+                sync: request_response::cbor::Behaviour::new(
+                    [(
+                        StreamProtocol::new("/tiny-blockchain/sync/1"),
+                        ProtocolSupport::Full,
+                    )],
+                    request_response::Config::default(),
+                ),
+                relay: request_response::cbor::Behaviour::new(
+                    [(
+                        StreamProtocol::new("/tiny-blockchain/relay/1"),
+                        ProtocolSupport::Full,
+                    )],
+                    request_response::Config::default(),
+                ),
+                gossipsub: gossipsub::Behaviour::new(
+                    gossipsub::MessageAuthenticity::Signed(key.clone()),
+                    gossipsub_config(),
+                )?,
+            })
         })?
         .with_swarm_config(|c| c.with_idle_connection_timeout(Duration::from_secs(60)))
         .build();
@@ -67,7 +93,17 @@ pub(crate) async fn new(
         .kademlia
         .set_mode(Some(kad::Mode::Server));
 
-    let (command_sender, command_receiver) = mpsc::channel(0);
+    // Transactions are relayed by inventory, see `relay`.
+    for topic in [BLOCK_TOPIC, COMPACT_BLOCK_TOPIC] {
+        swarm
+            .behaviour_mut()
+            .gossipsub
+            .subscribe(&gossipsub::IdentTopic::new(topic))?;
+    }
+
+    // Commands are unbounded so the application can publish while the event
+    // loop is waiting to hand it a gossip message.
+    let (command_sender, command_receiver) = mpsc::unbounded();
     let (event_sender, event_receiver) = mpsc::channel(0);
 
     Ok((
@@ -81,7 +117,7 @@ pub(crate) async fn new(
 
 #[derive(Clone)]
 pub(crate) struct Client {
-    sender: mpsc::Sender<Command>,
+    sender: mpsc::UnboundedSender<Command>,
 }
 
 impl Client {
@@ -165,23 +201,100 @@ impl Client {
             .await
             .expect("Command receiver not to be dropped.");
     }
+
+    /// Send a sync request to the given peer, the response comes back as an
+    /// event.
+    pub(crate) async fn send_sync_request(&mut self, peer: PeerId, request: SyncRequest) {
+        self.sender
+            .send(Command::SendSyncRequest { peer, request })
+            .await
+            .expect("Command receiver not to be dropped.");
+    }
+
+    /// Answer a peer's sync request.
+    pub(crate) async fn respond_sync(
+        &mut self,
+        response: SyncResponse,
+        channel: ResponseChannel<SyncResponseBytes>,
+    ) {
+        self.sender
+            .send(Command::RespondSync { response, channel })
+            .await
+            .expect("Command receiver not to be dropped.");
+    }
+
+    /// Send a relay request to the given peer, the response comes back as an
+    /// event.
+    pub(crate) async fn send_relay_request(&mut self, peer: PeerId, request: RelayRequest) {
+        self.sender
+            .send(Command::SendRelayRequest { peer, request })
+            .await
+            .expect("Command receiver not to be dropped.");
+    }
+
+    /// Answer a peer's relay request.
+    pub(crate) async fn respond_relay(
+        &mut self,
+        response: RelayResponse,
+        channel: ResponseChannel<RelayResponseBytes>,
+    ) {
+        self.sender
+            .send(Command::RespondRelay { response, channel })
+            .await
+            .expect("Command receiver not to be dropped.");
+    }
+
+    /// Close the connections to the given peer.
+    pub(crate) async fn disconnect(&mut self, peer: PeerId) {
+        self.sender
+            .send(Command::Disconnect { peer })
+            .await
+            .expect("Command receiver not to be dropped.");
+    }
+
+    /// Publish a block or transaction on its gossip topic.
+    pub(crate) async fn publish(&mut self, message: GossipMessage) {
+        self.sender
+            .send(Command::Publish { message })
+            .await
+            .expect("Command receiver not to be dropped.");
+    }
+
+    /// Report whether a gossiped message is valid, only accepted messages are
+    /// forwarded to other peers.
+    pub(crate) async fn report_validation(
+        &mut self,
+        message_id: gossipsub::MessageId,
+        source: PeerId,
+        acceptance: Acceptance,
+    ) {
+        self.sender
+            .send(Command::ReportValidation {
+                message_id,
+                source,
+                acceptance,
+            })
+            .await
+            .expect("Command receiver not to be dropped.");
+    }
 }
 
+type ResultSender<T> = oneshot::Sender<Result<T, Box<dyn Error + Send>>>;
+
 pub(crate) struct EventLoop {
     swarm: Swarm<Behaviour>,
-    command_receiver: mpsc::Receiver<Command>,
+    command_receiver: mpsc::UnboundedReceiver<Command>,
     event_sender: mpsc::Sender<Event>,
-    pending_dial: HashMap<PeerId, oneshot::Sender<Result<(), Box<dyn Error + Send>>>>,
+    pending_dial: HashMap<PeerId, ResultSender<()>>,
     pending_start_providing: HashMap<kad::QueryId, oneshot::Sender<()>>,
     pending_get_providers: HashMap<kad::QueryId, oneshot::Sender<HashSet<PeerId>>>,
-    pending_request_file:
-        HashMap<OutboundRequestId, oneshot::Sender<Result<Vec<u8>, Box<dyn Error + Send>>>>,
+    pending_request_file: HashMap<OutboundRequestId, ResultSender<Vec<u8>>>,
 }
 
 impl EventLoop {
     fn new(
         swarm: Swarm<Behaviour>,
-        command_receiver: mpsc::Receiver<Command>,
+        command_receiver: mpsc::UnboundedReceiver<Command>,
         event_sender: mpsc::Sender<Event>,
     ) -> Self {
         Self {
@@ -295,6 +408,101 @@ impl EventLoop {
             SwarmEvent::Behaviour(BehaviourEvent::RequestResponse(
                 request_response::Event::ResponseSent { .. },
             )) => {}
+            SwarmEvent::Behaviour(BehaviourEvent::Sync(request_response::Event::Message {
+                peer,
+                message,
+            })) => match message {
+                request_response::Message::Request {
+                    request, channel, ..
+                } => {
+                    // Malformed requests are dropped, closing the channel.
+                    if let Ok(request) = SyncRequest::from_bytes(&request.0) {
+                        self.event_sender
+                            .send(Event::SyncRequest { request, channel })
+                            .await
+                            .expect("Event receiver not to be dropped.");
+                    }
+                }
+                request_response::Message::Response { response, .. } => {
+                    let event = match SyncResponse::from_bytes(&response.0) {
+                        Ok(response) => Event::SyncResponse { peer, response },
+                        Err(_) => Event::SyncFailed { peer },
+                    };
+                    self.event_sender
+                        .send(event)
+                        .await
+                        .expect("Event receiver not to be dropped.");
+                }
+            },
+            SwarmEvent::Behaviour(BehaviourEvent::Sync(
+                request_response::Event::OutboundFailure { peer, .. },
+            )) => {
+                self.event_sender
+                    .send(Event::SyncFailed { peer })
+                    .await
+                    .expect("Event receiver not to be dropped.");
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Sync(_)) => {}
+            SwarmEvent::Behaviour(BehaviourEvent::Relay(request_response::Event::Message {
+                peer,
+                message,
+            })) => match message {
+                request_response::Message::Request {
+                    request, channel, ..
+                } => {
+                    // Malformed requests are dropped, closing the channel.
+                    if let Ok(request) = RelayRequest::from_bytes(&request.0) {
+                        self.event_sender
+                            .send(Event::RelayRequest {
+                                peer,
+                                request,
+                                channel,
+                            })
+                            .await
+                            .expect("Event receiver not to be dropped.");
+                    }
+                }
+                request_response::Message::Response { response, .. } => {
+                    let event = match RelayResponse::from_bytes(&response.0) {
+                        Ok(response) => Event::RelayResponse { peer, response },
+                        Err(_) => Event::RelayFailed { peer },
+                    };
+                    self.event_sender
+                        .send(event)
+                        .await
+                        .expect("Event receiver not to be dropped.");
+                }
+            },
+            SwarmEvent::Behaviour(BehaviourEvent::Relay(
+                request_response::Event::OutboundFailure { peer, .. },
+            )) => {
+                self.event_sender
+                    .send(Event::RelayFailed { peer })
+                    .await
+                    .expect("Event receiver not to be dropped.");
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Relay(_)) => {}
+            SwarmEvent::Behaviour(BehaviourEvent::Gossipsub(gossipsub::Event::Message {
+                propagation_source,
+                message_id,
+                message,
+            })) => match GossipMessage::decode(message.topic.as_str(), &message.data) {
+                Ok(message) => {
+                    self.event_sender
+                        .send(Event::Gossip {
+                            message: Box::new(message),
+                            message_id,
+                            source: propagation_source,
+                        })
+                        .await
+                        .expect("Event receiver not to be dropped.");
+                }
+                Err(err) => {
+                    eprintln!("Rejecting gossip from {propagation_source}: {err}");
+                    self.report_validation(&message_id, &propagation_source, Acceptance::Reject);
+                }
+            },
+            SwarmEvent::Behaviour(BehaviourEvent::Gossipsub(_)) => {}
             SwarmEvent::NewListenAddr { address, .. } => {
                 let local_peer_id = *self.swarm.local_peer_id();
                 eprintln!(
@@ -304,15 +512,35 @@ impl EventLoop {
             }
             SwarmEvent::IncomingConnection { .. } => {}
             SwarmEvent::ConnectionEstablished {
-                peer_id, endpoint, ..
+                peer_id,
+                endpoint,
+                num_established,
+                ..
             } => {
                 if endpoint.is_dialer() {
                     if let Some(sender) = self.pending_dial.remove(&peer_id) {
                         let _ = sender.send(Ok(()));
                     }
                 }
+                if num_established.get() == 1 {
+                    self.event_sender
+                        .send(Event::PeerConnected(peer_id))
+                        .await
+                        .expect("Event receiver not to be dropped.");
+                }
+            }
+            SwarmEvent::ConnectionClosed {
+                peer_id,
+                num_established,
+                ..
+            } => {
+                if num_established == 0 {
+                    self.event_sender
+                        .send(Event::PeerDisconnected(peer_id))
+                        .await
+                        .expect("Event receiver not to be dropped.");
+                }
             }
-            SwarmEvent::ConnectionClosed { .. } => {}
             SwarmEvent::OutgoingConnectionError { peer_id, error, .. } => {
                 if let Some(peer_id) = peer_id {
                     if let Some(sender) = self.pending_dial.remove(&peer_id) {
@@ -395,14 +623,78 @@ impl EventLoop {
                     .send_response(channel, FileResponse(file))
                     .expect("Connection to peer to be still open.");
             }
+            Command::SendSyncRequest { peer, request } => {
+                self.swarm
+                    .behaviour_mut()
+                    .sync
+                    .send_request(&peer, SyncRequestBytes(request.to_bytes()));
+            }
+            Command::RespondSync { response, channel } => {
+                // The peer may have gone away in the meantime.
+                let _ = self
+                    .swarm
+                    .behaviour_mut()
+                    .sync
+                    .send_response(channel, SyncResponseBytes(response.to_bytes()));
+            }
+            Command::SendRelayRequest { peer, request } => {
+                self.swarm
+                    .behaviour_mut()
+                    .relay
+                    .send_request(&peer, RelayRequestBytes(request.to_bytes()));
+            }
+            Command::RespondRelay { response, channel } => {
+                // The peer may have gone away in the meantime.
+                let _ = self
+                    .swarm
+                    .behaviour_mut()
+                    .relay
+                    .send_response(channel, RelayResponseBytes(response.to_bytes()));
+            }
+            Command::Disconnect { peer } => {
+                let _ = self.swarm.disconnect_peer_id(peer);
+            }
+            Command::Publish { message } => {
+                let topic = gossipsub::IdentTopic::new(message.topic());
+                if let Err(err) = self
+                    .swarm
+                    .behaviour_mut()
+                    .gossipsub
+                    .publish(topic, message.data())
+                {
+                    eprintln!("Failed to publish on {}: {err}", message.topic());
+                }
+            }
+            Command::ReportValidation {
+                message_id,
+                source,
+                acceptance,
+            } => self.report_validation(&message_id, &source, acceptance),
         }
     }
+
+    fn report_validation(
+        &mut self,
+        message_id: &gossipsub::MessageId,
+        source: &PeerId,
+        acceptance: Acceptance,
+    ) {
+        // The message may have left the cache already, nothing to forward then.
+        let _ = self
+            .swarm
+            .behaviour_mut()
+            .gossipsub
+            .report_message_validation_result(message_id, source, acceptance.into());
+    }
 }
 
 #[derive(NetworkBehaviour)]
 struct Behaviour {
     request_response: request_response::cbor::Behaviour<FileRequest, FileResponse>,
     kademlia: kad::Behaviour<kad::store::MemoryStore>,
+    sync: request_response::cbor::Behaviour<SyncRequestBytes, SyncResponseBytes>,
+    relay: request_response::cbor::Behaviour<RelayRequestBytes, RelayResponseBytes>,
+    gossipsub: gossipsub::Behaviour,
 }
 
 #[derive(Debug)]
@@ -433,6 +725,33 @@ enum Command {
         file: Vec<u8>,
         channel: ResponseChannel<FileResponse>,
     },
+    SendSyncRequest {
+        peer: PeerId,
+        request: SyncRequest,
+    },
+    RespondSync {
+        response: SyncResponse,
+        channel: ResponseChannel<SyncResponseBytes>,
+    },
+    SendRelayRequest {
+        peer: PeerId,
+        request: RelayRequest,
+    },
+    RespondRelay {
+        response: RelayResponse,
+        channel: ResponseChannel<RelayResponseBytes>,
+    },
+    Disconnect {
+        peer: PeerId,
+    },
+    Publish {
+        message: GossipMessage,
+    },
+    ReportValidation {
+        message_id: gossipsub::MessageId,
+        source: PeerId,
+        acceptance: Acceptance,
+    },
 }
 
 #[derive(Debug)]
@@ -441,6 +760,42 @@ pub(crate) enum Event {
         request: String,
         channel: ResponseChannel<FileResponse>,
     },
+    PeerConnected(PeerId),
+    PeerDisconnected(PeerId),
+    SyncRequest {
+        request: SyncRequest,
+        channel: ResponseChannel<SyncResponseBytes>,
+    },
+    SyncResponse {
+        peer: PeerId,
+        response: SyncResponse,
+    },
+    /// An outstanding sync request to `peer` failed or got a malformed
+    /// response.
+    SyncFailed {
+        peer: PeerId,
+    },
+    RelayRequest {
+        peer: PeerId,
+        request: RelayRequest,
+        channel: ResponseChannel<RelayResponseBytes>,
+    },
+    RelayResponse {
+        peer: PeerId,
+        response: RelayResponse,
+    },
+    /// An outstanding relay request to `peer` failed or got a malformed
+    /// response.
+    RelayFailed {
+        peer: PeerId,
+    },
+    /// A block or transaction gossiped by `source`, to be validated before
+    /// it's forwarded.
+    Gossip {
+        message: Box<GossipMessage>,
+        message_id: gossipsub::MessageId,
+        source: PeerId,
+    },
 }
 
 // Simple file exchange protocol
@@ -448,3 +803,13 @@ pub(crate) enum Event {
 struct FileRequest(String);
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub(crate) struct FileResponse(Vec<u8>);
+
+// Sync and relay messages travel in their canonical encoding.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct SyncRequestBytes(Vec<u8>);
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct SyncResponseBytes(Vec<u8>);
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct RelayRequestBytes(Vec<u8>);
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct RelayResponseBytes(Vec<u8>);
@@ -0,0 +1,601 @@
+use crate::{Block, BlockHeader, CompactTarget, Hash};
+use ethnum::U256;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+
+/// Expected number of hashes needed to meet the target encoded in `bits`,
+/// i.e. `2^256 / (target + 1)`. Bits that don't encode a positive target
+/// are worth nothing.
+pub fn block_work(bits: u32) -> U256 {
+    let target = match CompactTarget::new(bits).to_target() {
+        Ok(target) if target != U256::ZERO => target,
+        _ => return U256::ZERO,
+    };
+
+    // 2^256 doesn't fit, but 2^256 / (t + 1) == (2^256 - t - 1) / (t + 1) + 1
+    (!target / (target + 1)) + 1
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlockStatus {
+    /// Only the header is known.
+    HeaderOnly,
+    /// The full block is stored and hasn't failed validation.
+    HaveData,
+    /// The block, or one of its ancestors, failed validation.
+    Invalid,
+}
+
+#[derive(Clone, Debug)]
+pub struct BlockIndexEntry {
+    pub hash: U256,
+    pub header: BlockHeader,
+    pub height: usize,
+    /// Total work of the chain up to and including this block.
+    pub chainwork: U256,
+    pub status: BlockStatus,
+    // Order in which blocks were first seen, used to break ties in work.
+    sequence: u64,
+    // The bodies of the block and all of its ancestors are stored.
+    chain_available: bool,
+}
+
+impl BlockIndexEntry {
+    /// Orders candidates by work, and the first seen first among equals.
+    fn candidate_key(&self) -> (U256, Reverse<u64>) {
+        (self.chainwork, Reverse(self.sequence))
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum BlockIndexError {
+    UnknownParent(U256),
+    Duplicate(U256),
+}
+
+impl std::fmt::Display for BlockIndexError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BlockIndexError::UnknownParent(hash) => write!(f, "unknown parent {:#064x}", hash),
+            BlockIndexError::Duplicate(hash) => write!(f, "block {:#064x} is already known", hash),
+        }
+    }
+}
+
+impl std::error::Error for BlockIndexError {}
+
+/// Every known header arranged as a tree rooted at the genesis block, with
+/// the block bodies that have been received.
+#[derive(Debug)]
+pub struct BlockIndex {
+    // Weight each header adds to its chain, decided by the consensus engine.
+    work: fn(&BlockHeader) -> U256,
+    entries: HashMap<U256, BlockIndexEntry>,
+    children: HashMap<U256, Vec<U256>>,
+    blocks: HashMap<U256, Block>,
+    /// Valid blocks whose whole chain is stored, best last.
+    candidates: BTreeMap<(U256, Reverse<u64>), U256>,
+    /// The valid header with the most work, kept up to date as headers are
+    /// added or found invalid.
+    best_header: U256,
+    genesis: Option<U256>,
+    next_sequence: u64,
+}
+
+impl BlockIndex {
+    /// An index weighing blocks by proof of work.
+    pub fn new(genesis: Block) -> Self {
+        Self::with_work(genesis, |header| block_work(header.bits))
+    }
+
+    pub fn with_work(genesis: Block, work: fn(&BlockHeader) -> U256) -> Self {
+        let mut index = BlockIndex {
+            work,
+            entries: HashMap::new(),
+            children: HashMap::new(),
+            blocks: HashMap::new(),
+            candidates: BTreeMap::new(),
+            best_header: genesis.header_hash,
+            genesis: None,
+            next_sequence: 0,
+        };
+        let hash = genesis.header_hash;
+
+        index.insert_entry(hash, genesis.header.clone(), 0, work(&genesis.header));
+        index.blocks.insert(hash, genesis);
+        index.genesis = Some(hash);
+        index.entries.get_mut(&hash).unwrap().status = BlockStatus::HaveData;
+        index.add_candidates(hash);
+
+        index
+    }
+
+    pub fn genesis(&self) -> U256 {
+        self.genesis.expect("Index to have a genesis block")
+    }
+
+    pub fn get(&self, hash: &U256) -> Option<&BlockIndexEntry> {
+        self.entries.get(hash)
+    }
+
+    pub fn contains(&self, hash: &U256) -> bool {
+        self.entries.contains_key(hash)
+    }
+
+    pub fn block(&self, hash: &U256) -> Option<&Block> {
+        self.blocks.get(hash)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Adds a header whose parent is already indexed.
+    pub fn insert_header(
+        &mut self,
+        header: BlockHeader,
+    ) -> Result<&BlockIndexEntry, BlockIndexError> {
+        let hash = header.hash();
+
+        if self.entries.contains_key(&hash) {
+            return Err(BlockIndexError::Duplicate(hash));
+        }
+
+        let parent = self
+            .entries
+            .get(&header.prev)
+            .ok_or(BlockIndexError::UnknownParent(header.prev))?;
+        let height = parent.height + 1;
+        let chainwork = parent.chainwork + (self.work)(&header);
+        let invalid = parent.status == BlockStatus::Invalid;
+
+        self.insert_entry(hash, header, height, chainwork);
+        // Among equal work the header seen first stays the best.
+        if invalid {
+            self.entries.get_mut(&hash).unwrap().status = BlockStatus::Invalid;
+        } else if chainwork > self.entries[&self.best_header].chainwork {
+            self.best_header = hash;
+        }
+
+        Ok(&self.entries[&hash])
+    }
+
+    /// Adds a full block, indexing its header first if needed.
+    pub fn insert_block(&mut self, block: Block) -> Result<&BlockIndexEntry, BlockIndexError> {
+        let hash = block.header_hash;
+
+        match self.entries.get(&hash) {
+            Some(entry) if entry.status != BlockStatus::HeaderOnly => {
+                return Err(BlockIndexError::Duplicate(hash));
+            }
+            Some(_) => {}
+            None => {
+                self.insert_header(block.header.clone())?;
+            }
+        }
+
+        self.blocks.insert(hash, block);
+        let entry = self.entries.get_mut(&hash).unwrap();
+        if entry.status == BlockStatus::HeaderOnly {
+            entry.status = BlockStatus::HaveData;
+            self.add_candidates(hash);
+        }
+
+        Ok(&self.entries[&hash])
+    }
+
+    /// Forgets the body of a block whose header stays valid, so that it can
+    /// be stored again.
+    pub fn remove_block(&mut self, hash: &U256) -> Option<Block> {
+        let block = self.blocks.remove(hash)?;
+        if let Some(entry) = self.entries.get_mut(hash) {
+            if entry.status == BlockStatus::HaveData {
+                entry.status = BlockStatus::HeaderOnly;
+            }
+        }
+        self.remove_candidates(hash);
+        Some(block)
+    }
+
+    /// Marks a block and all of its descendants as invalid.
+    pub fn mark_invalid(&mut self, hash: &U256) {
+        let mut pending = vec![*hash];
+
+        while let Some(hash) = pending.pop() {
+            if let Some(entry) = self.entries.get_mut(&hash) {
+                entry.status = BlockStatus::Invalid;
+                if entry.chain_available {
+                    entry.chain_available = false;
+                    self.candidates.remove(&entry.candidate_key());
+                }
+            }
+            if let Some(children) = self.children.get(&hash) {
+                pending.extend(children.iter().copied());
+            }
+        }
+
+        // Only losing the best header needs a search for the next one.
+        if self.entries[&self.best_header].status == BlockStatus::Invalid {
+            self.best_header = self
+                .entries
+                .values()
+                .filter(|entry| entry.status != BlockStatus::Invalid)
+                .max_by(|a, b| {
+                    a.chainwork
+                        .cmp(&b.chainwork)
+                        .then_with(|| b.sequence.cmp(&a.sequence))
+                })
+                .expect("Genesis block to be valid")
+                .hash;
+        }
+    }
+
+    /// The ancestor of `hash` at `height`.
+    pub fn ancestor(&self, hash: &U256, height: usize) -> Option<&BlockIndexEntry> {
+        let mut entry = self.entries.get(hash)?;
+
+        if height > entry.height {
+            return None;
+        }
+
+        while entry.height > height {
+            entry = self.entries.get(&entry.header.prev)?;
+        }
+
+        Some(entry)
+    }
+
+    /// The last block both `a` and `b` descend from.
+    pub fn find_fork(&self, a: &U256, b: &U256) -> Option<&BlockIndexEntry> {
+        let height = self.get(a)?.height.min(self.get(b)?.height);
+        let mut a = self.ancestor(a, height)?;
+        let mut b = self.ancestor(b, height)?;
+
+        while a.hash != b.hash {
+            a = self.entries.get(&a.header.prev)?;
+            b = self.entries.get(&b.header.prev)?;
+        }
+
+        Some(a)
+    }
+
+    /// Hashes of the blocks after `ancestor` up to and including `hash`, in
+    /// chain order.
+    pub fn path_from(&self, ancestor: &U256, hash: &U256) -> Vec<U256> {
+        let mut path = Vec::new();
+        let mut current = self.entries.get(hash);
+
+        while let Some(entry) = current {
+            if entry.hash == *ancestor {
+                break;
+            }
+            path.push(entry.hash);
+            current = self.entries.get(&entry.header.prev);
+        }
+
+        path.reverse();
+        path
+    }
+
+    /// The valid block with the most cumulative work whose whole chain is
+    /// available. Among equal work the block seen first wins.
+    pub fn best_candidate(&self) -> Option<&BlockIndexEntry> {
+        let hash = self.candidates.values().next_back()?;
+        self.entries.get(hash)
+    }
+
+    /// The valid header with the most cumulative work, whether its block is
+    /// available or not.
+    pub fn best_header(&self) -> &BlockIndexEntry {
+        &self.entries[&self.best_header]
+    }
+
+    /// Hashes describing the chain ending at `hash` to a peer, dense near the
+    /// tip and exponentially sparser down to the genesis block, so that the
+    /// fork with the peer's chain is found in few round trips.
+    pub fn locator(&self, hash: &U256) -> Vec<U256> {
+        let Some(mut entry) = self.entries.get(hash) else {
+            return Vec::new();
+        };
+        let mut locator = Vec::new();
+        let mut step = 1;
+
+        loop {
+            locator.push(entry.hash);
+            if entry.height == 0 {
+                break;
+            }
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+            entry = self
+                .ancestor(&entry.hash, entry.height.saturating_sub(step))
+                .expect("Ancestors to be indexed");
+        }
+
+        locator
+    }
+
+    /// Makes the block `hash`, whose body just arrived, a candidate if its
+    /// parent's chain is stored, along with the descendants it completes.
+    fn add_candidates(&mut self, hash: U256) {
+        let entry = &self.entries[&hash];
+        let parent_available = entry.height == 0
+            || self
+                .entries
+                .get(&entry.header.prev)
+                .is_some_and(|parent| parent.chain_available);
+        if !parent_available {
+            return;
+        }
+
+        let mut pending = vec![hash];
+        while let Some(hash) = pending.pop() {
+            let entry = self.entries.get_mut(&hash).unwrap();
+            if entry.status != BlockStatus::HaveData || entry.chain_available {
+                continue;
+            }
+            entry.chain_available = true;
+            self.candidates.insert(entry.candidate_key(), hash);
+            if let Some(children) = self.children.get(&hash) {
+                pending.extend(children.iter().copied());
+            }
+        }
+    }
+
+    /// Withdraws the block `hash`, whose body is gone, and its descendants.
+    fn remove_candidates(&mut self, hash: &U256) {
+        let mut pending = vec![*hash];
+        while let Some(hash) = pending.pop() {
+            let Some(entry) = self.entries.get_mut(&hash) else {
+                continue;
+            };
+            if !entry.chain_available {
+                continue;
+            }
+            entry.chain_available = false;
+            self.candidates.remove(&entry.candidate_key());
+            if let Some(children) = self.children.get(&hash) {
+                pending.extend(children.iter().copied());
+            }
+        }
+    }
+
+    fn insert_entry(&mut self, hash: U256, header: BlockHeader, height: usize, chainwork: U256) {
+        if height > 0 {
+            self.children.entry(header.prev).or_default().push(hash);
+        }
+
+        self.entries.insert(
+            hash,
+            BlockIndexEntry {
+                hash,
+                header,
+                height,
+                chainwork,
+                status: BlockStatus::HeaderOnly,
+                sequence: self.next_sequence,
+                chain_available: false,
+            },
+        );
+        self.next_sequence += 1;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn header(prev: U256, nonce: u32, bits: u32) -> BlockHeader {
+        BlockHeader {
+            version: 1,
+            timestamp: 0,
+            prev,
+            merkle_root: U256::ZERO,
+            bits,
+            nonce,
+            extra: Vec::new(),
+        }
+    }
+
+    fn block(prev: &Block, nonce: u32, bits: u32) -> Block {
+        let header = header(prev.header_hash, nonce, bits);
+        Block::new(prev.height + 1, header.hash(), header, vec![])
+    }
+
+    fn genesis() -> Block {
+        let header = header(U256::ZERO, 0, 0x207fffff);
+        Block::new(0, header.hash(), header, vec![])
+    }
+
+    #[test]
+    fn test_block_work() {
+        // Bitcoin's genesis target requires 2^32 + 2^16 + 1 hashes on average.
+        assert_eq!(block_work(0x1d00ffff), U256::from(0x1_0001_0001u64));
+        assert_eq!(block_work(0x207fffff), U256::from(2u32));
+        assert!(block_work(0x1c00ffff) > block_work(0x1d00ffff));
+    }
+
+    #[test]
+    fn test_chainwork_accumulates() {
+        let genesis = genesis();
+        let mut index = BlockIndex::new(genesis.clone());
+        let child = block(&genesis, 1, 0x207fffff);
+        let entry = index.insert_block(child).unwrap();
+        assert_eq!(entry.height, 1);
+        assert_eq!(entry.chainwork, U256::from(4u32));
+    }
+
+    #[test]
+    fn test_rejects_orphan_and_duplicate() {
+        let genesis = genesis();
+        let mut index = BlockIndex::new(genesis.clone());
+        let child = block(&genesis, 1, 0x207fffff);
+        let orphan = block(&child, 2, 0x207fffff);
+
+        assert_eq!(
+            index.insert_block(orphan).unwrap_err(),
+            BlockIndexError::UnknownParent(child.header_hash)
+        );
+        index.insert_block(child.clone()).unwrap();
+        assert_eq!(
+            index.insert_block(child.clone()).unwrap_err(),
+            BlockIndexError::Duplicate(child.header_hash)
+        );
+    }
+
+    #[test]
+    fn test_best_candidate_by_work_not_length() {
+        let genesis = genesis();
+        let mut index = BlockIndex::new(genesis.clone());
+
+        // A longer branch of easy blocks...
+        let a1 = block(&genesis, 1, 0x207fffff);
+        let a2 = block(&a1, 2, 0x207fffff);
+        let a3 = block(&a2, 3, 0x207fffff);
+        // ...loses against a single harder block.
+        let b1 = block(&genesis, 4, 0x1f00ffff);
+
+        for block in [a1, a2, a3.clone(), b1.clone()] {
+            index.insert_block(block).unwrap();
+        }
+
+        assert_eq!(index.best_candidate().unwrap().hash, b1.header_hash);
+        assert_eq!(
+            index
+                .find_fork(&a3.header_hash, &b1.header_hash)
+                .unwrap()
+                .hash,
+            genesis.header_hash
+        );
+    }
+
+    #[test]
+    fn test_ties_go_to_first_seen() {
+        let genesis = genesis();
+        let mut index = BlockIndex::new(genesis.clone());
+        let first = block(&genesis, 1, 0x207fffff);
+        let second = block(&genesis, 2, 0x207fffff);
+        index.insert_block(first.clone()).unwrap();
+        index.insert_block(second).unwrap();
+
+        assert_eq!(index.best_candidate().unwrap().hash, first.header_hash);
+    }
+
+    #[test]
+    fn test_invalid_branch_is_skipped() {
+        let genesis = genesis();
+        let mut index = BlockIndex::new(genesis.clone());
+        let a1 = block(&genesis, 1, 0x207fffff);
+        let a2 = block(&a1, 2, 0x207fffff);
+        let b1 = block(&genesis, 3, 0x207fffff);
+        for block in [a1.clone(), a2.clone(), b1.clone()] {
+            index.insert_block(block).unwrap();
+        }
+
+        index.mark_invalid(&a1.header_hash);
+        assert_eq!(
+            index.get(&a2.header_hash).unwrap().status,
+            BlockStatus::Invalid
+        );
+        assert_eq!(index.best_candidate().unwrap().hash, b1.header_hash);
+
+        // Descendants of invalid blocks are invalid on arrival.
+        let a3 = block(&a2, 4, 0x207fffff);
+        let entry = index.insert_block(a3).unwrap();
+        assert_eq!(entry.status, BlockStatus::Invalid);
+    }
+
+    #[test]
+    fn test_header_only_blocks_are_not_candidates() {
+        let genesis = genesis();
+        let mut index = BlockIndex::new(genesis.clone());
+        let a1 = block(&genesis, 1, 0x207fffff);
+        let a2 = block(&a1, 2, 0x207fffff);
+        index.insert_header(a1.header.clone()).unwrap();
+        index.insert_block(a2.clone()).unwrap();
+
+        assert_eq!(index.best_candidate().unwrap().hash, genesis.header_hash);
+
+        index.insert_block(a1).unwrap();
+        assert_eq!(index.best_candidate().unwrap().hash, a2.header_hash);
+    }
+
+    #[test]
+    fn test_removed_body_is_not_a_candidate() {
+        let genesis = genesis();
+        let mut index = BlockIndex::new(genesis.clone());
+        let a1 = block(&genesis, 1, 0x207fffff);
+        let a2 = block(&a1, 2, 0x207fffff);
+        index.insert_block(a1.clone()).unwrap();
+        index.insert_block(a2.clone()).unwrap();
+
+        assert_eq!(index.remove_block(&a1.header_hash), Some(a1.clone()));
+        assert_eq!(
+            index.get(&a1.header_hash).unwrap().status,
+            BlockStatus::HeaderOnly
+        );
+        assert_eq!(index.best_candidate().unwrap().hash, genesis.header_hash);
+
+        index.insert_block(a1).unwrap();
+        assert_eq!(index.best_candidate().unwrap().hash, a2.header_hash);
+    }
+
+    #[test]
+    fn test_path_and_ancestor() {
+        let genesis = genesis();
+        let mut index = BlockIndex::new(genesis.clone());
+        let a1 = block(&genesis, 1, 0x207fffff);
+        let a2 = block(&a1, 2, 0x207fffff);
+        index.insert_block(a1.clone()).unwrap();
+        index.insert_block(a2.clone()).unwrap();
+
+        assert_eq!(
+            index.path_from(&genesis.header_hash, &a2.header_hash),
+            vec![a1.header_hash, a2.header_hash]
+        );
+        assert_eq!(
+            index.ancestor(&a2.header_hash, 1).unwrap().hash,
+            a1.header_hash
+        );
+        assert!(index.ancestor(&a1.header_hash, 2).is_none());
+    }
+
+    #[test]
+    fn test_best_header_and_locator() {
+        let genesis = genesis();
+        let mut index = BlockIndex::new(genesis.clone());
+        let mut blocks = vec![genesis];
+        for nonce in 1..=30 {
+            let block = block(blocks.last().unwrap(), nonce, 0x207fffff);
+            index.insert_header(block.header.clone()).unwrap();
+            blocks.push(block);
+        }
+
+        let tip = blocks[30].header_hash;
+        assert_eq!(index.best_header().hash, tip);
+        assert_eq!(index.best_candidate().unwrap().hash, blocks[0].header_hash);
+
+        let heights: Vec<usize> = index
+            .locator(&tip)
+            .iter()
+            .map(|hash| index.get(hash).unwrap().height)
+            .collect();
+        assert_eq!(
+            heights,
+            vec![30, 29, 28, 27, 26, 25, 24, 23, 22, 21, 19, 15, 7, 0]
+        );
+
+        index.mark_invalid(&blocks[20].header_hash);
+        assert_eq!(index.best_header().hash, blocks[19].header_hash);
+
+        // An equally heavy header seen later doesn't take over.
+        let sibling = block(&blocks[18], 100, 0x207fffff);
+        index.insert_header(sibling.header).unwrap();
+        assert_eq!(index.best_header().hash, blocks[19].header_hash);
+    }
+}
@@ -0,0 +1,472 @@
+use crate::{Block, Decode, Encode};
+use ethnum::U256;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Marks the start of every record in a block file.
+pub const BLOCK_FILE_MAGIC: [u8; 4] = *b"TBLK";
+/// A new block file is started once the current one reaches this size.
+pub const MAX_BLOCK_FILE_SIZE: u64 = 128 * 1024 * 1024;
+
+// magic, payload length (u32) and the first 4 bytes of sha256(payload)
+const RECORD_HEADER_SIZE: usize = 12;
+
+/// Where a block is stored on disk.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BlockPos {
+    pub file: u32,
+    /// Offset of the record header in the file.
+    pub offset: u64,
+    /// Length of the encoded block.
+    pub len: u32,
+}
+
+#[derive(Debug)]
+pub enum BlockStoreError {
+    Io(io::Error),
+    /// A record read back from disk doesn't match what was written.
+    Corrupted(BlockPos),
+}
+
+impl std::fmt::Display for BlockStoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BlockStoreError::Io(err) => write!(f, "block store io error: {}", err),
+            BlockStoreError::Corrupted(pos) => write!(
+                f,
+                "corrupted block record in file {} at offset {}",
+                pos.file, pos.offset
+            ),
+        }
+    }
+}
+
+impl std::error::Error for BlockStoreError {}
+
+impl From<io::Error> for BlockStoreError {
+    fn from(err: io::Error) -> Self {
+        BlockStoreError::Io(err)
+    }
+}
+
+fn checksum(payload: &[u8]) -> [u8; 4] {
+    let digest = Sha256::digest(payload);
+    [digest[0], digest[1], digest[2], digest[3]]
+}
+
+/// Append-only store of blocks in `blk00000.dat`, `blk00001.dat`, ... files.
+///
+/// Blocks are only ever appended, so the hash and height indexes are rebuilt
+/// by scanning the files on open. A record cut short by a crash fails its
+/// length or checksum check and is truncated away, everything before it is
+/// kept.
+#[derive(Debug)]
+pub struct BlockStore {
+    dir: PathBuf,
+    max_file_size: u64,
+    positions: HashMap<U256, BlockPos>,
+    heights: BTreeMap<usize, Vec<U256>>,
+    // Hashes in the order they were appended. During a headers-first sync a
+    // body may be accepted and stored before its parent's, so a block can
+    // come before its parent.
+    order: Vec<U256>,
+    current_file: u32,
+    current_size: u64,
+}
+
+impl BlockStore {
+    pub fn open(dir: impl AsRef<Path>) -> Result<Self, BlockStoreError> {
+        Self::open_with_max_file_size(dir, MAX_BLOCK_FILE_SIZE)
+    }
+
+    pub fn open_with_max_file_size(
+        dir: impl AsRef<Path>,
+        max_file_size: u64,
+    ) -> Result<Self, BlockStoreError> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let mut store = BlockStore {
+            dir,
+            max_file_size,
+            positions: HashMap::new(),
+            heights: BTreeMap::new(),
+            order: Vec::new(),
+            current_file: 0,
+            current_size: 0,
+        };
+        store.recover()?;
+
+        Ok(store)
+    }
+
+    fn file_path(&self, file: u32) -> PathBuf {
+        self.dir.join(format!("blk{:05}.dat", file))
+    }
+
+    /// Rebuilds the indexes from the block files. A file is truncated at its
+    /// first damaged record, the files after it are still read, so only the
+    /// blocks of the damaged file past that record are lost.
+    fn recover(&mut self) -> Result<(), BlockStoreError> {
+        let mut file = 0;
+
+        while self.file_path(file).exists() {
+            let path = self.file_path(file);
+            let bytes = fs::read(&path)?;
+            let valid_len = self.scan_file(file, &bytes);
+
+            self.current_file = file;
+            self.current_size = valid_len as u64;
+
+            if valid_len < bytes.len() {
+                OpenOptions::new()
+                    .write(true)
+                    .open(&path)?
+                    .set_len(valid_len as u64)?;
+            }
+
+            file += 1;
+        }
+
+        Ok(())
+    }
+
+    /// Indexes the records of one file and returns the length of its valid
+    /// prefix.
+    fn scan_file(&mut self, file: u32, bytes: &[u8]) -> usize {
+        let mut offset = 0;
+
+        while offset < bytes.len() {
+            let Some((block, record_len)) = Self::parse_record(&bytes[offset..]) else {
+                break;
+            };
+
+            let pos = BlockPos {
+                file,
+                offset: offset as u64,
+                len: (record_len - RECORD_HEADER_SIZE) as u32,
+            };
+            self.index(&block, pos);
+            offset += record_len;
+        }
+
+        offset
+    }
+
+    fn parse_record(bytes: &[u8]) -> Option<(Block, usize)> {
+        if bytes.len() < RECORD_HEADER_SIZE || bytes[..4] != BLOCK_FILE_MAGIC {
+            return None;
+        }
+
+        let len = u32::from_le_bytes(bytes[4..8].try_into().unwrap()) as usize;
+        let payload = bytes.get(RECORD_HEADER_SIZE..RECORD_HEADER_SIZE + len)?;
+        if bytes[8..12] != checksum(payload) {
+            return None;
+        }
+
+        let block = Block::from_bytes(payload).ok()?;
+        Some((block, RECORD_HEADER_SIZE + len))
+    }
+
+    fn index(&mut self, block: &Block, pos: BlockPos) {
+        let hash = block.header_hash;
+
+        if self.positions.insert(hash, pos).is_none() {
+            self.heights.entry(block.height).or_default().push(hash);
+            self.order.push(hash);
+        }
+    }
+
+    /// Writes `block` to the end of the current file and syncs it to disk.
+    /// Blocks that are already stored are not written again.
+    pub fn append(&mut self, block: &Block) -> Result<BlockPos, BlockStoreError> {
+        if let Some(pos) = self.positions.get(&block.header_hash) {
+            return Ok(*pos);
+        }
+
+        let payload = block.to_bytes();
+        let record_len = (RECORD_HEADER_SIZE + payload.len()) as u64;
+
+        if self.current_size > 0 && self.current_size + record_len > self.max_file_size {
+            self.current_file += 1;
+            self.current_size = 0;
+        }
+
+        let mut record = Vec::with_capacity(record_len as usize);
+        record.extend_from_slice(&BLOCK_FILE_MAGIC);
+        record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        record.extend_from_slice(&checksum(&payload));
+        record.extend_from_slice(&payload);
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.file_path(self.current_file))?;
+        if let Err(err) = file.write_all(&record).and_then(|_| file.sync_data()) {
+            // Don't leave a partial record for the next append to land after.
+            let _ = file.set_len(self.current_size);
+            return Err(err.into());
+        }
+
+        let pos = BlockPos {
+            file: self.current_file,
+            offset: self.current_size,
+            len: payload.len() as u32,
+        };
+        self.current_size += record_len;
+        self.index(block, pos);
+
+        Ok(pos)
+    }
+
+    pub fn get(&self, hash: &U256) -> Result<Option<Block>, BlockStoreError> {
+        match self.positions.get(hash) {
+            Some(pos) => self.read(*pos).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    pub fn read(&self, pos: BlockPos) -> Result<Block, BlockStoreError> {
+        let mut file = File::open(self.file_path(pos.file))?;
+        file.seek(SeekFrom::Start(pos.offset))?;
+
+        let mut record = vec![0; RECORD_HEADER_SIZE + pos.len as usize];
+        file.read_exact(&mut record)?;
+
+        match Self::parse_record(&record) {
+            Some((block, _)) => Ok(block),
+            None => Err(BlockStoreError::Corrupted(pos)),
+        }
+    }
+
+    pub fn position(&self, hash: &U256) -> Option<BlockPos> {
+        self.positions.get(hash).copied()
+    }
+
+    pub fn contains(&self, hash: &U256) -> bool {
+        self.positions.contains_key(hash)
+    }
+
+    /// Hashes of all stored blocks at `height`, including side branches.
+    pub fn hashes_at_height(&self, height: usize) -> &[U256] {
+        self.heights.get(&height).map_or(&[], Vec::as_slice)
+    }
+
+    pub fn max_height(&self) -> Option<usize> {
+        self.heights.keys().next_back().copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+
+    /// Reads back every block in the order it was appended.
+    pub fn blocks(&self) -> impl Iterator<Item = Result<Block, BlockStoreError>> + '_ {
+        self.order
+            .iter()
+            .map(|hash| self.read(self.positions[hash]))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{BlockHeader, Hash, Transaction, UtxoOutput};
+
+    fn block(prev: &Block, tag: u64) -> Block {
+        let header = BlockHeader {
+            version: 1,
+            timestamp: prev.header.timestamp + 600,
+            prev: prev.header_hash,
+            merkle_root: U256::ZERO,
+            bits: 0x207fffff,
+            nonce: tag as u32,
+            extra: Vec::new(),
+        };
+        let coinbase = Transaction::coinbase(
+            prev.height as u64 + 1,
+            tag,
+            vec![UtxoOutput::new(U256::from(tag), 50)],
+        );
+        Block::new(prev.height + 1, header.hash(), header, vec![coinbase])
+    }
+
+    fn genesis() -> Block {
+        let header = BlockHeader {
+            version: 1,
+            timestamp: 1_700_000_000,
+            prev: U256::ZERO,
+            merkle_root: U256::ZERO,
+            bits: 0x207fffff,
+            nonce: 0,
+            extra: Vec::new(),
+        };
+        Block::new(0, header.hash(), header, vec![])
+    }
+
+    fn chain(len: usize) -> Vec<Block> {
+        let mut blocks = vec![genesis()];
+        for tag in 1..len {
+            let next = block(blocks.last().unwrap(), tag as u64);
+            blocks.push(next);
+        }
+        blocks
+    }
+
+    fn file_len(dir: &Path, file: u32) -> u64 {
+        fs::metadata(dir.join(format!("blk{:05}.dat", file)))
+            .unwrap()
+            .len()
+    }
+
+    fn truncate(dir: &Path, file: u32, len: u64) {
+        OpenOptions::new()
+            .write(true)
+            .open(dir.join(format!("blk{:05}.dat", file)))
+            .unwrap()
+            .set_len(len)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_append_and_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let blocks = chain(5);
+
+        let mut store = BlockStore::open(dir.path()).unwrap();
+        for block in blocks.iter() {
+            store.append(block).unwrap();
+        }
+        drop(store);
+
+        let store = BlockStore::open(dir.path()).unwrap();
+        assert_eq!(store.len(), 5);
+        assert_eq!(store.max_height(), Some(4));
+        assert_eq!(store.hashes_at_height(2), &[blocks[2].header_hash]);
+        assert_eq!(
+            store.get(&blocks[3].header_hash).unwrap(),
+            Some(blocks[3].clone())
+        );
+        assert_eq!(
+            store.blocks().collect::<Result<Vec<_>, _>>().unwrap(),
+            blocks
+        );
+    }
+
+    #[test]
+    fn test_append_is_idempotent_and_indexes_forks() {
+        let dir = tempfile::tempdir().unwrap();
+        let blocks = chain(2);
+        let fork = block(&blocks[0], 99);
+
+        let mut store = BlockStore::open(dir.path()).unwrap();
+        let pos = store.append(&blocks[0]).unwrap();
+        assert_eq!(store.append(&blocks[0]).unwrap(), pos);
+        store.append(&blocks[1]).unwrap();
+        store.append(&fork).unwrap();
+
+        assert_eq!(store.len(), 3);
+        assert_eq!(
+            store.hashes_at_height(1),
+            &[blocks[1].header_hash, fork.header_hash]
+        );
+        assert!(store.hashes_at_height(2).is_empty());
+    }
+
+    #[test]
+    fn test_rolls_over_to_new_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let blocks = chain(4);
+
+        let mut store = BlockStore::open_with_max_file_size(dir.path(), 200).unwrap();
+        for block in blocks.iter() {
+            store.append(block).unwrap();
+        }
+
+        assert!(store.position(&blocks[3].header_hash).unwrap().file > 0);
+        drop(store);
+
+        let store = BlockStore::open_with_max_file_size(dir.path(), 200).unwrap();
+        assert_eq!(
+            store.blocks().collect::<Result<Vec<_>, _>>().unwrap(),
+            blocks
+        );
+    }
+
+    #[test]
+    fn test_recovers_from_truncated_record() {
+        let blocks = chain(4);
+
+        // Cut the last record at every point of its header and payload.
+        for kept in 0..RECORD_HEADER_SIZE as u64 + 200 {
+            let dir = tempfile::tempdir().unwrap();
+            let mut store = BlockStore::open(dir.path()).unwrap();
+            for block in blocks.iter() {
+                store.append(block).unwrap();
+            }
+            let last = store.position(&blocks[3].header_hash).unwrap();
+            drop(store);
+
+            if kept >= RECORD_HEADER_SIZE as u64 + last.len as u64 {
+                break;
+            }
+            truncate(dir.path(), 0, last.offset + kept);
+
+            let mut store = BlockStore::open(dir.path()).unwrap();
+            assert_eq!(store.len(), 3);
+            assert!(!store.contains(&blocks[3].header_hash));
+            assert_eq!(file_len(dir.path(), 0), last.offset);
+
+            // New blocks are written right after the recovered prefix.
+            store.append(&blocks[3]).unwrap();
+            drop(store);
+            let store = BlockStore::open(dir.path()).unwrap();
+            assert_eq!(
+                store.blocks().collect::<Result<Vec<_>, _>>().unwrap(),
+                blocks
+            );
+        }
+    }
+
+    #[test]
+    fn test_recovers_from_corrupted_record() {
+        let dir = tempfile::tempdir().unwrap();
+        let blocks = chain(3);
+
+        let mut store = BlockStore::open_with_max_file_size(dir.path(), 200).unwrap();
+        for block in blocks.iter() {
+            store.append(block).unwrap();
+        }
+        let pos = store.position(&blocks[1].header_hash).unwrap();
+        let last_file = store.position(&blocks[2].header_hash).unwrap().file;
+        assert!(last_file > pos.file);
+        drop(store);
+
+        // Flip a payload byte in the middle record, the files after it are
+        // kept.
+        let path = dir.path().join(format!("blk{:05}.dat", pos.file));
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[pos.offset as usize + RECORD_HEADER_SIZE + 1] ^= 0xff;
+        fs::write(&path, bytes).unwrap();
+
+        let mut store = BlockStore::open_with_max_file_size(dir.path(), 200).unwrap();
+        assert_eq!(store.len(), 2);
+        assert!(store.contains(&blocks[0].header_hash));
+        assert!(!store.contains(&blocks[1].header_hash));
+        assert!(store.contains(&blocks[2].header_hash));
+        assert_eq!(file_len(dir.path(), pos.file), pos.offset);
+
+        // The lost block can be stored again.
+        store.append(&blocks[1]).unwrap();
+        drop(store);
+        let store = BlockStore::open_with_max_file_size(dir.path(), 200).unwrap();
+        assert_eq!(store.len(), 3);
+    }
+}
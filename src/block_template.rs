@@ -0,0 +1,448 @@
+use crate::{
+    block_subsidy, Block, BlockHeader, Clock, ConsensusEngine, Encode, Hash, TinyBlockchain,
+    Transaction, UtxoOutput, MAX_BLOCK_SIZE,
+};
+use ethnum::U256;
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, BinaryHeap, HashMap, HashSet};
+
+/// Room left for the transaction count to grow in the encoded block.
+const TX_COUNT_SLACK: usize = 8;
+/// Packages in a row that may fail to fit once the block is within
+/// `BLOCK_FULL_MARGIN` bytes of full before selection gives up.
+const MAX_CONSECUTIVE_MISSES: usize = 1000;
+const BLOCK_FULL_MARGIN: usize = 4000;
+
+/// An unconfirmed transaction offered to a template with the fee it pays.
+/// Parents are found through its inputs, an input spending another candidate
+/// makes that candidate an ancestor which has to be included first.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TemplateCandidate {
+    pub tx: Transaction,
+    pub fee: u64,
+}
+
+impl TemplateCandidate {
+    pub fn new(tx: Transaction, fee: u64) -> Self {
+        TemplateCandidate { tx, fee }
+    }
+
+    fn weight(&self) -> usize {
+        self.tx.to_bytes().len()
+    }
+}
+
+/// Fee and weight of a candidate together with its ancestors not yet in the
+/// block. Orders by fee rate, the earlier candidate first on a tie.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct PackageScore {
+    fee: u64,
+    weight: usize,
+    index: usize,
+}
+
+impl Ord for PackageScore {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // Compared without dividing.
+        let rate = self.fee as u128 * other.weight as u128;
+        let other_rate = other.fee as u128 * self.weight as u128;
+        rate.cmp(&other_rate)
+            .then_with(|| other.index.cmp(&self.index))
+    }
+}
+
+impl PartialOrd for PackageScore {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// A block ready to be mined, its header commits to the transactions but
+/// isn't sealed.
+#[derive(Clone, Debug)]
+pub struct BlockTemplate {
+    pub block: Block,
+    /// Fees of the selected transactions, claimed by the coinbase.
+    pub fees: u64,
+}
+
+/// Builds the next block on the tip of a blockchain. Transactions are picked
+/// by the fee rate of the package they form with their unconfirmed
+/// ancestors, like Bitcoin Core does, so a child paying for its parent gets
+/// both mined.
+#[derive(Clone, Debug)]
+pub struct BlockTemplateBuilder {
+    payout: U256,
+    max_weight: usize,
+    candidates: Vec<TemplateCandidate>,
+}
+
+impl BlockTemplateBuilder {
+    /// A builder paying the coinbase to the public key hash `payout`.
+    pub fn new(payout: U256) -> Self {
+        BlockTemplateBuilder {
+            payout,
+            max_weight: MAX_BLOCK_SIZE,
+            candidates: Vec::new(),
+        }
+    }
+
+    /// Caps the encoded size of the block, at most `MAX_BLOCK_SIZE`.
+    pub fn with_max_weight(mut self, max_weight: usize) -> Self {
+        self.max_weight = max_weight.min(MAX_BLOCK_SIZE);
+        self
+    }
+
+    pub fn add_candidate(&mut self, candidate: TemplateCandidate) {
+        self.candidates.push(candidate);
+    }
+
+    pub fn add_candidates(&mut self, candidates: impl IntoIterator<Item = TemplateCandidate>) {
+        self.candidates.extend(candidates);
+    }
+
+    pub fn build<E: ConsensusEngine, C: Clock>(
+        &self,
+        blockchain: &TinyBlockchain<E, C>,
+    ) -> BlockTemplate {
+        let chain = blockchain.chain();
+        let tip = blockchain.tip();
+        let height = tip.height + 1;
+
+        let header = BlockHeader {
+            version: 1,
+            timestamp: blockchain.clock().now().max(chain.median_time_past() + 1),
+            prev: tip.header_hash,
+            merkle_root: U256::ZERO,
+            bits: blockchain.engine().next_bits(chain, blockchain.params()),
+            nonce: 0,
+            extra: Vec::new(),
+        };
+        // The coinbase value is fixed width, so claiming the fees later
+        // doesn't change the size.
+        let mut block = Block::new(height, U256::ZERO, header, vec![self.coinbase(height, 0)]);
+
+        let budget = self
+            .max_weight
+            .saturating_sub(block.to_bytes().len() + TX_COUNT_SLACK);
+        let selected = self.select(budget);
+        let fees = selected.iter().map(|&i| self.candidates[i].fee).sum();
+
+        block.transactions[0] = self.coinbase(height, block_subsidy(height) + fees);
+        for i in selected {
+            block.add_transaction(self.candidates[i].tx.clone());
+        }
+        block.header.merkle_root = block.compute_merkle_root();
+        block.header_hash = block.header.hash();
+
+        BlockTemplate { block, fees }
+    }
+
+    fn coinbase(&self, height: usize, value: u64) -> Transaction {
+        Transaction::coinbase(height as u64, 0, vec![UtxoOutput::new(self.payout, value)])
+    }
+
+    /// Indices of the candidates to include within `budget` bytes, parents
+    /// before their children.
+    fn select(&self, budget: usize) -> Vec<usize> {
+        let by_txid: HashMap<U256, usize> = self
+            .candidates
+            .iter()
+            .enumerate()
+            .map(|(i, candidate)| (candidate.tx.hash, i))
+            .collect();
+        let ancestors: Vec<BTreeSet<usize>> = (0..self.candidates.len())
+            .map(|i| self.ancestors(i, &by_txid))
+            .collect();
+        let mut descendants = vec![Vec::new(); self.candidates.len()];
+        for (i, ancestors) in ancestors.iter().enumerate() {
+            for &a in ancestors.iter().filter(|&&a| a != i) {
+                descendants[a].push(i);
+            }
+        }
+        let weights: Vec<usize> = self.candidates.iter().map(|c| c.weight()).collect();
+
+        // The package of a candidate is itself and its ancestors that aren't
+        // in the block yet. Scores are updated as ancestors get included, the
+        // heap keeps the outdated ones until they are popped.
+        let mut packages: Vec<PackageScore> = ancestors
+            .iter()
+            .enumerate()
+            .map(|(index, ancestors)| PackageScore {
+                fee: ancestors.iter().map(|&a| self.candidates[a].fee).sum(),
+                weight: ancestors.iter().map(|&a| weights[a]).sum(),
+                index,
+            })
+            .collect();
+        let mut queue: BinaryHeap<PackageScore> = packages.iter().copied().collect();
+
+        let mut included = HashSet::new();
+        let mut selected = Vec::new();
+        let mut used = 0;
+        let mut misses = 0;
+
+        while let Some(package) = queue.pop() {
+            if included.contains(&package.index) || package != packages[package.index] {
+                continue;
+            }
+
+            if used + package.weight > budget {
+                misses += 1;
+                if misses > MAX_CONSECUTIVE_MISSES && used + BLOCK_FULL_MARGIN > budget {
+                    break;
+                }
+                continue;
+            }
+            misses = 0;
+
+            // An ancestor has fewer ancestors than any of its descendants.
+            let mut members: Vec<usize> = ancestors[package.index]
+                .iter()
+                .copied()
+                .filter(|a| !included.contains(a))
+                .collect();
+            members.sort_by_key(|&a| (ancestors[a].len(), a));
+            for a in members {
+                included.insert(a);
+                selected.push(a);
+
+                for &d in descendants[a].iter().filter(|d| !included.contains(*d)) {
+                    packages[d].fee -= self.candidates[a].fee;
+                    packages[d].weight -= weights[a];
+                    queue.push(packages[d]);
+                }
+            }
+            used += package.weight;
+        }
+
+        selected
+    }
+
+    /// Candidate `index` and the candidates it depends on, directly or not.
+    fn ancestors(&self, index: usize, by_txid: &HashMap<U256, usize>) -> BTreeSet<usize> {
+        let mut ancestors = BTreeSet::new();
+        let mut pending = vec![index];
+
+        while let Some(i) = pending.pop() {
+            if !ancestors.insert(i) {
+                continue;
+            }
+            for input in &self.candidates[i].tx.inputs {
+                if let Some(parent) = input.prev_output().and_then(|p| by_txid.get(&p.txid)) {
+                    pending.push(*parent);
+                }
+            }
+        }
+
+        ancestors
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::{mature_blockchain, mine_block};
+    use crate::{Mempool, OutPoint, UtxoInput, INITIAL_SUBSIDY};
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    fn keypair() -> Ed25519KeyPair {
+        Ed25519KeyPair::from_seed_unchecked(&[3u8; 32]).unwrap()
+    }
+
+    /// A chain whose genesis pays `outputs` coins of `INITIAL_SUBSIDY / 8` to
+    /// the test key, already mature.
+    fn blockchain(outputs: usize) -> TinyBlockchain {
+        let value = INITIAL_SUBSIDY / 8;
+        mature_blockchain(
+            (0..outputs)
+                .map(|_| UtxoOutput::to_pub_key(keypair().public_key().as_ref(), value))
+                .collect(),
+        )
+    }
+
+    fn mine(blockchain: &mut TinyBlockchain, builder: BlockTemplateBuilder) -> Block {
+        let block = mine_block(blockchain, builder);
+        blockchain.add_block(block.clone()).unwrap();
+        block
+    }
+
+    /// Spends `outpoint` worth `value` to the test key, paying `fee`.
+    fn spend(outpoint: OutPoint, value: u64, fee: u64) -> TemplateCandidate {
+        let pub_key = keypair().public_key().as_ref().try_into().unwrap();
+        let mut tx = Transaction::new(
+            vec![UtxoInput::spend(outpoint, pub_key)],
+            vec![UtxoOutput::to_pub_key(&pub_key, value - fee)],
+        );
+        tx.sign(&keypair());
+        TemplateCandidate::new(tx, fee)
+    }
+
+    fn genesis_outpoint(blockchain: &TinyBlockchain, vout: u32) -> OutPoint {
+        OutPoint::new(blockchain.chain().items[0].transactions[0].hash, vout)
+    }
+
+    fn txids(block: &Block) -> Vec<U256> {
+        block.transactions[1..].iter().map(|tx| tx.hash).collect()
+    }
+
+    #[test]
+    fn test_empty_template() {
+        let blockchain = blockchain(0);
+        let payout = U256::from(7u32);
+        let template = BlockTemplateBuilder::new(payout).build(&blockchain);
+        let block = template.block;
+
+        assert_eq!(block.height, blockchain.tip().height + 1);
+        assert_eq!(block.header.prev, blockchain.tip().header_hash);
+        assert_eq!(block.header.merkle_root, block.compute_merkle_root());
+        assert!(block.header.timestamp > blockchain.chain().median_time_past());
+        assert_eq!(template.fees, 0);
+
+        let coinbase = &block.transactions[0];
+        assert_eq!(
+            coinbase.inputs[0].coinbase_height(),
+            Some(block.height as u64)
+        );
+        assert_eq!(
+            coinbase.outputs,
+            vec![UtxoOutput::new(payout, block_subsidy(block.height))]
+        );
+    }
+
+    #[test]
+    fn test_selects_by_ancestor_fee_rate() {
+        let mut blockchain = blockchain(3);
+        let value = INITIAL_SUBSIDY / 8;
+
+        let low = spend(genesis_outpoint(&blockchain, 0), value, 1_000);
+        let mid = spend(genesis_outpoint(&blockchain, 1), value, 5_000);
+        // A parent paying almost nothing, pulled in by its rich child.
+        let parent = spend(genesis_outpoint(&blockchain, 2), value, 10);
+        let child = spend(OutPoint::new(parent.tx.hash, 0), value - 10, 20_000);
+
+        let mut builder = BlockTemplateBuilder::new(U256::from(7u32));
+        builder.add_candidates([low.clone(), child.clone(), mid.clone(), parent.clone()]);
+        let template = builder.build(&blockchain);
+
+        assert_eq!(
+            txids(&template.block),
+            vec![parent.tx.hash, child.tx.hash, mid.tx.hash, low.tx.hash]
+        );
+        assert_eq!(template.fees, 1_000 + 5_000 + 10 + 20_000);
+        assert_eq!(
+            template.block.transactions[0].outputs[0].value(),
+            block_subsidy(template.block.height) + template.fees
+        );
+
+        // The template is a valid block once sealed.
+        let block = mine(&mut blockchain, builder);
+        assert_eq!(blockchain.tip().header_hash, block.header_hash);
+    }
+
+    #[test]
+    fn test_respects_weight_limit() {
+        let blockchain = blockchain(3);
+        let value = INITIAL_SUBSIDY / 8;
+        let candidates = [
+            spend(genesis_outpoint(&blockchain, 0), value, 1_000),
+            spend(genesis_outpoint(&blockchain, 1), value, 3_000),
+            spend(genesis_outpoint(&blockchain, 2), value, 2_000),
+        ];
+        let empty = BlockTemplateBuilder::new(U256::ONE)
+            .build(&blockchain)
+            .block;
+        let tx_weight = candidates[0].weight();
+
+        // Room for two transactions only, the best paying ones.
+        let limit = empty.to_bytes().len() + TX_COUNT_SLACK + 2 * tx_weight;
+        let mut builder = BlockTemplateBuilder::new(U256::ONE).with_max_weight(limit);
+        builder.add_candidates(candidates.clone());
+        let block = builder.build(&blockchain).block;
+
+        assert_eq!(
+            txids(&block),
+            vec![candidates[1].tx.hash, candidates[2].tx.hash]
+        );
+        assert!(block.to_bytes().len() <= limit);
+    }
+
+    #[test]
+    fn test_skips_package_that_does_not_fit() {
+        let blockchain = blockchain(2);
+        let value = INITIAL_SUBSIDY / 8;
+        let parent = spend(genesis_outpoint(&blockchain, 0), value, 10);
+        let child = spend(OutPoint::new(parent.tx.hash, 0), value - 10, 50_000);
+        let single = spend(genesis_outpoint(&blockchain, 1), value, 1_000);
+
+        let empty = BlockTemplateBuilder::new(U256::ONE)
+            .build(&blockchain)
+            .block;
+        let limit = empty.to_bytes().len() + TX_COUNT_SLACK + single.weight();
+        let mut builder = BlockTemplateBuilder::new(U256::ONE).with_max_weight(limit);
+        builder.add_candidates([parent, child, single.clone()]);
+
+        assert_eq!(
+            txids(&builder.build(&blockchain).block),
+            vec![single.tx.hash]
+        );
+    }
+
+    #[test]
+    fn test_mines_mempool_package() {
+        let mut blockchain = blockchain(2);
+        let value = INITIAL_SUBSIDY / 8;
+        let height = blockchain.tip().height + 1;
+        let mut mempool = Mempool::default();
+
+        // The parent pays nothing and only enters with a child paying for it.
+        let parent = spend(genesis_outpoint(&blockchain, 0), value, 0);
+        let child = spend(OutPoint::new(parent.tx.hash, 0), value, 10_000);
+        let single = spend(genesis_outpoint(&blockchain, 1), value, 3_000);
+        assert!(mempool
+            .add(parent.tx.clone(), blockchain.utxo(), height)
+            .is_err());
+        mempool
+            .add_package(
+                vec![parent.tx.clone(), child.tx.clone()],
+                blockchain.utxo(),
+                height,
+            )
+            .unwrap();
+        mempool
+            .add(single.tx.clone(), blockchain.utxo(), height)
+            .unwrap();
+
+        // Room for two transactions, the package pays a higher fee rate.
+        let empty = BlockTemplateBuilder::new(U256::ONE)
+            .build(&blockchain)
+            .block;
+        let limit = empty.to_bytes().len() + TX_COUNT_SLACK + 2 * single.weight();
+        let mut builder = BlockTemplateBuilder::new(U256::ONE).with_max_weight(limit);
+        builder.add_candidates(mempool.candidates());
+        let block = mine(&mut blockchain, builder);
+        assert_eq!(txids(&block), vec![parent.tx.hash, child.tx.hash]);
+
+        mempool.remove_for_block(&block);
+        assert_eq!(mempool.len(), 1);
+        assert!(mempool.contains(&single.tx.hash));
+    }
+
+    #[test]
+    fn test_rescores_children_of_included_packages() {
+        let blockchain = blockchain(2);
+        let value = INITIAL_SUBSIDY / 8;
+
+        // The child's package rate is high only thanks to its parent, once
+        // the parent is in the child pays less than `other`.
+        let parent = spend(genesis_outpoint(&blockchain, 0), value, 50_000);
+        let child = spend(OutPoint::new(parent.tx.hash, 0), value - 50_000, 1_000);
+        let other = spend(genesis_outpoint(&blockchain, 1), value, 2_000);
+
+        let mut builder = BlockTemplateBuilder::new(U256::ONE);
+        builder.add_candidates([child.clone(), other.clone(), parent.clone()]);
+        assert_eq!(
+            txids(&builder.build(&blockchain).block),
+            vec![parent.tx.hash, other.tx.hash, child.tx.hash]
+        );
+    }
+}
@@ -1,6 +1,13 @@
-use crate::{pow_validate, seconds_now, Block, BlockHeader, Hash, Transaction};
+use crate::{
+    check_block, connect_block, Block, BlockHeader, BlockIndex, BlockIndexError, BlockStatus,
+    BlockStore, BlockStoreError, BlockUndo, Clock, CompactTarget, ConsensusEngine,
+    DifficultyAlgorithm, Hash, ProofOfWork, SystemClock, Transaction, UtxoSet, ValidationError,
+    MEDIAN_TIME_SPAN,
+};
 use ethnum::*;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::mpsc;
 
 const DEFAULT_DIFFICULTY_TARGET: u32 = 0x1d00ffff;
 
@@ -17,7 +24,10 @@ impl Chain {
 
     pub fn add_block(&mut self, block: Block) {
         self.items.push(block);
-        self.last_update = seconds_now();
+    }
+
+    pub fn pop_block(&mut self) -> Option<Block> {
+        self.items.pop()
     }
 
     pub fn len(&self) -> usize {
@@ -28,67 +38,553 @@ impl Chain {
         self.items.is_empty()
     }
 
-    pub fn is_valid(&self) -> bool {
-        pow_validate(&self.items)
+    /// Checks that the blocks are linked and sealed according to `engine`.
+    pub fn is_valid<E: ConsensusEngine>(&self, engine: &E) -> bool {
+        if self.items.is_empty() {
+            return false;
+        }
+
+        self.items.windows(2).all(|pair| {
+            let (prev_block, block) = (&pair[0], &pair[1]);
+            block.header.prev == prev_block.header_hash
+                && block.header_hash == block.header.hash()
+                && engine.verify_seal(block).is_ok()
+        })
     }
 
     pub fn previous_block(&self) -> Option<&Block> {
         self.items.last()
     }
+
+    /// Median timestamp of the last `MEDIAN_TIME_SPAN` blocks, or of all of
+    /// them on a shorter chain. Zero for an empty chain.
+    pub fn median_time_past(&self) -> u64 {
+        let start = self.items.len().saturating_sub(MEDIAN_TIME_SPAN);
+        let mut timestamps: Vec<u64> = self.items[start..]
+            .iter()
+            .map(|block| block.header.timestamp)
+            .collect();
+        timestamps.sort_unstable();
+
+        timestamps.get(timestamps.len() / 2).copied().unwrap_or(0)
+    }
 }
 
-enum TinyBlockchainError {
-    IvalidChain,
+#[derive(Debug, PartialEq, Eq)]
+pub enum TinyBlockchainError {
+    /// The parent of the block is unknown, it can't be placed in the tree.
+    UnknownParent(U256),
+    DuplicateBlock(U256),
+    /// The block or one of its ancestors was already found to be invalid.
+    KnownInvalid(U256),
+    InvalidBlock(ValidationError),
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+impl std::fmt::Display for TinyBlockchainError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TinyBlockchainError::UnknownParent(hash) => {
+                write!(f, "unknown parent block {:#064x}", hash)
+            }
+            TinyBlockchainError::DuplicateBlock(hash) => {
+                write!(f, "block {:#064x} is already known", hash)
+            }
+            TinyBlockchainError::KnownInvalid(hash) => {
+                write!(f, "block {:#064x} is known to be invalid", hash)
+            }
+            TinyBlockchainError::InvalidBlock(err) => write!(f, "invalid block: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for TinyBlockchainError {}
+
+impl From<ValidationError> for TinyBlockchainError {
+    fn from(err: ValidationError) -> Self {
+        TinyBlockchainError::InvalidBlock(err)
+    }
+}
+
+impl From<BlockIndexError> for TinyBlockchainError {
+    fn from(err: BlockIndexError) -> Self {
+        match err {
+            BlockIndexError::UnknownParent(hash) => TinyBlockchainError::UnknownParent(hash),
+            BlockIndexError::Duplicate(hash) => TinyBlockchainError::DuplicateBlock(hash),
+        }
+    }
+}
+
+/// Sent to subscribers whenever the active chain gets a new tip.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TipChanged {
+    pub old_tip: U256,
+    pub new_tip: U256,
+    pub height: usize,
+    /// Blocks removed from the active chain, tip first.
+    pub disconnected: Vec<U256>,
+    /// Blocks added to the active chain, in chain order.
+    pub connected: Vec<U256>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct TinyBlockchainParams {
     pub blocks_in_epoch: usize,
     pub init_difficulty: u32,
-    pub epoch: u64,
+    /// Expected seconds between blocks.
+    pub target_spacing: u64,
+    /// Most a retarget may scale the target by, in either direction.
+    pub max_adjustment: u64,
+    /// Bits of the easiest target allowed.
+    pub pow_limit: u32,
+    /// How the target follows the hashrate.
+    pub difficulty: DifficultyAlgorithm,
+    /// Seconds a block timestamp may be ahead of the blockchain's clock,
+    /// network adjusted time on a node.
+    pub max_future_drift: u64,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct TinyBlockchain {
+#[derive(Debug, PartialEq, Eq)]
+pub enum ParamsError {
+    /// A parameter that is divided by is zero.
+    Zero(&'static str),
+    InvalidTarget(u32),
+    /// The first blocks would be easier than the proof of work limit.
+    InitDifficultyAboveLimit {
+        init_difficulty: u32,
+        pow_limit: u32,
+    },
+}
+
+impl std::fmt::Display for ParamsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParamsError::Zero(name) => write!(f, "{} must not be zero", name),
+            ParamsError::InvalidTarget(bits) => write!(f, "bits {:#010x} aren't a target", bits),
+            ParamsError::InitDifficultyAboveLimit {
+                init_difficulty,
+                pow_limit,
+            } => write!(
+                f,
+                "initial difficulty {:#010x} is easier than the limit {:#010x}",
+                init_difficulty, pow_limit
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ParamsError {}
+
+impl TinyBlockchainParams {
+    /// Checks that the parameters can drive difficulty adjustment.
+    pub fn validate(&self) -> Result<(), ParamsError> {
+        if self.blocks_in_epoch == 0 {
+            return Err(ParamsError::Zero("blocks_in_epoch"));
+        }
+        if self.target_spacing == 0 {
+            return Err(ParamsError::Zero("target_spacing"));
+        }
+        if self.max_adjustment == 0 {
+            return Err(ParamsError::Zero("max_adjustment"));
+        }
+        match self.difficulty {
+            DifficultyAlgorithm::Lwma { window: 0 } => return Err(ParamsError::Zero("window")),
+            DifficultyAlgorithm::Asert { half_life: 0 } => {
+                return Err(ParamsError::Zero("half_life"))
+            }
+            _ => {}
+        }
+
+        let target = |bits| {
+            CompactTarget::new(bits)
+                .to_target()
+                .map_err(|_| ParamsError::InvalidTarget(bits))
+        };
+        if target(self.init_difficulty)? > target(self.pow_limit)? {
+            return Err(ParamsError::InitDifficultyAboveLimit {
+                init_difficulty: self.init_difficulty,
+                pow_limit: self.pow_limit,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Expected duration of an epoch in seconds.
+    pub fn epoch_timespan(&self) -> u64 {
+        self.blocks_in_epoch as u64 * self.target_spacing
+    }
+}
+
+#[derive(Debug)]
+pub struct TinyBlockchain<E: ConsensusEngine = ProofOfWork, C: Clock = SystemClock> {
     chain: Chain,
     params: TinyBlockchainParams,
+    engine: E,
+    utxo: UtxoSet,
+    index: BlockIndex,
+    undo: HashMap<U256, BlockUndo>,
+    clock: C,
+    subscribers: Vec<mpsc::Sender<TipChanged>>,
 }
 
 impl TinyBlockchain {
+    /// A proof of work blockchain, see `with_engine`.
     pub fn new(chain: Chain, params: TinyBlockchainParams) -> Self {
-        TinyBlockchain { chain, params }
+        Self::with_engine(chain, params, ProofOfWork)
     }
 
-    fn init_genesis_block(transactions: Vec<Transaction>) -> Block {
+    fn init_genesis_block(timestamp: u64, transactions: Vec<Transaction>) -> Block {
         let block_header = BlockHeader {
             version: 1,
             prev: 0.as_u256(),
             merkle_root: 0.as_u256(),
-            timestamp: seconds_now(),
+            timestamp,
             bits: DEFAULT_DIFFICULTY_TARGET,
             nonce: 1,
+            extra: Vec::new(),
         };
         Block::new(0, block_header.hash(), block_header, transactions)
     }
+}
+
+impl<E: ConsensusEngine> TinyBlockchain<E> {
+    /// A blockchain on the system clock, see `with_clock`.
+    pub fn with_engine(chain: Chain, params: TinyBlockchainParams, engine: E) -> Self {
+        Self::with_clock(chain, params, engine, SystemClock)
+    }
+}
+
+impl<E: ConsensusEngine, C: Clock> TinyBlockchain<E, C> {
+    /// Creates the blockchain from a trusted, non-empty initial chain, e.g.
+    /// the genesis block, whose outputs seed the UTXO set. Block timestamps
+    /// are checked against `clock`, a `NetworkTime` adjusts it by the peers'
+    /// clocks.
+    pub fn with_clock(chain: Chain, params: TinyBlockchainParams, engine: E, clock: C) -> Self {
+        if let Err(err) = params.validate() {
+            panic!("Invalid blockchain params: {err}");
+        }
+        let genesis = chain
+            .items
+            .first()
+            .expect("Initial chain to have a genesis block");
+        let mut index = BlockIndex::with_work(genesis.clone(), E::block_work);
+        let mut utxo = UtxoSet::new();
+        let mut undo = HashMap::new();
+
+        for (height, block) in chain.items.iter().enumerate() {
+            if height > 0 {
+                index
+                    .insert_block(block.clone())
+                    .expect("Initial chain to be linked");
+            }
+            let block_undo = utxo
+                .apply_block(block)
+                .expect("Initial chain to have consistent spends");
+            undo.insert(block.header_hash, block_undo);
+        }
 
-    fn add_block(&mut self, block: Block) {
-        self.chain.add_block(block);
+        TinyBlockchain {
+            chain,
+            params,
+            engine,
+            utxo,
+            index,
+            undo,
+            clock,
+            subscribers: Vec::new(),
+        }
     }
 
-    // fn create_block(&self, block_header: BlockHeader, transactions: Vec<Transaction>) -> Block {
-    //     Block::new(self.chain.len() + 1, block_header, transactions)
-    // }
+    /// Restores the blockchain from `store`, writing `genesis` first if the
+    /// store is empty. Stored blocks are validated again as they are
+    /// replayed, the ones that fail are skipped like they were when first
+    /// received, so the node resumes from the same most-work tip.
+    pub fn open(
+        store: &mut BlockStore,
+        genesis: Block,
+        params: TinyBlockchainParams,
+        engine: E,
+        clock: C,
+    ) -> Result<Self, BlockStoreError> {
+        if store.is_empty() {
+            store.append(&genesis)?;
+        }
+
+        let mut blocks = store.blocks();
+        let genesis = blocks.next().expect("Store to have a genesis block")?;
+        let chain = Chain {
+            items: vec![genesis],
+            last_update: clock.now(),
+        };
+        let mut blockchain = TinyBlockchain::with_clock(chain, params, engine, clock);
 
-    fn set_chain(&mut self, new_chain: Chain) -> Result<(), TinyBlockchainError> {
-        if !pow_validate(&new_chain.items) || self.chain.last_update >= new_chain.last_update {
-            return Err(TinyBlockchainError::IvalidChain);
+        // A block stored again after recovery comes after its descendants,
+        // those wait for it.
+        let mut orphans: HashMap<U256, Vec<Block>> = HashMap::new();
+        for block in blocks {
+            let mut pending = vec![block?];
+            while let Some(block) = pending.pop() {
+                if !blockchain.index().contains(&block.header.prev) {
+                    orphans.entry(block.header.prev).or_default().push(block);
+                    continue;
+                }
+                let hash = block.header_hash;
+                let _ = blockchain.add_block(block);
+                pending.extend(orphans.remove(&hash).unwrap_or_default());
+            }
         }
 
-        self.chain = new_chain;
+        Ok(blockchain)
+    }
+
+    pub fn chain(&self) -> &Chain {
+        &self.chain
+    }
+
+    pub fn params(&self) -> &TinyBlockchainParams {
+        &self.params
+    }
+
+    pub fn engine(&self) -> &E {
+        &self.engine
+    }
+
+    pub fn engine_mut(&mut self) -> &mut E {
+        &mut self.engine
+    }
+
+    pub fn utxo(&self) -> &UtxoSet {
+        &self.utxo
+    }
+
+    pub fn index(&self) -> &BlockIndex {
+        &self.index
+    }
+
+    /// The clock block timestamps are checked against.
+    pub fn clock(&self) -> &C {
+        &self.clock
+    }
+
+    /// Lets peers feed their time to a `NetworkTime` clock.
+    pub fn clock_mut(&mut self) -> &mut C {
+        &mut self.clock
+    }
+
+    pub fn tip(&self) -> &Block {
+        self.chain
+            .previous_block()
+            .expect("Active chain to contain the genesis block")
+    }
+
+    /// Receives a `TipChanged` for every change of the active chain.
+    pub fn subscribe(&mut self) -> mpsc::Receiver<TipChanged> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers.push(sender);
+        receiver
+    }
+
+    /// Adds `block` to the block tree and switches the active chain to the
+    /// valid branch with the most cumulative work, reorganizing if needed.
+    pub fn add_block(&mut self, block: Block) -> Result<(), TinyBlockchainError> {
+        let hash = block.header_hash;
+
+        // The header may have arrived first, then only the body is new.
+        match self.index.get(&hash).map(|entry| entry.status) {
+            Some(BlockStatus::Invalid) => return Err(TinyBlockchainError::KnownInvalid(hash)),
+            Some(BlockStatus::HaveData) => return Err(TinyBlockchainError::DuplicateBlock(hash)),
+            Some(BlockStatus::HeaderOnly) | None => {}
+        }
+
+        check_block(&block, &self.engine)?;
+        self.check_timestamp(&block.header)?;
+
+        // The header hash doesn't cover the height, a wrong one must not get
+        // the hash marked invalid.
+        if let Some(parent) = self.index.get(&block.header.prev) {
+            let expected = parent.height + 1;
+            if block.height != expected {
+                return Err(ValidationError::BadHeight {
+                    expected,
+                    found: block.height,
+                }
+                .into());
+            }
+            self.engine
+                .verify_branch(&block.header, &self.index, &self.params)?;
+        }
+
+        if self.index.insert_block(block)?.status == BlockStatus::Invalid {
+            return Err(TinyBlockchainError::KnownInvalid(hash));
+        }
+
+        for (invalid, err) in self.activate_best_chain() {
+            if invalid == hash {
+                return Err(err.into());
+            }
+        }
 
         Ok(())
     }
 
+    /// Adds a header ahead of its block, so that the body can be downloaded
+    /// later. Only what the header proves on its own is checked, the rest
+    /// waits until the block connects.
+    pub fn add_header(&mut self, header: BlockHeader) -> Result<(), TinyBlockchainError> {
+        let hash = header.hash();
+        match self.check_header(&header) {
+            Ok(()) | Err(TinyBlockchainError::UnknownParent(_)) => {}
+            Err(err) => return Err(err),
+        }
+
+        if self.index.insert_header(header)?.status == BlockStatus::Invalid {
+            return Err(TinyBlockchainError::KnownInvalid(hash));
+        }
+
+        Ok(())
+    }
+
+    /// Checks what [`add_header`](Self::add_header) would without indexing
+    /// the header, which also has to extend a known block.
+    pub fn check_header(&self, header: &BlockHeader) -> Result<(), TinyBlockchainError> {
+        let hash = header.hash();
+
+        if let Some(entry) = self.index.get(&hash) {
+            return Err(match entry.status {
+                BlockStatus::Invalid => TinyBlockchainError::KnownInvalid(hash),
+                _ => TinyBlockchainError::DuplicateBlock(hash),
+            });
+        }
+
+        let block = Block::new(0, hash, header.clone(), vec![]);
+        self.engine.verify_seal(&block)?;
+        self.check_timestamp(&block.header)?;
+        if !self.index.contains(&block.header.prev) {
+            return Err(TinyBlockchainError::UnknownParent(block.header.prev));
+        }
+        self.engine
+            .verify_branch(&block.header, &self.index, &self.params)?;
+
+        Ok(())
+    }
+
+    // A block from the future may become valid later, so it's turned away
+    // before the index could remember it as invalid.
+    fn check_timestamp(&self, header: &BlockHeader) -> Result<(), ValidationError> {
+        let max = self.clock.now() + self.params.max_future_drift;
+        if header.timestamp > max {
+            return Err(ValidationError::TimestampTooLate {
+                max,
+                found: header.timestamp,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Moves the active chain to the best valid candidate. Blocks that fail to
+    /// connect are marked invalid together with their descendants and the
+    /// next best candidate is tried, which may be the previous tip. A failure
+    /// the header doesn't commit to only drops the stored body, so the block
+    /// can still be received again.
+    fn activate_best_chain(&mut self) -> Vec<(U256, ValidationError)> {
+        let old_tip = self.tip().header_hash;
+        let mut failures = Vec::new();
+
+        loop {
+            let tip = self.tip().header_hash;
+            let Some(best) = self.index.best_candidate() else {
+                break;
+            };
+            // The tip is always a candidate, so it only loses to more work or
+            // to an equally heavy block that was seen before it.
+            if best.hash == tip {
+                break;
+            }
+
+            let best = best.hash;
+            let fork = self
+                .index
+                .find_fork(&tip, &best)
+                .expect("Blocks to share the genesis block")
+                .hash;
+
+            while self.tip().header_hash != fork {
+                self.disconnect_tip();
+            }
+
+            for hash in self.index.path_from(&fork, &best) {
+                let block = self
+                    .index
+                    .block(&hash)
+                    .expect("Candidate to be stored")
+                    .clone();
+
+                match connect_block(
+                    &block,
+                    &self.chain,
+                    &self.params,
+                    &self.engine,
+                    &mut self.utxo,
+                ) {
+                    Ok(block_undo) => {
+                        self.undo.insert(hash, block_undo);
+                        self.chain.add_block(block);
+                    }
+                    Err(err) => {
+                        if committed_by_header(&err) {
+                            self.index.mark_invalid(&hash);
+                        } else {
+                            self.index.remove_block(&hash);
+                        }
+                        failures.push((hash, err));
+                        break;
+                    }
+                }
+            }
+        }
+
+        let new_tip = self.tip().header_hash;
+        if new_tip != old_tip {
+            self.chain.last_update = self.clock.now();
+            self.notify_tip_changed(old_tip, new_tip);
+        }
+
+        failures
+    }
+
+    fn disconnect_tip(&mut self) {
+        let block = self.chain.pop_block().expect("Tip to exist");
+        let block_undo = self
+            .undo
+            .remove(&block.header_hash)
+            .expect("Connected block to have undo data");
+        self.utxo
+            .undo_block(&block, &block_undo)
+            .expect("Undo data to match the tip");
+    }
+
+    fn notify_tip_changed(&mut self, old_tip: U256, new_tip: U256) {
+        let fork = self
+            .index
+            .find_fork(&old_tip, &new_tip)
+            .expect("Blocks to share the genesis block")
+            .hash;
+        let mut disconnected = self.index.path_from(&fork, &old_tip);
+        disconnected.reverse();
+
+        let event = TipChanged {
+            old_tip,
+            new_tip,
+            height: self.tip().height,
+            disconnected,
+            connected: self.index.path_from(&fork, &new_tip),
+        };
+
+        self.subscribers
+            .retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+
     // fn count_blocks_in_epoch(&self) -> usize {
     //     let ago = seconds_now() - EPOCH;
     //     let mut blocks_in_two_weeks = 0;
@@ -105,8 +601,15 @@ impl TinyBlockchain {
     // }
 }
 
-pub fn generate_mock_blocks(n: u32) -> Vec<Block> {
-    let mut blocks = vec![TinyBlockchain::init_genesis_block(vec![])];
+/// Whether a block failing with `err` is invalid whatever the rest of the
+/// chain or the height it came with.
+fn committed_by_header(err: &ValidationError) -> bool {
+    !matches!(err, ValidationError::BadHeight { .. })
+}
+
+pub fn generate_mock_blocks(n: u32, clock: &impl Clock) -> Vec<Block> {
+    let timestamp = clock.now();
+    let mut blocks = vec![TinyBlockchain::init_genesis_block(timestamp, vec![])];
 
     for i in 1..n {
         let block_header = BlockHeader {
@@ -114,9 +617,9 @@ pub fn generate_mock_blocks(n: u32) -> Vec<Block> {
             nonce: 1,
             prev: blocks[(i - 1) as usize].header_hash,
             merkle_root: 0.as_u256(),
-            // bits: 0x1d00ffff,
-            bits: 0x1dffffff,
-            timestamp: seconds_now(),
+            bits: DEFAULT_DIFFICULTY_TARGET,
+            timestamp,
+            extra: Vec::new(),
         };
         let block = Block::new(i as usize, block_header.hash(), block_header, vec![]);
         blocks.push(block)
@@ -124,3 +627,560 @@ pub fn generate_mock_blocks(n: u32) -> Vec<Block> {
 
     blocks
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::{test_blockchain, test_genesis, test_params, TEST_BITS};
+    use crate::{
+        retarget, FixedClock, MockClock, NetworkTime, SealError, UtxoOutput, ValidationError,
+        INITIAL_SUBSIDY, MIN_TIME_SAMPLES,
+    };
+    use std::sync::Arc;
+
+    /// A child of `parent` whose coinbase pays `value`, `tag` keeps the
+    /// coinbases of sibling blocks distinct.
+    fn child(parent: &Block, tag: u64, value: u64) -> Block {
+        sealed_child(&ProofOfWork, parent, tag, value)
+    }
+
+    fn sealed_child<E: ConsensusEngine>(engine: &E, parent: &Block, tag: u64, value: u64) -> Block {
+        let height = parent.height + 1;
+        let coinbase = Transaction::coinbase(
+            height as u64,
+            tag,
+            vec![UtxoOutput::new(U256::from(tag), value)],
+        );
+        let header = BlockHeader {
+            version: 1,
+            timestamp: parent.header.timestamp + 600,
+            prev: parent.header_hash,
+            merkle_root: U256::ZERO,
+            bits: parent.header.bits,
+            nonce: 0,
+            extra: Vec::new(),
+        };
+        let mut block = Block::new(height, U256::ZERO, header, vec![coinbase]);
+        block.header.merkle_root = block.compute_merkle_root();
+        let clock = FixedClock(block.header.timestamp);
+        engine.seal(&mut block, &clock).unwrap();
+        block
+    }
+
+    fn branch(parent: &Block, tag: u64, len: usize) -> Vec<Block> {
+        let mut blocks: Vec<Block> = Vec::new();
+        for _ in 0..len {
+            let parent = blocks.last().unwrap_or(parent);
+            blocks.push(child(parent, tag, INITIAL_SUBSIDY));
+        }
+        blocks
+    }
+
+    fn hashes(blocks: &[Block]) -> Vec<U256> {
+        blocks.iter().map(|block| block.header_hash).collect()
+    }
+
+    fn coinbase_outpoint(block: &Block) -> crate::OutPoint {
+        crate::OutPoint::new(block.transactions[0].hash, 0)
+    }
+
+    #[test]
+    fn test_extends_active_chain() {
+        let mut blockchain = test_blockchain();
+        let events = blockchain.subscribe();
+        let blocks = branch(&test_genesis(), 1, 2);
+
+        for block in blocks.iter() {
+            blockchain.add_block(block.clone()).unwrap();
+        }
+
+        assert_eq!(blockchain.tip().header_hash, blocks[1].header_hash);
+        assert_eq!(blockchain.chain().len(), 3);
+        assert_eq!(events.try_iter().count(), 2);
+    }
+
+    #[test]
+    fn test_reorg_to_most_work() {
+        let mut blockchain = test_blockchain();
+        let events = blockchain.subscribe();
+        let main = branch(&test_genesis(), 1, 2);
+        let fork = branch(&test_genesis(), 2, 3);
+
+        for block in main.iter().chain(fork[..2].iter()) {
+            blockchain.add_block(block.clone()).unwrap();
+        }
+        // Equal work keeps the branch that was seen first.
+        assert_eq!(blockchain.tip().header_hash, main[1].header_hash);
+        assert_eq!(events.try_iter().count(), 2);
+
+        blockchain.add_block(fork[2].clone()).unwrap();
+
+        assert_eq!(blockchain.tip().header_hash, fork[2].header_hash);
+        assert_eq!(blockchain.chain().len(), 4);
+        assert_eq!(
+            events.try_recv().unwrap(),
+            TipChanged {
+                old_tip: main[1].header_hash,
+                new_tip: fork[2].header_hash,
+                height: 3,
+                disconnected: vec![main[1].header_hash, main[0].header_hash],
+                connected: hashes(&fork),
+            }
+        );
+
+        for block in main.iter() {
+            assert!(!blockchain.utxo().contains(&coinbase_outpoint(block)));
+        }
+        for block in fork.iter() {
+            assert!(blockchain.utxo().contains(&coinbase_outpoint(block)));
+        }
+        assert_eq!(blockchain.utxo().best_block(), fork[2].header_hash);
+    }
+
+    #[test]
+    fn test_invalid_fork_keeps_previous_tip() {
+        let mut blockchain = test_blockchain();
+        let events = blockchain.subscribe();
+        let main = branch(&test_genesis(), 1, 1);
+        let fork = branch(&test_genesis(), 2, 1);
+        // Heavier, but claims more than the subsidy.
+        let bad = child(&fork[0], 2, INITIAL_SUBSIDY + 1);
+        let after_bad = child(&bad, 2, INITIAL_SUBSIDY);
+
+        blockchain.add_block(main[0].clone()).unwrap();
+        blockchain.add_block(fork[0].clone()).unwrap();
+
+        assert!(matches!(
+            blockchain.add_block(bad.clone()),
+            Err(TinyBlockchainError::InvalidBlock(
+                ValidationError::ExcessiveCoinbase { .. }
+            ))
+        ));
+        assert_eq!(blockchain.tip().header_hash, main[0].header_hash);
+        assert!(blockchain.utxo().contains(&coinbase_outpoint(&main[0])));
+        assert!(!blockchain.utxo().contains(&coinbase_outpoint(&fork[0])));
+        assert_eq!(
+            blockchain.index().get(&bad.header_hash).unwrap().status,
+            BlockStatus::Invalid
+        );
+        // Only the first block moved the tip, the failed reorg was undone.
+        assert_eq!(events.try_iter().count(), 1);
+
+        assert_eq!(
+            blockchain.add_block(bad.clone()),
+            Err(TinyBlockchainError::KnownInvalid(bad.header_hash))
+        );
+        assert_eq!(
+            blockchain.add_block(after_bad.clone()),
+            Err(TinyBlockchainError::KnownInvalid(after_bad.header_hash))
+        );
+    }
+
+    #[test]
+    fn test_open_resumes_from_stored_tip() {
+        let dir = tempfile::tempdir().unwrap();
+        let main = branch(&test_genesis(), 1, 2);
+        let fork = branch(&test_genesis(), 2, 3);
+        let bad = child(&fork[2], 2, INITIAL_SUBSIDY + 1);
+
+        let mut store = BlockStore::open(dir.path()).unwrap();
+        let mut blockchain = TinyBlockchain::open(
+            &mut store,
+            test_genesis(),
+            test_params(),
+            ProofOfWork,
+            SystemClock,
+        )
+        .unwrap();
+        for block in main.iter().chain(fork.iter()) {
+            blockchain.add_block(block.clone()).unwrap();
+            store.append(block).unwrap();
+        }
+        // Only valid blocks get stored, but replay must cope with bad ones.
+        store.append(&bad).unwrap();
+        drop(store);
+
+        let mut store = BlockStore::open(dir.path()).unwrap();
+        let restored = TinyBlockchain::open(
+            &mut store,
+            test_genesis(),
+            test_params(),
+            ProofOfWork,
+            SystemClock,
+        )
+        .unwrap();
+
+        assert_eq!(restored.tip().header_hash, fork[2].header_hash);
+        assert_eq!(restored.chain().len(), 4);
+        assert_eq!(restored.utxo().len(), blockchain.utxo().len());
+        assert!(restored.index().contains(&main[1].header_hash));
+    }
+
+    #[test]
+    fn test_open_replays_blocks_stored_before_their_parent() {
+        let dir = tempfile::tempdir().unwrap();
+        let blocks = branch(&test_genesis(), 1, 3);
+
+        let mut store = BlockStore::open(dir.path()).unwrap();
+        store.append(&test_genesis()).unwrap();
+        for block in blocks.iter().rev() {
+            store.append(block).unwrap();
+        }
+        drop(store);
+
+        let mut store = BlockStore::open(dir.path()).unwrap();
+        let restored = TinyBlockchain::open(
+            &mut store,
+            test_genesis(),
+            test_params(),
+            ProofOfWork,
+            SystemClock,
+        )
+        .unwrap();
+        assert_eq!(restored.tip().header_hash, blocks[2].header_hash);
+    }
+
+    /// Accepts any block with zero `bits`, every block weighs the same.
+    #[derive(Debug)]
+    struct LongestChain;
+
+    impl ConsensusEngine for LongestChain {
+        fn next_bits(&self, _chain: &Chain, _params: &TinyBlockchainParams) -> u32 {
+            0
+        }
+
+        fn seal(&self, block: &mut Block, _clock: &dyn Clock) -> Result<(), SealError> {
+            block.header_hash = block.header.hash();
+            Ok(())
+        }
+
+        fn verify_seal(&self, _block: &Block) -> Result<(), ValidationError> {
+            Ok(())
+        }
+
+        fn block_work(_header: &BlockHeader) -> U256 {
+            U256::ONE
+        }
+    }
+
+    #[test]
+    fn test_custom_engine() {
+        let mut genesis = test_genesis();
+        genesis.header.bits = 0;
+        genesis.header_hash = genesis.header.hash();
+        let chain = Chain {
+            items: vec![genesis.clone()],
+            last_update: 0,
+        };
+        let mut blockchain = TinyBlockchain::with_engine(chain, test_params(), LongestChain);
+
+        let main = sealed_child(&LongestChain, &genesis, 1, INITIAL_SUBSIDY);
+        let fork = sealed_child(&LongestChain, &genesis, 2, INITIAL_SUBSIDY);
+        let fork_next = sealed_child(&LongestChain, &fork, 2, INITIAL_SUBSIDY);
+        for block in [&main, &fork, &fork_next] {
+            blockchain.add_block(block.clone()).unwrap();
+        }
+        assert_eq!(blockchain.tip().header_hash, fork_next.header_hash);
+        assert!(blockchain.chain().is_valid(&LongestChain));
+
+        let mut wrong_bits = sealed_child(&LongestChain, &fork_next, 3, INITIAL_SUBSIDY);
+        wrong_bits.header.bits = TEST_BITS;
+        LongestChain.seal(&mut wrong_bits, &SystemClock).unwrap();
+        assert_eq!(
+            blockchain.add_block(wrong_bits),
+            Err(TinyBlockchainError::InvalidBlock(
+                ValidationError::BadDifficulty {
+                    expected: 0,
+                    found: TEST_BITS
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn test_rejects_unknown_parent_and_duplicates() {
+        let mut blockchain = test_blockchain();
+        let blocks = branch(&test_genesis(), 1, 2);
+
+        assert_eq!(
+            blockchain.add_block(blocks[1].clone()),
+            Err(TinyBlockchainError::UnknownParent(blocks[0].header_hash))
+        );
+
+        blockchain.add_block(blocks[0].clone()).unwrap();
+        assert_eq!(
+            blockchain.add_block(blocks[0].clone()),
+            Err(TinyBlockchainError::DuplicateBlock(blocks[0].header_hash))
+        );
+    }
+
+    #[test]
+    fn test_changed_height_does_not_poison_block() {
+        let mut blockchain = test_blockchain();
+        let block = child(&test_genesis(), 1, INITIAL_SUBSIDY);
+        let mut changed = block.clone();
+        changed.height = 7;
+
+        assert_eq!(
+            blockchain.add_block(changed),
+            Err(TinyBlockchainError::InvalidBlock(
+                ValidationError::BadHeight {
+                    expected: 1,
+                    found: 7
+                }
+            ))
+        );
+        assert!(!blockchain.index().contains(&block.header_hash));
+
+        blockchain.add_block(block.clone()).unwrap();
+        assert_eq!(blockchain.tip().header_hash, block.header_hash);
+    }
+
+    #[test]
+    fn test_headers_first() {
+        let mut blockchain = test_blockchain();
+        let blocks = branch(&test_genesis(), 1, 3);
+
+        let mut forged = blocks[0].header.clone();
+        forged.bits = 0x1d00ffff;
+        assert_eq!(
+            blockchain.add_header(forged.clone()),
+            Err(TinyBlockchainError::InvalidBlock(
+                ValidationError::HighHash(forged.hash())
+            ))
+        );
+
+        // Checking a header leaves the index alone and wants its parent.
+        assert_eq!(
+            blockchain.check_header(&blocks[1].header),
+            Err(TinyBlockchainError::UnknownParent(blocks[0].header_hash))
+        );
+        assert_eq!(blockchain.check_header(&blocks[0].header), Ok(()));
+        assert!(!blockchain.index().contains(&blocks[0].header_hash));
+
+        for block in &blocks {
+            blockchain.add_header(block.header.clone()).unwrap();
+        }
+        assert_eq!(
+            blockchain.add_header(blocks[0].header.clone()),
+            Err(TinyBlockchainError::DuplicateBlock(blocks[0].header_hash))
+        );
+        assert_eq!(blockchain.tip().header_hash, test_genesis().header_hash);
+
+        // Bodies may arrive in any order, the chain moves once they connect.
+        blockchain.add_block(blocks[1].clone()).unwrap();
+        assert_eq!(blockchain.tip().header_hash, test_genesis().header_hash);
+        blockchain.add_block(blocks[0].clone()).unwrap();
+        assert_eq!(blockchain.tip().header_hash, blocks[1].header_hash);
+        blockchain.add_block(blocks[2].clone()).unwrap();
+        assert_eq!(blockchain.tip().header_hash, blocks[2].header_hash);
+        assert_eq!(
+            blockchain.add_block(blocks[2].clone()),
+            Err(TinyBlockchainError::DuplicateBlock(blocks[2].header_hash))
+        );
+    }
+
+    #[test]
+    fn test_headers_follow_branch_difficulty() {
+        let params = || TinyBlockchainParams {
+            difficulty: DifficultyAlgorithm::Lwma { window: 3 },
+            ..test_params()
+        };
+        let clock = Arc::new(MockClock::new(test_genesis().header.timestamp));
+        let chain = || Chain {
+            items: vec![test_genesis()],
+            last_update: 0,
+        };
+        let mut source = TinyBlockchain::with_clock(chain(), params(), ProofOfWork, clock.clone());
+        for _ in 0..6 {
+            clock.advance(150);
+            let bits = ProofOfWork.next_bits(source.chain(), source.params());
+            let block = timed_child(source.tip(), clock.now(), bits, &clock);
+            source.add_block(block).unwrap();
+        }
+
+        // The headers carry the bits of their branch and connect as headers.
+        let mut blockchain =
+            TinyBlockchain::with_clock(chain(), params(), ProofOfWork, clock.clone());
+        for block in &source.chain().items[1..] {
+            blockchain.add_header(block.header.clone()).unwrap();
+        }
+        let tip = source.tip();
+        assert_eq!(blockchain.index().best_header().hash, tip.header_hash);
+
+        let expected = ProofOfWork.next_bits(source.chain(), source.params());
+        assert_ne!(expected, TEST_BITS);
+        let easy = timed_child(tip, clock.now() + 150, TEST_BITS, &clock);
+        assert_eq!(
+            blockchain.add_header(easy.header),
+            Err(TinyBlockchainError::InvalidBlock(
+                ValidationError::BadDifficulty {
+                    expected,
+                    found: TEST_BITS
+                }
+            ))
+        );
+
+        let mut beyond_limit = tip.header.clone();
+        beyond_limit.prev = tip.header_hash;
+        beyond_limit.bits = 0x2100ffff;
+        assert_eq!(
+            ProofOfWork.verify_branch(&beyond_limit, blockchain.index(), blockchain.params()),
+            Err(ValidationError::TargetAboveLimit {
+                limit: TEST_BITS,
+                found: 0x2100ffff
+            })
+        );
+    }
+
+    /// A child of `parent` with the given timestamp and bits, sealed with the
+    /// time of `clock`.
+    fn timed_child(parent: &Block, timestamp: u64, bits: u32, clock: &MockClock) -> Block {
+        let mut block = child(parent, 1, INITIAL_SUBSIDY);
+        block.header.timestamp = timestamp;
+        block.header.bits = bits;
+        ProofOfWork.seal(&mut block, clock).unwrap();
+        block
+    }
+
+    #[test]
+    fn test_future_block_accepted_once_clock_catches_up() {
+        let clock = Arc::new(MockClock::new(test_genesis().header.timestamp));
+        let chain = Chain {
+            items: vec![test_genesis()],
+            last_update: 0,
+        };
+        let mut blockchain =
+            TinyBlockchain::with_clock(chain, test_params(), ProofOfWork, clock.clone());
+
+        let max = clock.now() + test_params().max_future_drift;
+        let block = timed_child(&test_genesis(), max + 600, TEST_BITS, &clock);
+        assert_eq!(
+            blockchain.add_block(block.clone()),
+            Err(TinyBlockchainError::InvalidBlock(
+                ValidationError::TimestampTooLate {
+                    max,
+                    found: max + 600
+                }
+            ))
+        );
+        assert!(!blockchain.index().contains(&block.header_hash));
+
+        clock.advance(600);
+        blockchain.add_block(block.clone()).unwrap();
+        assert_eq!(blockchain.tip().header_hash, block.header_hash);
+        assert_eq!(blockchain.chain().last_update, clock.now());
+    }
+
+    #[test]
+    fn test_future_drift_follows_network_time() {
+        let clock = Arc::new(MockClock::new(test_genesis().header.timestamp));
+        let chain = Chain {
+            items: vec![test_genesis()],
+            last_update: 0,
+        };
+        let time = NetworkTime::new(clock.clone());
+        let mut blockchain = TinyBlockchain::with_clock(chain, test_params(), ProofOfWork, time);
+
+        let max = clock.now() + test_params().max_future_drift;
+        let block = timed_child(&test_genesis(), max + 600, TEST_BITS, &clock);
+        assert!(blockchain.add_block(block.clone()).is_err());
+
+        // The peers' clocks are ahead of ours.
+        for peer in 0..MIN_TIME_SAMPLES {
+            blockchain.clock_mut().add_sample(peer, clock.now() + 600);
+        }
+        blockchain.add_block(block.clone()).unwrap();
+        assert_eq!(blockchain.tip().header_hash, block.header_hash);
+    }
+
+    #[test]
+    fn test_validates_params() {
+        assert_eq!(test_params().validate(), Ok(()));
+        assert_eq!(
+            TinyBlockchainParams {
+                max_adjustment: 0,
+                ..test_params()
+            }
+            .validate(),
+            Err(ParamsError::Zero("max_adjustment"))
+        );
+        assert_eq!(
+            TinyBlockchainParams {
+                difficulty: DifficultyAlgorithm::Lwma { window: 0 },
+                ..test_params()
+            }
+            .validate(),
+            Err(ParamsError::Zero("window"))
+        );
+        assert_eq!(
+            TinyBlockchainParams {
+                difficulty: DifficultyAlgorithm::Asert { half_life: 0 },
+                ..test_params()
+            }
+            .validate(),
+            Err(ParamsError::Zero("half_life"))
+        );
+        assert_eq!(
+            TinyBlockchainParams {
+                init_difficulty: 0x1e00ffff,
+                pow_limit: 0x1d00ffff,
+                ..test_params()
+            }
+            .validate(),
+            Err(ParamsError::InitDifficultyAboveLimit {
+                init_difficulty: 0x1e00ffff,
+                pow_limit: 0x1d00ffff
+            })
+        );
+        // The sign bit is set.
+        assert_eq!(
+            TinyBlockchainParams {
+                init_difficulty: 0x1dffffff,
+                ..test_params()
+            }
+            .validate(),
+            Err(ParamsError::InvalidTarget(0x1dffffff))
+        );
+    }
+
+    #[test]
+    fn test_retarget_follows_block_timestamps() {
+        let params = TinyBlockchainParams {
+            blocks_in_epoch: 4,
+            ..test_params()
+        };
+        let clock = Arc::new(MockClock::new(test_genesis().header.timestamp));
+        let chain = Chain {
+            items: vec![test_genesis()],
+            last_update: 0,
+        };
+        let mut blockchain = TinyBlockchain::with_clock(chain, params, ProofOfWork, clock.clone());
+
+        // Blocks come four times faster than the spacing.
+        for _ in 0..4 {
+            clock.advance(150);
+            let block = timed_child(blockchain.tip(), clock.now(), TEST_BITS, &clock);
+            blockchain.add_block(block).unwrap();
+        }
+
+        let bits = blockchain
+            .engine()
+            .next_bits(blockchain.chain(), blockchain.params());
+        // The epoch spans the timestamps of blocks 1 to 4.
+        assert_eq!(bits, retarget(TEST_BITS, 450, blockchain.params()));
+        assert_ne!(bits, TEST_BITS);
+
+        clock.advance(150);
+        let stale = timed_child(blockchain.tip(), clock.now(), TEST_BITS, &clock);
+        assert!(matches!(
+            blockchain.add_block(stale),
+            Err(TinyBlockchainError::InvalidBlock(
+                ValidationError::BadDifficulty { .. }
+            ))
+        ));
+        let block = timed_child(blockchain.tip(), clock.now(), bits, &clock);
+        blockchain.add_block(block).unwrap();
+        assert_eq!(blockchain.tip().height, 5);
+    }
+}
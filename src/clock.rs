@@ -0,0 +1,184 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Furthest the peers' median clock may pull network time away from the
+/// local clock, in seconds. A bigger disagreement means our clock or the
+/// peers are broken, so neither is trusted over the local clock.
+pub const MAX_TIME_ADJUSTMENT: i64 = 70 * 60;
+/// Peers needed before their clocks adjust network time.
+pub const MIN_TIME_SAMPLES: usize = 5;
+const MAX_TIME_SAMPLES: usize = 200;
+
+/// A source of the current time, in seconds since the UNIX epoch.
+pub trait Clock {
+    fn now(&self) -> u64;
+}
+
+impl<C: Clock + ?Sized> Clock for &C {
+    fn now(&self) -> u64 {
+        (**self).now()
+    }
+}
+
+/// Lets a test keep a handle on a `MockClock` it handed to the blockchain.
+impl<C: Clock + ?Sized> Clock for Arc<C> {
+    fn now(&self) -> u64 {
+        (**self).now()
+    }
+}
+
+/// The operating system clock.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_secs()
+    }
+}
+
+/// A clock stopped at one instant.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FixedClock(pub u64);
+
+impl Clock for FixedClock {
+    fn now(&self) -> u64 {
+        self.0
+    }
+}
+
+/// A clock that only moves when told to.
+#[derive(Debug, Default)]
+pub struct MockClock(AtomicU64);
+
+impl MockClock {
+    pub fn new(now: u64) -> Self {
+        MockClock(AtomicU64::new(now))
+    }
+
+    pub fn set(&self, now: u64) {
+        self.0.store(now, Ordering::SeqCst);
+    }
+
+    pub fn advance(&self, seconds: u64) {
+        self.0.fetch_add(seconds, Ordering::SeqCst);
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> u64 {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// The local clock corrected by the median offset of the peers' clocks, like
+/// Bitcoin's network adjusted time. Every peer counts once.
+#[derive(Debug)]
+pub struct NetworkTime<C: Clock, P> {
+    clock: C,
+    offsets: HashMap<P, i64>,
+}
+
+impl<C: Clock, P: Eq + Hash> NetworkTime<C, P> {
+    pub fn new(clock: C) -> Self {
+        NetworkTime {
+            clock,
+            offsets: HashMap::new(),
+        }
+    }
+
+    pub fn clock(&self) -> &C {
+        &self.clock
+    }
+
+    /// Records the time `peer` reported. Later reports of a known peer and
+    /// peers beyond the first few hundred are ignored.
+    pub fn add_sample(&mut self, peer: P, peer_time: u64) {
+        if self.offsets.len() >= MAX_TIME_SAMPLES || self.offsets.contains_key(&peer) {
+            return;
+        }
+
+        let offset = peer_time as i64 - self.clock.now() as i64;
+        self.offsets.insert(peer, offset);
+    }
+
+    /// Seconds the peers' clocks are ahead of ours, zero until enough peers
+    /// reported or when they disagree by more than `MAX_TIME_ADJUSTMENT`.
+    pub fn offset(&self) -> i64 {
+        if self.offsets.len() < MIN_TIME_SAMPLES {
+            return 0;
+        }
+
+        let mut offsets: Vec<i64> = self.offsets.values().copied().collect();
+        offsets.sort_unstable();
+        let median = offsets[offsets.len() / 2];
+
+        if median.abs() > MAX_TIME_ADJUSTMENT {
+            return 0;
+        }
+        median
+    }
+}
+
+impl<C: Clock, P: Eq + Hash> Clock for NetworkTime<C, P> {
+    fn now(&self) -> u64 {
+        self.clock.now().saturating_add_signed(self.offset())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_mock_clock() {
+        let clock = MockClock::new(1_000);
+        clock.advance(5);
+        assert_eq!(clock.now(), 1_005);
+        clock.set(10);
+        assert_eq!(clock.now(), 10);
+
+        let shared = Arc::new(MockClock::new(1_000));
+        let handed_out: Box<dyn Clock> = Box::new(shared.clone());
+        shared.advance(60);
+        assert_eq!(handed_out.now(), 1_060);
+        assert_eq!(FixedClock(7).now(), 7);
+    }
+
+    #[test]
+    fn test_network_time_uses_median_offset() {
+        let mut time = NetworkTime::new(MockClock::new(1_000_000));
+
+        for (peer, offset) in [(1, 30), (2, -10), (3, 20), (4, 600)] {
+            time.add_sample(peer, (1_000_000 + offset) as u64);
+        }
+        // Too few peers to trust.
+        assert_eq!(time.now(), 1_000_000);
+
+        time.add_sample(5, 999_000);
+        assert_eq!(time.offset(), 20);
+        assert_eq!(time.now(), 1_000_020);
+
+        // A peer reporting again doesn't get a second vote.
+        for _ in 0..10 {
+            time.add_sample(4, 1_000_600);
+        }
+        assert_eq!(time.offset(), 20);
+    }
+
+    #[test]
+    fn test_network_time_ignores_large_disagreement() {
+        let mut time = NetworkTime::new(MockClock::new(1_000_000));
+        for peer in 0..5 {
+            time.add_sample(peer, 1_000_000 + MAX_TIME_ADJUSTMENT as u64 + 1);
+        }
+        assert_eq!(time.offset(), 0);
+        assert_eq!(time.now(), 1_000_000);
+    }
+}
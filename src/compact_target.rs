@@ -0,0 +1,341 @@
+use ethnum::U256;
+use serde::{Deserialize, Serialize};
+
+/// Bits of the mantissa, the top bit of the 3 mantissa bytes is a sign.
+const MANTISSA_MASK: u32 = 0x007fffff;
+const SIGN_BIT: u32 = 0x00800000;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum TargetError {
+    /// The sign bit is set on a non-zero mantissa.
+    Negative,
+    /// The target doesn't fit in 256 bits.
+    Overflow,
+}
+
+impl std::fmt::Display for TargetError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TargetError::Negative => write!(f, "negative target"),
+            TargetError::Overflow => write!(f, "target overflows 256 bits"),
+        }
+    }
+}
+
+impl std::error::Error for TargetError {}
+
+/// The result of expanding compact bits, with the flags Bitcoin's
+/// `SetCompact` reports.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ExpandedTarget {
+    /// The magnitude, truncated to 256 bits when `overflow` is set.
+    pub value: U256,
+    pub negative: bool,
+    pub overflow: bool,
+}
+
+/// A 256-bit target in the compact `bits` format of block headers: one size
+/// byte followed by a 3 byte mantissa, `mantissa * 256^(size - 3)`. The top
+/// bit of the mantissa is a sign bit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct CompactTarget(u32);
+
+impl CompactTarget {
+    pub const fn new(bits: u32) -> Self {
+        CompactTarget(bits)
+    }
+
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+
+    fn size(self) -> u32 {
+        self.0 >> 24
+    }
+
+    /// Expands the compact form like Bitcoin's `SetCompact`.
+    pub fn expand(self) -> ExpandedTarget {
+        let size = self.size();
+        let mut word = self.0 & MANTISSA_MASK;
+
+        let value = if size <= 3 {
+            word >>= 8 * (3 - size);
+            U256::from(word)
+        } else {
+            let shift = 8 * (size - 3);
+            if shift < 256 {
+                U256::from(word) << shift
+            } else {
+                U256::ZERO
+            }
+        };
+
+        ExpandedTarget {
+            value,
+            negative: word != 0 && self.0 & SIGN_BIT != 0,
+            overflow: word != 0
+                && (size > 34 || (word > 0xff && size > 33) || (word > 0xffff && size > 32)),
+        }
+    }
+
+    /// The target as an unsigned 256-bit number.
+    pub fn to_target(self) -> Result<U256, TargetError> {
+        let expanded = self.expand();
+
+        if expanded.negative {
+            return Err(TargetError::Negative);
+        }
+        if expanded.overflow {
+            return Err(TargetError::Overflow);
+        }
+
+        Ok(expanded.value)
+    }
+
+    /// The compact form of `target`, rounded down to the 3 most significant
+    /// bytes.
+    pub fn from_target(target: U256) -> Self {
+        Self::encode(target, false)
+    }
+
+    /// Compacts `value` like Bitcoin's `GetCompact`, setting the sign bit when
+    /// `negative` and the value isn't zero.
+    pub fn encode(value: U256, negative: bool) -> Self {
+        let mut size = (256 - value.leading_zeros()).div_ceil(8);
+
+        let mut compact = if size <= 3 {
+            value.as_u32() << (8 * (3 - size))
+        } else {
+            (value >> (8 * (size - 3))).as_u32()
+        };
+
+        // The mantissa can't have its sign bit set, move to a bigger size.
+        if compact & SIGN_BIT != 0 {
+            compact >>= 8;
+            size += 1;
+        }
+
+        compact |= size << 24;
+        if negative && compact & MANTISSA_MASK != 0 {
+            compact |= SIGN_BIT;
+        }
+
+        CompactTarget(compact)
+    }
+
+    /// How many times harder the target is than the easiest mainnet target
+    /// `0x1d00ffff`, like Bitcoin's `GetDifficulty`.
+    pub fn difficulty(self) -> f64 {
+        let mut shift = (self.0 >> 24) & 0xff;
+        let mut difficulty = 0x0000ffff as f64 / (self.0 & 0x00ffffff) as f64;
+
+        while shift < 29 {
+            difficulty *= 256.0;
+            shift += 1;
+        }
+        while shift > 29 {
+            difficulty /= 256.0;
+            shift -= 1;
+        }
+
+        difficulty
+    }
+}
+
+impl From<u32> for CompactTarget {
+    fn from(bits: u32) -> Self {
+        CompactTarget(bits)
+    }
+}
+
+impl From<CompactTarget> for u32 {
+    fn from(target: CompactTarget) -> Self {
+        target.0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn u256_from_hex(hex: &str) -> U256 {
+        U256::from_str_hex(hex).unwrap()
+    }
+
+    #[test]
+    fn test_mainnet_vectors() {
+        let vectors = [
+            // genesis
+            (
+                0x1d00ffff,
+                "0x00000000ffff0000000000000000000000000000000000000000000000000000",
+            ),
+            // first retarget, block 32256
+            (
+                0x1d00d86a,
+                "0x00000000d86a0000000000000000000000000000000000000000000000000000",
+            ),
+            (
+                0x1b0404cb,
+                "0x00000000000404cb000000000000000000000000000000000000000000000000",
+            ),
+            (
+                0x181bc330,
+                "0x00000000000000001bc330000000000000000000000000000000000000000000",
+            ),
+            (
+                0x170ed0eb,
+                "0x0000000000000000000ed0eb0000000000000000000000000000000000000000",
+            ),
+            // regtest
+            (
+                0x207fffff,
+                "0x7fffff0000000000000000000000000000000000000000000000000000000000",
+            ),
+        ];
+
+        for (bits, target) in vectors {
+            let target = u256_from_hex(target);
+            assert_eq!(CompactTarget::new(bits).to_target(), Ok(target));
+            assert_eq!(CompactTarget::from_target(target).bits(), bits);
+        }
+    }
+
+    #[test]
+    fn test_set_compact_vectors() {
+        // (bits, value, negative, overflow, compact of the value)
+        let vectors = [
+            (0x00000000, 0u128, false, false, 0x00000000),
+            (0x00123456, 0, false, false, 0x00000000),
+            (0x01003456, 0, false, false, 0x00000000),
+            (0x02000056, 0, false, false, 0x00000000),
+            (0x03000000, 0, false, false, 0x00000000),
+            (0x04000000, 0, false, false, 0x00000000),
+            (0x00923456, 0, false, false, 0x00000000),
+            (0x01803456, 0, false, false, 0x00000000),
+            (0x02800056, 0, false, false, 0x00000000),
+            (0x03800000, 0, false, false, 0x00000000),
+            (0x04800000, 0, false, false, 0x00000000),
+            (0x01123456, 0x12, false, false, 0x01120000),
+            (0x01fedcba, 0x7e, true, false, 0x01fe0000),
+            (0x02123456, 0x1234, false, false, 0x02123400),
+            (0x03123456, 0x123456, false, false, 0x03123456),
+            (0x04123456, 0x12345600, false, false, 0x04123456),
+            (0x04923456, 0x12345600, true, false, 0x04923456),
+            (0x05009234, 0x92340000, false, false, 0x05009234),
+        ];
+
+        for (bits, value, negative, overflow, compact) in vectors {
+            let expanded = CompactTarget::new(bits).expand();
+            assert_eq!(
+                expanded,
+                ExpandedTarget {
+                    value: U256::from(value),
+                    negative,
+                    overflow
+                },
+                "bits {:#010x}",
+                bits
+            );
+            assert_eq!(
+                CompactTarget::encode(expanded.value, negative).bits(),
+                compact,
+                "bits {:#010x}",
+                bits
+            );
+        }
+
+        let big = CompactTarget::new(0x20123456).expand();
+        assert_eq!(big.value, U256::from(0x123456u32) << 232);
+        assert_eq!(CompactTarget::from_target(big.value).bits(), 0x20123456);
+
+        assert!(CompactTarget::new(0xff123456).expand().overflow);
+        assert_eq!(
+            CompactTarget::new(0xff123456).to_target(),
+            Err(TargetError::Overflow)
+        );
+        assert_eq!(
+            CompactTarget::new(0x04923456).to_target(),
+            Err(TargetError::Negative)
+        );
+        // A sign bit on a zero mantissa is ignored.
+        assert_eq!(CompactTarget::new(0x04800000).to_target(), Ok(U256::ZERO));
+    }
+
+    #[test]
+    fn test_sign_bit_moves_to_next_size() {
+        assert_eq!(
+            CompactTarget::from_target(U256::from(0x80u32)).bits(),
+            0x02008000
+        );
+        assert_eq!(
+            CompactTarget::from_target(U256::from(0x800000u32)).bits(),
+            0x04008000
+        );
+    }
+
+    #[test]
+    fn test_overflow_boundaries() {
+        assert!(!CompactTarget::new(0x220000ff).expand().overflow);
+        assert!(CompactTarget::new(0x22000100).expand().overflow);
+        assert!(!CompactTarget::new(0x2100ffff).expand().overflow);
+        assert!(CompactTarget::new(0x21010000).expand().overflow);
+        assert!(CompactTarget::new(0x23000001).expand().overflow);
+        assert_eq!(
+            CompactTarget::from_target(U256::MAX).to_target(),
+            Ok(U256::from(0xffffu32) << 240)
+        );
+    }
+
+    #[test]
+    fn test_difficulty() {
+        assert_eq!(CompactTarget::new(0x1d00ffff).difficulty(), 1.0);
+        assert!((CompactTarget::new(0x1b0404cb).difficulty() - 16307.420938523983).abs() < 1e-9);
+        assert!(CompactTarget::new(0x207fffff).difficulty() < 1.0);
+    }
+
+    fn any_u256() -> impl Strategy<Value = U256> {
+        (any::<u128>(), any::<u128>(), 0..256u32)
+            .prop_map(|(high, low, shift)| U256::from_words(high, low) >> shift)
+    }
+
+    proptest! {
+        #[test]
+        fn test_expanded_value_roundtrips(size in 0..=34u32, mantissa in 0..=0xffffffu32) {
+            let bits = size << 24 | mantissa;
+            let expanded = CompactTarget::new(bits).expand();
+            prop_assume!(!expanded.overflow);
+
+            let compact = CompactTarget::encode(expanded.value, expanded.negative);
+            prop_assert_eq!(compact.expand(), expanded);
+        }
+
+        #[test]
+        fn test_canonical_bits_roundtrip(target in any_u256()) {
+            let compact = CompactTarget::from_target(target);
+            let rounded = compact.to_target().unwrap();
+            prop_assert_eq!(CompactTarget::from_target(rounded), compact);
+        }
+
+        #[test]
+        fn test_from_target_only_drops_low_bits(target in any_u256()) {
+            let compact = CompactTarget::from_target(target);
+            let rounded = compact.to_target().unwrap();
+            prop_assert!(rounded <= target);
+
+            // Only the bytes below the mantissa are lost.
+            let dropped = 8 * (compact.bits() >> 24).saturating_sub(3);
+            prop_assert!(target - rounded < U256::ONE << dropped);
+        }
+
+        #[test]
+        fn test_from_target_is_monotonic(a in any_u256(), b in any_u256()) {
+            let (low, high) = if a <= b { (a, b) } else { (b, a) };
+            prop_assert!(
+                CompactTarget::from_target(low).to_target().unwrap()
+                    <= CompactTarget::from_target(high).to_target().unwrap()
+            );
+        }
+    }
+}
@@ -0,0 +1,385 @@
+use crate::{BlockHeader, Chain, CompactTarget, TinyBlockchainParams};
+use ethnum::U256;
+use serde::{Deserialize, Serialize};
+
+/// How the proof of work target of the next block is chosen.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DifficultyAlgorithm {
+    /// Bitcoin's retarget once every `blocks_in_epoch` blocks.
+    Epoch,
+    /// Linearly weighted moving average of the targets and solve times of
+    /// the last `window` blocks, recent blocks weigh the most (LWMA-1).
+    Lwma { window: usize },
+    /// Absolutely scheduled exponential adjustment against the genesis
+    /// block, the target doubles or halves for every `half_life` seconds
+    /// the chain is behind or ahead of schedule (aserti3-2d).
+    Asert { half_life: u64 },
+}
+
+/// The headers the difficulty of the next block is computed from, by height.
+/// The active chain is one, an indexed side branch is another.
+pub trait HeaderChain {
+    /// Height of the block following the chain.
+    fn next_height(&self) -> usize;
+
+    /// The header at `height`, `None` past the end of the chain or where the
+    /// chain doesn't keep it.
+    fn header_at(&self, height: usize) -> Option<&BlockHeader>;
+}
+
+impl HeaderChain for Chain {
+    fn next_height(&self) -> usize {
+        self.len()
+    }
+
+    fn header_at(&self, height: usize) -> Option<&BlockHeader> {
+        self.get_block(height).map(|block| &block.header)
+    }
+}
+
+pub(crate) fn pow_limit(params: &TinyBlockchainParams) -> U256 {
+    CompactTarget::new(params.pow_limit)
+        .to_target()
+        .expect("Proof of work limit to be a valid target")
+}
+
+/// `target * num / den` without overflowing 256 bits.
+pub(crate) fn mul_div(target: U256, num: u64, den: u64) -> U256 {
+    match target.checked_mul(U256::from(num)) {
+        Some(scaled) => scaled / U256::from(den),
+        // Only targets close to 2^256 get here. Dividing first and scaling
+        // the remainder separately gives the same result as the product
+        // would, saturating if it doesn't fit.
+        None => {
+            let (den, num) = (U256::from(den), U256::from(num));
+            (target / den)
+                .saturating_mul(num)
+                .saturating_add(target % den * num / den)
+        }
+    }
+}
+
+/// LWMA-1 over the last `window` blocks of `chain`. Solve times are taken
+/// from timestamps forced to increase and capped at six target spacings, so
+/// a single bad timestamp can't swing the target far.
+pub fn lwma_next_bits(
+    chain: &impl HeaderChain,
+    window: usize,
+    params: &TinyBlockchainParams,
+) -> u32 {
+    assert!(window > 0, "LWMA window must not be empty");
+
+    let len = chain.next_height();
+    if len <= window {
+        return params.init_difficulty;
+    }
+
+    let spacing = params.target_spacing;
+    let header = |height| chain.header_at(height).expect("Window headers to be kept");
+    let mut prev_timestamp = header(len - window - 1).timestamp;
+    let mut weighted_time = 0u64;
+    let mut target_quotients = U256::ZERO;
+    let mut target_remainders = U256::ZERO;
+
+    for (weight, height) in (1u64..).zip(len - window..len) {
+        let header = header(height);
+        let timestamp = header.timestamp.max(prev_timestamp + 1);
+        let solve_time = (timestamp - prev_timestamp).min(6 * spacing);
+        prev_timestamp = timestamp;
+
+        weighted_time += solve_time * weight;
+        // Averaging quotient and remainder separately keeps the sum within
+        // 256 bits even at the easiest targets.
+        let target = CompactTarget::new(header.bits)
+            .to_target()
+            .unwrap_or_else(|_| pow_limit(params));
+        target_quotients += target / U256::from(window as u64);
+        target_remainders += target % U256::from(window as u64);
+    }
+
+    // With every solve time equal to the spacing the weighted time is
+    // spacing * window * (window + 1) / 2, which keeps the average target.
+    let window = window as u64;
+    let expected_time = spacing * window * (window + 1) / 2;
+    let average_target = target_quotients + target_remainders / U256::from(window);
+    let next_target = mul_div(average_target, weighted_time, expected_time);
+
+    CompactTarget::from_target(next_target.clamp(U256::ONE, pow_limit(params))).bits()
+}
+
+/// aserti3-2d anchored at the genesis block. The target is the genesis
+/// target scaled by `2^((time_behind_schedule) / half_life)`, with the power
+/// of two approximated by a cubic polynomial in 16.16 fixed point.
+pub fn asert_next_bits(
+    chain: &impl HeaderChain,
+    half_life: u64,
+    params: &TinyBlockchainParams,
+) -> u32 {
+    assert!(half_life > 0, "ASERT half life must not be zero");
+
+    let Some(prev_height) = chain.next_height().checked_sub(1) else {
+        return params.init_difficulty;
+    };
+    let (Some(anchor), Some(prev_header)) = (chain.header_at(0), chain.header_at(prev_height))
+    else {
+        return params.init_difficulty;
+    };
+    let anchor_target = CompactTarget::new(anchor.bits)
+        .to_target()
+        .unwrap_or_else(|_| pow_limit(params));
+
+    let time_diff = prev_header.timestamp as i128 - anchor.timestamp as i128;
+    let height_diff = prev_height as i128;
+    let exponent =
+        (time_diff - params.target_spacing as i128 * height_diff) * 65536 / half_life as i128;
+
+    // exponent = shifts * 65536 + frac, with frac in [0, 65536)
+    let shifts = exponent >> 16;
+    let frac = (exponent - (shifts << 16)) as u128;
+    let factor = 65536
+        + ((195_766_423_245_049 * frac
+            + 971_821_376 * frac * frac
+            + 5127 * frac * frac * frac
+            + (1 << 47))
+            >> 48);
+
+    // The factor has 17 significant bits, so drop low bits of the anchor
+    // target until the product fits in 256 bits. The compact encoding keeps
+    // far fewer bits than are dropped here.
+    let limit = pow_limit(params);
+    let dropped = 17u32.saturating_sub(anchor_target.leading_zeros());
+    let mut next_target = (anchor_target >> dropped) * U256::from(factor);
+
+    // Undo the 16 fractional bits of the factor and the dropped bits, then
+    // apply the whole shifts, clamped to what a 256 bit target can hold.
+    let shifts = (shifts + dropped as i128 - 16).clamp(-256, 256);
+    if shifts < 0 {
+        let shifts = shifts.unsigned_abs() as u32;
+        next_target = next_target.checked_shr(shifts).unwrap_or(U256::ZERO);
+    } else {
+        let shifts = shifts as u32;
+        if shifts >= next_target.leading_zeros() {
+            return params.pow_limit;
+        }
+        next_target <<= shifts;
+    }
+
+    CompactTarget::from_target(next_target.clamp(U256::ONE, limit)).bits()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::test_params;
+    use crate::{block_work, next_bits, Block, BlockHeader, Hash};
+
+    const SPACING: u64 = 600;
+
+    fn params(difficulty: DifficultyAlgorithm) -> TinyBlockchainParams {
+        TinyBlockchainParams {
+            blocks_in_epoch: 20,
+            init_difficulty: 0x1d00ffff,
+            target_spacing: SPACING,
+            difficulty,
+            ..test_params()
+        }
+    }
+
+    fn push_block(chain: &mut Chain, timestamp: u64, bits: u32) {
+        let height = chain.len();
+        let header = BlockHeader {
+            version: 1,
+            timestamp,
+            prev: chain.previous_block().map_or(U256::ZERO, |b| b.header_hash),
+            merkle_root: U256::ZERO,
+            bits,
+            nonce: 0,
+            extra: Vec::new(),
+        };
+        chain.add_block(Block::new(height, header.hash(), header, vec![]));
+    }
+
+    /// Mines `blocks` blocks where every block takes exactly the expected
+    /// number of hashes at `hashrate` hashes per second, and returns the
+    /// solve times.
+    fn simulate(
+        chain: &mut Chain,
+        params: &TinyBlockchainParams,
+        hashrate: u64,
+        blocks: usize,
+    ) -> Vec<u64> {
+        (0..blocks)
+            .map(|_| {
+                let bits = next_bits(chain, params);
+                let solve_time = (block_work(bits) / U256::from(hashrate)).as_u64().max(1);
+                let timestamp = chain.previous_block().unwrap().header.timestamp + solve_time;
+                push_block(chain, timestamp, bits);
+                solve_time
+            })
+            .collect()
+    }
+
+    fn average(times: &[u64]) -> u64 {
+        times.iter().sum::<u64>() / times.len() as u64
+    }
+
+    /// Starts at a hashrate matching the genesis target, then multiplies the
+    /// hashrate by ten and checks the block times come back to the spacing.
+    fn assert_converges_after_shock(difficulty: DifficultyAlgorithm, blocks: usize) {
+        let params = params(difficulty);
+        let mut chain = Chain {
+            items: Vec::new(),
+            last_update: 0,
+        };
+        push_block(&mut chain, 1_700_000_000, params.init_difficulty);
+        let hashrate = (block_work(params.init_difficulty) / U256::from(SPACING)).as_u64();
+
+        let before = simulate(&mut chain, &params, hashrate, 60);
+        assert!(average(&before[40..]).abs_diff(SPACING) < SPACING / 10);
+
+        let after = simulate(&mut chain, &params, hashrate * 10, blocks);
+        assert!(average(&after[..5]) < SPACING / 2, "{:?}", &after[..5]);
+        let settled = average(&after[blocks - 20..]);
+        assert!(
+            settled.abs_diff(SPACING) < SPACING * 15 / 100,
+            "{:?} settled at {}",
+            difficulty,
+            settled
+        );
+
+        // And back down when the hashrate leaves again.
+        let recovered = simulate(&mut chain, &params, hashrate, blocks);
+        let settled = average(&recovered[blocks - 20..]);
+        assert!(
+            settled.abs_diff(SPACING) < SPACING * 15 / 100,
+            "{:?} recovered at {}",
+            difficulty,
+            settled
+        );
+    }
+
+    #[test]
+    fn test_epoch_converges_after_hashrate_shock() {
+        assert_converges_after_shock(DifficultyAlgorithm::Epoch, 100);
+    }
+
+    #[test]
+    fn test_lwma_converges_after_hashrate_shock() {
+        assert_converges_after_shock(DifficultyAlgorithm::Lwma { window: 45 }, 150);
+    }
+
+    #[test]
+    fn test_asert_converges_after_hashrate_shock() {
+        assert_converges_after_shock(
+            DifficultyAlgorithm::Asert {
+                half_life: 12 * SPACING,
+            },
+            150,
+        );
+    }
+
+    #[test]
+    fn test_lwma_on_schedule_keeps_target() {
+        let params = params(DifficultyAlgorithm::Lwma { window: 10 });
+        let mut chain = Chain {
+            items: Vec::new(),
+            last_update: 0,
+        };
+        for height in 0..=10 {
+            push_block(&mut chain, 1_700_000_000 + height * SPACING, 0x1d00ffff);
+        }
+        assert_eq!(lwma_next_bits(&chain, 10, &params), 0x1d00ffff);
+
+        // Too short a chain uses the initial difficulty.
+        chain.items.truncate(10);
+        assert_eq!(lwma_next_bits(&chain, 10, &params), 0x1d00ffff);
+    }
+
+    #[test]
+    fn test_lwma_ignores_timestamps_going_backwards() {
+        let params = params(DifficultyAlgorithm::Lwma { window: 3 });
+        let mut chain = Chain {
+            items: Vec::new(),
+            last_update: 0,
+        };
+        for timestamp in [1_000, 1_600, 100, 2_200] {
+            push_block(&mut chain, timestamp, 0x1d00ffff);
+        }
+        // The timestamp going back counts as one second, so the solve times are
+        // 600, 1 and 599, weighted 1, 2 and 3.
+        let expected = CompactTarget::from_target(mul_div(
+            CompactTarget::new(0x1d00ffff).to_target().unwrap(),
+            600 + 2 + 3 * 599,
+            SPACING * 6,
+        ));
+        assert_eq!(lwma_next_bits(&chain, 3, &params), expected.bits());
+    }
+
+    #[test]
+    fn test_asert_schedule() {
+        let half_life = 3600;
+        let params = params(DifficultyAlgorithm::Asert { half_life });
+        let genesis_target = CompactTarget::new(0x1d00ffff).to_target().unwrap();
+        let mut chain = Chain {
+            items: Vec::new(),
+            last_update: 0,
+        };
+        push_block(&mut chain, 1_000_000, 0x1d00ffff);
+
+        // On schedule the target stays at the anchor.
+        push_block(&mut chain, 1_000_000 + SPACING, 0x1d00ffff);
+        assert_eq!(asert_next_bits(&chain, half_life, &params), 0x1d00ffff);
+
+        // One half life behind schedule doubles the target, one ahead halves it.
+        chain.items[1].header.timestamp += half_life;
+        assert_eq!(
+            asert_next_bits(&chain, half_life, &params),
+            CompactTarget::from_target(genesis_target * 2).bits()
+        );
+        chain.items[1].header.timestamp -= 2 * half_life;
+        assert_eq!(
+            asert_next_bits(&chain, half_life, &params),
+            CompactTarget::from_target(genesis_target / 2).bits()
+        );
+
+        // Far behind schedule the target is capped at the limit.
+        chain.items[1].header.timestamp += 1_000 * half_life;
+        assert_eq!(asert_next_bits(&chain, half_life, &params), 0x207fffff);
+    }
+
+    #[test]
+    fn test_adjusts_at_the_regtest_limit() {
+        for difficulty in [
+            DifficultyAlgorithm::Lwma { window: 10 },
+            DifficultyAlgorithm::Asert { half_life: 3600 },
+        ] {
+            let params = params(difficulty);
+            let limit = pow_limit(&params);
+            let mut chain = Chain {
+                items: Vec::new(),
+                last_update: 0,
+            };
+            for height in 0..=10 {
+                push_block(&mut chain, 1_700_000_000 + height * SPACING, 0x207fffff);
+            }
+            // On schedule the easiest target stays put.
+            assert_eq!(next_bits(&chain, &params), 0x207fffff, "{:?}", difficulty);
+
+            // Blocks ten times too fast make it harder.
+            let mut chain = Chain {
+                items: Vec::new(),
+                last_update: 0,
+            };
+            for height in 0..=10 {
+                push_block(
+                    &mut chain,
+                    1_700_000_000 + height * SPACING / 10,
+                    0x207fffff,
+                );
+            }
+            let bits = next_bits(&chain, &params);
+            let target = CompactTarget::new(bits).to_target().unwrap();
+            assert!(target < limit / 2, "{:?} gave {:#x}", difficulty, bits);
+        }
+    }
+}
@@ -0,0 +1,71 @@
+use crate::{Block, BlockHeader, BlockIndex, Chain, Clock, TinyBlockchainParams, ValidationError};
+use ethnum::U256;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum SealError {
+    /// The engine isn't allowed to seal the block, e.g. it holds no
+    /// validator key.
+    NotAuthorized,
+}
+
+impl std::fmt::Display for SealError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SealError::NotAuthorized => write!(f, "not authorized to seal the block"),
+        }
+    }
+}
+
+impl std::error::Error for SealError {}
+
+/// The rules deciding who may produce the next block and which branch of the
+/// block tree wins. Everything else, transactions and the UTXO set, is the
+/// same whatever engine a network runs.
+pub trait ConsensusEngine {
+    /// The `bits` the block following the tip of `chain` must carry.
+    fn next_bits(&self, chain: &Chain, params: &TinyBlockchainParams) -> u32;
+
+    /// Completes the header of `block` so that it passes `verify_seal`, and
+    /// updates `header_hash` to match. Engines that need to move the
+    /// timestamp while sealing read the time from `clock`.
+    fn seal(&self, block: &mut Block, clock: &dyn Clock) -> Result<(), SealError>;
+
+    /// Checks the seal using the block alone, before its parent is known.
+    fn verify_seal(&self, block: &Block) -> Result<(), ValidationError>;
+
+    /// Checks the header against the indexed headers it descends from,
+    /// before its weight is counted. Unlike `verify_header` this also runs
+    /// for side branches and for headers whose body isn't known yet.
+    fn verify_branch(
+        &self,
+        _header: &BlockHeader,
+        _index: &BlockIndex,
+        _params: &TinyBlockchainParams,
+    ) -> Result<(), ValidationError> {
+        Ok(())
+    }
+
+    /// Checks the header against the chain it extends.
+    fn verify_header(
+        &self,
+        block: &Block,
+        chain: &Chain,
+        params: &TinyBlockchainParams,
+    ) -> Result<(), ValidationError> {
+        let expected = self.next_bits(chain, params);
+        if block.header.bits != expected {
+            return Err(ValidationError::BadDifficulty {
+                expected,
+                found: block.header.bits,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Weight a block adds to its branch, the branch with the most total
+    /// weight is the active chain.
+    fn block_work(header: &BlockHeader) -> U256
+    where
+        Self: Sized;
+}
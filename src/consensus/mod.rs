@@ -1,3 +1,9 @@
+mod difficulty;
+mod engine;
+mod poa;
 mod pow;
 
+pub use difficulty::*;
+pub use engine::*;
+pub use poa::*;
 pub use pow::*;
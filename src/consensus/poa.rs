@@ -0,0 +1,730 @@
+use crate::{
+    take_array, Block, BlockHeader, BlockIndex, Chain, Clock, ConsensusEngine, Decode, DecodeError,
+    Encode, Hash, SealError, TinyBlockchainParams, ValidationError, EXTRA_DATA_VERSION,
+};
+use ed25519_dalek::Signature;
+use ethnum::U256;
+use ring::signature::{Ed25519KeyPair, KeyPair, UnparsedPublicKey, ED25519};
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::sync::Mutex;
+
+/// `bits` of a block sealed by the validator whose turn it is.
+pub const DIFF_IN_TURN: u32 = 2;
+/// `bits` of a block sealed by any other validator.
+pub const DIFF_NO_TURN: u32 = 1;
+/// Heights at which the validator set is kept, so that a snapshot is
+/// replayed from at most this many headers.
+const SNAPSHOT_INTERVAL: usize = 64;
+
+pub type ValidatorKey = [u8; 32];
+
+/// A validator's vote to add or remove `candidate` from the validator set.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Vote {
+    pub candidate: ValidatorKey,
+    pub authorize: bool,
+}
+
+/// The extra data of a proof of authority header: an optional vote and the
+/// signer with its signature over the rest of the header.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PoaSeal {
+    pub vote: Option<Vote>,
+    pub signer: ValidatorKey,
+    pub signature: Signature,
+}
+
+impl PoaSeal {
+    pub fn from_header(header: &BlockHeader) -> Option<PoaSeal> {
+        if header.version < EXTRA_DATA_VERSION {
+            return None;
+        }
+
+        PoaSeal::from_bytes(&header.extra).ok()
+    }
+
+    fn encode_unsigned(vote: Option<Vote>, signer: &ValidatorKey, out: &mut Vec<u8>) {
+        match vote {
+            None => 0u8.encode(out),
+            Some(vote) => {
+                (if vote.authorize { 1u8 } else { 2u8 }).encode(out);
+                out.extend_from_slice(&vote.candidate);
+            }
+        }
+        out.extend_from_slice(signer);
+    }
+
+    /// The message signed by the validator: the header with only the vote
+    /// and the signer key in its extra data.
+    pub fn signing_message(
+        header: &BlockHeader,
+        vote: Option<Vote>,
+        signer: &ValidatorKey,
+    ) -> Vec<u8> {
+        let mut header = header.clone();
+        header.extra.clear();
+        Self::encode_unsigned(vote, signer, &mut header.extra);
+        header.to_bytes()
+    }
+
+    pub fn verify(&self, header: &BlockHeader) -> bool {
+        let message = Self::signing_message(header, self.vote, &self.signer);
+
+        UnparsedPublicKey::new(&ED25519, &self.signer)
+            .verify(&message, &self.signature.to_bytes())
+            .is_ok()
+    }
+}
+
+impl Encode for PoaSeal {
+    fn encode(&self, out: &mut Vec<u8>) {
+        Self::encode_unsigned(self.vote, &self.signer, out);
+        out.extend_from_slice(&self.signature.to_bytes());
+    }
+}
+
+impl Decode for PoaSeal {
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        let vote = match u8::decode(input)? {
+            0 => None,
+            tag @ (1 | 2) => Some(Vote {
+                candidate: take_array(input)?,
+                authorize: tag == 1,
+            }),
+            _ => return Err(DecodeError::InvalidValue("vote")),
+        };
+        let signer = take_array(input)?;
+        let signature = Signature::from_bytes(&take_array(input)?);
+
+        Ok(PoaSeal {
+            vote,
+            signer,
+            signature,
+        })
+    }
+}
+
+/// The validator set and pending votes after some block.
+#[derive(Clone, Debug)]
+pub struct Snapshot {
+    validators: BTreeSet<ValidatorKey>,
+    // Vote of each (voter, candidate) pair that hasn't passed yet.
+    votes: HashMap<(ValidatorKey, ValidatorKey), bool>,
+    // Signers of the latest blocks, a validator can't sign again until it
+    // drops out of this window.
+    recent: VecDeque<ValidatorKey>,
+}
+
+impl Snapshot {
+    pub fn new(validators: BTreeSet<ValidatorKey>) -> Self {
+        Snapshot {
+            validators,
+            votes: HashMap::new(),
+            recent: VecDeque::new(),
+        }
+    }
+
+    pub fn validators(&self) -> &BTreeSet<ValidatorKey> {
+        &self.validators
+    }
+
+    pub fn is_validator(&self, key: &ValidatorKey) -> bool {
+        self.validators.contains(key)
+    }
+
+    /// Validators take turns in the order of their keys.
+    pub fn in_turn(&self, height: usize, key: &ValidatorKey) -> bool {
+        if self.validators.is_empty() {
+            return false;
+        }
+
+        self.validators.iter().nth(height % self.validators.len()) == Some(key)
+    }
+
+    pub fn recently_signed(&self, key: &ValidatorKey) -> bool {
+        self.recent.contains(key)
+    }
+
+    /// Number of latest blocks a validator has to sit out after signing.
+    fn recent_window(&self) -> usize {
+        self.validators.len() / 2
+    }
+
+    /// Records a block sealed by `signer` and tallies its vote.
+    pub fn apply(&mut self, signer: ValidatorKey, vote: Option<Vote>) {
+        if let Some(vote) = vote {
+            self.tally(signer, vote);
+        }
+
+        self.recent.push_back(signer);
+        while self.recent.len() > self.recent_window() {
+            self.recent.pop_front();
+        }
+    }
+
+    fn tally(&mut self, voter: ValidatorKey, vote: Vote) {
+        // Votes for what the set already is are ignored, and so is removing
+        // the last validator.
+        if vote.authorize == self.is_validator(&vote.candidate)
+            || (!vote.authorize && self.validators.len() == 1)
+        {
+            return;
+        }
+
+        self.votes.insert((voter, vote.candidate), vote.authorize);
+        let count = self
+            .votes
+            .iter()
+            .filter(|((_, candidate), authorize)| {
+                *candidate == vote.candidate && **authorize == vote.authorize
+            })
+            .count();
+
+        if count <= self.validators.len() / 2 {
+            return;
+        }
+
+        if vote.authorize {
+            self.validators.insert(vote.candidate);
+        } else {
+            self.validators.remove(&vote.candidate);
+            self.votes.retain(|(voter, _), _| *voter != vote.candidate);
+            self.recent.retain(|signer| *signer != vote.candidate);
+        }
+        self.votes
+            .retain(|(_, candidate), _| *candidate != vote.candidate);
+    }
+}
+
+/// Round-robin proof of authority. A fixed genesis set of ed25519 validator
+/// keys takes turns sealing blocks, the in-turn validator's blocks weigh more
+/// so that they win forks, and validators vote others in and out through
+/// their headers.
+#[derive(Debug)]
+pub struct ProofOfAuthority {
+    validators: BTreeSet<ValidatorKey>,
+    signer: Option<Ed25519KeyPair>,
+    proposal: Option<Vote>,
+    /// Snapshots after the blocks at every `SNAPSHOT_INTERVAL` height seen,
+    /// by block hash.
+    checkpoints: Mutex<HashMap<U256, Snapshot>>,
+}
+
+impl ProofOfAuthority {
+    /// An engine that verifies blocks but can't seal them.
+    pub fn new(validators: impl IntoIterator<Item = ValidatorKey>) -> Self {
+        ProofOfAuthority {
+            validators: validators.into_iter().collect(),
+            signer: None,
+            proposal: None,
+            checkpoints: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Seals blocks with `keypair`.
+    pub fn with_signer(mut self, keypair: Ed25519KeyPair) -> Self {
+        self.signer = Some(keypair);
+        self
+    }
+
+    /// Vote to include in every block this engine seals, `None` stops voting.
+    pub fn propose(&mut self, vote: Option<Vote>) {
+        self.proposal = vote;
+    }
+
+    pub fn signer_key(&self) -> Option<ValidatorKey> {
+        let keypair = self.signer.as_ref()?;
+        keypair.public_key().as_ref().try_into().ok()
+    }
+
+    /// The validator set after the tip of `chain`, replayed from the genesis
+    /// set and the votes in the headers.
+    pub fn snapshot(&self, chain: &Chain) -> Snapshot {
+        self.replay(
+            chain
+                .items
+                .iter()
+                .rev()
+                .map(|block| (block.height, block.header_hash, &block.header)),
+        )
+    }
+
+    /// The validator set after the indexed block `hash`, replayed from the
+    /// headers of its branch.
+    pub fn branch_snapshot(&self, hash: &U256, index: &BlockIndex) -> Snapshot {
+        self.replay(
+            std::iter::successors(index.get(hash), |entry| index.get(&entry.header.prev))
+                .map(|entry| (entry.height, entry.hash, &entry.header)),
+        )
+    }
+
+    /// Replays the votes of `branch`, given from its tip down, on top of the
+    /// latest checkpoint in it or else the genesis set.
+    fn replay<'a>(&self, branch: impl Iterator<Item = (usize, U256, &'a BlockHeader)>) -> Snapshot {
+        let mut checkpoints = self
+            .checkpoints
+            .lock()
+            .expect("Checkpoints not to be poisoned");
+        let mut base = None;
+        let mut seals = Vec::new();
+        for (height, hash, header) in branch {
+            if let Some(snapshot) = checkpoints.get(&hash) {
+                base = Some(snapshot.clone());
+                break;
+            }
+            if height == 0 {
+                break;
+            }
+            let seal = PoaSeal::from_header(header).expect("Indexed header to be sealed");
+            seals.push((height, hash, seal));
+        }
+
+        let mut snapshot = base.unwrap_or_else(|| Snapshot::new(self.validators.clone()));
+        for (height, hash, seal) in seals.into_iter().rev() {
+            snapshot.apply(seal.signer, seal.vote);
+            if height.is_multiple_of(SNAPSHOT_INTERVAL) {
+                checkpoints.insert(hash, snapshot.clone());
+            }
+        }
+
+        snapshot
+    }
+}
+
+/// Checks the header at `height` is sealed by a validator of `snapshot`
+/// that didn't sign too recently, with the weight of its turn.
+fn check_signer(
+    snapshot: &Snapshot,
+    header: &BlockHeader,
+    height: usize,
+) -> Result<(), ValidationError> {
+    let seal =
+        PoaSeal::from_header(header).ok_or_else(|| ValidationError::BadSeal(header.hash()))?;
+
+    if !snapshot.is_validator(&seal.signer) {
+        return Err(ValidationError::UnauthorizedSigner(seal.signer));
+    }
+
+    if snapshot.recently_signed(&seal.signer) {
+        return Err(ValidationError::RecentlySigned(seal.signer));
+    }
+
+    let expected = if snapshot.in_turn(height, &seal.signer) {
+        DIFF_IN_TURN
+    } else {
+        DIFF_NO_TURN
+    };
+    if header.bits != expected {
+        return Err(ValidationError::BadDifficulty {
+            expected,
+            found: header.bits,
+        });
+    }
+
+    Ok(())
+}
+
+impl ConsensusEngine for ProofOfAuthority {
+    /// The difficulty for this engine's own key, the only one it can seal.
+    fn next_bits(&self, chain: &Chain, _params: &TinyBlockchainParams) -> u32 {
+        let height = chain.len();
+
+        match self.signer_key() {
+            Some(key) if self.snapshot(chain).in_turn(height, &key) => DIFF_IN_TURN,
+            _ => DIFF_NO_TURN,
+        }
+    }
+
+    fn seal(&self, block: &mut Block, _clock: &dyn Clock) -> Result<(), SealError> {
+        let (Some(keypair), Some(signer)) = (&self.signer, self.signer_key()) else {
+            return Err(SealError::NotAuthorized);
+        };
+
+        block.header.version = block.header.version.max(EXTRA_DATA_VERSION);
+        let message = PoaSeal::signing_message(&block.header, self.proposal, &signer);
+        let signed = keypair.sign(&message);
+        let seal = PoaSeal {
+            vote: self.proposal,
+            signer,
+            signature: Signature::from_slice(signed.as_ref())
+                .expect("Ed25519 signature to be 64 bytes"),
+        };
+
+        block.header.extra = seal.to_bytes();
+        block.header_hash = block.header.hash();
+        Ok(())
+    }
+
+    fn verify_seal(&self, block: &Block) -> Result<(), ValidationError> {
+        // Work is counted from `bits`, only the two weights exist.
+        if !matches!(block.header.bits, DIFF_IN_TURN | DIFF_NO_TURN) {
+            return Err(ValidationError::BadDifficulty {
+                expected: DIFF_IN_TURN,
+                found: block.header.bits,
+            });
+        }
+
+        match PoaSeal::from_header(&block.header) {
+            Some(seal) if seal.verify(&block.header) => Ok(()),
+            _ => Err(ValidationError::BadSeal(block.header_hash)),
+        }
+    }
+
+    /// Only validators of the branch may add weight to it, taking turns as
+    /// the branch says, so one key can't sign a heavy branch of its own.
+    fn verify_branch(
+        &self,
+        header: &BlockHeader,
+        index: &BlockIndex,
+        _params: &TinyBlockchainParams,
+    ) -> Result<(), ValidationError> {
+        let Some(parent) = index.get(&header.prev) else {
+            return Ok(());
+        };
+
+        check_signer(
+            &self.branch_snapshot(&header.prev, index),
+            header,
+            parent.height + 1,
+        )
+    }
+
+    fn verify_header(
+        &self,
+        block: &Block,
+        chain: &Chain,
+        _params: &TinyBlockchainParams,
+    ) -> Result<(), ValidationError> {
+        check_signer(&self.snapshot(chain), &block.header, block.height)
+    }
+
+    fn block_work(header: &BlockHeader) -> U256 {
+        U256::from(header.bits)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::test_params;
+    use crate::{FixedClock, TinyBlockchain, TinyBlockchainError, Transaction, UtxoOutput};
+
+    fn keypair(seed: u8) -> Ed25519KeyPair {
+        Ed25519KeyPair::from_seed_unchecked(&[seed; 32]).unwrap()
+    }
+
+    fn key(seed: u8) -> ValidatorKey {
+        keypair(seed).public_key().as_ref().try_into().unwrap()
+    }
+
+    fn signer(validators: &[u8], seed: u8) -> ProofOfAuthority {
+        ProofOfAuthority::new(validators.iter().map(|seed| key(*seed))).with_signer(keypair(seed))
+    }
+
+    fn blockchain(validators: &[u8]) -> TinyBlockchain<ProofOfAuthority> {
+        let header = BlockHeader {
+            version: 1,
+            timestamp: 1_700_000_000,
+            prev: U256::ZERO,
+            merkle_root: U256::ZERO,
+            bits: DIFF_IN_TURN,
+            nonce: 0,
+            extra: Vec::new(),
+        };
+        let chain = Chain {
+            items: vec![Block::new(0, header.hash(), header, vec![])],
+            last_update: 0,
+        };
+        let params = TinyBlockchainParams {
+            init_difficulty: DIFF_IN_TURN,
+            ..test_params()
+        };
+        let engine = ProofOfAuthority::new(validators.iter().map(|seed| key(*seed)));
+
+        TinyBlockchain::with_engine(chain, params, engine)
+    }
+
+    fn sealed_by(
+        engine: &ProofOfAuthority,
+        blockchain: &TinyBlockchain<ProofOfAuthority>,
+    ) -> Block {
+        let tip = blockchain.tip();
+        let height = tip.height + 1;
+        let coinbase = Transaction::coinbase(
+            height as u64,
+            engine.signer_key().unwrap()[0] as u64,
+            vec![UtxoOutput::new(U256::ONE, 1)],
+        );
+        let header = BlockHeader {
+            version: EXTRA_DATA_VERSION,
+            timestamp: tip.header.timestamp + 5,
+            prev: tip.header_hash,
+            merkle_root: U256::ZERO,
+            bits: engine.next_bits(blockchain.chain(), blockchain.params()),
+            nonce: 0,
+            extra: Vec::new(),
+        };
+        let mut block = Block::new(height, U256::ZERO, header, vec![coinbase]);
+        block.header.merkle_root = block.compute_merkle_root();
+        let clock = FixedClock(block.header.timestamp);
+        engine.seal(&mut block, &clock).unwrap();
+        block
+    }
+
+    fn in_turn_seed(blockchain: &TinyBlockchain<ProofOfAuthority>, seeds: &[u8]) -> u8 {
+        let snapshot = blockchain.engine().snapshot(blockchain.chain());
+        let height = blockchain.chain().len();
+        *seeds
+            .iter()
+            .find(|seed| snapshot.in_turn(height, &key(**seed)))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_seal_roundtrip() {
+        let blockchain = blockchain(&[1]);
+        let mut engine = signer(&[1], 1);
+        engine.propose(Some(Vote {
+            candidate: key(2),
+            authorize: true,
+        }));
+        let block = sealed_by(&engine, &blockchain);
+
+        let seal = PoaSeal::from_header(&block.header).unwrap();
+        assert_eq!(seal.signer, key(1));
+        assert_eq!(seal.vote.unwrap().candidate, key(2));
+        assert!(seal.verify(&block.header));
+        assert_eq!(engine.verify_seal(&block), Ok(()));
+
+        assert_eq!(
+            ProofOfAuthority::new([key(1)]).seal(&mut block.clone(), &FixedClock(0)),
+            Err(SealError::NotAuthorized)
+        );
+    }
+
+    #[test]
+    fn test_in_turn_block_wins() {
+        let seeds = [1, 2, 3];
+        let mut blockchain = blockchain(&seeds);
+        let in_turn = in_turn_seed(&blockchain, &seeds);
+        let out_of_turn = *seeds.iter().find(|seed| **seed != in_turn).unwrap();
+
+        let late = sealed_by(&signer(&seeds, out_of_turn), &blockchain);
+        let on_time = sealed_by(&signer(&seeds, in_turn), &blockchain);
+        assert_eq!(late.header.bits, DIFF_NO_TURN);
+        assert_eq!(on_time.header.bits, DIFF_IN_TURN);
+
+        blockchain.add_block(late.clone()).unwrap();
+        assert_eq!(blockchain.tip().header_hash, late.header_hash);
+        blockchain.add_block(on_time.clone()).unwrap();
+        assert_eq!(blockchain.tip().header_hash, on_time.header_hash);
+    }
+
+    #[test]
+    fn test_rejects_unauthorized_signer() {
+        let mut blockchain = blockchain(&[1, 2]);
+        let block = sealed_by(&signer(&[1, 2, 9], 9), &blockchain);
+
+        assert_eq!(
+            blockchain.add_block(block),
+            Err(TinyBlockchainError::InvalidBlock(
+                ValidationError::UnauthorizedSigner(key(9))
+            ))
+        );
+    }
+
+    #[test]
+    fn test_unauthorized_headers_add_no_work() {
+        let mut blockchain = blockchain(&[1, 2]);
+        let tip = blockchain.tip().header_hash;
+        let outsider = signer(&[1, 2, 9], 9);
+
+        let mut heavy = sealed_by(&outsider, &blockchain);
+        heavy.header.bits = u32::MAX;
+        outsider.seal(&mut heavy, &FixedClock(0)).unwrap();
+        assert_eq!(
+            blockchain.add_header(heavy.header.clone()),
+            Err(TinyBlockchainError::InvalidBlock(
+                ValidationError::BadDifficulty {
+                    expected: DIFF_IN_TURN,
+                    found: u32::MAX
+                }
+            ))
+        );
+
+        let block = sealed_by(&outsider, &blockchain);
+        assert_eq!(
+            blockchain.add_header(block.header.clone()),
+            Err(TinyBlockchainError::InvalidBlock(
+                ValidationError::UnauthorizedSigner(key(9))
+            ))
+        );
+        assert!(!blockchain.index().contains(&block.header_hash));
+        assert_eq!(blockchain.index().best_header().hash, tip);
+    }
+
+    #[test]
+    fn test_headers_take_turns() {
+        let seeds = [1, 2, 3];
+        let mut blockchain = blockchain(&seeds);
+        let in_turn = in_turn_seed(&blockchain, &seeds);
+        let out_of_turn = signer(
+            &seeds,
+            *seeds.iter().find(|seed| **seed != in_turn).unwrap(),
+        );
+
+        // Claiming the in-turn weight out of turn.
+        let mut heavy = sealed_by(&out_of_turn, &blockchain);
+        heavy.header.bits = DIFF_IN_TURN;
+        out_of_turn.seal(&mut heavy, &FixedClock(0)).unwrap();
+        assert_eq!(
+            blockchain.add_header(heavy.header.clone()),
+            Err(TinyBlockchainError::InvalidBlock(
+                ValidationError::BadDifficulty {
+                    expected: DIFF_NO_TURN,
+                    found: DIFF_IN_TURN
+                }
+            ))
+        );
+
+        // Signing twice in a row.
+        blockchain
+            .add_block(sealed_by(&out_of_turn, &blockchain))
+            .unwrap();
+        let again = sealed_by(&out_of_turn, &blockchain);
+        assert_eq!(
+            blockchain.add_header(again.header),
+            Err(TinyBlockchainError::InvalidBlock(
+                ValidationError::RecentlySigned(out_of_turn.signer_key().unwrap())
+            ))
+        );
+    }
+
+    #[test]
+    fn test_snapshots_resume_from_checkpoints() {
+        let seeds = [1, 2, 3];
+        let mut blockchain = blockchain(&seeds);
+        let mut engines: Vec<ProofOfAuthority> =
+            seeds.iter().map(|seed| signer(&seeds, *seed)).collect();
+        for engine in &mut engines[..2] {
+            engine.propose(Some(Vote {
+                candidate: key(4),
+                authorize: true,
+            }));
+        }
+
+        for height in 1..=2 * SNAPSHOT_INTERVAL + 1 {
+            let block = sealed_by(&engines[height % seeds.len()], &blockchain);
+            blockchain.add_block(block).unwrap();
+        }
+
+        let checkpoints = blockchain.engine().checkpoints.lock().unwrap().len();
+        assert_eq!(checkpoints, 2);
+        let snapshot = blockchain.engine().snapshot(blockchain.chain());
+        let replayed = ProofOfAuthority::new(seeds.map(key)).snapshot(blockchain.chain());
+        assert_eq!(snapshot.validators(), replayed.validators());
+        assert!(snapshot.is_validator(&key(4)));
+        assert_eq!(snapshot.recent, replayed.recent);
+    }
+
+    #[test]
+    fn test_rejects_bad_seal() {
+        let mut blockchain = blockchain(&[1]);
+        let block = sealed_by(&signer(&[1], 1), &blockchain);
+
+        let mut forged = block.clone();
+        let last = forged.header.extra.len() - 1;
+        forged.header.extra[last] ^= 1;
+        forged.header_hash = forged.header.hash();
+        assert_eq!(
+            blockchain.add_block(forged.clone()),
+            Err(TinyBlockchainError::InvalidBlock(ValidationError::BadSeal(
+                forged.header_hash
+            )))
+        );
+
+        // The signature commits to the rest of the header.
+        let mut moved = block.clone();
+        moved.header.timestamp += 1;
+        moved.header_hash = moved.header.hash();
+        assert_eq!(
+            blockchain.add_block(moved.clone()),
+            Err(TinyBlockchainError::InvalidBlock(ValidationError::BadSeal(
+                moved.header_hash
+            )))
+        );
+
+        blockchain.add_block(block).unwrap();
+    }
+
+    #[test]
+    fn test_rejects_recent_signer() {
+        let seeds = [1, 2, 3];
+        let mut blockchain = blockchain(&seeds);
+        let engine = signer(&seeds, 1);
+
+        blockchain
+            .add_block(sealed_by(&engine, &blockchain))
+            .unwrap();
+        assert_eq!(
+            blockchain.add_block(sealed_by(&engine, &blockchain)),
+            Err(TinyBlockchainError::InvalidBlock(
+                ValidationError::RecentlySigned(key(1))
+            ))
+        );
+
+        blockchain
+            .add_block(sealed_by(&signer(&seeds, 2), &blockchain))
+            .unwrap();
+        blockchain
+            .add_block(sealed_by(&engine, &blockchain))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_votes_add_and_remove_validator() {
+        let seeds = [1, 2, 3];
+        let mut blockchain = blockchain(&seeds);
+        let mut engines: Vec<ProofOfAuthority> = [1, 2, 3, 4]
+            .iter()
+            .map(|seed| signer(&seeds, *seed))
+            .collect();
+        let vote = |authorize| {
+            Some(Vote {
+                candidate: key(4),
+                authorize,
+            })
+        };
+
+        // Two of three validators are a majority.
+        for index in [0, 1] {
+            engines[index].propose(vote(true));
+            let block = sealed_by(&engines[index], &blockchain);
+            blockchain.add_block(block).unwrap();
+        }
+        let snapshot = blockchain.engine().snapshot(blockchain.chain());
+        assert!(snapshot.is_validator(&key(4)));
+        blockchain
+            .add_block(sealed_by(&engines[3], &blockchain))
+            .unwrap();
+
+        // With four validators it takes three votes to remove one.
+        for index in [2, 0, 1] {
+            let snapshot = blockchain.engine().snapshot(blockchain.chain());
+            assert!(snapshot.is_validator(&key(4)));
+            engines[index].propose(vote(false));
+            let block = sealed_by(&engines[index], &blockchain);
+            blockchain.add_block(block).unwrap();
+        }
+        let snapshot = blockchain.engine().snapshot(blockchain.chain());
+        assert!(!snapshot.is_validator(&key(4)));
+        assert_eq!(snapshot.validators().len(), 3);
+
+        assert_eq!(
+            blockchain.add_block(sealed_by(&engines[3], &blockchain)),
+            Err(TinyBlockchainError::InvalidBlock(
+                ValidationError::UnauthorizedSigner(key(4))
+            ))
+        );
+    }
+}
@@ -1,36 +1,97 @@
 use crate::{
-    get_epoch_time, hash_to_u256, pack_to_32_bits, seconds_now, unpack_to_256_bits, Block, Chain,
-    Hash, TinyBlockchainParams,
+    asert_next_bits, block_work, hash_to_u256, is_epoch, lwma_next_bits, mul_div, pow_limit, Block,
+    BlockHeader, BlockIndex, BlockIndexEntry, Chain, Clock, CompactTarget, ConsensusEngine,
+    DifficultyAlgorithm, Encode, Hash, HeaderChain, SealError, TinyBlockchainParams,
+    ValidationError, BLOCK_HEADER_SIZE,
 };
 use ethnum::*;
 
+/// Position of the nonce in the encoded header, only extra data follows it.
+pub(crate) const NONCE_OFFSET: usize = BLOCK_HEADER_SIZE - 4;
+
+/// Bitcoin style proof of work, the branch with the most expected hashes wins.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ProofOfWork;
+
+impl ConsensusEngine for ProofOfWork {
+    fn next_bits(&self, chain: &Chain, params: &TinyBlockchainParams) -> u32 {
+        next_bits(chain, params)
+    }
+
+    fn seal(&self, block: &mut Block, clock: &dyn Clock) -> Result<(), SealError> {
+        let proof = pow(&block.header, clock);
+        block.header.timestamp = proof.timestamp;
+        block.header.nonce = proof.nonce;
+        block.header_hash = proof.hash;
+        Ok(())
+    }
+
+    fn verify_seal(&self, block: &Block) -> Result<(), ValidationError> {
+        if !check_proof(&block.header) {
+            return Err(ValidationError::HighHash(block.header_hash));
+        }
+
+        Ok(())
+    }
+
+    /// The bits must be the ones the difficulty algorithm picks after the
+    /// indexed parent, so a branch of cheap headers can't claim any weight.
+    fn verify_branch(
+        &self,
+        header: &BlockHeader,
+        index: &BlockIndex,
+        params: &TinyBlockchainParams,
+    ) -> Result<(), ValidationError> {
+        let within_limit = CompactTarget::new(header.bits)
+            .to_target()
+            .is_ok_and(|target| target <= pow_limit(params));
+        if !within_limit {
+            return Err(ValidationError::TargetAboveLimit {
+                limit: params.pow_limit,
+                found: header.bits,
+            });
+        }
+
+        let Some(parent) = index.get(&header.prev) else {
+            return Ok(());
+        };
+        let expected = next_bits(&BranchHeaders::new(index, parent, params), params);
+        if header.bits != expected {
+            return Err(ValidationError::BadDifficulty {
+                expected,
+                found: header.bits,
+            });
+        }
+
+        Ok(())
+    }
+
+    fn block_work(header: &BlockHeader) -> U256 {
+        block_work(header.bits)
+    }
+}
+
 pub struct Proof {
     pub timestamp: u64,
     pub nonce: u32,
-    pub hash: U256, // merkle_root: U256,
+    pub hash: U256,
 }
 
-pub fn pow_validate(items: &Vec<Block>) -> bool {
+pub fn pow_validate(items: &[Block]) -> bool {
     if items.is_empty() {
         return false;
     }
 
-    // TODO: check if current index out of bounds
-    for prev in 0..items.len() - 1 {
-        let curr = prev + 1;
-        let curr_block = items.get(curr).expect("Current block should exist");
-        let prev_block = items.get(prev).expect("Previous block should exist");
-        let current_hash = unpack_to_256_bits(curr_block.header.bits);
-
+    for (prev_block, curr_block) in items.iter().zip(items.iter().skip(1)) {
         if curr_block.header.prev != prev_block.header.hash() {
             return false;
         }
 
-        let hash = hash_proof(curr_block.header.timestamp, curr_block.header.nonce);
+        if curr_block.header_hash != curr_block.header.hash() {
+            return false;
+        }
 
-        // TODO: why less or equal, not just equal
-        // if hash <= current_hash {
-        if hash != current_hash {
+        if !check_proof(&curr_block.header) {
             return false;
         }
     }
@@ -38,61 +99,365 @@ pub fn pow_validate(items: &Vec<Block>) -> bool {
     true
 }
 
-pub fn pow(bits: u32) -> Proof {
-    let mut nonce: u32 = 1;
-    let mut timestamp = seconds_now();
-    let mut hash: U256;
-    let target_hash = unpack_to_256_bits(bits);
+/// Checks that the header hash meets the target encoded in its own `bits`,
+/// which must be a positive target.
+pub fn check_proof(header: &BlockHeader) -> bool {
+    match CompactTarget::new(header.bits).to_target() {
+        Ok(target) if target != U256::ZERO => hash_proof(header) <= target,
+        _ => false,
+    }
+}
+
+/// Searches for a nonce that makes `header` meet its target. Every other
+/// field of the header is committed to by the proof; only the timestamp is
+/// moved forward when the nonce space is exhausted.
+pub fn pow<C: Clock + ?Sized>(header: &BlockHeader, clock: &C) -> Proof {
+    let target_hash = CompactTarget::new(header.bits)
+        .to_target()
+        .expect("Header bits to encode a valid target");
+    let mut timestamp = header.timestamp;
+    // The header is encoded once, only the nonce bytes change between attempts.
+    let mut bytes = header.to_bytes();
+    let mut nonce = header.nonce;
 
     loop {
-        hash = hash_proof(timestamp, nonce);
+        bytes[NONCE_OFFSET..NONCE_OFFSET + 4].copy_from_slice(&nonce.to_le_bytes());
+        let hash = hash_to_u256!(&bytes);
 
         if hash <= target_hash {
-            break;
+            return Proof {
+                timestamp,
+                nonce,
+                hash,
+            };
         }
 
         let (new_nonce, overflowed) = nonce.overflowing_add(1);
         if overflowed {
-            timestamp = seconds_now();
+            timestamp = clock.now().max(timestamp + 1);
+            let mut header = header.clone();
+            header.timestamp = timestamp;
+            bytes = header.to_bytes();
         }
 
         nonce = new_nonce;
     }
+}
+
+pub fn hash_proof(header: &BlockHeader) -> U256 {
+    header.hash()
+}
 
-    Proof {
-        timestamp,
-        nonce,
-        hash,
+// TODO: not sure that rest of the logic is related to POW
+
+/// The `bits` the block following the current tip must carry, as chosen by
+/// the difficulty algorithm of `params`.
+pub fn next_bits(chain: &impl HeaderChain, params: &TinyBlockchainParams) -> u32 {
+    match params.difficulty {
+        DifficultyAlgorithm::Epoch => epoch_next_bits(chain, params),
+        DifficultyAlgorithm::Lwma { window } => lwma_next_bits(chain, window, params),
+        DifficultyAlgorithm::Asert { half_life } => asert_next_bits(chain, half_life, params),
     }
 }
 
-pub fn hash_proof(timestamp: u64, nonce: u32) -> U256 {
-    let bytes = [timestamp.to_be_bytes(), (nonce as u64).to_be_bytes()].concat();
+/// Bitcoin's rule, the target only changes on the first block of an epoch.
+pub fn epoch_next_bits(chain: &impl HeaderChain, params: &TinyBlockchainParams) -> u32 {
+    let len = chain.next_height();
+    let Some(prev_header) = len
+        .checked_sub(1)
+        .and_then(|height| chain.header_at(height))
+    else {
+        return params.init_difficulty;
+    };
 
-    hash_to_u256!(bytes)
+    if is_epoch(len - 1, chain, params) {
+        let epoch_start = chain
+            .header_at(len - params.blocks_in_epoch)
+            .expect("Epoch start block should exist");
+        let timespan = prev_header.timestamp.saturating_sub(epoch_start.timestamp);
+        return retarget(prev_header.bits, timespan, params);
+    }
+
+    prev_header.bits
 }
 
-// TODO: not sure that rest of the logic is related to POW
+/// The end of an indexed branch, as deep as the difficulty algorithm looks
+/// back from its tip, and the genesis header ASERT is anchored at.
+struct BranchHeaders<'a> {
+    genesis: &'a BlockHeader,
+    /// Headers up to and including the tip, lowest first.
+    tail: Vec<&'a BlockHeader>,
+    next_height: usize,
+}
+
+impl<'a> BranchHeaders<'a> {
+    fn new(index: &'a BlockIndex, tip: &'a BlockIndexEntry, params: &TinyBlockchainParams) -> Self {
+        let depth = match params.difficulty {
+            DifficultyAlgorithm::Epoch if tip.height.is_multiple_of(params.blocks_in_epoch) => {
+                params.blocks_in_epoch
+            }
+            DifficultyAlgorithm::Epoch | DifficultyAlgorithm::Asert { .. } => 1,
+            DifficultyAlgorithm::Lwma { window } => window + 1,
+        };
 
-pub fn retarget(bits: u32, chain: &Chain, params: &TinyBlockchainParams) -> u32 {
-    let epoch_takes = get_epoch_time(chain, params);
-    let mut change_factor = epoch_takes as f64 / (params.blocks_in_epoch * 10 * 60) as f64;
+        let mut tail = Vec::new();
+        let mut current = Some(tip);
+        while let Some(entry) = current.filter(|_| tail.len() < depth) {
+            tail.push(&entry.header);
+            current = index.get(&entry.header.prev);
+        }
+        tail.reverse();
 
-    if change_factor > 4.0 {
-        change_factor = 4.0
-    } else if change_factor < 0.25 {
-        change_factor = 0.25
+        BranchHeaders {
+            genesis: &index
+                .get(&index.genesis())
+                .expect("Genesis to be indexed")
+                .header,
+            tail,
+            next_height: tip.height + 1,
+        }
     }
+}
 
-    let new_bits = calc_difficulty(change_factor, bits);
+impl HeaderChain for BranchHeaders<'_> {
+    fn next_height(&self) -> usize {
+        self.next_height
+    }
 
-    new_bits
+    fn header_at(&self, height: usize) -> Option<&BlockHeader> {
+        let first = self.next_height - self.tail.len();
+        match height {
+            height if height >= self.next_height => None,
+            height if height >= first => Some(self.tail[height - first]),
+            0 => Some(self.genesis),
+            _ => None,
+        }
+    }
 }
 
-pub fn calc_difficulty(change_factor: f64, bits: u32) -> u32 {
-    let mantissa = bits & 0x00ffffff;
-    let exp = ((bits >> 24) & 0xff) - 3;
-    let new_value = (change_factor * mantissa as f64).as_u256() * (256).as_u256().pow(exp);
+/// Scales the target of `bits` by how long the epoch took compared to the
+/// expected timespan, clamped to `max_adjustment` and the proof of work
+/// limit. Only integer arithmetic is used so every node gets the same bits.
+pub fn retarget(bits: u32, timespan: u64, params: &TinyBlockchainParams) -> u32 {
+    let expected = params.epoch_timespan();
+    let timespan = timespan.clamp(
+        expected / params.max_adjustment,
+        expected * params.max_adjustment,
+    );
+
+    let pow_limit = pow_limit(params);
+    let target = CompactTarget::new(bits).to_target().unwrap_or(pow_limit);
+    let new_target = mul_div(target, timespan, expected);
+
+    CompactTarget::from_target(new_target.min(pow_limit)).bits()
+}
 
-    pack_to_32_bits(new_value)
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::test_params;
+    use crate::FixedClock;
+
+    // Roughly one in 65536 hashes meets this target, so tests mine quickly
+    // while a tampered header practically never passes by chance.
+    const TEST_BITS: u32 = 0x1f00ffff;
+
+    fn genesis() -> Block {
+        let header = BlockHeader {
+            version: 1,
+            timestamp: 1_700_000_000,
+            prev: U256::ZERO,
+            merkle_root: U256::ZERO,
+            bits: TEST_BITS,
+            nonce: 0,
+            extra: Vec::new(),
+        };
+        Block::new(0, header.hash(), header, vec![])
+    }
+
+    fn mine_on(prev: &Block) -> Block {
+        let mut header = BlockHeader {
+            version: 1,
+            timestamp: prev.header.timestamp + 600,
+            prev: prev.header_hash,
+            merkle_root: U256::from(prev.height as u64 + 0xabcd),
+            bits: TEST_BITS,
+            nonce: 0,
+            extra: Vec::new(),
+        };
+        let proof = pow(&header, &FixedClock(header.timestamp));
+        header.timestamp = proof.timestamp;
+        header.nonce = proof.nonce;
+        assert_eq!(proof.hash, header.hash());
+
+        Block::new(prev.height + 1, proof.hash, header, vec![])
+    }
+
+    fn test_chain(len: usize) -> Vec<Block> {
+        let mut blocks = vec![genesis()];
+        for _ in 1..len {
+            let block = mine_on(blocks.last().unwrap());
+            blocks.push(block);
+        }
+        blocks
+    }
+
+    #[test]
+    fn test_mined_chain_is_valid() {
+        let blocks = test_chain(4);
+        assert!(pow_validate(&blocks));
+        for block in &blocks[1..] {
+            assert!(check_proof(&block.header));
+        }
+    }
+
+    #[test]
+    fn test_empty_chain_is_invalid() {
+        assert!(!pow_validate(&[]));
+    }
+
+    #[test]
+    fn test_tampering_any_header_field_invalidates_proof() {
+        let block = mine_on(&genesis());
+        let tampered: [fn(&mut BlockHeader); 6] = [
+            |h| h.version += 1,
+            |h| h.timestamp += 1,
+            |h| h.prev += 1,
+            |h| h.merkle_root += 1,
+            |h| h.bits -= 1,
+            |h| h.nonce = h.nonce.wrapping_add(1),
+        ];
+
+        for tamper in tampered {
+            let mut header = block.header.clone();
+            tamper(&mut header);
+            assert!(!check_proof(&header), "tampered header {:?}", header);
+        }
+    }
+
+    #[test]
+    fn test_easier_bits_do_not_validate_harder_claim() {
+        let block = mine_on(&genesis());
+        let mut header = block.header.clone();
+        // Claiming a harder target than the one mined for must fail.
+        header.bits = 0x1e00ffff;
+        assert!(!check_proof(&header));
+    }
+
+    #[test]
+    fn test_proof_cannot_be_reused_for_other_contents() {
+        let genesis = genesis();
+        let block = mine_on(&genesis);
+        let mut header = block.header.clone();
+        header.merkle_root = U256::from(0x1234u32);
+        let forged = Block::new(1, header.hash(), header, vec![]);
+        assert!(!pow_validate(&[genesis, forged]));
+    }
+
+    #[test]
+    fn test_tampered_block_breaks_chain() {
+        let mut blocks = test_chain(3);
+        blocks[1].header.timestamp += 1;
+        blocks[1].header_hash = blocks[1].header.hash();
+        assert!(!pow_validate(&blocks));
+    }
+
+    #[test]
+    fn test_stale_header_hash_is_rejected() {
+        let mut blocks = test_chain(2);
+        blocks[1].header_hash += 1;
+        assert!(!pow_validate(&blocks));
+    }
+
+    fn mainnet_params() -> TinyBlockchainParams {
+        TinyBlockchainParams {
+            blocks_in_epoch: 2016,
+            init_difficulty: 0x1d00ffff,
+            pow_limit: 0x1d00ffff,
+            ..test_params()
+        }
+    }
+
+    // Vectors from Bitcoin Core's pow_tests, timespans are the difference
+    // between the last and first block timestamps of the epoch.
+    #[test]
+    fn test_retarget_mainnet_vectors() {
+        let params = mainnet_params();
+
+        assert_eq!(
+            retarget(0x1d00ffff, 1262152739 - 1261130161, &params),
+            0x1d00d86a
+        );
+        // Would be easier than the limit.
+        assert_eq!(
+            retarget(0x1d00ffff, 1233061996 - 1231006505, &params),
+            0x1d00ffff
+        );
+        // Clamped to a quarter of the expected timespan.
+        assert_eq!(
+            retarget(0x1c05a3f4, 1279297671 - 1279008237, &params),
+            0x1c0168fd
+        );
+        // Clamped to four times the expected timespan.
+        assert_eq!(
+            retarget(0x1c387f6f, 1269211443 - 1263163443, &params),
+            0x1d00e1fd
+        );
+    }
+
+    #[test]
+    fn test_retarget_near_max_target_does_not_overflow() {
+        let params = TinyBlockchainParams {
+            pow_limit: 0x2100ffff,
+            ..mainnet_params()
+        };
+        let bits = retarget(0x2100ffff, params.epoch_timespan() * 2, &params);
+        assert_eq!(bits, 0x2100ffff);
+    }
+
+    fn timed_chain(len: usize, spacing: u64, bits: u32) -> Chain {
+        let items = (0..len)
+            .map(|height| {
+                let header = BlockHeader {
+                    version: 1,
+                    timestamp: 1_700_000_000 + height as u64 * spacing,
+                    prev: U256::ZERO,
+                    merkle_root: U256::ZERO,
+                    bits,
+                    nonce: 0,
+                    extra: Vec::new(),
+                };
+                Block::new(height, header.hash(), header, vec![])
+            })
+            .collect();
+
+        Chain {
+            items,
+            last_update: 0,
+        }
+    }
+
+    #[test]
+    fn test_next_bits_uses_block_timestamps() {
+        let params = TinyBlockchainParams {
+            blocks_in_epoch: 4,
+            pow_limit: 0x207fffff,
+            ..mainnet_params()
+        };
+
+        // Not at an epoch boundary, the bits carry over.
+        let chain = timed_chain(4, 300, 0x1f00ffff);
+        assert_eq!(next_bits(&chain, &params), 0x1f00ffff);
+
+        // Blocks came twice as fast, the target halves. The epoch spans
+        // three intervals between its first and last block.
+        let chain = timed_chain(5, 200, 0x1f00ffff);
+        let expected = CompactTarget::from_target(
+            CompactTarget::new(0x1f00ffff).to_target().unwrap() * 600 / 2400,
+        );
+        assert_eq!(next_bits(&chain, &params), expected.bits());
+
+        // The same chain gives the same bits whenever it is evaluated.
+        assert_eq!(next_bits(&chain, &params), next_bits(&chain, &params));
+    }
 }
@@ -0,0 +1,280 @@
+// Canonical consensus encoding.
+//
+// Every primitive that is hashed, signed or sent over the wire is serialized
+// with this byte-exact format. Fixed width integers are little-endian, lengths
+// are Bitcoin-style variable length integers (CompactSize) and 256-bit values
+// are written as 32 little-endian bytes. Changing anything here forks the
+// chain, so keep the golden vectors in the tests in sync with intent.
+use ethnum::U256;
+
+/// Upper bound for any length prefix read from untrusted input. It protects
+/// decoders from allocating huge buffers for a forged varint.
+pub const MAX_ENCODED_LENGTH: u64 = 32 * 1024 * 1024;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    UnexpectedEof,
+    NonCanonicalVarInt,
+    LengthTooLarge(u64),
+    TrailingBytes(usize),
+    UnsupportedVersion(u32),
+    InvalidSignature,
+    InvalidValue(&'static str),
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeError::UnexpectedEof => write!(f, "unexpected end of input"),
+            DecodeError::NonCanonicalVarInt => write!(f, "non-canonical varint"),
+            DecodeError::LengthTooLarge(len) => write!(f, "length {} is too large", len),
+            DecodeError::TrailingBytes(len) => write!(f, "{} trailing bytes", len),
+            DecodeError::UnsupportedVersion(version) => {
+                write!(f, "unsupported version {}", version)
+            }
+            DecodeError::InvalidSignature => write!(f, "invalid signature encoding"),
+            DecodeError::InvalidValue(what) => write!(f, "invalid value: {}", what),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+pub trait Encode {
+    /// Appends the canonical encoding of `self` to `out`.
+    fn encode(&self, out: &mut Vec<u8>);
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.encode(&mut out);
+        out
+    }
+}
+
+pub trait Decode: Sized {
+    /// Reads a value from the front of `input`, advancing it past the
+    /// consumed bytes.
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError>;
+
+    /// Decodes a value that must span the whole of `bytes`.
+    fn from_bytes(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let mut input = bytes;
+        let value = Self::decode(&mut input)?;
+
+        if !input.is_empty() {
+            return Err(DecodeError::TrailingBytes(input.len()));
+        }
+
+        Ok(value)
+    }
+}
+
+pub fn take<'a>(input: &mut &'a [u8], len: usize) -> Result<&'a [u8], DecodeError> {
+    if input.len() < len {
+        return Err(DecodeError::UnexpectedEof);
+    }
+
+    let (head, tail) = input.split_at(len);
+    *input = tail;
+
+    Ok(head)
+}
+
+pub fn take_array<const N: usize>(input: &mut &[u8]) -> Result<[u8; N], DecodeError> {
+    let mut array = [0u8; N];
+    array.copy_from_slice(take(input, N)?);
+    Ok(array)
+}
+
+pub fn encode_varint(value: u64, out: &mut Vec<u8>) {
+    match value {
+        0..=0xfc => out.push(value as u8),
+        0xfd..=0xffff => {
+            out.push(0xfd);
+            out.extend_from_slice(&(value as u16).to_le_bytes());
+        }
+        0x10000..=0xffff_ffff => {
+            out.push(0xfe);
+            out.extend_from_slice(&(value as u32).to_le_bytes());
+        }
+        _ => {
+            out.push(0xff);
+            out.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+}
+
+pub fn decode_varint(input: &mut &[u8]) -> Result<u64, DecodeError> {
+    let prefix = take_array::<1>(input)?[0];
+
+    let (value, min) = match prefix {
+        0xfd => (u16::from_le_bytes(take_array(input)?) as u64, 0xfd),
+        0xfe => (u32::from_le_bytes(take_array(input)?) as u64, 0x10000),
+        0xff => (u64::from_le_bytes(take_array(input)?), 0x1_0000_0000),
+        value => return Ok(value as u64),
+    };
+
+    // Every value has exactly one valid encoding, otherwise the same data
+    // could produce different hashes.
+    if value < min {
+        return Err(DecodeError::NonCanonicalVarInt);
+    }
+
+    Ok(value)
+}
+
+/// Decodes a varint used as the length of a following collection.
+pub fn decode_length(input: &mut &[u8]) -> Result<usize, DecodeError> {
+    let len = decode_varint(input)?;
+
+    if len > MAX_ENCODED_LENGTH {
+        return Err(DecodeError::LengthTooLarge(len));
+    }
+
+    Ok(len as usize)
+}
+
+pub fn encode_list<T: Encode>(items: &[T], out: &mut Vec<u8>) {
+    encode_varint(items.len() as u64, out);
+    for item in items {
+        item.encode(out);
+    }
+}
+
+pub fn decode_list<T: Decode>(input: &mut &[u8]) -> Result<Vec<T>, DecodeError> {
+    let len = decode_length(input)?;
+    // Every item takes at least one byte, so a length larger than the
+    // remaining input is malformed and must not reserve memory for it.
+    if len > input.len() {
+        return Err(DecodeError::UnexpectedEof);
+    }
+
+    let mut items = Vec::with_capacity(len);
+    for _ in 0..len {
+        items.push(T::decode(input)?);
+    }
+
+    Ok(items)
+}
+
+macro_rules! impl_int_encoding {
+    ($($t:ty),*) => {$(
+        impl Encode for $t {
+            fn encode(&self, out: &mut Vec<u8>) {
+                out.extend_from_slice(&self.to_le_bytes());
+            }
+        }
+
+        impl Decode for $t {
+            fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+                Ok(<$t>::from_le_bytes(take_array(input)?))
+            }
+        }
+    )*};
+}
+
+impl_int_encoding!(u8, u16, u32, u64);
+
+impl Encode for U256 {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.to_le_bytes());
+    }
+}
+
+impl Decode for U256 {
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        Ok(U256::from_le_bytes(take_array(input)?))
+    }
+}
+
+impl Encode for Vec<u8> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        encode_varint(self.len() as u64, out);
+        out.extend_from_slice(self);
+    }
+}
+
+impl Decode for Vec<u8> {
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        let len = decode_length(input)?;
+        Ok(take(input, len)?.to_vec())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn varint_bytes(value: u64) -> Vec<u8> {
+        let mut out = Vec::new();
+        encode_varint(value, &mut out);
+        out
+    }
+
+    #[test]
+    fn test_varint_golden_vectors() {
+        let vectors: &[(u64, &str)] = &[
+            (0, "00"),
+            (0xfc, "fc"),
+            (0xfd, "fdfd00"),
+            (0xffff, "fdffff"),
+            (0x10000, "fe00000100"),
+            (0xffff_ffff, "feffffffff"),
+            (0x1_0000_0000, "ff0000000001000000"),
+            (u64::MAX, "ffffffffffffffffff"),
+        ];
+
+        for (value, expected) in vectors {
+            let bytes = varint_bytes(*value);
+            assert_eq!(hex::encode(&bytes), *expected);
+            assert_eq!(decode_varint(&mut bytes.as_slice()), Ok(*value));
+        }
+    }
+
+    #[test]
+    fn test_varint_rejects_non_canonical() {
+        for bytes in ["fd0000", "fdfc00", "feffff0000", "ffffffffff00000000"] {
+            let bytes = hex::decode(bytes).unwrap();
+            assert_eq!(
+                decode_varint(&mut bytes.as_slice()),
+                Err(DecodeError::NonCanonicalVarInt)
+            );
+        }
+    }
+
+    #[test]
+    fn test_varint_truncated() {
+        let bytes = hex::decode("fe0000").unwrap();
+        assert_eq!(
+            decode_varint(&mut bytes.as_slice()),
+            Err(DecodeError::UnexpectedEof)
+        );
+    }
+
+    #[test]
+    fn test_u256_little_endian() {
+        let value = U256::from(0x0102u32);
+        let bytes = value.to_bytes();
+        assert_eq!(bytes.len(), 32);
+        assert_eq!(&bytes[..3], &[0x02, 0x01, 0x00]);
+        assert_eq!(U256::from_bytes(&bytes), Ok(value));
+    }
+
+    #[test]
+    fn test_trailing_bytes() {
+        assert_eq!(
+            u32::from_bytes(&[1, 0, 0, 0, 0]),
+            Err(DecodeError::TrailingBytes(1))
+        );
+    }
+
+    #[test]
+    fn test_oversized_list() {
+        let mut bytes = Vec::new();
+        encode_varint(MAX_ENCODED_LENGTH + 1, &mut bytes);
+        assert_eq!(
+            Vec::<u8>::from_bytes(&bytes),
+            Err(DecodeError::LengthTooLarge(MAX_ENCODED_LENGTH + 1))
+        );
+    }
+}
@@ -0,0 +1,482 @@
+use crate::{fee_at_rate, Block, MempoolEvent};
+use ethnum::U256;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Blocks an estimate can target at most.
+pub const MAX_CONFIRMATION_TARGET: usize = 48;
+/// Share of the transactions paying an estimated fee rate that confirmed in
+/// time, for estimates given to peers and wallets.
+pub const DEFAULT_CONFIDENCE: f64 = 0.85;
+
+/// Fee rates, per 1000 bytes, are bucketed from here up, everything below
+/// falls in the first bucket.
+const MIN_BUCKET_FEE_RATE: f64 = 1_000.0;
+const MAX_BUCKET_FEE_RATE: f64 = 10_000_000.0;
+const BUCKET_SPACING: f64 = 1.1;
+/// Weight kept by the history for every new block, so data fades with a
+/// half life of about 350 blocks.
+const DECAY: f64 = 0.998;
+/// Transactions a range of buckets needs before its success rate is trusted.
+const SUFFICIENT_TXS: f64 = 10.0;
+
+#[derive(Debug)]
+pub enum FeeEstimatorError {
+    Io(io::Error),
+    /// The saved estimates can't be read back.
+    Corrupted(String),
+}
+
+impl std::fmt::Display for FeeEstimatorError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FeeEstimatorError::Io(err) => write!(f, "fee estimates io error: {}", err),
+            FeeEstimatorError::Corrupted(reason) => {
+                write!(f, "corrupted fee estimates: {}", reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for FeeEstimatorError {}
+
+impl From<io::Error> for FeeEstimatorError {
+    fn from(err: io::Error) -> Self {
+        FeeEstimatorError::Io(err)
+    }
+}
+
+/// A transaction of the mempool waiting to confirm.
+#[derive(Clone, Copy, Debug)]
+struct Tracked {
+    height: usize,
+    bucket: usize,
+}
+
+/// Estimates the fee rate needed to confirm within a number of blocks, from
+/// how long mempool transactions of each fee rate took to confirm, like
+/// Bitcoin Core's estimator.
+///
+/// The node feeds the estimator the events of its mempool and every
+/// connected block. Transactions still waiting after a
+/// target count as failures for it, so a sudden rise in fees shows before
+/// those transactions confirm.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FeeEstimator {
+    /// Lowest fee rate of each bucket.
+    buckets: Vec<u64>,
+    /// Decayed count of the transactions confirmed in each bucket.
+    confirmed: Vec<f64>,
+    /// `within[target - 1][bucket]`, the decayed count confirmed in at most
+    /// `target` blocks.
+    within: Vec<Vec<f64>>,
+    best_height: usize,
+    /// The mempool isn't saved, neither are the transactions waiting in it.
+    #[serde(skip)]
+    tracked: HashMap<U256, Tracked>,
+}
+
+impl Default for FeeEstimator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FeeEstimator {
+    pub fn new() -> Self {
+        let mut buckets = Vec::new();
+        let mut fee_rate = MIN_BUCKET_FEE_RATE;
+        while fee_rate <= MAX_BUCKET_FEE_RATE {
+            buckets.push(fee_rate as u64);
+            fee_rate *= BUCKET_SPACING;
+        }
+
+        FeeEstimator {
+            confirmed: vec![0.0; buckets.len()],
+            within: vec![vec![0.0; buckets.len()]; MAX_CONFIRMATION_TARGET],
+            buckets,
+            best_height: 0,
+            tracked: HashMap::new(),
+        }
+    }
+
+    /// Reads the estimates saved at `path`, a missing file gives an empty
+    /// estimator.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, FeeEstimatorError> {
+        let bytes = match fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Self::new()),
+            Err(err) => return Err(err.into()),
+        };
+
+        let estimator: FeeEstimator = serde_json::from_slice(&bytes)
+            .map_err(|err| FeeEstimatorError::Corrupted(err.to_string()))?;
+        if estimator.buckets != Self::new().buckets
+            || estimator.confirmed.len() != estimator.buckets.len()
+            || estimator.within.len() != MAX_CONFIRMATION_TARGET
+            || estimator
+                .within
+                .iter()
+                .any(|within| within.len() != estimator.buckets.len())
+        {
+            return Err(FeeEstimatorError::Corrupted(
+                "bucket layout doesn't match".to_string(),
+            ));
+        }
+
+        Ok(estimator)
+    }
+
+    /// Saves the estimates to `path`. The file is replaced at once, so a
+    /// crash leaves either the old or the new estimates.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), FeeEstimatorError> {
+        let path = path.as_ref();
+        let tmp = path.with_extension("tmp");
+        let json = serde_json::to_vec(self).expect("Fee estimates to serialize");
+
+        fs::write(&tmp, json)?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    /// Height of the last block processed.
+    pub fn best_height(&self) -> usize {
+        self.best_height
+    }
+
+    /// Starts tracking a transaction paying `fee_rate` per 1000 bytes,
+    /// accepted to the mempool while the tip was at `height`.
+    pub fn process_transaction(&mut self, txid: U256, fee_rate: u64, height: usize) {
+        let bucket = self.bucket(fee_rate);
+        self.tracked.insert(txid, Tracked { height, bucket });
+    }
+
+    /// Stops tracking a transaction that left the mempool without being
+    /// mined, because it was replaced or evicted.
+    pub fn remove_transaction(&mut self, txid: &U256) {
+        self.tracked.remove(txid);
+    }
+
+    /// Follows a change of the mempool, transactions entering it are tracked
+    /// from the best height.
+    pub fn process_mempool_event(&mut self, event: &MempoolEvent) {
+        match event {
+            MempoolEvent::Added { txid, fee_rate } => {
+                self.process_transaction(*txid, *fee_rate, self.best_height)
+            }
+            // The block mining it tells how long it took.
+            MempoolEvent::Mined(_) => {}
+            MempoolEvent::Removed(txid) => self.remove_transaction(txid),
+            MempoolEvent::Replaced(replaced) => {
+                for txid in &replaced.replaced {
+                    self.remove_transaction(txid);
+                }
+            }
+        }
+    }
+
+    /// Records how long the tracked transactions of `block` took to confirm.
+    /// The history decays once per height, so a block at or below the best
+    /// height, replacing one after a reorganization, only adds the
+    /// transactions it confirms.
+    pub fn process_block(&mut self, block: &Block) {
+        if block.height > self.best_height {
+            self.best_height = block.height;
+
+            for count in self.confirmed.iter_mut() {
+                *count *= DECAY;
+            }
+            for count in self.within.iter_mut().flatten() {
+                *count *= DECAY;
+            }
+        }
+
+        for tx in &block.transactions {
+            let Some(tracked) = self.tracked.remove(&tx.hash) else {
+                continue;
+            };
+
+            let blocks = block.height.saturating_sub(tracked.height).max(1);
+            self.confirmed[tracked.bucket] += 1.0;
+            for within in self.within.iter_mut().skip(blocks - 1) {
+                within[tracked.bucket] += 1.0;
+            }
+        }
+    }
+
+    /// The lowest fee rate, per 1000 bytes, that confirmed within `target`
+    /// blocks at least a `confidence` share of the time, from 0 to 1. `None`
+    /// while there isn't enough data or for a target beyond
+    /// `MAX_CONFIRMATION_TARGET`.
+    ///
+    /// Buckets are grouped from the highest fee rate down until they hold
+    /// enough transactions, and the search stops at the first group failing
+    /// `confidence`.
+    pub fn estimate_fee_rate(&self, target: usize, confidence: f64) -> Option<u64> {
+        if target == 0 || target > MAX_CONFIRMATION_TARGET {
+            return None;
+        }
+
+        let mut waiting = vec![0.0; self.buckets.len()];
+        for tracked in self.tracked.values() {
+            if self.best_height.saturating_sub(tracked.height) >= target {
+                waiting[tracked.bucket] += 1.0;
+            }
+        }
+
+        let within = &self.within[target - 1];
+        let mut best = None;
+        let (mut succeeded, mut total) = (0.0, 0.0);
+
+        for bucket in (0..self.buckets.len()).rev() {
+            succeeded += within[bucket];
+            total += self.confirmed[bucket] + waiting[bucket];
+
+            if total >= SUFFICIENT_TXS {
+                if succeeded / total < confidence {
+                    break;
+                }
+                best = Some(bucket);
+                (succeeded, total) = (0.0, 0.0);
+            }
+        }
+
+        best.map(|bucket| self.buckets[bucket])
+    }
+
+    /// The fee for a transaction of `size` encoded bytes to confirm within
+    /// `target` blocks, see `estimate_fee_rate`.
+    pub fn estimate_fee(&self, size: usize, target: usize, confidence: f64) -> Option<u64> {
+        self.estimate_fee_rate(target, confidence)
+            .map(|fee_rate| fee_at_rate(fee_rate, size))
+    }
+
+    fn bucket(&self, fee_rate: u64) -> usize {
+        self.buckets
+            .partition_point(|&bucket| bucket <= fee_rate)
+            .saturating_sub(1)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{BlockHeader, OutPoint, Replaced, Transaction, UtxoInput, UtxoOutput};
+    use std::collections::HashSet;
+
+    const HIGH_RATE: u64 = 50_000;
+    const LOW_RATE: u64 = 2_000;
+
+    fn tx(id: u64) -> Transaction {
+        Transaction::new(
+            vec![UtxoInput::spend(OutPoint::new(U256::from(id), 0), [0; 32])],
+            vec![UtxoOutput::new(U256::ONE, 1)],
+        )
+    }
+
+    fn block(height: usize, mined: &[Transaction]) -> Block {
+        let header = BlockHeader {
+            version: 1,
+            timestamp: 1_700_000_000 + height as u64 * 600,
+            prev: U256::ZERO,
+            merkle_root: U256::ZERO,
+            bits: 0x207fffff,
+            nonce: 0,
+            extra: Vec::new(),
+        };
+        let mut transactions = vec![Transaction::coinbase(height as u64, 0, vec![])];
+        transactions.extend(mined.iter().cloned());
+        Block::new(height, U256::from(height as u64), header, transactions)
+    }
+
+    /// Every block two high fee transactions arrive and confirm in the next
+    /// block, and two low fee ones wait `low_wait` blocks.
+    fn simulate(estimator: &mut FeeEstimator, blocks: usize, low_wait: usize) {
+        let mut pending: Vec<(usize, Transaction)> = Vec::new();
+        let mut id = 0;
+
+        for height in 0..blocks {
+            if height > 0 {
+                let (mined, waiting) = pending
+                    .into_iter()
+                    .partition(|(confirm_at, _)| *confirm_at == height);
+                pending = waiting;
+                let mined: Vec<Transaction> = mined.into_iter().map(|(_, tx)| tx).collect();
+                estimator.process_block(&block(height, &mined));
+            }
+
+            for (fee_rate, wait) in [(HIGH_RATE, 1), (HIGH_RATE, 1), (LOW_RATE, low_wait)] {
+                id += 1;
+                let tx = tx(id);
+                estimator.process_transaction(tx.hash, fee_rate, height);
+                pending.push((height + wait, tx));
+            }
+        }
+    }
+
+    #[test]
+    fn test_estimates_by_confirmation_time() {
+        let mut estimator = FeeEstimator::new();
+        assert_eq!(estimator.estimate_fee_rate(1, 0.85), None);
+
+        simulate(&mut estimator, 200, 10);
+
+        let fast = estimator.estimate_fee_rate(1, 0.85).unwrap();
+        assert!(fast > LOW_RATE && fast <= HIGH_RATE, "{}", fast);
+        assert_eq!(estimator.estimate_fee_rate(9, 0.85), Some(fast));
+
+        let slow = estimator.estimate_fee_rate(10, 0.85).unwrap();
+        assert!(slow <= LOW_RATE && slow > LOW_RATE * 10 / 11, "{}", slow);
+        assert_eq!(
+            estimator.estimate_fee(250, 10, 0.85),
+            Some(fee_at_rate(slow, 250))
+        );
+
+        assert_eq!(estimator.estimate_fee_rate(0, 0.85), None);
+        assert_eq!(
+            estimator.estimate_fee_rate(MAX_CONFIRMATION_TARGET + 1, 0.85),
+            None
+        );
+    }
+
+    #[test]
+    fn test_waiting_transactions_count_as_failures() {
+        let mut estimator = FeeEstimator::new();
+        simulate(&mut estimator, 100, 1);
+        let before = estimator.estimate_fee_rate(2, 0.85).unwrap();
+        assert!(before <= LOW_RATE);
+
+        // Fees spike, low fee transactions stop confirming.
+        for id in 0..40 {
+            estimator.process_transaction(tx(10_000 + id).hash, LOW_RATE, 100);
+        }
+        estimator.process_block(&block(101, &[]));
+        estimator.process_block(&block(102, &[]));
+
+        let after = estimator.estimate_fee_rate(2, 0.85).unwrap();
+        assert!(after > LOW_RATE, "{}", after);
+
+        // Dropped transactions are forgotten.
+        for id in 0..40 {
+            estimator.remove_transaction(&tx(10_000 + id).hash);
+        }
+        assert_eq!(estimator.estimate_fee_rate(2, 0.85), Some(before));
+    }
+
+    #[test]
+    fn test_persists_estimates() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("fee_estimates.json");
+
+        let fresh = FeeEstimator::load(&path).unwrap();
+        assert_eq!(fresh.best_height(), 0);
+
+        let mut estimator = FeeEstimator::new();
+        simulate(&mut estimator, 100, 5);
+        estimator.save(&path).unwrap();
+
+        let loaded = FeeEstimator::load(&path).unwrap();
+        assert_eq!(loaded.best_height(), 99);
+        for target in [1, 5] {
+            assert_eq!(
+                loaded.estimate_fee_rate(target, 0.85),
+                estimator.estimate_fee_rate(target, 0.85)
+            );
+        }
+        // Blocks already processed aren't counted again.
+        let mut again = loaded.clone();
+        again.process_block(&block(99, &[]));
+        assert_eq!(again.confirmed, loaded.confirmed);
+
+        fs::write(&path, b"{\"buckets\": [1]}").unwrap();
+        assert!(matches!(
+            FeeEstimator::load(&path),
+            Err(FeeEstimatorError::Corrupted(_))
+        ));
+    }
+
+    #[test]
+    fn test_buckets_are_increasing() {
+        let estimator = FeeEstimator::new();
+        let buckets: HashSet<u64> = estimator.buckets.iter().copied().collect();
+        assert_eq!(buckets.len(), estimator.buckets.len());
+        assert!(estimator.buckets.windows(2).all(|pair| pair[0] < pair[1]));
+
+        assert_eq!(estimator.bucket(0), 0);
+        assert_eq!(estimator.bucket(1_000), 0);
+        assert_eq!(estimator.bucket(1_100), 1);
+        assert_eq!(estimator.bucket(u64::MAX), estimator.buckets.len() - 1);
+    }
+
+    #[test]
+    fn test_counts_blocks_replaced_by_reorg() {
+        let mut estimator = FeeEstimator::new();
+        let mined = [tx(1), tx(2)];
+        for tx in &mined {
+            estimator.process_mempool_event(&MempoolEvent::Added {
+                txid: tx.hash,
+                fee_rate: HIGH_RATE,
+            });
+        }
+
+        estimator.process_block(&block(1, &mined[..1]));
+        // The block is replaced by one at the same height mining the other
+        // transaction, which still counts.
+        estimator.process_block(&block(1, &mined[1..]));
+        assert_eq!(estimator.best_height(), 1);
+        assert!(estimator.tracked.is_empty());
+        assert_eq!(estimator.confirmed[estimator.bucket(HIGH_RATE)], 2.0);
+    }
+
+    #[test]
+    fn test_follows_mempool_events() {
+        let mut estimator = FeeEstimator::new();
+        for id in 0..3 {
+            estimator.process_mempool_event(&MempoolEvent::Added {
+                txid: tx(id).hash,
+                fee_rate: LOW_RATE,
+            });
+        }
+        estimator.process_mempool_event(&MempoolEvent::Removed(tx(0).hash));
+        estimator.process_mempool_event(&MempoolEvent::Replaced(Replaced {
+            replacement: tx(3).hash,
+            replaced: vec![tx(1).hash],
+        }));
+        // Mined transactions are counted by their block.
+        estimator.process_mempool_event(&MempoolEvent::Mined(tx(2).hash));
+        assert_eq!(
+            estimator.tracked.keys().collect::<Vec<_>>(),
+            vec![&tx(2).hash]
+        );
+    }
+
+    #[test]
+    fn test_persists_estimates_fed_by_mempool_events() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("fee_estimates.json");
+
+        let mut estimator = FeeEstimator::load(&path).unwrap();
+        for height in 1..=40 {
+            let txs: Vec<Transaction> = (0..2).map(|i| tx(height * 10 + i)).collect();
+            for tx in &txs {
+                estimator.process_mempool_event(&MempoolEvent::Added {
+                    txid: tx.hash,
+                    fee_rate: HIGH_RATE,
+                });
+                estimator.process_mempool_event(&MempoolEvent::Mined(tx.hash));
+            }
+            estimator.process_block(&block(height as usize, &txs));
+        }
+        let estimate = estimator.estimate_fee_rate(1, 0.85);
+        assert!(estimate.is_some());
+        estimator.save(&path).unwrap();
+
+        // A restarted node answers with the same estimates.
+        let loaded = FeeEstimator::load(&path).unwrap();
+        assert_eq!(loaded.best_height(), 40);
+        assert_eq!(loaded.estimate_fee_rate(1, 0.85), estimate);
+    }
+}
@@ -3,3 +3,69 @@ use ethnum::U256;
 pub trait Hash {
     fn hash(&self) -> U256;
 }
+
+/// SipHash-2-4 of `data` keyed with `k0` and `k1`, the keyed short hash
+/// compact blocks identify transactions by.
+pub fn siphash24(k0: u64, k1: u64, data: &[u8]) -> u64 {
+    let mut v = [
+        k0 ^ 0x736f6d6570736575,
+        k1 ^ 0x646f72616e646f6d,
+        k0 ^ 0x6c7967656e657261,
+        k1 ^ 0x7465646279746573,
+    ];
+
+    let chunks = data.chunks_exact(8);
+    let tail = chunks.remainder();
+    for chunk in chunks {
+        let m = u64::from_le_bytes(chunk.try_into().unwrap());
+        v[3] ^= m;
+        sip_rounds(&mut v, 2);
+        v[0] ^= m;
+    }
+
+    // The last word holds the remaining bytes and the length in its top byte.
+    let mut last = (data.len() as u64) << 56;
+    for (i, byte) in tail.iter().enumerate() {
+        last |= (*byte as u64) << (8 * i);
+    }
+    v[3] ^= last;
+    sip_rounds(&mut v, 2);
+    v[0] ^= last;
+
+    v[2] ^= 0xff;
+    sip_rounds(&mut v, 4);
+    v[0] ^ v[1] ^ v[2] ^ v[3]
+}
+
+fn sip_rounds(v: &mut [u64; 4], rounds: usize) {
+    for _ in 0..rounds {
+        v[0] = v[0].wrapping_add(v[1]);
+        v[1] = v[1].rotate_left(13) ^ v[0];
+        v[0] = v[0].rotate_left(32);
+        v[2] = v[2].wrapping_add(v[3]);
+        v[3] = v[3].rotate_left(16) ^ v[2];
+        v[0] = v[0].wrapping_add(v[3]);
+        v[3] = v[3].rotate_left(21) ^ v[0];
+        v[2] = v[2].wrapping_add(v[1]);
+        v[1] = v[1].rotate_left(17) ^ v[2];
+        v[2] = v[2].rotate_left(32);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_siphash24_reference_vectors() {
+        // Key 00..0f and messages 00..(n-1) from the SipHash paper.
+        let k0 = u64::from_le_bytes([0, 1, 2, 3, 4, 5, 6, 7]);
+        let k1 = u64::from_le_bytes([8, 9, 10, 11, 12, 13, 14, 15]);
+        let message: Vec<u8> = (0..64).collect();
+
+        assert_eq!(siphash24(k0, k1, &message[..0]), 0x726fdb47dd0e0e31);
+        assert_eq!(siphash24(k0, k1, &message[..8]), 0x93f5f5799a932462);
+        assert_eq!(siphash24(k0, k1, &message[..15]), 0xa129ca6149be45e5);
+        assert_eq!(siphash24(k0, k1, &message[..63]), 0x958a324ceb064572);
+    }
+}
@@ -1,18 +1,42 @@
+mod block_index;
+mod block_store;
+mod block_template;
 mod blockchain;
+mod clock;
+mod compact_target;
 mod consensus;
+mod encoding;
+mod fee_estimator;
 mod hash;
+mod mempool;
 mod merkle_tree;
+mod miner;
 mod network;
 mod primitives;
+#[cfg(test)]
+mod test_utils;
 mod utils;
+mod utxo;
+mod validation;
 
+pub use block_index::*;
+pub use block_store::*;
+pub use block_template::*;
 pub use blockchain::*;
+pub use clock::*;
+pub use compact_target::*;
 pub use consensus::*;
+pub use encoding::*;
+pub use fee_estimator::*;
 pub use hash::*;
+pub use mempool::*;
 pub use merkle_tree::*;
+pub use miner::*;
 pub use network::*;
 pub use primitives::*;
 pub use utils::*;
+pub use utxo::*;
+pub use validation::*;
 
 #[macro_export]
 macro_rules! hash_to_u256 {
@@ -22,7 +46,6 @@ macro_rules! hash_to_u256 {
 
         let mut hasher = Sha256::new();
         hasher.update($bytes);
-        let result = format!("0x{:x}", hasher.finalize());
-        U256::from_str_hex(&result).unwrap()
+        U256::from_be_bytes(hasher.finalize().into())
     }};
 }
@@ -1,5 +1,6 @@
 use tiny_blockchain::{
-    generate_mock_blocks, seconds_now, Chain, TinyBlockchain, TinyBlockchainParams,
+    generate_mock_blocks, Chain, Clock, DifficultyAlgorithm, SystemClock, TinyBlockchain,
+    TinyBlockchainParams,
 };
 
 fn main() {
@@ -11,15 +12,19 @@ fn main() {
     //     999,
     // )];
     let init_chain = Chain {
-        items: generate_mock_blocks(2016),
-        last_update: seconds_now(),
+        items: generate_mock_blocks(2016, &SystemClock),
+        last_update: SystemClock.now(),
     };
     let blockchain_params = TinyBlockchainParams {
-        init_difficulty: 0x1dffffff,
+        init_difficulty: 0x1d00ffff,
         blocks_in_epoch: 2016,
-        epoch: 2016 * 10,
+        target_spacing: 600,
+        max_adjustment: 4,
+        pow_limit: 0x1d00ffff,
+        difficulty: DifficultyAlgorithm::Epoch,
+        max_future_drift: 2 * 60 * 60,
     };
-    let blockchain = TinyBlockchain::new(init_chain, blockchain_params);
+    let _blockchain = TinyBlockchain::new(init_chain, blockchain_params);
 
     // println!("Result={:?}", init_chain.is_valid());
 
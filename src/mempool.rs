@@ -0,0 +1,1573 @@
+use crate::{
+    check_transaction, Block, BlockIndex, Encode, OutPoint, TemplateCandidate, TipChanged,
+    Transaction, UtxoError, UtxoInput, UtxoOutput, UtxoSet, ValidationError, COINBASE_MATURITY,
+    MAX_BLOCK_SIZE,
+};
+use ethnum::U256;
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::sync::mpsc;
+
+/// The rules a transaction has to follow to be kept in the mempool, on top of
+/// the consensus rules.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MempoolPolicy {
+    /// Fee per 1000 encoded bytes a transaction has to pay at least.
+    pub min_relay_fee: u64,
+    /// Encoded bytes of all the transactions in the pool.
+    pub max_size: usize,
+    /// Unconfirmed ancestors a transaction may have, itself included.
+    pub max_ancestors: usize,
+    /// Transactions a replacement may evict, descendants included.
+    pub max_replacements: usize,
+}
+
+impl Default for MempoolPolicy {
+    fn default() -> Self {
+        MempoolPolicy {
+            min_relay_fee: 1_000,
+            max_size: 100 * MAX_BLOCK_SIZE,
+            max_ancestors: 25,
+            max_replacements: 100,
+        }
+    }
+}
+
+/// The reason a transaction was not accepted to the mempool.
+#[derive(Debug, PartialEq, Eq)]
+pub enum MempoolError {
+    /// The transaction breaks a consensus rule.
+    Invalid(ValidationError),
+    /// A coinbase is only valid in a block.
+    Coinbase(U256),
+    AlreadyKnown(U256),
+    /// An input spends an output already spent by a transaction in the pool
+    /// that can't be replaced.
+    Conflict {
+        txid: U256,
+        conflicting: U256,
+    },
+    /// A replacement has to pay for the transactions it evicts and more.
+    ReplacementFeeTooLow {
+        txid: U256,
+        fee: u64,
+        min: u64,
+    },
+    TooManyReplacements {
+        txid: U256,
+        count: usize,
+        max: usize,
+    },
+    /// A replacement spends an output of a transaction it would evict.
+    SpendsReplaced {
+        txid: U256,
+        replaced: U256,
+    },
+    /// A replacement spends an output of the pool that none of the
+    /// transactions it replaces spent.
+    NewUnconfirmedInput {
+        txid: U256,
+        parent: U256,
+    },
+    FeeTooLow {
+        txid: U256,
+        fee: u64,
+        min: u64,
+    },
+    TooManyAncestors {
+        txid: U256,
+        count: usize,
+        max: usize,
+    },
+    /// The pool is full of transactions paying a higher fee rate.
+    Full(U256),
+}
+
+impl std::fmt::Display for MempoolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MempoolError::Invalid(err) => write!(f, "{}", err),
+            MempoolError::Coinbase(txid) => {
+                write!(f, "transaction {:#064x} is a coinbase", txid)
+            }
+            MempoolError::AlreadyKnown(txid) => {
+                write!(f, "transaction {:#064x} is already in the mempool", txid)
+            }
+            MempoolError::Conflict { txid, conflicting } => write!(
+                f,
+                "transaction {:#064x} conflicts with {:#064x}",
+                txid, conflicting
+            ),
+            MempoolError::ReplacementFeeTooLow { txid, fee, min } => write!(
+                f,
+                "replacement {:#064x} pays a fee of {} but at least {} is required",
+                txid, fee, min
+            ),
+            MempoolError::TooManyReplacements { txid, count, max } => write!(
+                f,
+                "replacement {:#064x} would evict {} transactions, at most {} are allowed",
+                txid, count, max
+            ),
+            MempoolError::SpendsReplaced { txid, replaced } => write!(
+                f,
+                "replacement {:#064x} spends {:#064x} which it replaces",
+                txid, replaced
+            ),
+            MempoolError::NewUnconfirmedInput { txid, parent } => write!(
+                f,
+                "replacement {:#064x} spends unconfirmed {:#064x} which the replaced transactions don't",
+                txid, parent
+            ),
+            MempoolError::FeeTooLow { txid, fee, min } => write!(
+                f,
+                "transaction {:#064x} pays a fee of {} but at least {} is required",
+                txid, fee, min
+            ),
+            MempoolError::TooManyAncestors { txid, count, max } => write!(
+                f,
+                "transaction {:#064x} has {} unconfirmed ancestors, at most {} are allowed",
+                txid, count, max
+            ),
+            MempoolError::Full(txid) => write!(
+                f,
+                "mempool is full and transaction {:#064x} pays too little to enter",
+                txid
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MempoolError {}
+
+impl From<ValidationError> for MempoolError {
+    fn from(err: ValidationError) -> Self {
+        MempoolError::Invalid(err)
+    }
+}
+
+impl From<UtxoError> for MempoolError {
+    fn from(err: UtxoError) -> Self {
+        MempoolError::Invalid(err.into())
+    }
+}
+
+/// A transaction in the mempool with the fee it pays and its relatives in
+/// the pool.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MempoolEntry {
+    pub tx: Transaction,
+    pub fee: u64,
+    /// Encoded bytes of the transaction.
+    pub size: usize,
+    parents: HashSet<U256>,
+    children: HashSet<U256>,
+    descendant_fee: u64,
+    descendant_size: usize,
+}
+
+impl MempoolEntry {
+    /// Fee paid per 1000 encoded bytes.
+    pub fn fee_rate(&self) -> u64 {
+        (self.fee as u128 * 1_000 / self.size as u128) as u64
+    }
+
+    /// Transactions in the pool this one spends outputs of.
+    pub fn parents(&self) -> impl Iterator<Item = &U256> {
+        self.parents.iter()
+    }
+
+    /// Transactions in the pool spending outputs of this one.
+    pub fn children(&self) -> impl Iterator<Item = &U256> {
+        self.children.iter()
+    }
+
+    /// Fee of this transaction and its descendants in the pool.
+    pub fn descendant_fee(&self) -> u64 {
+        self.descendant_fee
+    }
+
+    /// Encoded bytes of this transaction and its descendants in the pool.
+    pub fn descendant_size(&self) -> usize {
+        self.descendant_size
+    }
+}
+
+/// Orders the transactions of the pool for eviction, by the fee rate of the
+/// package they form with their descendants, lowest first. Ties are broken
+/// by txid so the order doesn't depend on the hash map order.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct EvictionKey {
+    fee: u64,
+    size: usize,
+    txid: U256,
+}
+
+impl EvictionKey {
+    fn of(entry: &MempoolEntry) -> Self {
+        EvictionKey {
+            fee: entry.descendant_fee,
+            size: entry.descendant_size,
+            txid: entry.tx.hash,
+        }
+    }
+}
+
+impl Ord for EvictionKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // Compared without dividing.
+        let rate = self.fee as u128 * other.size as u128;
+        let other_rate = other.fee as u128 * self.size as u128;
+        rate.cmp(&other_rate)
+            .then_with(|| self.txid.cmp(&other.txid))
+    }
+}
+
+impl PartialOrd for EvictionKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// A transaction that entered the pool in place of others.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Replaced {
+    pub replacement: U256,
+    /// The transactions it conflicts with and their descendants.
+    pub replaced: Vec<U256>,
+}
+
+/// Sent to the subscribers of a mempool as transactions enter and leave it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MempoolEvent {
+    /// A transaction entered the pool paying `fee_rate` per 1000 bytes.
+    Added { txid: U256, fee_rate: u64 },
+    /// A transaction left the pool because a connected block mined it.
+    Mined(U256),
+    /// A transaction was evicted, double spent by a block or couldn't be
+    /// added back after a reorganization.
+    Removed(U256),
+    /// Sent before the `Added` of the replacement.
+    Replaced(Replaced),
+}
+
+/// Unconfirmed transactions waiting to be mined. Every transaction spends
+/// outputs of the UTXO set or of other transactions in the pool, and no two
+/// transactions spend the same output.
+#[derive(Clone, Debug, Default)]
+pub struct Mempool {
+    policy: MempoolPolicy,
+    entries: HashMap<U256, MempoolEntry>,
+    /// The transaction in the pool spending each outpoint.
+    spent: HashMap<OutPoint, U256>,
+    by_eviction: BTreeSet<EvictionKey>,
+    size: usize,
+    subscribers: Vec<mpsc::Sender<MempoolEvent>>,
+}
+
+impl Mempool {
+    pub fn new(policy: MempoolPolicy) -> Self {
+        Mempool {
+            policy,
+            ..Default::default()
+        }
+    }
+
+    pub fn policy(&self) -> &MempoolPolicy {
+        &self.policy
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Encoded bytes of all the transactions in the pool.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn contains(&self, txid: &U256) -> bool {
+        self.entries.contains_key(txid)
+    }
+
+    pub fn get(&self, txid: &U256) -> Option<&MempoolEntry> {
+        self.entries.get(txid)
+    }
+
+    pub fn transactions(&self) -> impl Iterator<Item = &Transaction> {
+        self.entries.values().map(|entry| &entry.tx)
+    }
+
+    /// The transaction in the pool spending `outpoint`, if any.
+    pub fn spender(&self, outpoint: &OutPoint) -> Option<&U256> {
+        self.spent.get(outpoint)
+    }
+
+    /// Receives a `MempoolEvent` for every transaction entering or leaving
+    /// the pool.
+    pub fn subscribe(&mut self) -> mpsc::Receiver<MempoolEvent> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers.push(sender);
+        receiver
+    }
+
+    /// Validates `tx` against `utxo` and the pool, as if it was mined in a
+    /// block at `height`, and adds it. When the pool grows past its size
+    /// limit the packages paying the lowest fee rate are evicted, which may
+    /// be `tx` itself.
+    ///
+    /// A transaction spending outputs already spent in the pool replaces the
+    /// transactions it conflicts with if they are replaceable, see
+    /// `check_replacement`.
+    pub fn add(
+        &mut self,
+        tx: Transaction,
+        utxo: &UtxoSet,
+        height: usize,
+    ) -> Result<(), MempoolError> {
+        let (entry, conflicts) = self.check(tx, utxo, height)?;
+        let (txid, fee, size) = (entry.tx.hash, entry.fee, entry.size);
+
+        let min = fee_at_rate(self.policy.min_relay_fee, size);
+        if fee < min {
+            return Err(MempoolError::FeeTooLow { txid, fee, min });
+        }
+
+        let replaced = self.check_replacement(txid, fee, size, &conflicts, &entry.parents)?;
+        self.check_ancestors(txid, &entry.parents)?;
+
+        for conflicting in &conflicts {
+            self.remove_with_descendants(conflicting);
+        }
+        self.insert(entry);
+        let evicted = self.trim();
+        let fee_rate = self.entries.get(&txid).map(MempoolEntry::fee_rate);
+
+        let mut replaced: Vec<U256> = replaced.into_iter().collect();
+        replaced.sort();
+        match fee_rate {
+            Some(fee_rate) => {
+                if !replaced.is_empty() {
+                    self.notify(MempoolEvent::Replaced(Replaced {
+                        replacement: txid,
+                        replaced,
+                    }));
+                }
+                self.notify(MempoolEvent::Added { txid, fee_rate });
+            }
+            // The conflicts are gone even though `tx` didn't stay.
+            None => self.notify_removed(replaced),
+        }
+        self.notify_removed(evicted.into_iter().filter(|evicted| *evicted != txid));
+
+        if fee_rate.is_none() {
+            return Err(MempoolError::Full(txid));
+        }
+        Ok(())
+    }
+
+    /// Adds `package`, transactions ordered parents first, as a whole. A
+    /// transaction paying less than the minimum relay fee is accepted when
+    /// the package fee rate of it and its descendants in the package is high
+    /// enough, so a child can pay for its parent. Transactions already in the
+    /// pool are skipped. Packages can't replace transactions, a conflict with
+    /// the pool is refused.
+    ///
+    /// Either every transaction of the package is added or none of them.
+    pub fn add_package(
+        &mut self,
+        package: Vec<Transaction>,
+        utxo: &UtxoSet,
+        height: usize,
+    ) -> Result<(), MempoolError> {
+        let mut added = Vec::new();
+
+        for tx in package {
+            if self.entries.contains_key(&tx.hash) && !added.contains(&tx.hash) {
+                continue;
+            }
+
+            let result = self.check(tx, utxo, height).and_then(|(entry, conflicts)| {
+                let txid = entry.tx.hash;
+                if let Some(&conflicting) = conflicts.iter().next() {
+                    return Err(MempoolError::Conflict { txid, conflicting });
+                }
+                self.check_ancestors(txid, &entry.parents)?;
+                Ok(entry)
+            });
+
+            match result {
+                Ok(entry) => {
+                    added.push(entry.tx.hash);
+                    self.insert(entry);
+                }
+                Err(err) => {
+                    self.remove_package(&added);
+                    return Err(err);
+                }
+            }
+        }
+
+        for txid in &added {
+            let descendants = self.descendants(txid);
+            let package = added
+                .iter()
+                .filter(|a| *a == txid || descendants.contains(a));
+            let (fee, size) = package.fold((0, 0), |(fee, size), a| {
+                (fee + self.entries[a].fee, size + self.entries[a].size)
+            });
+
+            let min = fee_at_rate(self.policy.min_relay_fee, size);
+            if fee < min {
+                let txid = *txid;
+                self.remove_package(&added);
+                return Err(MempoolError::FeeTooLow { txid, fee, min });
+            }
+        }
+
+        let evicted = self.trim();
+        self.notify_removed(
+            evicted
+                .into_iter()
+                .filter(|evicted| !added.contains(evicted)),
+        );
+
+        if let Some(&evicted) = added.iter().find(|a| !self.entries.contains_key(a)) {
+            self.remove_package(&added);
+            return Err(MempoolError::Full(evicted));
+        }
+
+        for txid in added {
+            let fee_rate = self.entries[&txid].fee_rate();
+            self.notify(MempoolEvent::Added { txid, fee_rate });
+        }
+        Ok(())
+    }
+
+    /// Validates `tx` against `utxo` and the pool without the fee rules,
+    /// returning its entry and the transactions of the pool it conflicts
+    /// with.
+    fn check(
+        &self,
+        tx: Transaction,
+        utxo: &UtxoSet,
+        height: usize,
+    ) -> Result<(MempoolEntry, HashSet<U256>), MempoolError> {
+        let txid = tx.hash;
+        if tx.is_coinbase() {
+            return Err(MempoolError::Coinbase(txid));
+        }
+        if self.entries.contains_key(&txid) {
+            return Err(MempoolError::AlreadyKnown(txid));
+        }
+        check_transaction(&tx)?;
+
+        let mut parents = HashSet::new();
+        let mut conflicts = HashSet::new();
+        let mut input_value = 0u64;
+
+        for (index, input) in tx.inputs.iter().enumerate() {
+            let UtxoInput::Spend {
+                prev_output,
+                pub_key,
+                ..
+            } = input
+            else {
+                return Err(ValidationError::MisplacedCoinbaseInput(txid).into());
+            };
+
+            if let Some(&conflicting) = self.spent.get(prev_output) {
+                conflicts.insert(conflicting);
+            }
+
+            let output = self.output(prev_output, utxo, height, txid)?;
+            if !output.is_unlocked_by(pub_key) {
+                return Err(ValidationError::WrongUnlockKey { txid, input: index }.into());
+            }
+            if self.entries.contains_key(&prev_output.txid) {
+                parents.insert(prev_output.txid);
+            }
+
+            input_value += output.value();
+        }
+
+        let output_value: u64 = tx.outputs.iter().map(|output| output.value()).sum();
+        if output_value > input_value {
+            return Err(UtxoError::Overspend {
+                txid,
+                inputs: input_value,
+                outputs: output_value,
+            }
+            .into());
+        }
+
+        let fee = input_value - output_value;
+        let size = tx.to_bytes().len();
+        let entry = MempoolEntry {
+            fee,
+            size,
+            tx,
+            parents,
+            children: HashSet::new(),
+            descendant_fee: fee,
+            descendant_size: size,
+        };
+        Ok((entry, conflicts))
+    }
+
+    fn check_ancestors(&self, txid: U256, parents: &HashSet<U256>) -> Result<(), MempoolError> {
+        let count = self.ancestors_of(parents.iter().copied()).len() + 1;
+        if count > self.policy.max_ancestors {
+            return Err(MempoolError::TooManyAncestors {
+                txid,
+                count,
+                max: self.policy.max_ancestors,
+            });
+        }
+        Ok(())
+    }
+
+    /// The opt-in replace-by-fee rules of BIP 125. Every transaction `txid`
+    /// conflicts with has to be replaceable, and `txid` has to pay a higher
+    /// fee rate than each of them, without spending outputs of the pool they
+    /// didn't spend. It also pays for everything it evicts plus its own
+    /// relay, so replacing can't be used to relay for free. Returns the
+    /// transactions that would be evicted.
+    fn check_replacement(
+        &self,
+        txid: U256,
+        fee: u64,
+        size: usize,
+        conflicts: &HashSet<U256>,
+        parents: &HashSet<U256>,
+    ) -> Result<HashSet<U256>, MempoolError> {
+        let mut replaced = HashSet::new();
+        let mut min_rate_fee = 0;
+
+        for &conflicting in conflicts {
+            let entry = &self.entries[&conflicting];
+            if !entry.tx.is_replaceable() {
+                return Err(MempoolError::Conflict { txid, conflicting });
+            }
+
+            let rate_fee = (entry.fee as u128 * size as u128 / entry.size as u128) as u64 + 1;
+            min_rate_fee = min_rate_fee.max(rate_fee);
+            replaced.insert(conflicting);
+            replaced.extend(self.descendants(&conflicting));
+        }
+
+        if replaced.len() > self.policy.max_replacements {
+            return Err(MempoolError::TooManyReplacements {
+                txid,
+                count: replaced.len(),
+                max: self.policy.max_replacements,
+            });
+        }
+
+        if let Some(&parent) = parents.iter().find(|parent| replaced.contains(parent)) {
+            return Err(MempoolError::SpendsReplaced {
+                txid,
+                replaced: parent,
+            });
+        }
+
+        if replaced.is_empty() {
+            return Ok(replaced);
+        }
+
+        // Spending new outputs of the pool could make the replacement pay a
+        // lower package fee rate than what it replaces.
+        let replaced_parents: HashSet<&U256> = conflicts
+            .iter()
+            .flat_map(|conflicting| self.entries[conflicting].parents.iter())
+            .collect();
+        if let Some(&parent) = parents
+            .iter()
+            .find(|parent| !replaced_parents.contains(parent))
+        {
+            return Err(MempoolError::NewUnconfirmedInput { txid, parent });
+        }
+
+        let replaced_fee: u64 = replaced.iter().map(|r| self.entries[r].fee).sum();
+        let min = (replaced_fee + fee_at_rate(self.policy.min_relay_fee, size)).max(min_rate_fee);
+        if fee < min {
+            return Err(MempoolError::ReplacementFeeTooLow { txid, fee, min });
+        }
+
+        Ok(replaced)
+    }
+
+    /// Follows the active chain to its new tip. Transactions mined by the
+    /// connected blocks leave the pool. After a reorganization the pool is
+    /// rebuilt on `utxo` instead: the transactions of the disconnected blocks
+    /// come back first, and every transaction is validated again. `height`
+    /// is the height of the block after the new tip.
+    pub fn update_for_tip(
+        &mut self,
+        event: &TipChanged,
+        index: &BlockIndex,
+        utxo: &UtxoSet,
+        height: usize,
+    ) {
+        if event.disconnected.is_empty() {
+            for block in event.connected.iter().filter_map(|hash| index.block(hash)) {
+                self.remove_for_block(block);
+            }
+            return;
+        }
+
+        let mined: HashSet<U256> = event
+            .connected
+            .iter()
+            .filter_map(|hash| index.block(hash))
+            .flat_map(|block| block.transactions.iter().map(|tx| tx.hash))
+            .collect();
+
+        // Disconnected blocks are listed tip first.
+        let mut transactions: Vec<Transaction> = event
+            .disconnected
+            .iter()
+            .rev()
+            .filter_map(|hash| index.block(hash))
+            .flat_map(|block| block.transactions.iter().skip(1).cloned())
+            .collect();
+        let drained = self.drain();
+        let was_pooled: HashSet<U256> = drained.iter().map(|tx| tx.hash).collect();
+        transactions.extend(drained);
+
+        for tx in transactions {
+            let txid = tx.hash;
+            if self.add(tx, utxo, height).is_err() && was_pooled.contains(&txid) {
+                if mined.contains(&txid) {
+                    self.notify(MempoolEvent::Mined(txid));
+                } else {
+                    self.notify(MempoolEvent::Removed(txid));
+                }
+            }
+        }
+    }
+
+    /// Empties the pool, returning its transactions parents first.
+    fn drain(&mut self) -> Vec<Transaction> {
+        let mut entries: Vec<(usize, MempoolEntry)> = self
+            .entries
+            .iter()
+            .map(|(txid, entry)| (self.ancestors(txid).len(), entry.clone()))
+            .collect();
+        entries.sort_by_key(|(ancestors, entry)| (*ancestors, entry.tx.hash));
+
+        self.entries.clear();
+        self.spent.clear();
+        self.by_eviction.clear();
+        self.size = 0;
+        entries.into_iter().map(|(_, entry)| entry.tx).collect()
+    }
+
+    /// Removes the transactions mined in `block`, and every transaction
+    /// double spending one of its inputs together with its descendants.
+    /// Children of mined transactions stay, their inputs are confirmed now.
+    pub fn remove_for_block(&mut self, block: &Block) {
+        for tx in block.transactions.iter().filter(|tx| !tx.is_coinbase()) {
+            if self.entries.contains_key(&tx.hash) {
+                self.remove_entry(&tx.hash);
+                self.notify(MempoolEvent::Mined(tx.hash));
+                continue;
+            }
+
+            for outpoint in tx.inputs.iter().filter_map(|input| input.prev_output()) {
+                if let Some(&conflicting) = self.spent.get(outpoint) {
+                    let removed = self.remove_with_descendants(&conflicting);
+                    self.notify_removed(removed.iter().map(|tx| tx.hash));
+                }
+            }
+        }
+    }
+
+    /// Removes the transaction `txid` and every transaction depending on it.
+    pub fn remove(&mut self, txid: &U256) -> Vec<Transaction> {
+        let removed = self.remove_with_descendants(txid);
+        self.notify_removed(removed.iter().map(|tx| tx.hash));
+        removed
+    }
+
+    /// The transactions in the pool with their fees, to build a block from.
+    pub fn candidates(&self) -> Vec<TemplateCandidate> {
+        self.entries
+            .values()
+            .map(|entry| TemplateCandidate::new(entry.tx.clone(), entry.fee))
+            .collect()
+    }
+
+    /// Transactions of the pool `txid` depends on, directly or not.
+    pub fn ancestors(&self, txid: &U256) -> HashSet<U256> {
+        match self.entries.get(txid) {
+            Some(entry) => self.ancestors_of(entry.parents.iter().copied()),
+            None => HashSet::new(),
+        }
+    }
+
+    /// Transactions of the pool depending on `txid`, directly or not.
+    pub fn descendants(&self, txid: &U256) -> HashSet<U256> {
+        let mut descendants = HashSet::new();
+        let mut pending: Vec<U256> = match self.entries.get(txid) {
+            Some(entry) => entry.children.iter().copied().collect(),
+            None => return descendants,
+        };
+
+        while let Some(child) = pending.pop() {
+            if descendants.insert(child) {
+                pending.extend(self.entries[&child].children.iter().copied());
+            }
+        }
+
+        descendants
+    }
+
+    /// The output `outpoint` refers to, created in the pool or in the UTXO
+    /// set. Coinbase outputs have to be mature at `height`.
+    fn output(
+        &self,
+        outpoint: &OutPoint,
+        utxo: &UtxoSet,
+        height: usize,
+        txid: U256,
+    ) -> Result<UtxoOutput, MempoolError> {
+        if let Some(parent) = self.entries.get(&outpoint.txid) {
+            return parent
+                .tx
+                .outputs
+                .get(outpoint.vout as usize)
+                .cloned()
+                .ok_or_else(|| UtxoError::MissingInput(*outpoint).into());
+        }
+
+        let entry = utxo
+            .get(outpoint)
+            .ok_or(UtxoError::MissingInput(*outpoint))?;
+        if entry.is_coinbase && height.saturating_sub(entry.height) < COINBASE_MATURITY {
+            return Err(ValidationError::PrematureCoinbaseSpend {
+                txid,
+                outpoint: *outpoint,
+            }
+            .into());
+        }
+
+        Ok(entry.output.clone())
+    }
+
+    fn ancestors_of(&self, parents: impl IntoIterator<Item = U256>) -> HashSet<U256> {
+        let mut ancestors = HashSet::new();
+        let mut pending: Vec<U256> = parents.into_iter().collect();
+
+        while let Some(parent) = pending.pop() {
+            if ancestors.insert(parent) {
+                pending.extend(self.entries[&parent].parents.iter().copied());
+            }
+        }
+
+        ancestors
+    }
+
+    fn insert(&mut self, entry: MempoolEntry) {
+        let txid = entry.tx.hash;
+        let ancestors = self.ancestors_of(entry.parents.iter().copied());
+        self.update_descendant_packages(&ancestors, |package| {
+            package.descendant_fee += entry.fee;
+            package.descendant_size += entry.size;
+        });
+        for parent in &entry.parents {
+            if let Some(parent) = self.entries.get_mut(parent) {
+                parent.children.insert(txid);
+            }
+        }
+        for outpoint in entry
+            .tx
+            .inputs
+            .iter()
+            .filter_map(|input| input.prev_output())
+        {
+            self.spent.insert(*outpoint, txid);
+        }
+
+        self.size += entry.size;
+        self.by_eviction.insert(EvictionKey::of(&entry));
+        self.entries.insert(txid, entry);
+    }
+
+    /// Removes a single transaction, its children lose it as a parent. The
+    /// transaction only leaves the descendant packages of its ancestors, so
+    /// its descendants have to leave as well unless its ancestors left first,
+    /// as they do when a block is mined.
+    fn remove_entry(&mut self, txid: &U256) -> Option<Transaction> {
+        let entry = self.entries.remove(txid)?;
+        self.by_eviction.remove(&EvictionKey::of(&entry));
+
+        let ancestors = self.ancestors_of(entry.parents.iter().copied());
+        self.update_descendant_packages(&ancestors, |package| {
+            package.descendant_fee -= entry.fee;
+            package.descendant_size -= entry.size;
+        });
+
+        for parent in &entry.parents {
+            if let Some(parent) = self.entries.get_mut(parent) {
+                parent.children.remove(txid);
+            }
+        }
+        for child in &entry.children {
+            if let Some(child) = self.entries.get_mut(child) {
+                child.parents.remove(txid);
+            }
+        }
+        for outpoint in entry
+            .tx
+            .inputs
+            .iter()
+            .filter_map(|input| input.prev_output())
+        {
+            self.spent.remove(outpoint);
+        }
+
+        self.size -= entry.size;
+        Some(entry.tx)
+    }
+
+    /// Applies `update` to the descendant package of each of `txids`, keeping
+    /// the eviction order in step.
+    fn update_descendant_packages(
+        &mut self,
+        txids: &HashSet<U256>,
+        update: impl Fn(&mut MempoolEntry),
+    ) {
+        for txid in txids {
+            let entry = self
+                .entries
+                .get_mut(txid)
+                .expect("Ancestor to be in the pool");
+            self.by_eviction.remove(&EvictionKey::of(entry));
+            update(entry);
+            self.by_eviction.insert(EvictionKey::of(entry));
+        }
+    }
+
+    /// Undoes a partly added package, whatever of it is still in the pool.
+    fn remove_package(&mut self, added: &[U256]) {
+        for txid in added.iter().rev() {
+            self.remove_with_descendants(txid);
+        }
+    }
+
+    /// Removes `txid` with its descendants, deepest first, so that each
+    /// removed transaction still reaches all of its ancestors and leaves
+    /// their descendant packages.
+    fn remove_with_descendants(&mut self, txid: &U256) -> Vec<Transaction> {
+        if !self.entries.contains_key(txid) {
+            return Vec::new();
+        }
+
+        let mut pending: Vec<U256> = self.descendants(txid).into_iter().collect();
+        pending.push(*txid);
+        let mut removed = Vec::new();
+        while !pending.is_empty() {
+            let (leaves, rest): (Vec<U256>, Vec<U256>) = pending
+                .into_iter()
+                .partition(|txid| self.entries[txid].children.is_empty());
+            for leaf in &leaves {
+                removed.extend(self.remove_entry(leaf));
+            }
+            pending = rest;
+        }
+        removed
+    }
+
+    /// Evicts packages until the pool fits its size limit, returning the
+    /// evicted txids. A package is a transaction with its descendants, which
+    /// can't stay without it, and the one paying the lowest fee rate goes
+    /// first.
+    fn trim(&mut self) -> Vec<U256> {
+        let mut evicted = Vec::new();
+        while self.size > self.policy.max_size {
+            match self.by_eviction.first() {
+                Some(lowest) => {
+                    let txid = lowest.txid;
+                    let removed = self.remove_with_descendants(&txid);
+                    evicted.extend(removed.iter().map(|tx| tx.hash));
+                }
+                None => break,
+            }
+        }
+        evicted
+    }
+
+    fn notify(&mut self, event: MempoolEvent) {
+        self.subscribers
+            .retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+
+    fn notify_removed(&mut self, txids: impl IntoIterator<Item = U256>) {
+        for txid in txids {
+            self.notify(MempoolEvent::Removed(txid));
+        }
+    }
+}
+
+/// The fee `size` encoded bytes pay at `fee_rate` per 1000 bytes, rounded up.
+pub fn fee_at_rate(fee_rate: u64, size: usize) -> u64 {
+    (fee_rate as u128 * size as u128).div_ceil(1_000) as u64
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{BlockHeader, Hash, UtxoInput, COIN};
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    /// Height at which the coinbase of `utxo_set` is mature.
+    const HEIGHT: usize = COINBASE_MATURITY;
+
+    fn keypair() -> Ed25519KeyPair {
+        Ed25519KeyPair::from_seed_unchecked(&[5u8; 32]).unwrap()
+    }
+
+    fn pub_key() -> [u8; 32] {
+        keypair().public_key().as_ref().try_into().unwrap()
+    }
+
+    /// A UTXO set holding a coinbase of `outputs` coins paid to the test key.
+    fn utxo_set(outputs: usize) -> (UtxoSet, U256) {
+        let (utxo, genesis) = utxo_genesis(outputs);
+        (utxo, genesis.transactions[0].hash)
+    }
+
+    fn utxo_genesis(outputs: usize) -> (UtxoSet, Block) {
+        let coinbase = Transaction::coinbase(
+            0,
+            0,
+            (0..outputs)
+                .map(|_| UtxoOutput::to_pub_key(&pub_key(), COIN))
+                .collect(),
+        );
+        let header = BlockHeader {
+            version: 1,
+            timestamp: 1_700_000_000,
+            prev: U256::ZERO,
+            merkle_root: U256::ZERO,
+            bits: 0x207fffff,
+            nonce: 0,
+            extra: Vec::new(),
+        };
+        let block = Block::new(0, U256::ONE, header, vec![coinbase]);
+
+        let mut utxo = UtxoSet::new();
+        utxo.apply_block(&block).unwrap();
+        (utxo, block)
+    }
+
+    /// Spends `outpoint` worth `value` back to the test key, paying `fee`.
+    fn spend(outpoint: OutPoint, value: u64, fee: u64) -> Transaction {
+        let mut tx = Transaction::new(
+            vec![UtxoInput::spend(outpoint, pub_key())],
+            vec![UtxoOutput::to_pub_key(&pub_key(), value - fee)],
+        );
+        tx.sign(&keypair());
+        tx
+    }
+
+    /// Like `spend` but opting in to replace-by-fee, spending every outpoint.
+    fn replaceable(outpoints: &[OutPoint], value: u64, fee: u64) -> Transaction {
+        let mut tx = Transaction::replaceable(
+            outpoints
+                .iter()
+                .map(|outpoint| UtxoInput::spend(*outpoint, pub_key()))
+                .collect(),
+            vec![UtxoOutput::to_pub_key(&pub_key(), value - fee)],
+        );
+        tx.sign(&keypair());
+        tx
+    }
+
+    fn block(transactions: Vec<Transaction>) -> Block {
+        let header = BlockHeader {
+            version: 1,
+            timestamp: 1_700_000_600,
+            prev: U256::ONE,
+            merkle_root: U256::ZERO,
+            bits: 0x207fffff,
+            nonce: 0,
+            extra: Vec::new(),
+        };
+        let mut txs = vec![Transaction::coinbase(1, 0, vec![])];
+        txs.extend(transactions);
+        Block::new(1, header.hash(), header, txs)
+    }
+
+    #[test]
+    fn test_tracks_unconfirmed_chain() {
+        let (utxo, coinbase) = utxo_set(1);
+        let mut mempool = Mempool::default();
+
+        let parent = spend(OutPoint::new(coinbase, 0), COIN, 1_000);
+        let child = spend(OutPoint::new(parent.hash, 0), COIN - 1_000, 2_000);
+        mempool.add(parent.clone(), &utxo, HEIGHT).unwrap();
+        mempool.add(child.clone(), &utxo, HEIGHT).unwrap();
+
+        assert_eq!(mempool.len(), 2);
+        assert_eq!(
+            mempool.size(),
+            parent.to_bytes().len() + child.to_bytes().len()
+        );
+        assert_eq!(mempool.get(&child.hash).unwrap().fee, 2_000);
+        assert_eq!(
+            mempool
+                .get(&parent.hash)
+                .unwrap()
+                .children()
+                .collect::<Vec<_>>(),
+            vec![&child.hash]
+        );
+        assert_eq!(mempool.ancestors(&child.hash), HashSet::from([parent.hash]));
+        assert_eq!(
+            mempool.descendants(&parent.hash),
+            HashSet::from([child.hash])
+        );
+        assert_eq!(
+            mempool.spender(&OutPoint::new(parent.hash, 0)),
+            Some(&child.hash)
+        );
+
+        let mut candidates = mempool.candidates();
+        candidates.sort_by_key(|candidate| candidate.fee);
+        assert_eq!(
+            candidates,
+            vec![
+                TemplateCandidate::new(parent, 1_000),
+                TemplateCandidate::new(child, 2_000)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tracks_descendant_packages() {
+        let (utxo, coinbase) = utxo_set(1);
+        let mut mempool = Mempool::default();
+
+        let parent = spend(OutPoint::new(coinbase, 0), COIN, 1_000);
+        let child = spend(OutPoint::new(parent.hash, 0), COIN - 1_000, 2_000);
+        let grandchild = spend(OutPoint::new(child.hash, 0), COIN - 3_000, 4_000);
+        for tx in [&parent, &child, &grandchild] {
+            mempool.add(tx.clone(), &utxo, HEIGHT).unwrap();
+        }
+        let package = |mempool: &Mempool, txid: &U256| {
+            let entry = mempool.get(txid).unwrap();
+            (entry.descendant_fee(), entry.descendant_size())
+        };
+        let size = parent.to_bytes().len();
+        assert_eq!(package(&mempool, &parent.hash), (7_000, 3 * size));
+        assert_eq!(package(&mempool, &child.hash), (6_000, 2 * size));
+        assert_eq!(package(&mempool, &grandchild.hash), (4_000, size));
+
+        mempool.remove(&grandchild.hash);
+        assert_eq!(package(&mempool, &parent.hash), (3_000, 2 * size));
+
+        // Once the parent is mined the child is a package on its own.
+        mempool.remove_for_block(&block(vec![parent]));
+        assert_eq!(package(&mempool, &child.hash), (2_000, size));
+    }
+
+    #[test]
+    fn test_removes_descendants_from_ancestor_packages() {
+        let (utxo, coinbase) = utxo_set(1);
+        let parent = spend(OutPoint::new(coinbase, 0), COIN, 1_000);
+        let tx = spend(OutPoint::new(parent.hash, 0), COIN - 1_000, 2_000);
+        let child = spend(OutPoint::new(tx.hash, 0), COIN - 3_000, 4_000);
+        let grandchild = spend(OutPoint::new(child.hash, 0), COIN - 7_000, 8_000);
+        let size = parent.to_bytes().len();
+
+        // Descendants come out of a hash set, try a few pools.
+        for _ in 0..20 {
+            let mut mempool = Mempool::default();
+            for tx in [&parent, &tx, &child, &grandchild] {
+                mempool.add(tx.clone(), &utxo, HEIGHT).unwrap();
+            }
+
+            assert_eq!(mempool.remove(&tx.hash).len(), 3);
+            let entry = mempool.get(&parent.hash).unwrap();
+            assert_eq!(
+                (entry.descendant_fee(), entry.descendant_size()),
+                (1_000, size)
+            );
+            assert_eq!(mempool.size(), size);
+        }
+    }
+
+    #[test]
+    fn test_rejects_by_policy() {
+        let (utxo, coinbase) = utxo_set(2);
+        let mut mempool = Mempool::default();
+        let tx = spend(OutPoint::new(coinbase, 0), COIN, 1_000);
+        mempool.add(tx.clone(), &utxo, HEIGHT).unwrap();
+
+        assert_eq!(
+            mempool.add(tx.clone(), &utxo, HEIGHT),
+            Err(MempoolError::AlreadyKnown(tx.hash))
+        );
+
+        let double_spend = spend(OutPoint::new(coinbase, 0), COIN, 5_000);
+        assert_eq!(
+            mempool.add(double_spend.clone(), &utxo, HEIGHT),
+            Err(MempoolError::Conflict {
+                txid: double_spend.hash,
+                conflicting: tx.hash
+            })
+        );
+
+        let cheap = spend(OutPoint::new(coinbase, 1), COIN, 1);
+        let min = fee_at_rate(1_000, cheap.to_bytes().len());
+        assert_eq!(
+            mempool.add(cheap.clone(), &utxo, HEIGHT),
+            Err(MempoolError::FeeTooLow {
+                txid: cheap.hash,
+                fee: 1,
+                min
+            })
+        );
+
+        let coinbase_tx = Transaction::coinbase(1, 0, vec![UtxoOutput::new(U256::ONE, 1)]);
+        assert_eq!(
+            mempool.add(coinbase_tx.clone(), &utxo, HEIGHT),
+            Err(MempoolError::Coinbase(coinbase_tx.hash))
+        );
+
+        let missing = OutPoint::new(U256::from(9u32), 0);
+        assert_eq!(
+            mempool.add(spend(missing, COIN, 1_000), &utxo, HEIGHT),
+            Err(UtxoError::MissingInput(missing).into())
+        );
+
+        let immature = spend(OutPoint::new(coinbase, 1), COIN, 1_000);
+        assert_eq!(
+            mempool.add(immature.clone(), &utxo, HEIGHT - 1),
+            Err(ValidationError::PrematureCoinbaseSpend {
+                txid: immature.hash,
+                outpoint: OutPoint::new(coinbase, 1)
+            }
+            .into())
+        );
+        assert_eq!(mempool.len(), 1);
+    }
+
+    #[test]
+    fn test_limits_ancestors() {
+        let (utxo, coinbase) = utxo_set(1);
+        let mut mempool = Mempool::new(MempoolPolicy {
+            max_ancestors: 3,
+            ..Default::default()
+        });
+
+        let mut outpoint = OutPoint::new(coinbase, 0);
+        let mut value = COIN;
+        for _ in 0..3 {
+            let tx = spend(outpoint, value, 1_000);
+            outpoint = OutPoint::new(tx.hash, 0);
+            value -= 1_000;
+            mempool.add(tx, &utxo, HEIGHT).unwrap();
+        }
+
+        let tx = spend(outpoint, value, 1_000);
+        assert_eq!(
+            mempool.add(tx.clone(), &utxo, HEIGHT),
+            Err(MempoolError::TooManyAncestors {
+                txid: tx.hash,
+                count: 4,
+                max: 3
+            })
+        );
+    }
+
+    #[test]
+    fn test_evicts_lowest_fee_rate_package() {
+        let (utxo, coinbase) = utxo_set(4);
+        let size = spend(OutPoint::new(coinbase, 0), COIN, 1_000)
+            .to_bytes()
+            .len();
+        let mut mempool = Mempool::new(MempoolPolicy {
+            max_size: 3 * size,
+            ..Default::default()
+        });
+
+        // A cheap parent whose child pays enough for both, and a package
+        // paying a lower rate on its own.
+        let parent = spend(OutPoint::new(coinbase, 0), COIN, 1_000);
+        let child = spend(OutPoint::new(parent.hash, 0), COIN - 1_000, 20_000);
+        let low = spend(OutPoint::new(coinbase, 1), COIN, 5_000);
+        for tx in [&parent, &child, &low] {
+            mempool.add(tx.clone(), &utxo, HEIGHT).unwrap();
+        }
+        let events = mempool.subscribe();
+
+        let high = spend(OutPoint::new(coinbase, 2), COIN, 8_000);
+        mempool.add(high.clone(), &utxo, HEIGHT).unwrap();
+        assert!(!mempool.contains(&low.hash));
+        assert_eq!(
+            events.try_iter().collect::<Vec<_>>(),
+            vec![
+                MempoolEvent::Added {
+                    txid: high.hash,
+                    fee_rate: 8_000 * 1_000 / size as u64
+                },
+                MempoolEvent::Removed(low.hash)
+            ]
+        );
+        assert!(mempool.contains(&parent.hash) && mempool.contains(&child.hash));
+        assert_eq!(mempool.size(), 3 * size);
+
+        let lowest = spend(OutPoint::new(coinbase, 3), COIN, 2_000);
+        assert_eq!(
+            mempool.add(lowest.clone(), &utxo, HEIGHT),
+            Err(MempoolError::Full(lowest.hash))
+        );
+        assert_eq!(mempool.len(), 3);
+        assert_eq!(mempool.spender(&OutPoint::new(coinbase, 3)), None);
+        assert!(events.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_remove_for_block() {
+        let (utxo, coinbase) = utxo_set(2);
+        let mut mempool = Mempool::default();
+
+        let parent = spend(OutPoint::new(coinbase, 0), COIN, 1_000);
+        let child = spend(OutPoint::new(parent.hash, 0), COIN - 1_000, 1_000);
+        let spent = spend(OutPoint::new(coinbase, 1), COIN, 1_000);
+        let spent_child = spend(OutPoint::new(spent.hash, 0), COIN - 1_000, 1_000);
+        for tx in [&parent, &child, &spent, &spent_child] {
+            mempool.add(tx.clone(), &utxo, HEIGHT).unwrap();
+        }
+        let events = mempool.subscribe();
+
+        // The block mines the parent and double spends the other output.
+        let double_spend = spend(OutPoint::new(coinbase, 1), COIN, 3_000);
+        mempool.remove_for_block(&block(vec![parent.clone(), double_spend]));
+        assert_eq!(
+            events.try_iter().collect::<Vec<_>>(),
+            vec![
+                MempoolEvent::Mined(parent.hash),
+                MempoolEvent::Removed(spent_child.hash),
+                MempoolEvent::Removed(spent.hash)
+            ]
+        );
+
+        assert_eq!(mempool.len(), 1);
+        assert!(mempool.contains(&child.hash));
+        assert_eq!(mempool.get(&child.hash).unwrap().parents().count(), 0);
+        assert_eq!(mempool.ancestors(&child.hash), HashSet::new());
+        assert_eq!(mempool.size(), child.to_bytes().len());
+        assert_eq!(mempool.spender(&OutPoint::new(coinbase, 1)), None);
+
+        assert_eq!(mempool.remove(&child.hash), vec![child.clone()]);
+        assert_eq!(events.try_recv(), Ok(MempoolEvent::Removed(child.hash)));
+        assert!(mempool.is_empty());
+        assert_eq!(mempool.size(), 0);
+    }
+
+    #[test]
+    fn test_replaces_by_fee() {
+        let (utxo, coinbase) = utxo_set(1);
+        let mut mempool = Mempool::default();
+
+        let outpoint = OutPoint::new(coinbase, 0);
+        let original = replaceable(&[outpoint], COIN, 1_000);
+        let child = spend(OutPoint::new(original.hash, 0), COIN - 1_000, 1_000);
+        mempool.add(original.clone(), &utxo, HEIGHT).unwrap();
+        mempool.add(child.clone(), &utxo, HEIGHT).unwrap();
+        let events = mempool.subscribe();
+
+        // Paying for the evicted transactions isn't enough, the replacement
+        // has to pay for its own relay too.
+        let cheap = replaceable(&[outpoint], COIN, 2_000);
+        let min = 2_000 + fee_at_rate(1_000, cheap.to_bytes().len());
+        assert_eq!(
+            mempool.add(cheap.clone(), &utxo, HEIGHT),
+            Err(MempoolError::ReplacementFeeTooLow {
+                txid: cheap.hash,
+                fee: 2_000,
+                min
+            })
+        );
+        assert!(mempool.contains(&original.hash) && mempool.contains(&child.hash));
+
+        let replacement = spend(outpoint, COIN, 5_000);
+        mempool.add(replacement.clone(), &utxo, HEIGHT).unwrap();
+        assert_eq!(mempool.len(), 1);
+        assert_eq!(mempool.spender(&outpoint), Some(&replacement.hash));
+        assert_eq!(mempool.size(), replacement.to_bytes().len());
+
+        let mut replaced = vec![original.hash, child.hash];
+        replaced.sort();
+        assert_eq!(
+            events.try_iter().collect::<Vec<_>>(),
+            vec![
+                MempoolEvent::Replaced(Replaced {
+                    replacement: replacement.hash,
+                    replaced
+                }),
+                MempoolEvent::Added {
+                    txid: replacement.hash,
+                    fee_rate: mempool.get(&replacement.hash).unwrap().fee_rate()
+                }
+            ]
+        );
+
+        // The replacement didn't opt in, so it is final.
+        let again = replaceable(&[outpoint], COIN, 50_000);
+        assert_eq!(
+            mempool.add(again.clone(), &utxo, HEIGHT),
+            Err(MempoolError::Conflict {
+                txid: again.hash,
+                conflicting: replacement.hash
+            })
+        );
+        assert!(events.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_replacement_adds_no_unconfirmed_inputs() {
+        let (utxo, coinbase) = utxo_set(3);
+        let mut mempool = Mempool::default();
+
+        let parent = spend(OutPoint::new(coinbase, 0), COIN, 1_000);
+        let unrelated = spend(OutPoint::new(coinbase, 1), COIN, 1_000);
+        let original = replaceable(
+            &[OutPoint::new(parent.hash, 0), OutPoint::new(coinbase, 2)],
+            2 * COIN - 1_000,
+            1_000,
+        );
+        for tx in [&parent, &unrelated, &original] {
+            mempool.add(tx.clone(), &utxo, HEIGHT).unwrap();
+        }
+
+        let replacement = replaceable(
+            &[OutPoint::new(coinbase, 2), OutPoint::new(unrelated.hash, 0)],
+            2 * COIN - 1_000,
+            50_000,
+        );
+        assert_eq!(
+            mempool.add(replacement.clone(), &utxo, HEIGHT),
+            Err(MempoolError::NewUnconfirmedInput {
+                txid: replacement.hash,
+                parent: unrelated.hash
+            })
+        );
+        assert!(mempool.contains(&original.hash));
+
+        // Spending the parent the original spent is fine.
+        let replacement = replaceable(&[OutPoint::new(parent.hash, 0)], COIN - 1_000, 50_000);
+        mempool.add(replacement.clone(), &utxo, HEIGHT).unwrap();
+        assert!(!mempool.contains(&original.hash));
+    }
+
+    #[test]
+    fn test_replacement_limits() {
+        let (utxo, coinbase) = utxo_set(2);
+        let mut mempool = Mempool::new(MempoolPolicy {
+            max_replacements: 1,
+            ..Default::default()
+        });
+
+        let first = OutPoint::new(coinbase, 0);
+        let original = replaceable(&[first], COIN, 1_000);
+        mempool.add(original.clone(), &utxo, HEIGHT).unwrap();
+
+        // Spending the output of the transaction it replaces.
+        let spends_replaced = replaceable(
+            &[first, OutPoint::new(original.hash, 0)],
+            2 * COIN - 1_000,
+            50_000,
+        );
+        assert_eq!(
+            mempool.add(spends_replaced.clone(), &utxo, HEIGHT),
+            Err(MempoolError::SpendsReplaced {
+                txid: spends_replaced.hash,
+                replaced: original.hash
+            })
+        );
+
+        let child = spend(OutPoint::new(original.hash, 0), COIN - 1_000, 1_000);
+        mempool.add(child, &utxo, HEIGHT).unwrap();
+        let replacement = replaceable(&[first], COIN, 50_000);
+        assert_eq!(
+            mempool.add(replacement.clone(), &utxo, HEIGHT),
+            Err(MempoolError::TooManyReplacements {
+                txid: replacement.hash,
+                count: 2,
+                max: 1
+            })
+        );
+        assert_eq!(mempool.len(), 2);
+    }
+
+    #[test]
+    fn test_child_pays_for_parent() {
+        let (utxo, coinbase) = utxo_set(1);
+        let mut mempool = Mempool::default();
+
+        let parent = spend(OutPoint::new(coinbase, 0), COIN, 0);
+        let child = spend(OutPoint::new(parent.hash, 0), COIN, 2_000);
+        let min = fee_at_rate(1_000, parent.to_bytes().len());
+        assert_eq!(
+            mempool.add(parent.clone(), &utxo, HEIGHT),
+            Err(MempoolError::FeeTooLow {
+                txid: parent.hash,
+                fee: 0,
+                min
+            })
+        );
+
+        mempool
+            .add_package(vec![parent.clone(), child.clone()], &utxo, HEIGHT)
+            .unwrap();
+        assert_eq!(mempool.len(), 2);
+        assert_eq!(mempool.ancestors(&child.hash), HashSet::from([parent.hash]));
+
+        // Resending the package changes nothing, resending the child alone
+        // is a duplicate.
+        mempool
+            .add_package(vec![parent.clone(), child.clone()], &utxo, HEIGHT)
+            .unwrap();
+        assert_eq!(mempool.len(), 2);
+        assert_eq!(
+            mempool.add(child.clone(), &utxo, HEIGHT),
+            Err(MempoolError::AlreadyKnown(child.hash))
+        );
+    }
+
+    #[test]
+    fn test_rejects_package_as_a_whole() {
+        let (utxo, coinbase) = utxo_set(3);
+        let mut mempool = Mempool::default();
+
+        // The child doesn't pay enough for both.
+        let parent = spend(OutPoint::new(coinbase, 0), COIN, 0);
+        let child = spend(OutPoint::new(parent.hash, 0), COIN, 200);
+        let size = parent.to_bytes().len() + child.to_bytes().len();
+        assert_eq!(
+            mempool.add_package(vec![parent.clone(), child], &utxo, HEIGHT),
+            Err(MempoolError::FeeTooLow {
+                txid: parent.hash,
+                fee: 200,
+                min: fee_at_rate(1_000, size)
+            })
+        );
+
+        // An unrelated transaction can't pay for a free one.
+        let free = spend(OutPoint::new(coinbase, 1), COIN, 0);
+        let rich = spend(OutPoint::new(coinbase, 2), COIN, 50_000);
+        assert!(matches!(
+            mempool.add_package(vec![rich.clone(), free.clone()], &utxo, HEIGHT),
+            Err(MempoolError::FeeTooLow { txid, .. }) if txid == free.hash
+        ));
+        assert!(mempool.is_empty());
+        assert_eq!(mempool.size(), 0);
+
+        // Packages don't replace.
+        let original = replaceable(&[OutPoint::new(coinbase, 0)], COIN, 1_000);
+        mempool.add(original.clone(), &utxo, HEIGHT).unwrap();
+        let conflicting = spend(OutPoint::new(coinbase, 0), COIN, 0);
+        let bump = spend(OutPoint::new(conflicting.hash, 0), COIN, 90_000);
+        assert_eq!(
+            mempool.add_package(vec![rich.clone(), conflicting.clone(), bump], &utxo, HEIGHT),
+            Err(MempoolError::Conflict {
+                txid: conflicting.hash,
+                conflicting: original.hash
+            })
+        );
+        assert_eq!(mempool.len(), 1);
+        assert_eq!(mempool.spender(&OutPoint::new(coinbase, 2)), None);
+    }
+
+    #[test]
+    fn test_update_for_tip() {
+        let (utxo, genesis) = utxo_genesis(1);
+        let coinbase = genesis.transactions[0].hash;
+        let mut index = BlockIndex::new(genesis.clone());
+
+        let parent = spend(OutPoint::new(coinbase, 0), COIN, 1_000);
+        let child = spend(OutPoint::new(parent.hash, 0), COIN - 1_000, 1_000);
+        let mined = block(vec![parent.clone()]);
+        index.insert_block(mined.clone()).unwrap();
+        let mut mined_utxo = utxo.clone();
+        mined_utxo.apply_block(&mined).unwrap();
+
+        let mut mempool = Mempool::default();
+        mempool.add(parent.clone(), &utxo, HEIGHT).unwrap();
+        mempool.add(child.clone(), &utxo, HEIGHT).unwrap();
+
+        let connected = TipChanged {
+            old_tip: genesis.header_hash,
+            new_tip: mined.header_hash,
+            height: 1,
+            disconnected: vec![],
+            connected: vec![mined.header_hash],
+        };
+        mempool.update_for_tip(&connected, &index, &mined_utxo, HEIGHT + 1);
+        assert_eq!(mempool.len(), 1);
+        assert_eq!(mempool.ancestors(&child.hash), HashSet::new());
+
+        // The block is reorganized away, its transaction comes back as the
+        // parent of the child again.
+        let disconnected = TipChanged {
+            old_tip: mined.header_hash,
+            new_tip: genesis.header_hash,
+            height: 0,
+            disconnected: vec![mined.header_hash],
+            connected: vec![],
+        };
+        mempool.update_for_tip(&disconnected, &index, &utxo, HEIGHT);
+        assert_eq!(mempool.len(), 2);
+        assert_eq!(mempool.ancestors(&child.hash), HashSet::from([parent.hash]));
+        assert_eq!(
+            mempool.size(),
+            parent.to_bytes().len() + child.to_bytes().len()
+        );
+    }
+
+    #[test]
+    fn test_reports_transactions_mined_by_reorg() {
+        let (utxo, genesis) = utxo_genesis(2);
+        let coinbase = genesis.transactions[0].hash;
+        let mut index = BlockIndex::new(genesis.clone());
+
+        let parent = spend(OutPoint::new(coinbase, 0), COIN, 1_000);
+        let other = spend(OutPoint::new(coinbase, 1), COIN, 1_000);
+        let mut empty = block(vec![]);
+        empty.header.timestamp += 1;
+        empty.header_hash = empty.header.hash();
+        let mined = block(vec![parent.clone()]);
+        index.insert_block(empty.clone()).unwrap();
+        index.insert_block(mined.clone()).unwrap();
+        let mut mined_utxo = utxo.clone();
+        mined_utxo.apply_block(&mined).unwrap();
+
+        let mut mempool = Mempool::default();
+        mempool.add(parent.clone(), &utxo, HEIGHT).unwrap();
+        mempool.add(other.clone(), &utxo, HEIGHT).unwrap();
+        let events = mempool.subscribe();
+
+        // The new branch mines a pooled transaction.
+        let reorg = TipChanged {
+            old_tip: empty.header_hash,
+            new_tip: mined.header_hash,
+            height: 1,
+            disconnected: vec![empty.header_hash],
+            connected: vec![mined.header_hash],
+        };
+        mempool.update_for_tip(&reorg, &index, &mined_utxo, HEIGHT + 1);
+        assert_eq!(mempool.len(), 1);
+
+        let events: Vec<MempoolEvent> = events.try_iter().collect();
+        assert!(events.contains(&MempoolEvent::Mined(parent.hash)));
+        assert!(!events.contains(&MempoolEvent::Removed(parent.hash)));
+    }
+}
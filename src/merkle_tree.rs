@@ -42,7 +42,7 @@ impl<'a> MerkleProof<'a> {
     }
 
     pub fn verify(&self, candidate: U256) -> bool {
-        let mut result = self.0.iter().try_fold(candidate, |prev_hash, curr_node| {
+        let result = self.0.iter().try_fold(candidate, |prev_hash, curr_node| {
             let left_node = curr_node.1.unwrap_or(&prev_hash);
             let right_node = curr_node.2.unwrap_or(&prev_hash);
             let hash = hash_intermediate!(left_node, right_node);
@@ -105,7 +105,7 @@ impl MerkleTree {
         tree
     }
 
-    pub fn find_path(&self, index: usize) -> Option<MerkleProof> {
+    pub fn find_path(&self, index: usize) -> Option<MerkleProof<'_>> {
         if index >= self.leaf_count {
             return None;
         }
@@ -123,7 +123,7 @@ impl MerkleTree {
             if left_node.is_some() || right_node.is_some() {
                 path.push(MerkleProofEntry::new(target, left_node, right_node));
             }
-            if node_index % 2 == 0 {
+            if node_index.is_multiple_of(2) {
                 left_node = None;
                 right_node = if node_index + 1 < level.len() {
                     Some(&level[node_index + 1])
@@ -147,12 +147,37 @@ impl MerkleTree {
         self.nodes.last()
     }
 
+    /// The right siblings on the path from the first leaf to the root, which
+    /// stay the same when only the first leaf changes.
+    pub fn first_leaf_branch(&self) -> Vec<U256> {
+        let mut branch = Vec::new();
+        let mut level_len = self.leaf_count;
+        let mut level_start = 0;
+
+        while level_len > 1 {
+            branch.push(self.nodes[level_start + 1]);
+            level_start += level_len;
+            level_len = MerkleTree::next_level_len(level_len);
+        }
+
+        branch
+    }
+
+    /// The root of the tree whose first leaf is `item` and whose other nodes
+    /// are summed up by `branch`, see `first_leaf_branch`.
+    pub fn root_from_first_leaf<T: AsRef<[u8]>>(item: T, branch: &[U256]) -> U256 {
+        let item = item.as_ref();
+        branch.iter().fold(hash_leaf!(&item), |left, right| {
+            hash_intermediate!(left, right)
+        })
+    }
+
     #[inline]
     pub fn next_level_len(level_len: usize) -> usize {
         if level_len == 1 {
             0
         } else {
-            (level_len + 1) / 2
+            level_len.div_ceil(2)
         }
     }
 
@@ -239,6 +264,20 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_root_from_first_leaf() {
+        for len in 1..=TEST.len() {
+            let branch = MerkleTree::new(&TEST[..len]).first_leaf_branch();
+
+            let mut items = TEST[..len].to_vec();
+            items[0] = b"changed";
+            assert_eq!(
+                Some(&MerkleTree::root_from_first_leaf(items[0], &branch)),
+                MerkleTree::new(&items).get_root()
+            );
+        }
+    }
+
     #[test]
     fn test_proof_entry_instantiation_lsib_set() {
         MerkleProofEntry::new(&U256::default(), Some(&U256::default()), None);
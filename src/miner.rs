@@ -0,0 +1,384 @@
+use crate::{
+    hash_to_u256, Block, Clock, CompactTarget, Encode, MerkleTree, SystemClock, NONCE_OFFSET,
+};
+use ethnum::U256;
+use std::ops::RangeInclusive;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
+
+/// Hashes a worker tries between looking at the cancel flag.
+const BATCH_SIZE: u32 = 4096;
+
+/// The part of the nonce space worker `worker` of `workers` searches.
+pub fn nonce_range(worker: usize, workers: usize) -> RangeInclusive<u32> {
+    assert!(worker < workers, "Worker {} out of {}", worker, workers);
+
+    let span = (u32::MAX as u64 + 1) / workers as u64;
+    let start = worker as u64 * span;
+    let end = if worker + 1 == workers {
+        u32::MAX as u64
+    } else {
+        start + span - 1
+    };
+
+    start as u32..=end as u32
+}
+
+#[derive(Debug, Default)]
+struct Shared {
+    cancelled: AtomicBool,
+    hashes: AtomicU64,
+}
+
+#[derive(Debug)]
+struct Job {
+    shared: Arc<Shared>,
+    workers: Vec<JoinHandle<()>>,
+    found: mpsc::Receiver<Block>,
+    started: Instant,
+}
+
+impl Job {
+    fn stop(self) {
+        self.shared.cancelled.store(true, Ordering::SeqCst);
+        for worker in self.workers {
+            worker.join().expect("Mining worker not to panic");
+        }
+    }
+}
+
+/// Proof of work miner splitting the nonce space of a block template across
+/// worker threads. Starting a new template cancels the current one, so the
+/// owner restarts it whenever the tip changes.
+#[derive(Debug)]
+pub struct Miner<C: Clock + Send + Sync + 'static = SystemClock> {
+    threads: usize,
+    clock: Arc<C>,
+    job: Option<Job>,
+}
+
+impl Miner {
+    /// A miner on the system clock, see `with_clock`.
+    pub fn new(threads: usize) -> Self {
+        Self::with_clock(threads, Arc::new(SystemClock))
+    }
+}
+
+impl<C: Clock + Send + Sync + 'static> Miner<C> {
+    /// A miner running `threads` workers. Workers roll the extra nonce of the
+    /// coinbase once their nonces run out, or move the timestamp to the time
+    /// of `clock` when the template has no coinbase.
+    pub fn with_clock(threads: usize, clock: Arc<C>) -> Self {
+        assert!(threads > 0, "Miner needs at least one thread");
+
+        Miner {
+            threads,
+            clock,
+            job: None,
+        }
+    }
+
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    pub fn clock(&self) -> &C {
+        &self.clock
+    }
+
+    /// Starts searching a proof for `template`, whose header must commit to
+    /// its transactions and carry valid bits. The current job is cancelled.
+    pub fn start(&mut self, template: Block) {
+        self.cancel();
+
+        let target = CompactTarget::new(template.header.bits)
+            .to_target()
+            .expect("Header bits to encode a valid target");
+        let shared = Arc::new(Shared::default());
+        let (sender, found) = mpsc::channel();
+
+        let workers = (0..self.threads)
+            .map(|worker| {
+                let template = template.clone();
+                let nonces = nonce_range(worker, self.threads);
+                let shared = shared.clone();
+                let clock = self.clock.clone();
+                let sender = sender.clone();
+
+                thread::spawn(move || {
+                    if let Some(block) = search(template, nonces, target, &shared, &*clock) {
+                        // Only the first block found is kept.
+                        if !shared.cancelled.swap(true, Ordering::SeqCst) {
+                            let _ = sender.send(block);
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        self.job = Some(Job {
+            shared,
+            workers,
+            found,
+            started: Instant::now(),
+        });
+    }
+
+    /// Stops the workers of the current job and waits for them to exit.
+    pub fn cancel(&mut self) {
+        if let Some(job) = self.job.take() {
+            job.stop();
+        }
+    }
+
+    /// The block found for the current job, if any yet.
+    pub fn try_block(&mut self) -> Option<Block> {
+        let block = self.job.as_ref()?.found.try_recv().ok()?;
+        self.cancel();
+        Some(block)
+    }
+
+    /// Waits for the current job to find a block. `None` if there's no job.
+    pub fn wait(&mut self) -> Option<Block> {
+        let block = self.job.as_ref()?.found.recv().ok();
+        self.cancel();
+        block
+    }
+
+    /// Hashes tried for the current job.
+    pub fn hashes(&self) -> u64 {
+        self.job
+            .as_ref()
+            .map_or(0, |job| job.shared.hashes.load(Ordering::Relaxed))
+    }
+
+    /// Hashes per second over the current job.
+    pub fn hashrate(&self) -> f64 {
+        let Some(job) = &self.job else {
+            return 0.0;
+        };
+
+        let elapsed = job.started.elapsed().as_secs_f64();
+        if elapsed == 0.0 {
+            return 0.0;
+        }
+        job.shared.hashes.load(Ordering::Relaxed) as f64 / elapsed
+    }
+}
+
+impl<C: Clock + Send + Sync + 'static> Drop for Miner<C> {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+/// Tries the `nonces` of `block` until one meets `target` or the job is
+/// cancelled. Each time the range runs out the extra nonce of the coinbase is
+/// incremented, workers search disjoint nonce ranges so they never try the
+/// same header twice.
+fn search(
+    mut block: Block,
+    nonces: RangeInclusive<u32>,
+    target: U256,
+    shared: &Shared,
+    clock: &dyn Clock,
+) -> Option<Block> {
+    // Only the coinbase changes, the rest of the merkle tree is hashed once.
+    let branch = block.merkle_tree().first_leaf_branch();
+
+    loop {
+        // The header is encoded once, only the nonce bytes change between attempts.
+        let mut bytes = block.header.to_bytes();
+        let mut nonce = *nonces.start();
+
+        loop {
+            if shared.cancelled.load(Ordering::Relaxed) {
+                return None;
+            }
+
+            let batch_end = nonce.saturating_add(BATCH_SIZE - 1).min(*nonces.end());
+            for candidate in nonce..=batch_end {
+                bytes[NONCE_OFFSET..NONCE_OFFSET + 4].copy_from_slice(&candidate.to_le_bytes());
+                let hash = hash_to_u256!(&bytes);
+
+                if hash <= target {
+                    shared
+                        .hashes
+                        .fetch_add((candidate - nonce) as u64 + 1, Ordering::Relaxed);
+                    block.header.nonce = candidate;
+                    block.header_hash = hash;
+                    return Some(block);
+                }
+            }
+            shared
+                .hashes
+                .fetch_add((batch_end - nonce) as u64 + 1, Ordering::Relaxed);
+
+            if batch_end == *nonces.end() {
+                break;
+            }
+            nonce = batch_end + 1;
+        }
+
+        match block.transactions.first_mut() {
+            Some(coinbase) if coinbase.is_coinbase() => {
+                let extra_nonce = coinbase.inputs[0].extra_nonce().unwrap_or_default();
+                coinbase.set_extra_nonce(extra_nonce.wrapping_add(1));
+                block.header.merkle_root =
+                    MerkleTree::root_from_first_leaf(coinbase.hash.to_le_bytes(), &branch);
+            }
+            _ => block.header.timestamp = clock.now().max(block.header.timestamp + 1),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{check_proof, pow, BlockHeader, FixedClock, Hash, Transaction};
+
+    // Roughly one in 65536 hashes meets this target.
+    const TEST_BITS: u32 = 0x1f00ffff;
+    // Far beyond what a test could mine.
+    const HARD_BITS: u32 = 0x1b0404cb;
+
+    fn template(prev: u64, bits: u32) -> Block {
+        let header = BlockHeader {
+            version: 1,
+            timestamp: 1_700_000_000,
+            prev: U256::from(prev),
+            merkle_root: U256::ZERO,
+            bits,
+            nonce: 0,
+            extra: Vec::new(),
+        };
+        let mut block = Block::new(
+            1,
+            U256::ZERO,
+            header,
+            vec![Transaction::coinbase(1, 0, vec![])],
+        );
+        block.header.merkle_root = block.compute_merkle_root();
+        block
+    }
+
+    fn miner(threads: usize) -> Miner<FixedClock> {
+        Miner::with_clock(threads, Arc::new(FixedClock(1_700_000_000)))
+    }
+
+    #[test]
+    fn test_nonce_ranges_cover_nonce_space() {
+        for workers in [1, 2, 3, 7, 16] {
+            let ranges: Vec<_> = (0..workers).map(|w| nonce_range(w, workers)).collect();
+            assert_eq!(*ranges[0].start(), 0);
+            assert_eq!(*ranges[workers - 1].end(), u32::MAX);
+            for pair in ranges.windows(2) {
+                assert_eq!(*pair[0].end() as u64 + 1, *pair[1].start() as u64);
+            }
+        }
+    }
+
+    #[test]
+    fn test_mines_block() {
+        let mut miner = miner(4);
+        let template = template(1, TEST_BITS);
+        miner.start(template.clone());
+
+        let block = miner.wait().unwrap();
+        assert!(check_proof(&block.header));
+        assert_eq!(block.header_hash, block.header.hash());
+        assert_eq!(block.header.merkle_root, template.header.merkle_root);
+        assert_eq!(block.transactions, template.transactions);
+
+        // The job is over.
+        assert_eq!(miner.wait(), None);
+        assert_eq!(miner.hashes(), 0);
+    }
+
+    #[test]
+    fn test_matches_single_threaded_search() {
+        let template = template(2, TEST_BITS);
+        let mut miner = miner(1);
+        miner.start(template.clone());
+
+        let proof = pow(&template.header, &FixedClock(1_700_000_000));
+        let block = miner.wait().unwrap();
+        assert_eq!(block.header.nonce, proof.nonce);
+        assert_eq!(block.header.timestamp, proof.timestamp);
+        assert_eq!(block.header_hash, proof.hash);
+    }
+
+    #[test]
+    fn test_cancel_stops_workers() {
+        let mut miner = miner(2);
+        miner.start(template(3, HARD_BITS));
+
+        while miner.hashes() < 2 * BATCH_SIZE as u64 {
+            thread::yield_now();
+        }
+        assert!(miner.hashrate() > 0.0);
+        assert_eq!(miner.try_block(), None);
+
+        miner.cancel();
+        assert_eq!(miner.wait(), None);
+        assert_eq!(miner.hashrate(), 0.0);
+    }
+
+    #[test]
+    fn test_restart_with_new_template() {
+        let mut miner = miner(2);
+        miner.start(template(4, HARD_BITS));
+        miner.start(template(5, TEST_BITS));
+
+        let block = miner.wait().unwrap();
+        assert_eq!(block.header.prev, U256::from(5u64));
+        assert!(check_proof(&block.header));
+    }
+
+    #[test]
+    fn test_exhausted_nonces_roll_extra_nonce() {
+        let mut template = template(6, TEST_BITS);
+        for tag in 1..=4 {
+            template.add_transaction(Transaction::coinbase(1, tag, vec![]));
+        }
+        template.header.merkle_root = template.compute_merkle_root();
+
+        // Only nonces below 16 are tried before the extra nonce moves.
+        let target = U256::ONE << 240;
+        let found = search(
+            template.clone(),
+            0..=15,
+            target,
+            &Shared::default(),
+            &FixedClock(0),
+        )
+        .unwrap();
+
+        let extra_nonce = found.transactions[0].inputs[0].extra_nonce().unwrap();
+        assert!(extra_nonce > 0);
+        assert!(found.header.nonce < 16);
+        assert_eq!(found.header.timestamp, template.header.timestamp);
+        assert_eq!(found.transactions[1..], template.transactions[1..]);
+        assert_eq!(found.header.merkle_root, found.compute_merkle_root());
+        assert_eq!(found.header_hash, found.header.hash());
+        assert!(found.header_hash <= target);
+    }
+
+    #[test]
+    fn test_template_without_coinbase_moves_timestamp() {
+        let mut header = template(6, 0x2000ffff).header;
+        // Only nonces below 16 are tried before the timestamp moves.
+        let block = Block::new(1, U256::ZERO, header.clone(), vec![]);
+        let target = U256::ONE << 240;
+        let found = search(block, 0..=15, target, &Shared::default(), &FixedClock(0)).unwrap();
+
+        header.timestamp = found.header.timestamp;
+        header.nonce = found.header.nonce;
+        assert!(found.header.nonce < 16);
+        assert!(found.header.timestamp > 1_700_000_000);
+        assert_eq!(found.header_hash, header.hash());
+        assert!(found.header_hash <= target);
+    }
+}
@@ -0,0 +1,711 @@
+use crate::{
+    decode_length, decode_list, decode_varint, encode_list, encode_varint, hash_to_u256, siphash24,
+    take, Block, BlockHeader, BlockIndex, Decode, DecodeError, Encode, Hash, Mempool, Transaction,
+};
+use ethnum::U256;
+use std::collections::{HashMap, HashSet};
+
+/// Bytes of a short transaction ID on the wire.
+pub const SHORT_ID_SIZE: usize = 6;
+
+/// A transaction ID cut down to 48 bits of SipHash, keyed per block so that
+/// collisions can't be mined into every block.
+pub type ShortId = u64;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum CompactBlockError {
+    InvalidIndex(usize),
+    /// Two transactions of the block share a short ID, the full block has to
+    /// be fetched instead.
+    ShortIdCollision,
+    WrongBlock(U256),
+    WrongTransactionCount {
+        expected: usize,
+        found: usize,
+    },
+    MissingTransactions(usize),
+    /// The reconstructed block doesn't match its header, a mempool
+    /// transaction collided with a short ID.
+    BadMerkleRoot,
+    /// The block's height comes from its parent, which isn't indexed.
+    UnknownParent(U256),
+}
+
+impl std::fmt::Display for CompactBlockError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CompactBlockError::InvalidIndex(index) => {
+                write!(f, "invalid transaction index {}", index)
+            }
+            CompactBlockError::ShortIdCollision => write!(f, "short transaction ID collision"),
+            CompactBlockError::WrongBlock(hash) => {
+                write!(f, "transactions are for block {:#064x}", hash)
+            }
+            CompactBlockError::WrongTransactionCount { expected, found } => {
+                write!(f, "expected {} transactions but found {}", expected, found)
+            }
+            CompactBlockError::MissingTransactions(count) => {
+                write!(f, "{} transactions are missing", count)
+            }
+            CompactBlockError::BadMerkleRoot => {
+                write!(f, "reconstructed block doesn't match its merkle root")
+            }
+            CompactBlockError::UnknownParent(hash) => {
+                write!(f, "parent block {:#064x} is unknown", hash)
+            }
+        }
+    }
+}
+
+impl std::error::Error for CompactBlockError {}
+
+/// A transaction sent in full with a compact block, at its index in the
+/// block.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PrefilledTransaction {
+    pub index: usize,
+    pub tx: Transaction,
+}
+
+/// A block announced by its header and the short IDs of its transactions,
+/// as in BIP152. Peers rebuild it from their mempool and only ask for the
+/// transactions they don't have.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CompactBlock {
+    pub header: BlockHeader,
+    /// Salts the short ID keys.
+    pub nonce: u64,
+    /// IDs of the transactions that aren't prefilled, in block order.
+    pub short_ids: Vec<ShortId>,
+    /// Sorted by index. The coinbase is always prefilled, peers can't have
+    /// it yet.
+    pub prefilled: Vec<PrefilledTransaction>,
+}
+
+impl CompactBlock {
+    pub fn new(block: &Block, nonce: u64) -> Self {
+        let mut compact = CompactBlock {
+            header: block.header.clone(),
+            nonce,
+            short_ids: Vec::new(),
+            prefilled: Vec::new(),
+        };
+        let keys = compact.keys();
+
+        for (index, tx) in block.transactions.iter().enumerate() {
+            if tx.is_coinbase() {
+                compact.prefilled.push(PrefilledTransaction {
+                    index,
+                    tx: tx.clone(),
+                });
+            } else {
+                compact.short_ids.push(short_id(keys, &tx.hash));
+            }
+        }
+
+        compact
+    }
+
+    pub fn block_hash(&self) -> U256 {
+        self.header.hash()
+    }
+
+    pub fn transaction_count(&self) -> usize {
+        self.short_ids.len() + self.prefilled.len()
+    }
+
+    /// The SipHash keys, the first two little-endian words of the SHA-256 of
+    /// the header followed by the nonce.
+    pub fn keys(&self) -> (u64, u64) {
+        let mut bytes = self.header.to_bytes();
+        self.nonce.encode(&mut bytes);
+        let hash = hash_to_u256!(&bytes).to_le_bytes();
+
+        (
+            u64::from_le_bytes(hash[..8].try_into().unwrap()),
+            u64::from_le_bytes(hash[8..16].try_into().unwrap()),
+        )
+    }
+}
+
+pub fn short_id((k0, k1): (u64, u64), txid: &U256) -> ShortId {
+    siphash24(k0, k1, &txid.to_le_bytes()) & 0xffff_ffff_ffff
+}
+
+/// Asks the sender of a compact block for the transactions that couldn't be
+/// found, by index in the block.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BlockTransactionsRequest {
+    pub block_hash: U256,
+    pub indexes: Vec<usize>,
+}
+
+/// The transactions of a `BlockTransactionsRequest`, in the same order.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BlockTransactions {
+    pub block_hash: U256,
+    pub transactions: Vec<Transaction>,
+}
+
+/// Answers `request` from `block`.
+pub fn block_transactions(
+    block: &Block,
+    request: &BlockTransactionsRequest,
+) -> Result<BlockTransactions, CompactBlockError> {
+    if request.block_hash != block.header_hash {
+        return Err(CompactBlockError::WrongBlock(block.header_hash));
+    }
+
+    let transactions = request
+        .indexes
+        .iter()
+        .map(|&index| {
+            block
+                .transactions
+                .get(index)
+                .cloned()
+                .ok_or(CompactBlockError::InvalidIndex(index))
+        })
+        .collect::<Result<_, _>>()?;
+
+    Ok(BlockTransactions {
+        block_hash: block.header_hash,
+        transactions,
+    })
+}
+
+/// A block being rebuilt from a compact block.
+#[derive(Clone, Debug)]
+pub struct PartialBlock {
+    header: BlockHeader,
+    hash: U256,
+    transactions: Vec<Option<Transaction>>,
+}
+
+impl PartialBlock {
+    /// Places the prefilled transactions and the mempool transactions
+    /// matching a short ID. A short ID matched by several mempool
+    /// transactions is left missing.
+    pub fn new(compact: &CompactBlock, mempool: &Mempool) -> Result<Self, CompactBlockError> {
+        let count = compact.transaction_count();
+        let mut transactions = vec![None; count];
+
+        let mut next = 0;
+        for prefilled in &compact.prefilled {
+            if prefilled.index < next || prefilled.index >= count {
+                return Err(CompactBlockError::InvalidIndex(prefilled.index));
+            }
+            transactions[prefilled.index] = Some(prefilled.tx.clone());
+            next = prefilled.index + 1;
+        }
+
+        let mut slots = HashMap::new();
+        let empty = transactions
+            .iter()
+            .enumerate()
+            .filter(|(_, tx)| tx.is_none());
+        for (short_id, (index, _)) in compact.short_ids.iter().zip(empty) {
+            if slots.insert(*short_id, index).is_some() {
+                return Err(CompactBlockError::ShortIdCollision);
+            }
+        }
+
+        let keys = compact.keys();
+        let mut ambiguous = HashSet::new();
+        for tx in mempool.transactions() {
+            let Some(&index) = slots.get(&short_id(keys, &tx.hash)) else {
+                continue;
+            };
+            if ambiguous.contains(&index) {
+                continue;
+            }
+            if transactions[index].is_some() {
+                transactions[index] = None;
+                ambiguous.insert(index);
+            } else {
+                transactions[index] = Some(tx.clone());
+            }
+        }
+
+        Ok(PartialBlock {
+            header: compact.header.clone(),
+            hash: compact.block_hash(),
+            transactions,
+        })
+    }
+
+    pub fn block_hash(&self) -> U256 {
+        self.hash
+    }
+
+    pub fn header(&self) -> &BlockHeader {
+        &self.header
+    }
+
+    /// Indexes of the transactions still missing.
+    pub fn missing(&self) -> Vec<usize> {
+        self.transactions
+            .iter()
+            .enumerate()
+            .filter(|(_, tx)| tx.is_none())
+            .map(|(index, _)| index)
+            .collect()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.transactions.iter().all(Option::is_some)
+    }
+
+    pub fn request(&self) -> BlockTransactionsRequest {
+        BlockTransactionsRequest {
+            block_hash: self.hash,
+            indexes: self.missing(),
+        }
+    }
+
+    /// Fills the missing transactions with the answer to `request()`.
+    pub fn fill(&mut self, response: BlockTransactions) -> Result<(), CompactBlockError> {
+        if response.block_hash != self.hash {
+            return Err(CompactBlockError::WrongBlock(response.block_hash));
+        }
+
+        let missing = self.missing();
+        if response.transactions.len() != missing.len() {
+            return Err(CompactBlockError::WrongTransactionCount {
+                expected: missing.len(),
+                found: response.transactions.len(),
+            });
+        }
+
+        for (index, tx) in missing.into_iter().zip(response.transactions) {
+            self.transactions[index] = Some(tx);
+        }
+
+        Ok(())
+    }
+
+    /// The rebuilt block, one above its parent in `index`.
+    pub fn into_block(self, index: &BlockIndex) -> Result<Block, CompactBlockError> {
+        let missing = self.transactions.iter().filter(|tx| tx.is_none()).count();
+        if missing > 0 {
+            return Err(CompactBlockError::MissingTransactions(missing));
+        }
+        let parent = index
+            .get(&self.header.prev)
+            .ok_or(CompactBlockError::UnknownParent(self.header.prev))?;
+
+        let transactions = self.transactions.into_iter().flatten().collect();
+        let block = Block::new(parent.height + 1, self.hash, self.header, transactions);
+        if block.compute_merkle_root() != block.header.merkle_root {
+            return Err(CompactBlockError::BadMerkleRoot);
+        }
+
+        Ok(block)
+    }
+}
+
+/// Most compact blocks a single peer may have waiting for transactions.
+pub const MAX_PENDING_BLOCKS_PER_PEER: usize = 2;
+/// Most compact blocks waiting for transactions from all peers together.
+pub const MAX_PENDING_BLOCKS: usize = 16;
+
+/// Compact blocks waiting for their missing transactions from the peer that
+/// sent them, along with whatever the node reports back once they're
+/// rebuilt. Peers only get a few slots each, so they can't fill memory with
+/// blocks they never complete.
+#[derive(Debug)]
+pub struct PendingBlocks<P, T> {
+    blocks: HashMap<U256, (PartialBlock, P, T)>,
+}
+
+impl<P: PartialEq, T> Default for PendingBlocks<P, T> {
+    fn default() -> Self {
+        PendingBlocks {
+            blocks: HashMap::new(),
+        }
+    }
+}
+
+impl<P: PartialEq, T> PendingBlocks<P, T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    /// Stores `partial` until `peer` sends its transactions. Returns false
+    /// if the block is already waiting or there is no room left for it.
+    pub fn insert(&mut self, partial: PartialBlock, peer: P, data: T) -> bool {
+        let from_peer = self.blocks.values().filter(|(_, p, _)| *p == peer).count();
+        if self.blocks.len() >= MAX_PENDING_BLOCKS
+            || from_peer >= MAX_PENDING_BLOCKS_PER_PEER
+            || self.blocks.contains_key(&partial.hash)
+        {
+            return false;
+        }
+        self.blocks.insert(partial.hash, (partial, peer, data));
+        true
+    }
+
+    /// Takes the block `hash` if `peer` is the one it's waiting on.
+    pub fn take(&mut self, peer: &P, hash: &U256) -> Option<(PartialBlock, T)> {
+        match self.blocks.get(hash) {
+            Some((_, p, _)) if p == peer => {}
+            _ => return None,
+        }
+        self.blocks
+            .remove(hash)
+            .map(|(partial, _, data)| (partial, data))
+    }
+
+    /// Drops the blocks waiting on `peer`, e.g. after it failed a request.
+    pub fn remove_peer(&mut self, peer: &P) {
+        self.blocks.retain(|_, (_, p, _)| p != peer);
+    }
+}
+
+// Indexes are sent as the gap to the previous one, as in BIP152.
+fn decode_index(input: &mut &[u8], next: &mut usize) -> Result<usize, DecodeError> {
+    let index = usize::try_from(decode_varint(input)?)
+        .ok()
+        .and_then(|gap| gap.checked_add(*next))
+        .ok_or(DecodeError::InvalidValue("index"))?;
+    *next = index + 1;
+    Ok(index)
+}
+
+impl Encode for CompactBlock {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.header.encode(out);
+        self.nonce.encode(out);
+
+        encode_varint(self.short_ids.len() as u64, out);
+        for short_id in &self.short_ids {
+            out.extend_from_slice(&short_id.to_le_bytes()[..SHORT_ID_SIZE]);
+        }
+
+        encode_varint(self.prefilled.len() as u64, out);
+        let mut next = 0;
+        for prefilled in &self.prefilled {
+            encode_varint((prefilled.index - next) as u64, out);
+            prefilled.tx.encode(out);
+            next = prefilled.index + 1;
+        }
+    }
+}
+
+impl Decode for CompactBlock {
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        let header = BlockHeader::decode(input)?;
+        let nonce = u64::decode(input)?;
+
+        let len = decode_length(input)?;
+        let bytes = take(input, len.saturating_mul(SHORT_ID_SIZE))?;
+        let short_ids = bytes
+            .chunks_exact(SHORT_ID_SIZE)
+            .map(|chunk| {
+                let mut bytes = [0u8; 8];
+                bytes[..SHORT_ID_SIZE].copy_from_slice(chunk);
+                u64::from_le_bytes(bytes)
+            })
+            .collect();
+
+        let len = decode_length(input)?;
+        if len > input.len() {
+            return Err(DecodeError::UnexpectedEof);
+        }
+        let mut prefilled = Vec::with_capacity(len);
+        let mut next = 0;
+        for _ in 0..len {
+            prefilled.push(PrefilledTransaction {
+                index: decode_index(input, &mut next)?,
+                tx: Transaction::decode(input)?,
+            });
+        }
+
+        Ok(CompactBlock {
+            header,
+            nonce,
+            short_ids,
+            prefilled,
+        })
+    }
+}
+
+impl Encode for BlockTransactionsRequest {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.block_hash.encode(out);
+        encode_varint(self.indexes.len() as u64, out);
+        let mut next = 0;
+        for &index in &self.indexes {
+            encode_varint((index - next) as u64, out);
+            next = index + 1;
+        }
+    }
+}
+
+impl Decode for BlockTransactionsRequest {
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        let block_hash = U256::decode(input)?;
+        let len = decode_length(input)?;
+        if len > input.len() {
+            return Err(DecodeError::UnexpectedEof);
+        }
+
+        let mut next = 0;
+        let indexes = (0..len)
+            .map(|_| decode_index(input, &mut next))
+            .collect::<Result<_, _>>()?;
+
+        Ok(BlockTransactionsRequest {
+            block_hash,
+            indexes,
+        })
+    }
+}
+
+impl Encode for BlockTransactions {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.block_hash.encode(out);
+        encode_list(&self.transactions, out);
+    }
+}
+
+impl Decode for BlockTransactions {
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        Ok(BlockTransactions {
+            block_hash: U256::decode(input)?,
+            transactions: decode_list(input)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::{mature_blockchain, mine_block};
+    use crate::{
+        BlockTemplateBuilder, MempoolPolicy, OutPoint, TinyBlockchain, UtxoInput, UtxoOutput, COIN,
+    };
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    const FEE: u64 = 10_000;
+
+    fn keypair() -> Ed25519KeyPair {
+        Ed25519KeyPair::from_seed_unchecked(&[5u8; 32]).unwrap()
+    }
+
+    /// A mature chain whose genesis pays `outputs` coins to the test key.
+    fn blockchain(outputs: usize) -> TinyBlockchain {
+        mature_blockchain(
+            (0..outputs)
+                .map(|_| UtxoOutput::to_pub_key(keypair().public_key().as_ref(), COIN))
+                .collect(),
+        )
+    }
+
+    fn mine(blockchain: &TinyBlockchain, mempool: &Mempool) -> Block {
+        let mut builder = BlockTemplateBuilder::new(U256::ONE);
+        builder.add_candidates(mempool.candidates());
+        mine_block(blockchain, builder)
+    }
+
+    /// A mempool spending each genesis output.
+    fn mempool(blockchain: &TinyBlockchain) -> Mempool {
+        let genesis = &blockchain.chain().items[0].transactions[0];
+        let pub_key = keypair().public_key().as_ref().try_into().unwrap();
+        let mut mempool = Mempool::new(MempoolPolicy::default());
+
+        for vout in 0..genesis.outputs.len() {
+            let mut tx = Transaction::new(
+                vec![UtxoInput::spend(
+                    OutPoint::new(genesis.hash, vout as u32),
+                    pub_key,
+                )],
+                vec![UtxoOutput::new(U256::ONE, COIN - FEE)],
+            );
+            tx.sign(&keypair());
+            let height = blockchain.tip().height + 1;
+            mempool.add(tx, blockchain.utxo(), height).unwrap();
+        }
+        mempool
+    }
+
+    #[test]
+    fn test_compact_block_encoding() {
+        let blockchain = blockchain(3);
+        let block = mine(&blockchain, &mempool(&blockchain));
+        let compact = CompactBlock::new(&block, 42);
+        assert_eq!(compact.short_ids.len(), 3);
+        assert_eq!(compact.prefilled[0].index, 0);
+        assert_eq!(CompactBlock::from_bytes(&compact.to_bytes()), Ok(compact));
+
+        let request = BlockTransactionsRequest {
+            block_hash: block.header_hash,
+            indexes: vec![1, 3],
+        };
+        assert_eq!(
+            BlockTransactionsRequest::from_bytes(&request.to_bytes()),
+            Ok(request.clone())
+        );
+        let response = block_transactions(&block, &request).unwrap();
+        assert_eq!(
+            response.transactions,
+            vec![block.transactions[1].clone(), block.transactions[3].clone()]
+        );
+        assert_eq!(
+            BlockTransactions::from_bytes(&response.to_bytes()),
+            Ok(response)
+        );
+
+        let request = BlockTransactionsRequest {
+            block_hash: block.header_hash,
+            indexes: vec![4],
+        };
+        assert_eq!(
+            block_transactions(&block, &request),
+            Err(CompactBlockError::InvalidIndex(4))
+        );
+    }
+
+    #[test]
+    fn test_reconstructs_from_mempool() {
+        let blockchain = blockchain(50);
+        let mempool = mempool(&blockchain);
+        let block = mine(&blockchain, &mempool);
+        let compact = CompactBlock::new(&block, 7);
+
+        let partial = PartialBlock::new(&compact, &mempool).unwrap();
+        assert!(partial.is_complete());
+        assert_eq!(partial.into_block(blockchain.index()), Ok(block.clone()));
+
+        // Only the header, the coinbase and 6 bytes per transaction go over
+        // the wire instead of the whole block.
+        let full = block.to_bytes().len();
+        let compact = compact.to_bytes().len();
+        assert!(compact * 10 < full, "{} vs {} bytes", compact, full);
+    }
+
+    #[test]
+    fn test_fetches_missing_transactions() {
+        let blockchain = blockchain(50);
+        let mut mempool = mempool(&blockchain);
+        let block = mine(&blockchain, &mempool);
+        let compact = CompactBlock::new(&block, 7);
+
+        // The peer never saw two of the transactions.
+        for tx in &block.transactions[10..12] {
+            mempool.remove(&tx.hash);
+        }
+        let mut partial = PartialBlock::new(&compact, &mempool).unwrap();
+        assert_eq!(partial.missing(), vec![10, 11]);
+        assert_eq!(
+            partial.clone().into_block(blockchain.index()),
+            Err(CompactBlockError::MissingTransactions(2))
+        );
+
+        let request = partial.request();
+        let response = block_transactions(&block, &request).unwrap();
+        let round_trip =
+            compact.to_bytes().len() + request.to_bytes().len() + response.to_bytes().len();
+        assert!(round_trip * 5 < block.to_bytes().len());
+
+        let mut wrong = response.clone();
+        wrong.transactions.pop();
+        assert_eq!(
+            partial.clone().fill(wrong),
+            Err(CompactBlockError::WrongTransactionCount {
+                expected: 2,
+                found: 1
+            })
+        );
+        let mut swapped = response.clone();
+        swapped.transactions.swap(0, 1);
+        let mut bad = partial.clone();
+        bad.fill(swapped).unwrap();
+        assert_eq!(
+            bad.into_block(blockchain.index()),
+            Err(CompactBlockError::BadMerkleRoot)
+        );
+
+        // The height comes from the parent, which has to be known.
+        partial.fill(response).unwrap();
+        assert_eq!(
+            partial.clone().into_block(&BlockIndex::new(block.clone())),
+            Err(CompactBlockError::UnknownParent(block.header.prev))
+        );
+        assert_eq!(partial.into_block(blockchain.index()), Ok(block));
+    }
+
+    #[test]
+    fn test_caps_pending_blocks() {
+        let blockchain = blockchain(3);
+        let mempool = Mempool::default();
+        let partial = |nonce| {
+            let mut block = mine(&blockchain, &mempool);
+            block.header.nonce = nonce;
+            block.header_hash = block.header.hash();
+            PartialBlock::new(&CompactBlock::new(&block, 1), &mempool).unwrap()
+        };
+
+        let mut pending = PendingBlocks::new();
+        assert!(pending.insert(partial(0), 1, ()));
+        assert!(!pending.insert(partial(0), 2, ()));
+        for nonce in 1..MAX_PENDING_BLOCKS_PER_PEER as u32 {
+            assert!(pending.insert(partial(nonce), 1, ()));
+        }
+        assert!(!pending.insert(partial(100), 1, ()));
+
+        for peer in 2.. {
+            if !pending.insert(partial(100 + peer), peer, ()) {
+                break;
+            }
+        }
+        assert_eq!(pending.len(), MAX_PENDING_BLOCKS);
+
+        // Only the peer asked may complete a block, and a failed peer frees
+        // its slots.
+        let hash = partial(0).block_hash();
+        assert!(pending.take(&2, &hash).is_none());
+        assert!(pending.take(&1, &hash).is_some());
+        pending.remove_peer(&1);
+        assert_eq!(
+            pending.len(),
+            MAX_PENDING_BLOCKS - MAX_PENDING_BLOCKS_PER_PEER
+        );
+        assert!(pending.insert(partial(0), 1, ()));
+    }
+
+    #[test]
+    fn test_rejects_malformed_compact_blocks() {
+        let blockchain = blockchain(3);
+        let mempool = mempool(&blockchain);
+        let block = mine(&blockchain, &mempool);
+
+        let mut compact = CompactBlock::new(&block, 1);
+        compact.short_ids[1] = compact.short_ids[0];
+        assert_eq!(
+            PartialBlock::new(&compact, &mempool).unwrap_err(),
+            CompactBlockError::ShortIdCollision
+        );
+
+        let mut compact = CompactBlock::new(&block, 1);
+        compact.prefilled[0].index = 4;
+        assert_eq!(
+            PartialBlock::new(&compact, &mempool).unwrap_err(),
+            CompactBlockError::InvalidIndex(4)
+        );
+
+        // The keys change with the nonce, and so do the short IDs.
+        assert_ne!(
+            CompactBlock::new(&block, 1).short_ids,
+            CompactBlock::new(&block, 2).short_ids
+        );
+    }
+}
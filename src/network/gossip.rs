@@ -0,0 +1,426 @@
+use super::compact::{CompactBlock, CompactBlockError, PartialBlock};
+use crate::{
+    hash_to_u256, Block, Clock, ConsensusEngine, Decode, DecodeError, Encode, Mempool,
+    MempoolError, TinyBlockchain, TinyBlockchainError, TipChanged, Transaction, UtxoError,
+    ValidationError, MAX_BLOCK_SIZE,
+};
+use libp2p::gossipsub;
+use std::sync::mpsc;
+
+/// Topic new blocks are announced on.
+pub const BLOCK_TOPIC: &str = "/tiny-blockchain/blocks/1";
+/// Topic new blocks are announced on as compact blocks.
+pub const COMPACT_BLOCK_TOPIC: &str = "/tiny-blockchain/compact-blocks/1";
+/// Topic unconfirmed transactions are relayed on.
+pub const TRANSACTION_TOPIC: &str = "/tiny-blockchain/transactions/1";
+
+// Room for the gossipsub framing around the biggest block.
+const MAX_MESSAGE_OVERHEAD: usize = 1024;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum GossipError {
+    UnknownTopic(String),
+    Decode(DecodeError),
+}
+
+impl std::fmt::Display for GossipError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GossipError::UnknownTopic(topic) => write!(f, "unknown gossip topic {}", topic),
+            GossipError::Decode(err) => write!(f, "malformed gossip message: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for GossipError {}
+
+impl From<DecodeError> for GossipError {
+    fn from(err: DecodeError) -> Self {
+        GossipError::Decode(err)
+    }
+}
+
+/// A block or transaction published on its gossip topic. The payload is the
+/// canonical encoding, the topic tells which one it is.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum GossipMessage {
+    Block(Block),
+    CompactBlock(CompactBlock),
+    Transaction(Transaction),
+}
+
+impl GossipMessage {
+    pub fn topic(&self) -> &'static str {
+        match self {
+            GossipMessage::Block(_) => BLOCK_TOPIC,
+            GossipMessage::CompactBlock(_) => COMPACT_BLOCK_TOPIC,
+            GossipMessage::Transaction(_) => TRANSACTION_TOPIC,
+        }
+    }
+
+    pub fn data(&self) -> Vec<u8> {
+        match self {
+            GossipMessage::Block(block) => block.to_bytes(),
+            GossipMessage::CompactBlock(compact) => compact.to_bytes(),
+            GossipMessage::Transaction(tx) => tx.to_bytes(),
+        }
+    }
+
+    pub fn decode(topic: &str, data: &[u8]) -> Result<Self, GossipError> {
+        match topic {
+            BLOCK_TOPIC => Ok(GossipMessage::Block(Block::from_bytes(data)?)),
+            COMPACT_BLOCK_TOPIC => Ok(GossipMessage::CompactBlock(CompactBlock::from_bytes(data)?)),
+            TRANSACTION_TOPIC => Ok(GossipMessage::Transaction(Transaction::from_bytes(data)?)),
+            _ => Err(GossipError::UnknownTopic(topic.to_string())),
+        }
+    }
+}
+
+/// Messages are identified by their content, so the same block published by
+/// two miners is only relayed once.
+pub fn gossip_message_id(message: &gossipsub::Message) -> gossipsub::MessageId {
+    let mut bytes = message.topic.as_str().as_bytes().to_vec();
+    bytes.extend_from_slice(&message.data);
+    gossipsub::MessageId::from(hash_to_u256!(&bytes).to_be_bytes().to_vec())
+}
+
+/// Gossipsub settings for the blockchain topics. Messages are only forwarded
+/// once the node has validated them and reported an `Acceptance`.
+pub fn gossipsub_config() -> gossipsub::Config {
+    gossipsub::ConfigBuilder::default()
+        .validation_mode(gossipsub::ValidationMode::Strict)
+        .validate_messages()
+        .message_id_fn(gossip_message_id)
+        .max_transmit_size(MAX_BLOCK_SIZE + MAX_MESSAGE_OVERHEAD)
+        .build()
+        .expect("Gossipsub config to be valid")
+}
+
+/// The verdict on a gossiped message, reported back to gossipsub.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Acceptance {
+    /// Valid, deliver and forward it.
+    Accept,
+    /// Not worth forwarding, but not the sender's fault either.
+    Ignore,
+    /// Invalid, the sender is penalized.
+    Reject,
+}
+
+impl From<Acceptance> for gossipsub::MessageAcceptance {
+    fn from(acceptance: Acceptance) -> Self {
+        match acceptance {
+            Acceptance::Accept => gossipsub::MessageAcceptance::Accept,
+            Acceptance::Ignore => gossipsub::MessageAcceptance::Ignore,
+            Acceptance::Reject => gossipsub::MessageAcceptance::Reject,
+        }
+    }
+}
+
+/// Validates gossiped blocks and transactions against the local chain and
+/// mempool before they are forwarded, keeping the mempool in step with the
+/// active chain.
+#[derive(Debug)]
+pub struct GossipValidator {
+    tip_changes: mpsc::Receiver<TipChanged>,
+}
+
+impl GossipValidator {
+    pub fn new<E: ConsensusEngine, C: Clock>(blockchain: &mut TinyBlockchain<E, C>) -> Self {
+        GossipValidator {
+            tip_changes: blockchain.subscribe(),
+        }
+    }
+
+    /// Adds the block or transaction of `message` and tells whether it
+    /// should be relayed. Compact blocks are rebuilt from the mempool, those
+    /// missing transactions are ignored here: the node fetches them with a
+    /// `PartialBlock` and validates the full block.
+    pub fn validate<E: ConsensusEngine, C: Clock>(
+        &self,
+        message: GossipMessage,
+        blockchain: &mut TinyBlockchain<E, C>,
+        mempool: &mut Mempool,
+    ) -> Acceptance {
+        match message {
+            GossipMessage::Block(mut block) => {
+                // The height isn't covered by the header hash, so the one on
+                // the wire is only a hint. The parent tells the real one.
+                if let Some(parent) = blockchain.index().get(&block.header.prev) {
+                    block.height = parent.height + 1;
+                }
+                let result = blockchain.add_block(block);
+                self.update_mempool(blockchain, mempool);
+                block_acceptance(&result)
+            }
+            GossipMessage::CompactBlock(compact) => {
+                let block = PartialBlock::new(&compact, mempool)
+                    .and_then(|partial| partial.into_block(blockchain.index()));
+                match block {
+                    Ok(block) => self.validate(GossipMessage::Block(block), blockchain, mempool),
+                    Err(CompactBlockError::InvalidIndex(_)) => Acceptance::Reject,
+                    Err(_) => Acceptance::Ignore,
+                }
+            }
+            GossipMessage::Transaction(tx) => {
+                let height = blockchain.tip().height + 1;
+                transaction_acceptance(&mempool.add(tx, blockchain.utxo(), height))
+            }
+        }
+    }
+
+    /// Applies the tip changes since the last call to `mempool`. Blocks the
+    /// node mines itself go through here too.
+    pub fn update_mempool<E: ConsensusEngine, C: Clock>(
+        &self,
+        blockchain: &TinyBlockchain<E, C>,
+        mempool: &mut Mempool,
+    ) {
+        let height = blockchain.tip().height + 1;
+        for event in self.tip_changes.try_iter() {
+            mempool.update_for_tip(&event, blockchain.index(), blockchain.utxo(), height);
+        }
+    }
+}
+
+fn block_acceptance(result: &Result<(), TinyBlockchainError>) -> Acceptance {
+    match result {
+        Ok(()) => Acceptance::Accept,
+        // Orphans and blocks from the future may turn out valid later.
+        Err(TinyBlockchainError::UnknownParent(_))
+        | Err(TinyBlockchainError::DuplicateBlock(_))
+        | Err(TinyBlockchainError::InvalidBlock(ValidationError::TimestampTooLate { .. })) => {
+            Acceptance::Ignore
+        }
+        Err(TinyBlockchainError::KnownInvalid(_)) | Err(TinyBlockchainError::InvalidBlock(_)) => {
+            Acceptance::Reject
+        }
+    }
+}
+
+fn transaction_acceptance(result: &Result<(), MempoolError>) -> Acceptance {
+    match result {
+        Ok(()) => Acceptance::Accept,
+        // The sender may know a different chain or mempool than ours.
+        Err(MempoolError::Invalid(ValidationError::Utxo(UtxoError::MissingInput(_))))
+        | Err(MempoolError::Invalid(ValidationError::PrematureCoinbaseSpend { .. })) => {
+            Acceptance::Ignore
+        }
+        Err(MempoolError::Invalid(_)) | Err(MempoolError::Coinbase(_)) => Acceptance::Reject,
+        // Breaking our policy isn't breaking consensus.
+        Err(_) => Acceptance::Ignore,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::{mature_blockchain, mine_block};
+    use crate::{BlockTemplateBuilder, Hash, OutPoint, UtxoInput, UtxoOutput, COIN};
+    use ethnum::U256;
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    fn keypair() -> Ed25519KeyPair {
+        Ed25519KeyPair::from_seed_unchecked(&[9u8; 32]).unwrap()
+    }
+
+    /// A chain whose genesis pays one coin to the test key, already mature.
+    fn blockchain() -> TinyBlockchain {
+        mature_blockchain(vec![UtxoOutput::to_pub_key(
+            keypair().public_key().as_ref(),
+            COIN,
+        )])
+    }
+
+    fn next_block(blockchain: &TinyBlockchain, txs: &[Transaction]) -> Block {
+        let mut builder = BlockTemplateBuilder::new(U256::ONE);
+        for tx in txs {
+            builder.add_candidate(crate::TemplateCandidate::new(tx.clone(), 10_000));
+        }
+        mine_block(blockchain, builder)
+    }
+
+    fn payment(blockchain: &TinyBlockchain) -> Transaction {
+        let genesis = &blockchain.chain().items[0];
+        let pub_key = keypair().public_key().as_ref().try_into().unwrap();
+        let mut tx = Transaction::new(
+            vec![UtxoInput::spend(
+                OutPoint::new(genesis.transactions[0].hash, 0),
+                pub_key,
+            )],
+            vec![UtxoOutput::new(U256::ONE, COIN - 10_000)],
+        );
+        tx.sign(&keypair());
+        tx
+    }
+
+    #[test]
+    fn test_gossip_message_encoding() {
+        let tx = Transaction::coinbase(1, 0, vec![UtxoOutput::new(U256::ONE, 50)]);
+        let message = GossipMessage::Transaction(tx);
+        assert_eq!(
+            GossipMessage::decode(message.topic(), &message.data()),
+            Ok(message.clone())
+        );
+
+        assert_eq!(
+            GossipMessage::decode("/other/1", &message.data()),
+            Err(GossipError::UnknownTopic("/other/1".to_string()))
+        );
+        assert!(matches!(
+            GossipMessage::decode(BLOCK_TOPIC, &message.data()),
+            Err(GossipError::Decode(_))
+        ));
+
+        let gossiped = |data: Vec<u8>| gossipsub::Message {
+            source: None,
+            data,
+            sequence_number: None,
+            topic: gossipsub::IdentTopic::new(TRANSACTION_TOPIC).hash(),
+        };
+        assert_eq!(
+            gossip_message_id(&gossiped(message.data())),
+            gossip_message_id(&gossiped(message.data()))
+        );
+        assert_ne!(
+            gossip_message_id(&gossiped(message.data())),
+            gossip_message_id(&gossiped(vec![]))
+        );
+        assert!(gossipsub_config().max_transmit_size() > MAX_BLOCK_SIZE);
+    }
+
+    #[test]
+    fn test_validates_gossip() {
+        let mut blockchain = blockchain();
+        let mut mempool = Mempool::default();
+        let validator = GossipValidator::new(&mut blockchain);
+
+        let tx = payment(&blockchain);
+        let message = GossipMessage::Transaction(tx.clone());
+        assert_eq!(
+            validator.validate(message.clone(), &mut blockchain, &mut mempool),
+            Acceptance::Accept
+        );
+        assert_eq!(
+            validator.validate(message, &mut blockchain, &mut mempool),
+            Acceptance::Ignore
+        );
+
+        let mut forged = tx.clone();
+        forged.outputs[0] = UtxoOutput::new(U256::from(2u32), COIN - 10_000);
+        forged.hash = forged.hash();
+        assert_eq!(
+            validator.validate(
+                GossipMessage::Transaction(forged),
+                &mut blockchain,
+                &mut mempool
+            ),
+            Acceptance::Reject
+        );
+
+        // A block mining the transaction clears it from the mempool.
+        let block = next_block(&blockchain, &[tx]);
+        let mut tampered = block.clone();
+        tampered.transactions.pop();
+        assert_eq!(
+            validator.validate(
+                GossipMessage::Block(tampered),
+                &mut blockchain,
+                &mut mempool
+            ),
+            Acceptance::Reject
+        );
+
+        assert_eq!(
+            validator.validate(
+                GossipMessage::Block(block.clone()),
+                &mut blockchain,
+                &mut mempool
+            ),
+            Acceptance::Accept
+        );
+        assert_eq!(blockchain.tip().header_hash, block.header_hash);
+        assert!(mempool.is_empty());
+
+        assert_eq!(
+            validator.validate(GossipMessage::Block(block), &mut blockchain, &mut mempool),
+            Acceptance::Ignore
+        );
+    }
+
+    #[test]
+    fn test_ignores_relayed_height() {
+        let mut blockchain = blockchain();
+        let mut mempool = Mempool::default();
+        let validator = GossipValidator::new(&mut blockchain);
+
+        let block = next_block(&blockchain, &[]);
+        let mut changed = Block::from_bytes(&block.to_bytes()).unwrap();
+        changed.height = 7;
+        assert_eq!(
+            validator.validate(GossipMessage::Block(changed), &mut blockchain, &mut mempool),
+            Acceptance::Accept
+        );
+        assert_eq!(blockchain.tip().header_hash, block.header_hash);
+        assert_eq!(blockchain.tip().height, block.height);
+    }
+
+    #[test]
+    fn test_validates_compact_blocks() {
+        let mut blockchain = blockchain();
+        let mut mempool = Mempool::default();
+        let validator = GossipValidator::new(&mut blockchain);
+
+        let tx = payment(&blockchain);
+        let block = next_block(&blockchain, std::slice::from_ref(&tx));
+        let message = GossipMessage::CompactBlock(CompactBlock::new(&block, 3));
+        assert_eq!(
+            GossipMessage::decode(message.topic(), &message.data()),
+            Ok(message.clone())
+        );
+
+        // The transaction isn't known yet, the block can't be rebuilt.
+        assert_eq!(
+            validator.validate(message.clone(), &mut blockchain, &mut mempool),
+            Acceptance::Ignore
+        );
+
+        let height = blockchain.tip().height + 1;
+        mempool.add(tx, blockchain.utxo(), height).unwrap();
+        assert_eq!(
+            validator.validate(message, &mut blockchain, &mut mempool),
+            Acceptance::Accept
+        );
+        assert_eq!(blockchain.tip().header_hash, block.header_hash);
+        assert!(mempool.is_empty());
+    }
+
+    #[test]
+    fn test_acceptance_of_errors() {
+        let txid = U256::ONE;
+        assert_eq!(
+            block_acceptance(&Err(TinyBlockchainError::UnknownParent(txid))),
+            Acceptance::Ignore
+        );
+        assert_eq!(
+            block_acceptance(&Err(TinyBlockchainError::KnownInvalid(txid))),
+            Acceptance::Reject
+        );
+        assert_eq!(
+            transaction_acceptance(&Err(MempoolError::FeeTooLow {
+                txid,
+                fee: 0,
+                min: 1
+            })),
+            Acceptance::Ignore
+        );
+        assert_eq!(
+            transaction_acceptance(&Err(MempoolError::Invalid(ValidationError::NoInputs(txid)))),
+            Acceptance::Reject
+        );
+        assert_eq!(
+            transaction_acceptance(&Err(UtxoError::MissingInput(OutPoint::new(txid, 0)).into())),
+            Acceptance::Ignore
+        );
+    }
+}
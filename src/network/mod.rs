@@ -1 +1,5 @@
+pub mod compact;
 pub mod discovery;
+pub mod gossip;
+pub mod relay;
+pub mod sync;
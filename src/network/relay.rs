@@ -0,0 +1,950 @@
+use crate::{
+    decode_list, encode_list, Clock, ConsensusEngine, Decode, DecodeError, Encode, Mempool,
+    MempoolError, TinyBlockchain, TipChanged, Transaction, UtxoError, ValidationError,
+};
+use ethnum::U256;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+/// Most txids announced or asked in one message.
+pub const MAX_INVENTORY: usize = 1000;
+/// Seconds a peer may take to answer a `GetData` before the transactions are
+/// asked from another peer that announced them.
+pub const GET_DATA_TIMEOUT: u64 = 60;
+/// Most transactions kept waiting for their parents.
+pub const MAX_ORPHANS: usize = 100;
+/// Encoded size above which a transaction isn't kept as an orphan, so the
+/// pool can't take more than `MAX_ORPHANS * MAX_ORPHAN_SIZE` bytes.
+pub const MAX_ORPHAN_SIZE: usize = 100_000;
+/// Seconds an orphan waits for its parents.
+pub const ORPHAN_EXPIRY: u64 = 20 * 60;
+/// Most txids announced by a peer waiting to be asked for, later
+/// announcements are dropped until it delivers.
+pub const MAX_PEER_TX_ANNOUNCEMENTS: usize = 5000;
+/// Most txids remembered as known to a peer, the oldest are forgotten first.
+pub const MAX_PEER_KNOWN_TXIDS: usize = 50_000;
+/// Most txids remembered as rejected between tip changes, the oldest are
+/// forgotten first.
+pub const MAX_REJECTED_TXIDS: usize = 50_000;
+
+const INVENTORY: u8 = 0;
+const GET_DATA: u8 = 1;
+const ESTIMATE_FEE: u8 = 2;
+const ACK: u8 = 0;
+const TRANSACTIONS: u8 = 1;
+const FEE_RATE: u8 = 2;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RelayRequest {
+    /// Txids of transactions the sender accepted to its mempool.
+    Inventory(Vec<U256>),
+    /// The transactions with the given txids.
+    GetData(Vec<U256>),
+    /// The fee rate the peer expects to confirm a transaction within
+    /// `target` blocks, so a wallet can pick its fee.
+    EstimateFee { target: u32 },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RelayResponse {
+    /// Answers an `Inventory`.
+    Ack,
+    /// The requested transactions that were found, in request order.
+    Transactions(Vec<Transaction>),
+    /// Answers an `EstimateFee` with a fee rate per 1000 bytes, `None` while
+    /// the peer doesn't have enough data.
+    FeeRate(Option<u64>),
+}
+
+impl Encode for RelayRequest {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            RelayRequest::Inventory(txids) => {
+                out.push(INVENTORY);
+                encode_list(txids, out);
+            }
+            RelayRequest::GetData(txids) => {
+                out.push(GET_DATA);
+                encode_list(txids, out);
+            }
+            RelayRequest::EstimateFee { target } => {
+                out.push(ESTIMATE_FEE);
+                target.encode(out);
+            }
+        }
+    }
+}
+
+impl Decode for RelayRequest {
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        match u8::decode(input)? {
+            INVENTORY => Ok(RelayRequest::Inventory(decode_list(input)?)),
+            GET_DATA => Ok(RelayRequest::GetData(decode_list(input)?)),
+            ESTIMATE_FEE => Ok(RelayRequest::EstimateFee {
+                target: u32::decode(input)?,
+            }),
+            _ => Err(DecodeError::InvalidValue("relay request")),
+        }
+    }
+}
+
+impl Encode for RelayResponse {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            RelayResponse::Ack => out.push(ACK),
+            RelayResponse::Transactions(transactions) => {
+                out.push(TRANSACTIONS);
+                encode_list(transactions, out);
+            }
+            RelayResponse::FeeRate(fee_rate) => {
+                out.push(FEE_RATE);
+                match fee_rate {
+                    Some(fee_rate) => {
+                        out.push(1);
+                        fee_rate.encode(out);
+                    }
+                    None => out.push(0),
+                }
+            }
+        }
+    }
+}
+
+impl Decode for RelayResponse {
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        match u8::decode(input)? {
+            ACK => Ok(RelayResponse::Ack),
+            TRANSACTIONS => Ok(RelayResponse::Transactions(decode_list(input)?)),
+            FEE_RATE => match u8::decode(input)? {
+                0 => Ok(RelayResponse::FeeRate(None)),
+                1 => Ok(RelayResponse::FeeRate(Some(u64::decode(input)?))),
+                _ => Err(DecodeError::InvalidValue("fee rate")),
+            },
+            _ => Err(DecodeError::InvalidValue("relay response")),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Orphan<P> {
+    tx: Transaction,
+    /// The peer that sent the transaction.
+    from: P,
+    expires_at: u64,
+}
+
+/// Transactions spending outputs of transactions we don't know yet, indexed
+/// by the txids of their inputs so they can be retried once a parent is
+/// accepted. The pool is bounded by `MAX_ORPHANS`, the orphan closest to
+/// expiry makes room for a new one.
+#[derive(Debug)]
+pub struct OrphanPool<P> {
+    orphans: HashMap<U256, Orphan<P>>,
+    /// Txids of the orphans spending outputs of a transaction.
+    by_parent: HashMap<U256, HashSet<U256>>,
+}
+
+impl<P: Clone + PartialEq> Default for OrphanPool<P> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<P: Clone + PartialEq> OrphanPool<P> {
+    pub fn new() -> Self {
+        OrphanPool {
+            orphans: HashMap::new(),
+            by_parent: HashMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.orphans.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.orphans.is_empty()
+    }
+
+    pub fn contains(&self, txid: &U256) -> bool {
+        self.orphans.contains_key(txid)
+    }
+
+    /// Keeps `tx` sent by `from` until `ORPHAN_EXPIRY` seconds after `now`.
+    /// Returns false if it is already in the pool or too big.
+    pub fn add(&mut self, tx: Transaction, from: P, now: u64) -> bool {
+        if self.orphans.contains_key(&tx.hash) || tx.to_bytes().len() > MAX_ORPHAN_SIZE {
+            return false;
+        }
+
+        if self.orphans.len() >= MAX_ORPHANS {
+            let oldest = self
+                .orphans
+                .iter()
+                .min_by_key(|(txid, orphan)| (orphan.expires_at, **txid))
+                .map(|(txid, _)| *txid);
+            if let Some(oldest) = oldest {
+                self.remove(&oldest);
+            }
+        }
+
+        let txid = tx.hash;
+        for outpoint in tx.inputs.iter().filter_map(|input| input.prev_output()) {
+            self.by_parent
+                .entry(outpoint.txid)
+                .or_default()
+                .insert(txid);
+        }
+        let orphan = Orphan {
+            tx,
+            from,
+            expires_at: now + ORPHAN_EXPIRY,
+        };
+        self.orphans.insert(txid, orphan);
+        true
+    }
+
+    pub fn remove(&mut self, txid: &U256) -> Option<Transaction> {
+        let orphan = self.orphans.remove(txid)?;
+        for outpoint in orphan
+            .tx
+            .inputs
+            .iter()
+            .filter_map(|input| input.prev_output())
+        {
+            if let Some(children) = self.by_parent.get_mut(&outpoint.txid) {
+                children.remove(txid);
+                if children.is_empty() {
+                    self.by_parent.remove(&outpoint.txid);
+                }
+            }
+        }
+        Some(orphan.tx)
+    }
+
+    /// Removes and returns the orphans spending outputs of `parent`, with
+    /// the peers they came from, in txid order.
+    pub fn take_children(&mut self, parent: &U256) -> Vec<(Transaction, P)> {
+        let mut children: Vec<U256> = self
+            .by_parent
+            .get(parent)
+            .map(|children| children.iter().copied().collect())
+            .unwrap_or_default();
+        children.sort();
+
+        children
+            .into_iter()
+            .filter_map(|txid| {
+                let from = self.orphans.get(&txid)?.from.clone();
+                Some((self.remove(&txid)?, from))
+            })
+            .collect()
+    }
+
+    /// Removes the orphans sent by `peer`.
+    pub fn remove_from(&mut self, peer: &P) {
+        let txids: Vec<U256> = self
+            .orphans
+            .iter()
+            .filter(|(_, orphan)| orphan.from == *peer)
+            .map(|(txid, _)| *txid)
+            .collect();
+        for txid in txids {
+            self.remove(&txid);
+        }
+    }
+
+    /// Removes the orphans that waited too long, returns how many.
+    pub fn expire(&mut self, now: u64) -> usize {
+        let expired: Vec<U256> = self
+            .orphans
+            .iter()
+            .filter(|(_, orphan)| orphan.expires_at <= now)
+            .map(|(txid, _)| *txid)
+            .collect();
+        for txid in &expired {
+            self.remove(txid);
+        }
+        expired.len()
+    }
+}
+
+/// Txids in the order they were added, looked up and removed by txid.
+#[derive(Debug, Default)]
+struct TxidQueue {
+    by_seq: BTreeMap<u64, U256>,
+    seqs: HashMap<U256, u64>,
+    next_seq: u64,
+}
+
+impl TxidQueue {
+    fn len(&self) -> usize {
+        self.seqs.len()
+    }
+
+    fn contains(&self, txid: &U256) -> bool {
+        self.seqs.contains_key(txid)
+    }
+
+    /// Adds `txid` at the back, returns whether it wasn't there yet.
+    fn insert(&mut self, txid: U256) -> bool {
+        if self.seqs.contains_key(&txid) {
+            return false;
+        }
+        self.seqs.insert(txid, self.next_seq);
+        self.by_seq.insert(self.next_seq, txid);
+        self.next_seq += 1;
+        true
+    }
+
+    fn remove(&mut self, txid: &U256) {
+        if let Some(seq) = self.seqs.remove(txid) {
+            self.by_seq.remove(&seq);
+        }
+    }
+
+    fn pop_front(&mut self) -> Option<U256> {
+        let (_, txid) = self.by_seq.pop_first()?;
+        self.seqs.remove(&txid);
+        Some(txid)
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &U256> {
+        self.by_seq.values()
+    }
+
+    fn retain(&mut self, mut keep: impl FnMut(&U256) -> bool) {
+        let seqs = &mut self.seqs;
+        self.by_seq.retain(|_, txid| {
+            let kept = keep(txid);
+            if !kept {
+                seqs.remove(txid);
+            }
+            kept
+        });
+    }
+}
+
+#[derive(Debug, Default)]
+struct PeerState {
+    /// Txids the peer announced or was told about, not announced to it.
+    /// Bounded by `MAX_PEER_KNOWN_TXIDS`, and mined ones are forgotten.
+    known: TxidQueue,
+    /// Txids the peer announced that we may ask it for, in order. Bounded by
+    /// `MAX_PEER_TX_ANNOUNCEMENTS`.
+    announced: TxidQueue,
+    /// Txids to announce to the peer on the next poll.
+    to_announce: Vec<U256>,
+    /// Txids asked in the peer's outstanding `GetData`.
+    requested: Vec<U256>,
+    /// When the outstanding `GetData` was sent.
+    requested_at: Option<u64>,
+}
+
+impl PeerState {
+    /// Notes that the peer knows `txid`, returns whether it didn't yet.
+    fn remember(&mut self, txid: U256) -> bool {
+        let new = self.known.insert(txid);
+        while self.known.len() > MAX_PEER_KNOWN_TXIDS {
+            self.known.pop_front();
+        }
+        new
+    }
+
+    /// Notes that `txid` may be asked from the peer, unless it announced too
+    /// many transactions already.
+    fn add_announced(&mut self, txid: U256) {
+        if self.announced.len() < MAX_PEER_TX_ANNOUNCEMENTS {
+            self.announced.insert(txid);
+        }
+    }
+}
+
+/// Transaction relay by inventory. Peers announce the txids of transactions
+/// they accept, unknown ones are asked with `GetData` from one announcer at
+/// a time, and other announcers are asked if it doesn't deliver within
+/// `GET_DATA_TIMEOUT`. Accepted transactions are announced to every peer
+/// that doesn't know them yet.
+///
+/// Transactions whose parents are unknown wait in an `OrphanPool`, the
+/// parents are asked from the peer that sent them, and the orphans are
+/// retried once a parent is accepted or confirmed.
+///
+/// Like `InitialBlockDownload`, the state machine doesn't do any I/O.
+#[derive(Debug)]
+pub struct TransactionRelay<P> {
+    peers: BTreeMap<P, PeerState>,
+    in_flight: HashSet<U256>,
+    orphans: OrphanPool<P>,
+    /// Transactions refused by the mempool, not asked again until the tip
+    /// changes. Bounded by `MAX_REJECTED_TXIDS`.
+    rejected: TxidQueue,
+}
+
+impl<P: Clone + Ord> Default for TransactionRelay<P> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<P: Clone + Ord> TransactionRelay<P> {
+    pub fn new() -> Self {
+        TransactionRelay {
+            peers: BTreeMap::new(),
+            in_flight: HashSet::new(),
+            orphans: OrphanPool::new(),
+            rejected: TxidQueue::default(),
+        }
+    }
+
+    pub fn orphans(&self) -> &OrphanPool<P> {
+        &self.orphans
+    }
+
+    pub fn add_peer(&mut self, peer: P) {
+        self.peers.entry(peer).or_default();
+    }
+
+    /// Forgets `peer` and its orphans, the transactions it was asked for
+    /// are asked from other announcers.
+    pub fn remove_peer(&mut self, peer: &P) {
+        if let Some(state) = self.peers.remove(peer) {
+            for txid in state.requested {
+                self.in_flight.remove(&txid);
+            }
+        }
+        self.orphans.remove_from(peer);
+    }
+
+    /// Notes the transactions `peer` announced, the unknown ones are asked
+    /// on the next poll.
+    pub fn on_inventory(&mut self, peer: &P, txids: Vec<U256>, mempool: &Mempool) {
+        let Some(state) = self.peers.get_mut(peer) else {
+            return;
+        };
+
+        for txid in txids.into_iter().take(MAX_INVENTORY) {
+            state.remember(txid);
+            let unknown = !mempool.contains(&txid)
+                && !self.orphans.contains(&txid)
+                && !self.rejected.contains(&txid);
+            if unknown {
+                state.add_announced(txid);
+            }
+        }
+    }
+
+    /// Answers a `GetData` of `peer` from the mempool.
+    pub fn on_get_data(&mut self, peer: &P, txids: &[U256], mempool: &Mempool) -> RelayResponse {
+        let transactions: Vec<Transaction> = txids
+            .iter()
+            .take(MAX_INVENTORY)
+            .filter_map(|txid| mempool.get(txid))
+            .map(|entry| entry.tx.clone())
+            .collect();
+        if let Some(state) = self.peers.get_mut(peer) {
+            for tx in &transactions {
+                state.remember(tx.hash);
+            }
+        }
+        RelayResponse::Transactions(transactions)
+    }
+
+    /// Adds the transactions `peer` sent to the mempool, returns the txids
+    /// accepted, orphans resolved by them included. Transactions the peer
+    /// was asked for and left out are asked from other announcers.
+    pub fn on_transactions<E: ConsensusEngine, C: Clock>(
+        &mut self,
+        peer: &P,
+        transactions: Vec<Transaction>,
+        blockchain: &TinyBlockchain<E, C>,
+        mempool: &mut Mempool,
+        now: u64,
+    ) -> Vec<U256> {
+        let received: HashSet<U256> = transactions.iter().map(|tx| tx.hash).collect();
+        if let Some(state) = self.peers.get_mut(peer) {
+            for txid in state.requested.drain(..) {
+                self.in_flight.remove(&txid);
+                if !received.contains(&txid) {
+                    state.announced.remove(&txid);
+                }
+            }
+            state.requested_at = None;
+            for txid in &received {
+                state.remember(*txid);
+            }
+        }
+
+        let work = transactions
+            .into_iter()
+            .map(|tx| (tx, peer.clone()))
+            .collect();
+        self.accept(work, blockchain, mempool, now)
+    }
+
+    /// `peer` didn't answer its `GetData`, the transactions are asked from
+    /// other announcers.
+    pub fn on_request_failed(&mut self, peer: &P) {
+        if let Some(state) = self.peers.get_mut(peer) {
+            release(state, &mut self.in_flight);
+        }
+    }
+
+    /// Retries the orphans spending outputs confirmed by a tip change, and
+    /// forgets the rejected transactions, a new tip may make them valid.
+    /// Mined transactions are forgotten by the peers' state. The mempool
+    /// must already be updated for the tip change.
+    pub fn on_tip_changed<E: ConsensusEngine, C: Clock>(
+        &mut self,
+        event: &TipChanged,
+        blockchain: &TinyBlockchain<E, C>,
+        mempool: &mut Mempool,
+        now: u64,
+    ) -> Vec<U256> {
+        self.rejected = TxidQueue::default();
+
+        let mut work = Vec::new();
+        for block in event
+            .connected
+            .iter()
+            .filter_map(|hash| blockchain.index().block(hash))
+        {
+            for tx in &block.transactions {
+                self.orphans.remove(&tx.hash);
+                work.extend(self.orphans.take_children(&tx.hash));
+                for state in self.peers.values_mut() {
+                    state.known.remove(&tx.hash);
+                    state.announced.remove(&tx.hash);
+                }
+            }
+        }
+        self.accept(work, blockchain, mempool, now)
+    }
+
+    /// The messages to send: announcements of accepted transactions, and a
+    /// `GetData` for idle peers that announced transactions we don't have.
+    pub fn poll(&mut self, mempool: &Mempool, now: u64) -> Vec<(P, RelayRequest)> {
+        self.orphans.expire(now);
+
+        let mut requests = Vec::new();
+        for (peer, state) in &mut self.peers {
+            if state
+                .requested_at
+                .is_some_and(|at| now.saturating_sub(at) > GET_DATA_TIMEOUT)
+            {
+                release(state, &mut self.in_flight);
+            }
+
+            while !state.to_announce.is_empty() {
+                let count = state.to_announce.len().min(MAX_INVENTORY);
+                let txids = state.to_announce.drain(..count).collect();
+                requests.push((peer.clone(), RelayRequest::Inventory(txids)));
+            }
+
+            if state.requested_at.is_some() {
+                continue;
+            }
+            state.announced.retain(|txid| {
+                !mempool.contains(txid)
+                    && !self.orphans.contains(txid)
+                    && !self.rejected.contains(txid)
+            });
+            let batch: Vec<U256> = state
+                .announced
+                .iter()
+                .filter(|txid| !self.in_flight.contains(*txid))
+                .take(MAX_INVENTORY)
+                .copied()
+                .collect();
+            if batch.is_empty() {
+                continue;
+            }
+
+            self.in_flight.extend(&batch);
+            state.requested = batch.clone();
+            state.requested_at = Some(now);
+            requests.push((peer.clone(), RelayRequest::GetData(batch)));
+        }
+
+        requests
+    }
+
+    /// Adds each transaction to the mempool, announcing the accepted ones
+    /// and retrying their orphans, and keeps those with unknown parents as
+    /// orphans.
+    fn accept<E: ConsensusEngine, C: Clock>(
+        &mut self,
+        mut work: Vec<(Transaction, P)>,
+        blockchain: &TinyBlockchain<E, C>,
+        mempool: &mut Mempool,
+        now: u64,
+    ) -> Vec<U256> {
+        let height = blockchain.tip().height + 1;
+        let mut accepted = Vec::new();
+        work.reverse();
+
+        while let Some((tx, from)) = work.pop() {
+            let txid = tx.hash;
+            match mempool.add(tx.clone(), blockchain.utxo(), height) {
+                Ok(()) => {
+                    for state in self.peers.values_mut() {
+                        if state.remember(txid) {
+                            state.to_announce.push(txid);
+                        }
+                    }
+                    work.extend(self.orphans.take_children(&txid));
+                    accepted.push(txid);
+                }
+                Err(MempoolError::Invalid(ValidationError::Utxo(UtxoError::MissingInput(_)))) => {
+                    let parents: Vec<U256> = tx
+                        .inputs
+                        .iter()
+                        .filter_map(|input| input.prev_output())
+                        .filter(|outpoint| {
+                            !blockchain.utxo().contains(outpoint)
+                                && !mempool.contains(&outpoint.txid)
+                        })
+                        .map(|outpoint| outpoint.txid)
+                        .collect();
+                    if parents.iter().any(|parent| self.rejected.contains(parent)) {
+                        self.reject(txid);
+                        continue;
+                    }
+                    if !self.orphans.add(tx, from.clone(), now) {
+                        continue;
+                    }
+                    // The peer that sent the orphan should have its parents.
+                    if let Some(state) = self.peers.get_mut(&from) {
+                        for parent in parents {
+                            state.add_announced(parent);
+                        }
+                    }
+                }
+                Err(MempoolError::AlreadyKnown(_)) => {}
+                Err(_) => self.reject(txid),
+            }
+        }
+
+        accepted
+    }
+
+    fn reject(&mut self, txid: U256) {
+        self.rejected.insert(txid);
+        while self.rejected.len() > MAX_REJECTED_TXIDS {
+            self.rejected.pop_front();
+        }
+    }
+}
+
+/// Gives up the outstanding `GetData` of a peer, it isn't asked for those
+/// transactions again.
+fn release(state: &mut PeerState, in_flight: &mut HashSet<U256>) {
+    for txid in state.requested.drain(..) {
+        in_flight.remove(&txid);
+        state.announced.remove(&txid);
+    }
+    state.requested_at = None;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::{mature_blockchain, mine_block};
+    use crate::{
+        Block, BlockTemplateBuilder, MempoolPolicy, OutPoint, UtxoInput, UtxoOutput, COIN,
+    };
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    const FEE: u64 = 10_000;
+
+    fn keypair() -> Ed25519KeyPair {
+        Ed25519KeyPair::from_seed_unchecked(&[6u8; 32]).unwrap()
+    }
+
+    fn pub_key() -> [u8; 32] {
+        keypair().public_key().as_ref().try_into().unwrap()
+    }
+
+    /// A mature chain whose genesis pays `outputs` coins to the test key.
+    fn blockchain(outputs: usize) -> TinyBlockchain {
+        mature_blockchain(
+            (0..outputs)
+                .map(|_| UtxoOutput::to_pub_key(&pub_key(), COIN))
+                .collect(),
+        )
+    }
+
+    fn mine(blockchain: &TinyBlockchain, mempool: &Mempool) -> Block {
+        let mut builder = BlockTemplateBuilder::new(U256::ONE);
+        builder.add_candidates(mempool.candidates());
+        mine_block(blockchain, builder)
+    }
+
+    /// Spends `outpoint` worth `value` back to the test key.
+    fn spend(outpoint: OutPoint, value: u64) -> Transaction {
+        let mut tx = Transaction::new(
+            vec![UtxoInput::spend(outpoint, pub_key())],
+            vec![UtxoOutput::to_pub_key(&pub_key(), value - FEE)],
+        );
+        tx.sign(&keypair());
+        tx
+    }
+
+    fn genesis_output(blockchain: &TinyBlockchain, vout: u32) -> OutPoint {
+        OutPoint::new(blockchain.chain().items[0].transactions[0].hash, vout)
+    }
+
+    #[test]
+    fn test_relay_message_encoding() {
+        let txids = vec![U256::ONE, U256::from(7u32)];
+        for request in [
+            RelayRequest::Inventory(txids.clone()),
+            RelayRequest::GetData(txids),
+            RelayRequest::EstimateFee { target: 6 },
+        ] {
+            assert_eq!(RelayRequest::from_bytes(&request.to_bytes()), Ok(request));
+        }
+
+        let tx = spend(OutPoint::new(U256::ONE, 0), COIN);
+        for response in [
+            RelayResponse::Ack,
+            RelayResponse::Transactions(vec![tx]),
+            RelayResponse::FeeRate(Some(2_000)),
+            RelayResponse::FeeRate(None),
+        ] {
+            assert_eq!(
+                RelayResponse::from_bytes(&response.to_bytes()),
+                Ok(response)
+            );
+        }
+
+        assert_eq!(
+            RelayRequest::from_bytes(&[9]),
+            Err(DecodeError::InvalidValue("relay request"))
+        );
+        assert_eq!(
+            RelayResponse::from_bytes(&[FEE_RATE, 2]),
+            Err(DecodeError::InvalidValue("fee rate"))
+        );
+    }
+
+    #[test]
+    fn test_requests_announced_transactions() {
+        let blockchain = blockchain(1);
+        let tx = spend(genesis_output(&blockchain, 0), COIN);
+
+        let mut sender = Mempool::new(MempoolPolicy::default());
+        let height = blockchain.tip().height + 1;
+        sender.add(tx.clone(), blockchain.utxo(), height).unwrap();
+
+        let mut mempool = Mempool::new(MempoolPolicy::default());
+        let mut relay = TransactionRelay::new();
+        for peer in 0..3 {
+            relay.add_peer(peer);
+        }
+
+        // Two peers announce the transaction, only the first one is asked.
+        relay.on_inventory(&0, vec![tx.hash], &mempool);
+        relay.on_inventory(&1, vec![tx.hash], &mempool);
+        assert_eq!(
+            relay.poll(&mempool, 0),
+            vec![(0, RelayRequest::GetData(vec![tx.hash]))]
+        );
+        assert!(relay.poll(&mempool, 1).is_empty());
+
+        let response = relay.on_get_data(&0, &[tx.hash, U256::ONE], &sender);
+        assert_eq!(response, RelayResponse::Transactions(vec![tx.clone()]));
+
+        let accepted = relay.on_transactions(&0, vec![tx.clone()], &blockchain, &mut mempool, 2);
+        assert_eq!(accepted, vec![tx.hash]);
+        assert!(mempool.contains(&tx.hash));
+
+        // Only the peer that didn't announce it hears about it.
+        assert_eq!(
+            relay.poll(&mempool, 3),
+            vec![(2, RelayRequest::Inventory(vec![tx.hash]))]
+        );
+        assert!(relay.poll(&mempool, 4).is_empty());
+
+        // Known transactions aren't asked again.
+        relay.on_inventory(&2, vec![tx.hash], &mempool);
+        assert!(relay.poll(&mempool, 5).is_empty());
+    }
+
+    #[test]
+    fn test_asks_other_announcers() {
+        let blockchain = blockchain(1);
+        let tx = spend(genesis_output(&blockchain, 0), COIN);
+        let mut mempool = Mempool::new(MempoolPolicy::default());
+        let mut relay = TransactionRelay::new();
+        for peer in 0..3 {
+            relay.add_peer(peer);
+            relay.on_inventory(&peer, vec![tx.hash], &mempool);
+        }
+
+        assert_eq!(
+            relay.poll(&mempool, 0),
+            vec![(0, RelayRequest::GetData(vec![tx.hash]))]
+        );
+
+        // Peer 0 doesn't have it after all.
+        relay.on_transactions(&0, Vec::new(), &blockchain, &mut mempool, 1);
+        assert_eq!(
+            relay.poll(&mempool, 1),
+            vec![(1, RelayRequest::GetData(vec![tx.hash]))]
+        );
+
+        // Peer 1 doesn't answer in time.
+        assert!(relay.poll(&mempool, 1 + GET_DATA_TIMEOUT).is_empty());
+        assert_eq!(
+            relay.poll(&mempool, 2 + GET_DATA_TIMEOUT),
+            vec![(2, RelayRequest::GetData(vec![tx.hash]))]
+        );
+
+        // Nor does peer 2, the transaction is given up.
+        relay.remove_peer(&2);
+        assert!(relay.poll(&mempool, 3 + GET_DATA_TIMEOUT).is_empty());
+    }
+
+    #[test]
+    fn test_resolves_orphans() {
+        let blockchain = blockchain(1);
+        let parent = spend(genesis_output(&blockchain, 0), COIN);
+        let child = spend(OutPoint::new(parent.hash, 0), COIN - FEE);
+        let grandchild = spend(OutPoint::new(child.hash, 0), COIN - 2 * FEE);
+
+        let mut mempool = Mempool::new(MempoolPolicy::default());
+        let mut relay = TransactionRelay::new();
+        relay.add_peer(0);
+        relay.add_peer(1);
+
+        let accepted = relay.on_transactions(
+            &0,
+            vec![grandchild.clone(), child.clone()],
+            &blockchain,
+            &mut mempool,
+            0,
+        );
+        assert!(accepted.is_empty());
+        assert_eq!(relay.orphans().len(), 2);
+
+        // The missing parent is asked from the peer that sent the orphan.
+        assert_eq!(
+            relay.poll(&mempool, 0),
+            vec![(0, RelayRequest::GetData(vec![parent.hash]))]
+        );
+
+        let accepted =
+            relay.on_transactions(&0, vec![parent.clone()], &blockchain, &mut mempool, 1);
+        assert_eq!(accepted, vec![parent.hash, child.hash, grandchild.hash]);
+        assert!(relay.orphans().is_empty());
+        assert_eq!(mempool.len(), 3);
+        assert_eq!(
+            relay.poll(&mempool, 2),
+            vec![(
+                1,
+                RelayRequest::Inventory(vec![parent.hash, child.hash, grandchild.hash])
+            )]
+        );
+    }
+
+    #[test]
+    fn test_resolves_orphans_of_confirmed_parents() {
+        let mut blockchain = blockchain(1);
+        let tip_changes = blockchain.subscribe();
+        let parent = spend(genesis_output(&blockchain, 0), COIN);
+        let child = spend(OutPoint::new(parent.hash, 0), COIN - FEE);
+
+        let mut mempool = Mempool::new(MempoolPolicy::default());
+        let mut relay = TransactionRelay::new();
+        relay.add_peer(0);
+        relay.on_transactions(&0, vec![child.clone()], &blockchain, &mut mempool, 0);
+        assert!(relay.orphans().contains(&child.hash));
+
+        let mut with_parent = Mempool::new(MempoolPolicy::default());
+        let height = blockchain.tip().height + 1;
+        with_parent
+            .add(parent.clone(), blockchain.utxo(), height)
+            .unwrap();
+        blockchain
+            .add_block(mine(&blockchain, &with_parent))
+            .unwrap();
+
+        assert!(relay.peers[&0]
+            .announced
+            .iter()
+            .any(|txid| *txid == parent.hash));
+
+        let event = tip_changes.try_recv().unwrap();
+        let accepted = relay.on_tip_changed(&event, &blockchain, &mut mempool, 1);
+        assert_eq!(accepted, vec![child.hash]);
+        assert!(relay.orphans().is_empty());
+
+        // The mined parent is forgotten by the peer's state.
+        assert_eq!(relay.peers[&0].announced.len(), 0);
+        assert!(!relay.peers[&0]
+            .known
+            .iter()
+            .any(|txid| *txid == parent.hash));
+    }
+
+    #[test]
+    fn test_bounds_peer_state() {
+        let mempool = Mempool::new(MempoolPolicy::default());
+        let mut relay = TransactionRelay::new();
+        relay.add_peer(0);
+
+        let txids: Vec<U256> = (1..=MAX_PEER_TX_ANNOUNCEMENTS as u32 + 10)
+            .map(U256::from)
+            .collect();
+        for chunk in txids.chunks(MAX_INVENTORY) {
+            relay.on_inventory(&0, chunk.to_vec(), &mempool);
+        }
+        let state = &relay.peers[&0];
+        assert_eq!(state.announced.len(), MAX_PEER_TX_ANNOUNCEMENTS);
+        assert_eq!(state.known.len(), txids.len());
+
+        let mut state = PeerState::default();
+        for txid in 0..MAX_PEER_KNOWN_TXIDS as u32 + 1 {
+            assert!(state.remember(U256::from(txid)));
+        }
+        assert_eq!(state.known.len(), MAX_PEER_KNOWN_TXIDS);
+        assert_eq!(state.known.iter().next(), Some(&U256::ONE));
+
+        for txid in 0..MAX_REJECTED_TXIDS as u32 + 1 {
+            relay.reject(U256::from(txid));
+        }
+        assert_eq!(relay.rejected.len(), MAX_REJECTED_TXIDS);
+        assert!(!relay.rejected.contains(&U256::ZERO));
+    }
+
+    #[test]
+    fn test_orphan_pool_limits() {
+        let mut pool = OrphanPool::new();
+        let orphans: Vec<Transaction> = (0..=MAX_ORPHANS as u32)
+            .map(|vout| spend(OutPoint::new(U256::ONE, vout), COIN))
+            .collect();
+
+        for (at, orphan) in orphans.iter().enumerate() {
+            assert!(pool.add(orphan.clone(), 0, at as u64));
+        }
+        assert!(!pool.add(orphans[1].clone(), 0, 0));
+
+        // The oldest orphan made room for the last one.
+        assert_eq!(pool.len(), MAX_ORPHANS);
+        assert!(!pool.contains(&orphans[0].hash));
+        assert!(pool.contains(&orphans[MAX_ORPHANS].hash));
+
+        let mut big = spend(OutPoint::new(U256::ONE, 0), COIN);
+        big.outputs = vec![UtxoOutput::new(U256::ONE, 1); MAX_ORPHAN_SIZE / 32];
+        assert!(!pool.add(big, 0, 0));
+
+        assert_eq!(pool.expire(ORPHAN_EXPIRY + 10), 10);
+        assert_eq!(pool.len(), MAX_ORPHANS - 10);
+
+        pool.add(spend(OutPoint::new(U256::from(2u32), 0), COIN), 1, 0);
+        pool.remove_from(&0);
+        assert_eq!(pool.len(), 1);
+        assert_eq!(pool.take_children(&U256::from(2u32)).len(), 1);
+        assert!(pool.is_empty());
+    }
+}
@@ -0,0 +1,792 @@
+use super::compact::{block_transactions, BlockTransactions, BlockTransactionsRequest};
+use crate::{
+    decode_list, encode_list, Block, BlockHeader, BlockStatus, Clock, ConsensusEngine, Decode,
+    DecodeError, Encode, TinyBlockchain, TinyBlockchainError,
+};
+use ethnum::U256;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+/// Most headers sent in one response, a full response means the peer has
+/// more.
+pub const MAX_HEADERS_RESULTS: usize = 2000;
+/// Most blocks asked from a peer at once.
+pub const MAX_BLOCKS_PER_REQUEST: usize = 16;
+/// Encoded size above which a blocks response stops growing, the blocks left
+/// out are asked again.
+pub const MAX_BLOCKS_RESPONSE_SIZE: usize = 4_000_000;
+/// How far past the tip blocks are downloaded. A peer holding up the window
+/// can't make the others download an unbounded backlog.
+pub const BLOCK_DOWNLOAD_WINDOW: usize = 1024;
+/// Seconds a peer may take to answer a request before it is dropped.
+pub const STALL_TIMEOUT: u64 = 10;
+
+const GET_HEADERS: u8 = 0;
+const GET_BLOCKS: u8 = 1;
+const GET_BLOCK_TRANSACTIONS: u8 = 2;
+const GET_TIME: u8 = 3;
+const HEADERS: u8 = 0;
+const BLOCKS: u8 = 1;
+const BLOCK_TRANSACTIONS: u8 = 2;
+const TIME: u8 = 3;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SyncRequest {
+    /// Headers of the peer's active chain after the first block of
+    /// `locator` it knows, up to and including `stop` if not zero.
+    GetHeaders { locator: Vec<U256>, stop: U256 },
+    /// The blocks with the given header hashes.
+    GetBlocks(Vec<U256>),
+    /// Transactions of a compact block that couldn't be rebuilt.
+    GetBlockTransactions(BlockTransactionsRequest),
+    /// The peer's time, asked once on connection to adjust network time.
+    GetTime,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SyncResponse {
+    Headers(Vec<BlockHeader>),
+    /// The requested blocks that were found, in request order.
+    Blocks(Vec<Block>),
+    /// Empty if the block or one of the indexes is unknown.
+    BlockTransactions(BlockTransactions),
+    /// Seconds since the UNIX epoch by the peer's clock.
+    Time(u64),
+}
+
+impl Encode for SyncRequest {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            SyncRequest::GetHeaders { locator, stop } => {
+                out.push(GET_HEADERS);
+                encode_list(locator, out);
+                stop.encode(out);
+            }
+            SyncRequest::GetBlocks(hashes) => {
+                out.push(GET_BLOCKS);
+                encode_list(hashes, out);
+            }
+            SyncRequest::GetBlockTransactions(request) => {
+                out.push(GET_BLOCK_TRANSACTIONS);
+                request.encode(out);
+            }
+            SyncRequest::GetTime => out.push(GET_TIME),
+        }
+    }
+}
+
+impl Decode for SyncRequest {
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        match u8::decode(input)? {
+            GET_HEADERS => Ok(SyncRequest::GetHeaders {
+                locator: decode_list(input)?,
+                stop: U256::decode(input)?,
+            }),
+            GET_BLOCKS => Ok(SyncRequest::GetBlocks(decode_list(input)?)),
+            GET_BLOCK_TRANSACTIONS => Ok(SyncRequest::GetBlockTransactions(
+                BlockTransactionsRequest::decode(input)?,
+            )),
+            GET_TIME => Ok(SyncRequest::GetTime),
+            _ => Err(DecodeError::InvalidValue("sync request")),
+        }
+    }
+}
+
+impl Encode for SyncResponse {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            SyncResponse::Headers(headers) => {
+                out.push(HEADERS);
+                encode_list(headers, out);
+            }
+            SyncResponse::Blocks(blocks) => {
+                out.push(BLOCKS);
+                encode_list(blocks, out);
+            }
+            SyncResponse::BlockTransactions(transactions) => {
+                out.push(BLOCK_TRANSACTIONS);
+                transactions.encode(out);
+            }
+            SyncResponse::Time(time) => {
+                out.push(TIME);
+                time.encode(out);
+            }
+        }
+    }
+}
+
+impl Decode for SyncResponse {
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        match u8::decode(input)? {
+            HEADERS => Ok(SyncResponse::Headers(decode_list(input)?)),
+            BLOCKS => Ok(SyncResponse::Blocks(decode_list(input)?)),
+            BLOCK_TRANSACTIONS => Ok(SyncResponse::BlockTransactions(BlockTransactions::decode(
+                input,
+            )?)),
+            TIME => Ok(SyncResponse::Time(u64::decode(input)?)),
+            _ => Err(DecodeError::InvalidValue("sync response")),
+        }
+    }
+}
+
+/// Answers a peer's sync request from the local chain.
+pub fn handle_request<E: ConsensusEngine, C: Clock>(
+    blockchain: &TinyBlockchain<E, C>,
+    request: &SyncRequest,
+) -> SyncResponse {
+    match request {
+        SyncRequest::GetHeaders { locator, stop } => {
+            SyncResponse::Headers(headers_after(blockchain, locator, *stop))
+        }
+        SyncRequest::GetBlocks(hashes) => {
+            let mut blocks = Vec::new();
+            let mut size = 0;
+            for block in hashes
+                .iter()
+                .take(MAX_BLOCKS_PER_REQUEST)
+                .filter_map(|hash| blockchain.index().block(hash))
+            {
+                size += block.to_bytes().len();
+                if size > MAX_BLOCKS_RESPONSE_SIZE && !blocks.is_empty() {
+                    break;
+                }
+                blocks.push(block.clone());
+            }
+            SyncResponse::Blocks(blocks)
+        }
+        SyncRequest::GetBlockTransactions(request) => SyncResponse::BlockTransactions(
+            blockchain
+                .index()
+                .block(&request.block_hash)
+                .and_then(|block| block_transactions(block, request).ok())
+                .unwrap_or(BlockTransactions {
+                    block_hash: request.block_hash,
+                    transactions: Vec::new(),
+                }),
+        ),
+        SyncRequest::GetTime => SyncResponse::Time(blockchain.clock().now()),
+    }
+}
+
+fn headers_after<E: ConsensusEngine, C: Clock>(
+    blockchain: &TinyBlockchain<E, C>,
+    locator: &[U256],
+    stop: U256,
+) -> Vec<BlockHeader> {
+    let items = &blockchain.chain().items;
+    // Without a common block the peer gets the chain from the genesis block.
+    let fork = locator
+        .iter()
+        .filter_map(|hash| blockchain.index().get(hash))
+        .find(|entry| {
+            items
+                .get(entry.height)
+                .is_some_and(|block| block.header_hash == entry.hash)
+        })
+        .map_or(0, |entry| entry.height);
+
+    let mut headers = Vec::new();
+    for block in items.iter().skip(fork + 1).take(MAX_HEADERS_RESULTS) {
+        headers.push(block.header.clone());
+        if block.header_hash == stop {
+            break;
+        }
+    }
+
+    headers
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum SyncError {
+    InvalidHeader(TinyBlockchainError),
+    InvalidBlock(TinyBlockchainError),
+}
+
+impl std::fmt::Display for SyncError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SyncError::InvalidHeader(err) => write!(f, "invalid header: {}", err),
+            SyncError::InvalidBlock(err) => write!(f, "invalid block: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for SyncError {}
+
+#[derive(Debug, Default)]
+struct PeerState {
+    headers_synced: bool,
+    /// Blocks asked in the peer's outstanding `GetBlocks`.
+    blocks_in_flight: Vec<U256>,
+    /// When the outstanding `GetBlocks` was sent.
+    requested_at: Option<u64>,
+    /// Blocks the peer left out of a response, not asked from it again.
+    missing: HashSet<U256>,
+}
+
+/// Headers-first initial block download. The header chain is fetched from
+/// one peer at a time and validated first, then the block bodies along the
+/// best header chain are downloaded in parallel from every peer, no further
+/// than `window` blocks past the tip. Peers that leave a request unanswered
+/// for longer than `STALL_TIMEOUT` are dropped and their blocks are asked
+/// from the others.
+///
+/// The state machine doesn't do any I/O: the node feeds it peers, responses
+/// and the time, and sends the requests it returns.
+#[derive(Debug)]
+pub struct InitialBlockDownload<P> {
+    peers: BTreeMap<P, PeerState>,
+    in_flight: HashMap<U256, P>,
+    /// The peer asked for headers and when.
+    headers_peer: Option<(P, u64)>,
+    window: usize,
+}
+
+impl<P: Clone + Ord> Default for InitialBlockDownload<P> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<P: Clone + Ord> InitialBlockDownload<P> {
+    pub fn new() -> Self {
+        InitialBlockDownload {
+            peers: BTreeMap::new(),
+            in_flight: HashMap::new(),
+            headers_peer: None,
+            window: BLOCK_DOWNLOAD_WINDOW,
+        }
+    }
+
+    /// Downloads at most `window` blocks past the tip.
+    pub fn with_window(mut self, window: usize) -> Self {
+        self.window = window.max(1);
+        self
+    }
+
+    pub fn add_peer(&mut self, peer: P) {
+        self.peers.entry(peer).or_default();
+    }
+
+    /// Forgets `peer`, the blocks it was asked for are asked from others.
+    pub fn remove_peer(&mut self, peer: &P) {
+        if let Some(state) = self.peers.remove(peer) {
+            for hash in state.blocks_in_flight {
+                self.in_flight.remove(&hash);
+            }
+        }
+        if self.headers_peer.as_ref().is_some_and(|(p, _)| p == peer) {
+            self.headers_peer = None;
+        }
+    }
+
+    /// Syncs headers with `peer` again, e.g. after it announced a block on
+    /// top of headers we don't have.
+    pub fn request_headers(&mut self, peer: &P) {
+        if let Some(state) = self.peers.get_mut(peer) {
+            state.headers_synced = false;
+        }
+    }
+
+    /// Whether the headers of every peer are known and the active chain
+    /// reached the best of them.
+    pub fn is_complete<E: ConsensusEngine, C: Clock>(
+        &self,
+        blockchain: &TinyBlockchain<E, C>,
+    ) -> bool {
+        self.peers.values().all(|state| state.headers_synced)
+            && blockchain.index().best_header().hash == blockchain.tip().header_hash
+    }
+
+    /// The requests to send: headers from the next peer whose headers
+    /// aren't synced, and blocks of the download window for idle peers.
+    pub fn poll<E: ConsensusEngine, C: Clock>(
+        &mut self,
+        blockchain: &TinyBlockchain<E, C>,
+        now: u64,
+    ) -> Vec<(P, SyncRequest)> {
+        let mut requests = Vec::new();
+
+        if self.headers_peer.is_none() {
+            let peer = self
+                .peers
+                .iter()
+                .find(|(_, state)| !state.headers_synced)
+                .map(|(peer, _)| peer.clone());
+            if let Some(peer) = peer {
+                let best = blockchain.index().best_header().hash;
+                let request = SyncRequest::GetHeaders {
+                    locator: blockchain.index().locator(&best),
+                    stop: U256::ZERO,
+                };
+                requests.push((peer.clone(), request));
+                self.headers_peer = Some((peer, now));
+            }
+        }
+
+        let mut pending = self.blocks_to_download(blockchain);
+        for (peer, state) in &mut self.peers {
+            if pending.is_empty() {
+                break;
+            }
+            if state.requested_at.is_some() {
+                continue;
+            }
+
+            let mut batch = Vec::new();
+            pending.retain(|hash| {
+                if batch.len() < MAX_BLOCKS_PER_REQUEST && !state.missing.contains(hash) {
+                    batch.push(*hash);
+                    false
+                } else {
+                    true
+                }
+            });
+            if batch.is_empty() {
+                continue;
+            }
+
+            for hash in &batch {
+                self.in_flight.insert(*hash, peer.clone());
+            }
+            state.blocks_in_flight = batch.clone();
+            state.requested_at = Some(now);
+            requests.push((peer.clone(), SyncRequest::GetBlocks(batch)));
+        }
+
+        requests
+    }
+
+    /// Removes and returns the peers that didn't answer in time.
+    pub fn check_stalls(&mut self, now: u64) -> Vec<P> {
+        let stalled_at = |at: u64| now.saturating_sub(at) > STALL_TIMEOUT;
+        let mut stalled: Vec<P> = self
+            .peers
+            .iter()
+            .filter(|(_, state)| state.requested_at.is_some_and(stalled_at))
+            .map(|(peer, _)| peer.clone())
+            .collect();
+        if let Some((peer, at)) = &self.headers_peer {
+            if stalled_at(*at) && !stalled.contains(peer) {
+                stalled.push(peer.clone());
+            }
+        }
+
+        for peer in &stalled {
+            self.remove_peer(peer);
+        }
+
+        stalled
+    }
+
+    /// Validates and indexes headers received from `peer`. A full response
+    /// means the peer has more, they are asked on the next poll. A peer
+    /// sending new headers may have the blocks it was missing by now.
+    pub fn on_headers<E: ConsensusEngine, C: Clock>(
+        &mut self,
+        peer: &P,
+        headers: Vec<BlockHeader>,
+        blockchain: &mut TinyBlockchain<E, C>,
+    ) -> Result<(), SyncError> {
+        if self.headers_peer.as_ref().is_some_and(|(p, _)| p == peer) {
+            self.headers_peer = None;
+        }
+        let more = headers.len() >= MAX_HEADERS_RESULTS;
+        let mut added = false;
+
+        for header in headers {
+            match blockchain.add_header(header) {
+                Ok(()) => added = true,
+                Err(TinyBlockchainError::DuplicateBlock(_)) => {}
+                Err(err) => return Err(SyncError::InvalidHeader(err)),
+            }
+        }
+
+        if let Some(state) = self.peers.get_mut(peer) {
+            state.headers_synced = !more;
+            if added {
+                state.missing.clear();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Adds the blocks `peer` answered its `GetBlocks` with. Blocks it
+    /// wasn't asked for are ignored, and so are blocks at another height than
+    /// their indexed header since the hash doesn't cover the height. Blocks
+    /// it left out are asked from other peers.
+    pub fn on_blocks<E: ConsensusEngine, C: Clock>(
+        &mut self,
+        peer: &P,
+        blocks: Vec<Block>,
+        blockchain: &mut TinyBlockchain<E, C>,
+    ) -> Result<(), SyncError> {
+        let Some(state) = self.peers.get_mut(peer) else {
+            return Ok(());
+        };
+        let mut requested: HashSet<U256> = state.blocks_in_flight.drain(..).collect();
+        state.requested_at = None;
+        for hash in &requested {
+            self.in_flight.remove(hash);
+        }
+
+        let blocks: Vec<Block> = blocks
+            .into_iter()
+            .filter(|block| {
+                let indexed = blockchain.index().get(&block.header_hash);
+                indexed.is_some_and(|entry| entry.height == block.height)
+                    && requested.remove(&block.header_hash)
+            })
+            .collect();
+        state.missing.extend(requested);
+
+        for block in blocks {
+            match blockchain.add_block(block) {
+                Ok(())
+                | Err(TinyBlockchainError::DuplicateBlock(_))
+                | Err(TinyBlockchainError::UnknownParent(_)) => {}
+                Err(err) => return Err(SyncError::InvalidBlock(err)),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Forgets the outstanding `GetBlocks` of `peer` after it failed, so its
+    /// blocks are asked again.
+    pub fn on_request_failed(&mut self, peer: &P) {
+        if let Some(state) = self.peers.get_mut(peer) {
+            for hash in state.blocks_in_flight.drain(..) {
+                self.in_flight.remove(&hash);
+            }
+            state.requested_at = None;
+        }
+        if self.headers_peer.as_ref().is_some_and(|(p, _)| p == peer) {
+            self.headers_peer = None;
+        }
+    }
+
+    /// Blocks along the best header chain within the window that are
+    /// neither stored nor in flight, lowest first.
+    fn blocks_to_download<E: ConsensusEngine, C: Clock>(
+        &self,
+        blockchain: &TinyBlockchain<E, C>,
+    ) -> Vec<U256> {
+        let index = blockchain.index();
+        let tip = blockchain.tip();
+        let best = index.best_header();
+        let fork = index
+            .find_fork(&tip.header_hash, &best.hash)
+            .expect("Blocks to share the genesis block");
+        let end = best.height.min(fork.height + self.window);
+        let end = index
+            .ancestor(&best.hash, end)
+            .expect("Ancestors to be indexed");
+
+        index
+            .path_from(&fork.hash, &end.hash)
+            .into_iter()
+            .filter(|hash| {
+                index
+                    .get(hash)
+                    .is_some_and(|entry| entry.status == BlockStatus::HeaderOnly)
+            })
+            .filter(|hash| !self.in_flight.contains_key(hash))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::{test_blockchain, TEST_BITS};
+    use crate::{
+        Chain, FixedClock, Hash, ProofOfWork, Transaction, UtxoOutput, ValidationError,
+        INITIAL_SUBSIDY,
+    };
+
+    /// A blockchain with `len` blocks on top of the genesis block.
+    fn source(len: usize) -> TinyBlockchain {
+        let mut blockchain = test_blockchain();
+        for _ in 0..len {
+            let parent = blockchain.tip().clone();
+            let height = parent.height + 1;
+            let coinbase = Transaction::coinbase(
+                height as u64,
+                0,
+                vec![UtxoOutput::new(U256::ONE, INITIAL_SUBSIDY)],
+            );
+            let header = BlockHeader {
+                version: 1,
+                timestamp: parent.header.timestamp + 600,
+                prev: parent.header_hash,
+                merkle_root: U256::ZERO,
+                bits: TEST_BITS,
+                nonce: 0,
+                extra: Vec::new(),
+            };
+            let mut block = Block::new(height, U256::ZERO, header, vec![coinbase]);
+            block.header.merkle_root = block.compute_merkle_root();
+            let clock = FixedClock(block.header.timestamp);
+            ProofOfWork.seal(&mut block, &clock).unwrap();
+            blockchain.add_block(block).unwrap();
+        }
+        blockchain
+    }
+
+    /// Answers `requests` from `sources`, indexed by peer, and feeds the
+    /// responses back.
+    fn deliver(
+        ibd: &mut InitialBlockDownload<usize>,
+        requests: Vec<(usize, SyncRequest)>,
+        sources: &[TinyBlockchain],
+        blockchain: &mut TinyBlockchain,
+    ) {
+        for (peer, request) in requests {
+            let response = SyncResponse::from_bytes(
+                &handle_request(
+                    &sources[peer],
+                    &SyncRequest::from_bytes(&request.to_bytes()).unwrap(),
+                )
+                .to_bytes(),
+            )
+            .unwrap();
+            match response {
+                SyncResponse::Headers(headers) => ibd.on_headers(&peer, headers, blockchain),
+                SyncResponse::Blocks(blocks) => ibd.on_blocks(&peer, blocks, blockchain),
+                SyncResponse::BlockTransactions(_) | SyncResponse::Time(_) => {
+                    unreachable!("Not asked by the download")
+                }
+            }
+            .unwrap();
+        }
+    }
+
+    #[test]
+    fn test_serves_headers_after_locator() {
+        let source = source(5);
+        let items = &source.chain().items;
+        let header = |height: usize| items[height].header.clone();
+
+        let locator = vec![U256::from(7u32), items[2].header_hash, items[0].header_hash];
+        let request = SyncRequest::GetHeaders {
+            locator,
+            stop: U256::ZERO,
+        };
+        assert_eq!(
+            handle_request(&source, &request),
+            SyncResponse::Headers(vec![header(3), header(4), header(5)])
+        );
+
+        let request = SyncRequest::GetHeaders {
+            locator: vec![],
+            stop: items[2].header_hash,
+        };
+        assert_eq!(
+            handle_request(&source, &request),
+            SyncResponse::Headers(vec![header(1), header(2)])
+        );
+
+        let request = SyncRequest::GetBlocks(vec![items[4].header_hash, U256::ONE]);
+        assert_eq!(
+            handle_request(&source, &request),
+            SyncResponse::Blocks(vec![items[4].clone()])
+        );
+
+        let request = SyncRequest::GetBlockTransactions(BlockTransactionsRequest {
+            block_hash: items[4].header_hash,
+            indexes: vec![0],
+        });
+        assert_eq!(
+            handle_request(&source, &request),
+            SyncResponse::BlockTransactions(BlockTransactions {
+                block_hash: items[4].header_hash,
+                transactions: vec![items[4].transactions[0].clone()],
+            })
+        );
+        let request = SyncRequest::GetBlockTransactions(BlockTransactionsRequest {
+            block_hash: items[4].header_hash,
+            indexes: vec![1],
+        });
+        assert_eq!(
+            handle_request(&source, &request),
+            SyncResponse::BlockTransactions(BlockTransactions {
+                block_hash: items[4].header_hash,
+                transactions: vec![],
+            })
+        );
+        assert_eq!(
+            SyncRequest::from_bytes(&request.to_bytes()),
+            Ok(request.clone())
+        );
+    }
+
+    #[test]
+    fn test_serves_time() {
+        let chain = Chain {
+            items: vec![test_blockchain().tip().clone()],
+            last_update: 0,
+        };
+        let params = test_blockchain().params().clone();
+        let source = TinyBlockchain::with_clock(chain, params, ProofOfWork, FixedClock(7));
+
+        let response = handle_request(&source, &SyncRequest::GetTime);
+        assert_eq!(response, SyncResponse::Time(7));
+        assert_eq!(SyncResponse::from_bytes(&response.to_bytes()), Ok(response));
+        assert_eq!(
+            SyncRequest::from_bytes(&SyncRequest::GetTime.to_bytes()),
+            Ok(SyncRequest::GetTime)
+        );
+    }
+
+    #[test]
+    fn test_downloads_headers_first_from_multiple_peers() {
+        let len = MAX_BLOCKS_PER_REQUEST * 3;
+        let sources = vec![source(len), source(len)];
+        let mut blockchain = test_blockchain();
+        let mut ibd = InitialBlockDownload::new().with_window(MAX_BLOCKS_PER_REQUEST * 2);
+        ibd.add_peer(0);
+        ibd.add_peer(1);
+
+        // Headers come first, from a single peer.
+        let requests = ibd.poll(&blockchain, 0);
+        assert!(matches!(
+            requests.as_slice(),
+            [(0, SyncRequest::GetHeaders { .. })]
+        ));
+        deliver(&mut ibd, requests, &sources, &mut blockchain);
+        assert_eq!(blockchain.index().best_header().height, len);
+        assert_eq!(blockchain.tip().height, 0);
+
+        // Peer 1 is asked for its headers too, both fill the window.
+        let requests = ibd.poll(&blockchain, 0);
+        let blocks: Vec<(usize, usize)> = requests
+            .iter()
+            .filter_map(|(peer, request)| match request {
+                SyncRequest::GetBlocks(hashes) => Some((*peer, hashes.len())),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            blocks,
+            vec![(0, MAX_BLOCKS_PER_REQUEST), (1, MAX_BLOCKS_PER_REQUEST)]
+        );
+        deliver(&mut ibd, requests, &sources, &mut blockchain);
+        assert_eq!(blockchain.tip().height, MAX_BLOCKS_PER_REQUEST * 2);
+        assert!(!ibd.is_complete(&blockchain));
+
+        // The window moved with the tip.
+        let requests = ibd.poll(&blockchain, 0);
+        deliver(&mut ibd, requests, &sources, &mut blockchain);
+        assert_eq!(blockchain.tip().header_hash, sources[0].tip().header_hash);
+        assert!(ibd.is_complete(&blockchain));
+        assert!(ibd.poll(&blockchain, 0).is_empty());
+    }
+
+    #[test]
+    fn test_drops_stalling_peer() {
+        let sources = vec![source(4), source(4)];
+        let mut blockchain = test_blockchain();
+        let mut ibd = InitialBlockDownload::new();
+        ibd.add_peer(0);
+        ibd.add_peer(1);
+
+        let requests = ibd.poll(&blockchain, 0);
+        deliver(&mut ibd, requests, &sources, &mut blockchain);
+
+        // Peer 0 is asked for every block and never answers.
+        let requests = ibd.poll(&blockchain, 100);
+        assert!(requests
+            .iter()
+            .any(|(peer, request)| *peer == 0 && matches!(request, SyncRequest::GetBlocks(_))));
+        let headers: Vec<_> = requests
+            .into_iter()
+            .filter(|(_, request)| matches!(request, SyncRequest::GetHeaders { .. }))
+            .collect();
+        deliver(&mut ibd, headers, &sources, &mut blockchain);
+
+        assert!(ibd.check_stalls(100 + STALL_TIMEOUT).is_empty());
+        assert_eq!(ibd.check_stalls(101 + STALL_TIMEOUT), vec![0]);
+
+        let requests = ibd.poll(&blockchain, 200);
+        assert!(requests.iter().all(|(peer, _)| *peer == 1));
+        deliver(&mut ibd, requests, &sources, &mut blockchain);
+        assert_eq!(blockchain.tip().height, 4);
+    }
+
+    #[test]
+    fn test_rejects_invalid_headers_and_missing_blocks() {
+        let source = source(3);
+        let mut blockchain = test_blockchain();
+        let mut ibd = InitialBlockDownload::new();
+        ibd.add_peer(0);
+
+        let mut forged = source.chain().items[1].header.clone();
+        forged.bits = 0x1d00ffff;
+        assert_eq!(
+            ibd.on_headers(&0, vec![forged.clone()], &mut blockchain),
+            Err(SyncError::InvalidHeader(TinyBlockchainError::InvalidBlock(
+                ValidationError::HighHash(forged.hash())
+            )))
+        );
+
+        let headers: Vec<BlockHeader> = source.chain().items[1..]
+            .iter()
+            .map(|block| block.header.clone())
+            .collect();
+        ibd.on_headers(&0, headers[..1].to_vec(), &mut blockchain)
+            .unwrap();
+
+        // A peer that doesn't have a block isn't asked for it again.
+        let requests = ibd.poll(&blockchain, 0);
+        assert_eq!(requests.len(), 1);
+        ibd.on_blocks(&0, vec![], &mut blockchain).unwrap();
+        assert!(ibd.poll(&blockchain, 0).is_empty());
+
+        // Until it sends new headers.
+        ibd.on_headers(&0, headers, &mut blockchain).unwrap();
+        let hashes = source.chain().items[1..]
+            .iter()
+            .map(|block| block.header_hash)
+            .collect();
+        assert_eq!(
+            ibd.poll(&blockchain, 0),
+            vec![(0, SyncRequest::GetBlocks(hashes))]
+        );
+    }
+
+    #[test]
+    fn test_ignores_unrequested_blocks_and_wrong_heights() {
+        let source = source(2);
+        let mut blockchain = test_blockchain();
+        let mut ibd = InitialBlockDownload::new().with_window(1);
+        ibd.add_peer(0);
+        let headers = source.chain().items[1..]
+            .iter()
+            .map(|block| block.header.clone())
+            .collect();
+        ibd.on_headers(&0, headers, &mut blockchain).unwrap();
+
+        let first = source.chain().items[1].clone();
+        let second = source.chain().items[2].clone();
+        assert_eq!(
+            ibd.poll(&blockchain, 0),
+            vec![(0, SyncRequest::GetBlocks(vec![first.header_hash]))]
+        );
+
+        // The block comes at another height, and one more block the peer
+        // wasn't asked for.
+        let mut relabeled = first.clone();
+        relabeled.height = 5;
+        ibd.on_blocks(&0, vec![relabeled, second], &mut blockchain)
+            .unwrap();
+        assert_eq!(blockchain.tip().height, 0);
+        assert_eq!(
+            blockchain.index().get(&first.header_hash).unwrap().status,
+            BlockStatus::HeaderOnly
+        );
+
+        // The block counts as missing from the peer.
+        assert!(ibd.poll(&blockchain, 0).is_empty());
+    }
+}
@@ -1,8 +1,18 @@
-use crate::{hash_to_u256, Hash, Transaction, U256Def};
+use crate::{
+    decode_list, decode_varint, encode_list, encode_varint, hash_to_u256, Decode, DecodeError,
+    Encode, Hash, MerkleTree, Transaction, U256Def,
+};
 use ethnum::prelude::*;
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize)]
+/// Size of an encoded `BlockHeader` in bytes, not counting extra data.
+pub const BLOCK_HEADER_SIZE: usize = 84;
+/// Headers from this version on carry `extra` data after the nonce.
+pub const EXTRA_DATA_VERSION: u32 = 2;
+/// Maximum size of a header's extra data.
+pub const MAX_EXTRA_DATA_SIZE: usize = 256;
+
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct BlockHeader {
     pub version: u32,
     pub timestamp: u64,
@@ -12,25 +22,73 @@ pub struct BlockHeader {
     pub merkle_root: U256,
     pub bits: u32,
     pub nonce: u32,
+    /// Engine specific data such as a signature, only encoded from
+    /// `EXTRA_DATA_VERSION` on.
+    #[serde(default)]
+    pub extra: Vec<u8>,
 }
 
 impl std::fmt::Debug for BlockHeader {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "Prev Hash: {:#064x}, Merkle Hash: {:#064x}, Timestamp: {}, Nonce: {}",
-            self.prev, self.merkle_root, self.timestamp, self.nonce
+            "Version: {}, Prev Hash: {:#064x}, Merkle Hash: {:#064x}, Timestamp: {}, Bits: {:#010x}, Nonce: {}, Extra: {}",
+            self.version,
+            self.prev,
+            self.merkle_root,
+            self.timestamp,
+            self.bits,
+            self.nonce,
+            hex::encode(&self.extra)
         )
     }
 }
 
+impl Encode for BlockHeader {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.version.encode(out);
+        self.prev.encode(out);
+        self.merkle_root.encode(out);
+        self.timestamp.encode(out);
+        self.bits.encode(out);
+        self.nonce.encode(out);
+
+        if self.version >= EXTRA_DATA_VERSION {
+            self.extra.encode(out);
+        }
+    }
+}
+
+impl Decode for BlockHeader {
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        let mut header = BlockHeader {
+            version: u32::decode(input)?,
+            prev: U256::decode(input)?,
+            merkle_root: U256::decode(input)?,
+            timestamp: u64::decode(input)?,
+            bits: u32::decode(input)?,
+            nonce: u32::decode(input)?,
+            extra: Vec::new(),
+        };
+
+        if header.version >= EXTRA_DATA_VERSION {
+            header.extra = Vec::decode(input)?;
+            if header.extra.len() > MAX_EXTRA_DATA_SIZE {
+                return Err(DecodeError::LengthTooLarge(header.extra.len() as u64));
+            }
+        }
+
+        Ok(header)
+    }
+}
+
 impl Hash for BlockHeader {
     fn hash(&self) -> U256 {
-        hash_to_u256!(format!("{:?}", self).as_bytes())
+        hash_to_u256!(self.to_bytes())
     }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Block {
     pub height: usize,
     pub header: BlockHeader,
@@ -45,26 +103,7 @@ impl std::fmt::Debug for Block {
         let transactions_str: String = self
             .transactions
             .iter()
-            .map(|tx| {
-                let mut sig = None;
-
-                if let Some(ref signature) = tx.sig {
-                    sig = Some(signature.as_ref());
-                }
-
-                // format!(
-                //     // "From: {:#064x}, To: {:#064x}, Amount: {}",
-                //     "From: {:?}, To: {:?}, Amount: {}, Signature: {:?}",
-                //     tx.from.as_ref(),
-                //     tx.to.as_ref(),
-                //     tx.amount,
-                //     sig
-                // )
-                format!(
-                    "Inputs: {:?}, outputs: {:?}, Signature: {:?}",
-                    tx.inputs, tx.outputs, sig
-                )
-            })
+            .map(|tx| format!("<{:?}>", tx))
             .collect::<Vec<String>>()
             .join(",");
         write!(
@@ -95,7 +134,185 @@ impl Block {
         }
     }
 
-    fn add_transaction(&mut self, transaction: Transaction) {
+    pub fn add_transaction(&mut self, transaction: Transaction) {
         self.transactions.push(transaction);
+        self.transactions_count = self.transactions.len();
+    }
+
+    /// Builds the merkle tree whose leaves are the encoded transaction ids.
+    pub fn merkle_tree(&self) -> MerkleTree {
+        let leaves: Vec<[u8; 32]> = self
+            .transactions
+            .iter()
+            .map(|tx| tx.hash.to_le_bytes())
+            .collect();
+
+        MerkleTree::new(&leaves)
+    }
+
+    /// The merkle root committed to by the header, zero for a block without
+    /// transactions.
+    pub fn compute_merkle_root(&self) -> U256 {
+        self.merkle_tree().get_root().copied().unwrap_or(U256::ZERO)
+    }
+}
+
+// The height and header hash aren't consensus data, but the height is kept
+// on the wire so that a decoded block is complete without a chain lookup.
+// The header hash doesn't cover it, so a block from a peer is only trusted
+// with the height of its parent's index entry plus one.
+impl Encode for Block {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.header.encode(out);
+        encode_varint(self.height as u64, out);
+        encode_list(&self.transactions, out);
+    }
+}
+
+impl Decode for Block {
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        let header = BlockHeader::decode(input)?;
+        let height = decode_varint(input)?;
+        let height = usize::try_from(height).map_err(|_| DecodeError::InvalidValue("height"))?;
+        let transactions = decode_list(input)?;
+
+        Ok(Block::new(height, header.hash(), header, transactions))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn test_header() -> BlockHeader {
+        BlockHeader {
+            version: 1,
+            timestamp: 0x5f5e1000,
+            prev: U256::from(0x11u32),
+            merkle_root: U256::from(0x22u32),
+            bits: 0x1d00ffff,
+            nonce: 0xdeadbeef,
+            extra: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_header_encoding_golden() {
+        let bytes = test_header().to_bytes();
+        let expected = [
+            "01000000",
+            "1100000000000000000000000000000000000000000000000000000000000000",
+            "2200000000000000000000000000000000000000000000000000000000000000",
+            "00105e5f00000000",
+            "ffff001d",
+            "efbeadde",
+        ]
+        .concat();
+        assert_eq!(bytes.len(), BLOCK_HEADER_SIZE);
+        assert_eq!(hex::encode(&bytes), expected);
+    }
+
+    #[test]
+    fn test_header_hash_golden() {
+        // This golden hash will need to be updated whenever the header
+        // encoding changes, which is a consensus change.
+        let expected = U256::from_str_hex(
+            "0xaa1abf6e8213122df48e8d68361c2a5474bb5fb53bc94c93afc47906a951e3a1",
+        )
+        .unwrap();
+        assert_eq!(test_header().hash(), expected);
+    }
+
+    #[test]
+    fn test_header_hash_commits_to_every_field() {
+        let header = test_header();
+        let hash = header.hash();
+        let tampered: [fn(&mut BlockHeader); 6] = [
+            |h| h.version += 1,
+            |h| h.timestamp += 1,
+            |h| h.prev += 1,
+            |h| h.merkle_root += 1,
+            |h| h.bits += 1,
+            |h| h.nonce += 1,
+        ];
+
+        for tamper in tampered {
+            let mut header = header.clone();
+            tamper(&mut header);
+            assert_ne!(header.hash(), hash);
+        }
+    }
+
+    #[test]
+    fn test_header_roundtrip() {
+        let header = test_header();
+        assert_eq!(BlockHeader::from_bytes(&header.to_bytes()), Ok(header));
+    }
+
+    #[test]
+    fn test_extra_data_from_version_2() {
+        let mut header = test_header();
+        header.extra = vec![1, 2, 3];
+        // Version 1 headers don't carry it.
+        assert_eq!(header.to_bytes().len(), BLOCK_HEADER_SIZE);
+
+        header.version = EXTRA_DATA_VERSION;
+        let bytes = header.to_bytes();
+        assert_eq!(bytes.len(), BLOCK_HEADER_SIZE + 4);
+        assert_eq!(BlockHeader::from_bytes(&bytes), Ok(header.clone()));
+
+        let hash = header.hash();
+        header.extra[0] += 1;
+        assert_ne!(header.hash(), hash);
+
+        header.extra = vec![0; MAX_EXTRA_DATA_SIZE + 1];
+        assert_eq!(
+            BlockHeader::from_bytes(&header.to_bytes()),
+            Err(DecodeError::LengthTooLarge(MAX_EXTRA_DATA_SIZE as u64 + 1))
+        );
+    }
+
+    #[test]
+    fn test_header_truncated() {
+        let bytes = test_header().to_bytes();
+        assert_eq!(
+            BlockHeader::from_bytes(&bytes[..BLOCK_HEADER_SIZE - 1]),
+            Err(DecodeError::UnexpectedEof)
+        );
+    }
+
+    #[test]
+    fn test_block_roundtrip() {
+        let header = test_header();
+        let block = Block::new(7, header.hash(), header, vec![]);
+        let decoded = Block::from_bytes(&block.to_bytes()).unwrap();
+        assert_eq!(decoded, block);
+    }
+
+    #[test]
+    fn test_merkle_root_commits_to_txids() {
+        let transactions: Vec<Transaction> = (0..3u32)
+            .map(|i| {
+                let mut tx = Transaction::new(vec![], vec![]);
+                tx.hash = U256::from(i);
+                tx
+            })
+            .collect();
+        let header = test_header();
+        let mut block = Block::new(1, header.hash(), header, transactions);
+
+        let leaves: Vec<[u8; 32]> = (0..3u32).map(|i| U256::from(i).to_le_bytes()).collect();
+        let root = block.compute_merkle_root();
+        assert_eq!(Some(&root), MerkleTree::new(&leaves).get_root());
+
+        block.transactions[2].hash = U256::from(3u32);
+        assert_ne!(block.compute_merkle_root(), root);
+    }
+
+    #[test]
+    fn test_empty_merkle_root() {
+        let header = test_header();
+        let block = Block::new(0, header.hash(), header, vec![]);
+        assert_eq!(block.compute_merkle_root(), U256::ZERO);
     }
 }
@@ -1,158 +1,640 @@
-use crate::{hash_to_u256, DigestWrapper, Hash, U256Def};
-use ethnum::{AsU256, U256};
-use ring::{
-    digest,
-    signature::{Ed25519KeyPair, KeyPair, Signature, UnparsedPublicKey},
+use crate::{
+    decode_list, decode_varint, encode_list, encode_varint, hash_to_u256, take_array, Decode,
+    DecodeError, Encode, Hash, U256Def,
 };
+use ed25519_dalek::Signature;
+use ethnum::{AsU256, U256};
+use ring::signature::{Ed25519KeyPair, KeyPair, UnparsedPublicKey, ED25519};
 use serde::{Deserialize, Serialize};
 
-/** An input of a transaction. It contains the location of the previous
- * transaction's output that it claims and a signature that matches the
- * output's public key.
+/// The newest transaction format this node can decode.
+pub const TRANSACTION_VERSION: u32 = 2;
+/// Transactions from this version on opt in to be replaced in the mempool by
+/// a conflicting transaction paying more, like BIP 125. The encoding is the
+/// same as version 1.
+pub const REPLACEABLE_VERSION: u32 = 2;
+
+/// Encoded in place of an output index when an input doesn't spend anything.
+const NULL_OUTPUT_INDEX: u32 = u32::MAX;
+
+/// A reference to a single output of a previous transaction.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct OutPoint {
+    #[serde(with = "U256Def")]
+    pub txid: U256,
+    pub vout: u32,
+}
+
+impl OutPoint {
+    pub fn new(txid: U256, vout: u32) -> Self {
+        OutPoint { txid, vout }
+    }
+
+    /// The outpoint encoded by inputs that don't spend a previous output.
+    pub fn null() -> Self {
+        OutPoint {
+            txid: U256::ZERO,
+            vout: NULL_OUTPUT_INDEX,
+        }
+    }
+
+    pub fn is_null(&self) -> bool {
+        *self == OutPoint::null()
+    }
+}
+
+impl Encode for OutPoint {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.txid.encode(out);
+        self.vout.encode(out);
+    }
+}
+
+impl Decode for OutPoint {
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        Ok(OutPoint {
+            txid: U256::decode(input)?,
+            vout: u32::decode(input)?,
+        })
+    }
+}
+
+/// Hash of an ed25519 public key, which is what outputs are locked to.
+pub fn pub_key_hash(pub_key: &[u8]) -> U256 {
+    hash_to_u256!(pub_key)
+}
+
+/** An input of a transaction. A coinbase input creates new coins and commits
+ * to the height of its block, any other input claims the output of a previous
+ * transaction and unlocks it with a signature of the output's owner.
  */
-#[derive(Serialize, Deserialize, Clone, Debug)]
-pub struct UtxoInput {
-    prev_output: Option<usize>,
-    sig: DigestWrapper,
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum UtxoInput {
+    Coinbase {
+        height: u64,
+        extra_nonce: u64,
+    },
+    Spend {
+        prev_output: OutPoint,
+        pub_key: [u8; 32],
+        signature: Option<Signature>,
+    },
+}
+
+impl UtxoInput {
+    /// The only input of a coinbase transaction. `extra_nonce` is free for
+    /// miners to change when the header nonce space is exhausted.
+    pub fn coinbase(height: u64, extra_nonce: u64) -> Self {
+        UtxoInput::Coinbase {
+            height,
+            extra_nonce,
+        }
+    }
+
+    /// An unsigned input spending `prev_output`, which must be locked to the
+    /// hash of `pub_key`.
+    pub fn spend(prev_output: OutPoint, pub_key: [u8; 32]) -> Self {
+        UtxoInput::Spend {
+            prev_output,
+            pub_key,
+            signature: None,
+        }
+    }
+
+    pub fn is_coinbase(&self) -> bool {
+        matches!(self, UtxoInput::Coinbase { .. })
+    }
+
+    pub fn prev_output(&self) -> Option<&OutPoint> {
+        match self {
+            UtxoInput::Coinbase { .. } => None,
+            UtxoInput::Spend { prev_output, .. } => Some(prev_output),
+        }
+    }
+
+    pub fn pub_key(&self) -> Option<&[u8; 32]> {
+        match self {
+            UtxoInput::Coinbase { .. } => None,
+            UtxoInput::Spend { pub_key, .. } => Some(pub_key),
+        }
+    }
+
+    pub fn signature(&self) -> Option<&Signature> {
+        match self {
+            UtxoInput::Coinbase { .. } => None,
+            UtxoInput::Spend { signature, .. } => signature.as_ref(),
+        }
+    }
+
+    pub fn coinbase_height(&self) -> Option<u64> {
+        match self {
+            UtxoInput::Coinbase { height, .. } => Some(*height),
+            UtxoInput::Spend { .. } => None,
+        }
+    }
+
+    pub fn extra_nonce(&self) -> Option<u64> {
+        match self {
+            UtxoInput::Coinbase { extra_nonce, .. } => Some(*extra_nonce),
+            UtxoInput::Spend { .. } => None,
+        }
+    }
+
+    fn encode_unsigned(&self, out: &mut Vec<u8>) {
+        match self {
+            UtxoInput::Coinbase {
+                height,
+                extra_nonce,
+            } => {
+                OutPoint::null().encode(out);
+                encode_varint(*height, out);
+                extra_nonce.encode(out);
+            }
+            UtxoInput::Spend {
+                prev_output,
+                pub_key,
+                ..
+            } => {
+                prev_output.encode(out);
+                out.extend_from_slice(pub_key);
+            }
+        }
+    }
+}
+
+impl Encode for UtxoInput {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.encode_unsigned(out);
+
+        if let UtxoInput::Spend { signature, .. } = self {
+            match signature {
+                Some(signature) => {
+                    out.push(1);
+                    out.extend_from_slice(&signature.to_bytes());
+                }
+                None => out.push(0),
+            }
+        }
+    }
 }
 
-/** An output of a transaction. It contains the public key that the next input
- * must be able to sign with to claim it.
+impl Decode for UtxoInput {
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        let prev_output = OutPoint::decode(input)?;
+
+        if prev_output.is_null() {
+            return Ok(UtxoInput::Coinbase {
+                height: decode_varint(input)?,
+                extra_nonce: u64::decode(input)?,
+            });
+        }
+
+        let pub_key = take_array(input)?;
+        let signature = match u8::decode(input)? {
+            0 => None,
+            1 => Some(Signature::from_bytes(&take_array(input)?)),
+            _ => return Err(DecodeError::InvalidSignature),
+        };
+
+        Ok(UtxoInput::Spend {
+            prev_output,
+            pub_key,
+            signature,
+        })
+    }
+}
+
+/** An output of a transaction. It contains the hash of the public key that
+ * the next input must be able to sign with to claim it.
  */
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub struct UtxoOutput {
-    pk: DigestWrapper,
-    value: u32,
+    #[serde(with = "U256Def")]
+    pk: U256,
+    value: u64,
+}
+
+impl UtxoOutput {
+    pub fn new(pk: U256, value: u64) -> Self {
+        UtxoOutput { pk, value }
+    }
+
+    /// An output that can be spent by the owner of `pub_key`.
+    pub fn to_pub_key(pub_key: &[u8], value: u64) -> Self {
+        UtxoOutput::new(pub_key_hash(pub_key), value)
+    }
+
+    pub fn pk(&self) -> &U256 {
+        &self.pk
+    }
+
+    pub fn value(&self) -> u64 {
+        self.value
+    }
+
+    pub fn is_unlocked_by(&self, pub_key: &[u8]) -> bool {
+        pub_key_hash(pub_key) == self.pk
+    }
+}
+
+impl Encode for UtxoOutput {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.pk.encode(out);
+        self.value.encode(out);
+    }
+}
+
+impl Decode for UtxoOutput {
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        Ok(UtxoOutput {
+            pk: U256::decode(input)?,
+            value: u64::decode(input)?,
+        })
+    }
 }
 
 /** The basic transaction that is broadcasted on the network and contained in
  * blocks. A transaction can contain multiple inputs and outputs.
  */
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct Transaction {
     pub version: u32,
     pub inputs: Vec<UtxoInput>,
     pub outputs: Vec<UtxoOutput>,
     #[serde(with = "U256Def")]
     pub hash: U256,
-    pub sig: Option<Signature>,
 }
 
-// pub struct Transaction {
-//     pub version: u32,
-//     pub sig: Option<Signature>,
-//     pub amount: u32,
-//     pub from: digest::Digest,
-//     pub to: digest::Digest,
-// }
-
 impl Transaction {
-    // pub fn new(from_keypair: &Ed25519KeyPair, to_pubkey: digest::Digest, amount: u32) -> Self {
-    //     // Get the public key
-    //     let pubkey_bytes = from_keypair.public_key().as_ref();
-
-    //     // Represent the public key as a digest
-    //     let pubkey_digest = digest::digest(&digest::SHA256, pubkey_bytes);
-
-    //     let mut tx = Transaction {
-    //         version: 1,
-    //         from: pubkey_digest,
-    //         to: to_pubkey,
-    //         amount,
-    //         sig: None,
-    //     };
-    //     tx.sign(from_keypair);
-
-    //     tx
-    // }
-
-    pub fn new(
-        from_keypair: &Ed25519KeyPair,
-        inputs: Vec<UtxoInput>,
-        outputs: Vec<UtxoOutput>,
-    ) -> Self {
-        // Get the public key
-        let pubkey_bytes = from_keypair.public_key().as_ref();
-
-        // Represent the public key as a digest
-        let pubkey_digest = digest::digest(&digest::SHA256, pubkey_bytes);
+    /// Creates an unsigned transaction, inputs are signed with `sign`.
+    pub fn new(inputs: Vec<UtxoInput>, outputs: Vec<UtxoOutput>) -> Self {
+        Transaction::with_version(1, inputs, outputs)
+    }
+
+    /// Creates an unsigned transaction that can be replaced while it is
+    /// unconfirmed, see `REPLACEABLE_VERSION`.
+    pub fn replaceable(inputs: Vec<UtxoInput>, outputs: Vec<UtxoOutput>) -> Self {
+        Transaction::with_version(REPLACEABLE_VERSION, inputs, outputs)
+    }
 
+    fn with_version(version: u32, inputs: Vec<UtxoInput>, outputs: Vec<UtxoOutput>) -> Self {
         let mut tx = Transaction {
-            version: 1,
+            version,
             inputs,
             outputs,
             hash: 0.as_u256(),
-            sig: None,
         };
         tx.hash = tx.hash();
-        tx.sign(from_keypair);
 
         tx
     }
 
+    pub fn coinbase(height: u64, extra_nonce: u64, outputs: Vec<UtxoOutput>) -> Self {
+        Transaction::new(vec![UtxoInput::coinbase(height, extra_nonce)], outputs)
+    }
+
     pub fn is_coinbase(&self) -> bool {
-        self.inputs.len() == 1 && self.inputs[0].prev_output.is_none()
+        self.inputs.len() == 1 && self.inputs[0].is_coinbase()
     }
 
-    // TODO: move validation logic outside
-    // pub fn is_valid(&self) -> bool {
-    //     self.sig.is_some() && self.amount > 0
-    // }
+    pub fn is_replaceable(&self) -> bool {
+        self.version >= REPLACEABLE_VERSION
+    }
 
-    pub fn sign(&mut self, keypair: &Ed25519KeyPair) {
-        self.sig = Some(keypair.sign(&self.as_bytes()));
+    /// Replaces the extra nonce of a coinbase and updates the hash, other
+    /// transactions are left alone.
+    pub fn set_extra_nonce(&mut self, extra_nonce: u64) {
+        if let [UtxoInput::Coinbase {
+            extra_nonce: current,
+            ..
+        }] = self.inputs.as_mut_slice()
+        {
+            *current = extra_nonce;
+            self.hash = self.hash();
+        }
     }
 
-    pub fn verify(&self, pub_key: UnparsedPublicKey<&[u8]>, temp_keypair: &Ed25519KeyPair) -> bool {
-        if let Some(signature) = self.sig {
-            // TODO: think about cloning, maybe there is a way to avoid the cloning (to make sig None)
-            let mut temp_sig = self.clone();
-            temp_sig.sig = None;
+    /// Signs every input that is unlocked by `keypair`.
+    pub fn sign(&mut self, keypair: &Ed25519KeyPair) {
+        let pub_key = keypair.public_key().as_ref();
 
-            match pub_key.verify(&temp_sig.as_bytes(), signature.as_ref()) {
-                Ok(_) => return true,
-                Err(err) => {
-                    eprintln!("Error: {:?}", err);
-                    return false;
-                }
+        for index in 0..self.inputs.len() {
+            if self.inputs[index].pub_key().map(|key| key.as_slice()) == Some(pub_key) {
+                self.sign_input(index, keypair);
             }
         }
+    }
+
+    pub fn sign_input(&mut self, index: usize, keypair: &Ed25519KeyPair) {
+        let message = self.signature_message(index);
+
+        if let UtxoInput::Spend { signature, .. } = &mut self.inputs[index] {
+            let signed = keypair.sign(&message);
+            *signature = Some(
+                Signature::from_slice(signed.as_ref()).expect("Ed25519 signature to be 64 bytes"),
+            );
+        }
+    }
+
+    /// Checks the signature of a single input against the key it reveals.
+    /// Whether that key owns the spent output is up to the caller.
+    pub fn verify_input(&self, index: usize) -> bool {
+        let Some(UtxoInput::Spend {
+            pub_key,
+            signature: Some(signature),
+            ..
+        }) = self.inputs.get(index)
+        else {
+            return false;
+        };
+
+        UnparsedPublicKey::new(&ED25519, pub_key)
+            .verify(&self.signature_message(index), &signature.to_bytes())
+            .is_ok()
+    }
+
+    /// Checks the signatures of all non-coinbase inputs.
+    pub fn verify(&self) -> bool {
+        self.is_coinbase() || (0..self.inputs.len()).all(|index| self.verify_input(index))
+    }
 
-        false
+    /// The message signed by input `index`: the transaction without any
+    /// signatures followed by the input index.
+    pub fn signature_message(&self, index: usize) -> Vec<u8> {
+        let mut message = self.as_bytes();
+        (index as u32).encode(&mut message);
+        message
     }
 
+    /// The canonical encoding of the transaction without signatures. The
+    /// transaction id commits to the same bytes, so signing doesn't change it.
     pub fn as_bytes(&self) -> Vec<u8> {
-        format!("{:?}", self).into_bytes()
+        let mut out = Vec::new();
+        self.version.encode(&mut out);
+        encode_varint(self.inputs.len() as u64, &mut out);
+        for input in &self.inputs {
+            input.encode_unsigned(&mut out);
+        }
+        encode_list(&self.outputs, &mut out);
+        out
     }
 }
 
-impl std::fmt::Debug for Transaction {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let mut sig = String::with_capacity(0);
+impl Encode for Transaction {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.version.encode(out);
+        encode_list(&self.inputs, out);
+        encode_list(&self.outputs, out);
+    }
+}
 
-        if let Some(ref signature) = self.sig {
-            sig = signature
-                .as_ref()
-                .iter()
-                .map(|c| format!("{:x}", c))
-                .collect::<String>();
+impl Decode for Transaction {
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        let version = u32::decode(input)?;
+        if version == 0 || version > TRANSACTION_VERSION {
+            return Err(DecodeError::UnsupportedVersion(version));
         }
 
-        // write!(
-        //     f,
-        //     "\nFrom: {:?}\nTo: {:?}\nAmount: {}\nSignature: {:?}\n",
-        //     self.from, self.to, self.amount, sig
-        // )
+        let mut tx = Transaction {
+            version,
+            inputs: decode_list(input)?,
+            outputs: decode_list(input)?,
+            hash: 0.as_u256(),
+        };
+        tx.hash = tx.hash();
+
+        Ok(tx)
+    }
+}
+
+impl std::fmt::Debug for Transaction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "Inputs: {:?}\nOtputs: {:?}\nSignature: {:?}\n",
-            self.inputs, self.outputs, sig
+            "Hash: {:#064x}, Inputs: {:?}, Outputs: {:?}",
+            self.hash, self.inputs, self.outputs
         )
     }
 }
 
 impl Hash for Transaction {
     fn hash(&self) -> U256 {
-        hash_to_u256!(format!("{:?}", self).as_bytes())
+        hash_to_u256!(self.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn test_keypair() -> Ed25519KeyPair {
+        Ed25519KeyPair::from_seed_unchecked(&[7u8; 32]).unwrap()
+    }
+
+    fn test_pub_key() -> [u8; 32] {
+        test_keypair().public_key().as_ref().try_into().unwrap()
+    }
+
+    fn test_transaction() -> Transaction {
+        let mut tx = Transaction::new(
+            vec![UtxoInput::spend(
+                OutPoint::new(U256::from(0xccu32), 1),
+                test_pub_key(),
+            )],
+            vec![UtxoOutput::new(U256::from(0xbbu32), 50)],
+        );
+        tx.sign(&test_keypair());
+        tx
+    }
+
+    fn golden_transaction() -> Transaction {
+        Transaction::new(
+            vec![UtxoInput::spend(
+                OutPoint::new(U256::from(0xccu32), 1),
+                [0xaa; 32],
+            )],
+            vec![UtxoOutput::new(U256::from(0xbbu32), 50)],
+        )
+    }
+
+    #[test]
+    fn test_unsigned_encoding_golden() {
+        // Golden vector: version, one input (outpoint txid and index 1,
+        // 32 byte public key), one output (32 byte key hash, value 50).
+        let input = [
+            "cc00000000000000000000000000000000000000000000000000000000000000",
+            "01000000",
+            "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa",
+        ]
+        .concat();
+        let output = [
+            "bb00000000000000000000000000000000000000000000000000000000000000",
+            "3200000000000000",
+        ]
+        .concat();
+        let tx = golden_transaction();
+        let unsigned = ["01000000", "01", &input, "01", &output].concat();
+        assert_eq!(hex::encode(tx.as_bytes()), unsigned);
+
+        // The full encoding adds the signature presence flag to the input.
+        let full = ["01000000", "01", &input, "00", "01", &output].concat();
+        assert_eq!(hex::encode(tx.to_bytes()), full);
+    }
+
+    #[test]
+    fn test_hash_golden() {
+        let tx = golden_transaction();
+        // This golden hash will need to be updated whenever the transaction
+        // encoding changes, which is a consensus change.
+        let expected = U256::from_str_hex(
+            "0x9ce7185985da117efdfca9f35f25c77715e6c8ccd5618502512a38c953dc53f0",
+        )
+        .unwrap();
+        assert_eq!(tx.hash, expected);
+        assert_eq!(tx.hash(), expected);
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let tx = test_transaction();
+        let bytes = tx.to_bytes();
+        assert_eq!(bytes.len(), tx.as_bytes().len() + 1 + 64);
+        assert_eq!(Transaction::from_bytes(&bytes), Ok(tx));
+    }
+
+    #[test]
+    fn test_signature_does_not_change_hash() {
+        let tx = test_transaction();
+        let mut unsigned = tx.clone();
+        unsigned.inputs[0] = UtxoInput::spend(*tx.inputs[0].prev_output().unwrap(), test_pub_key());
+        assert_eq!(unsigned.hash(), tx.hash);
+    }
+
+    #[test]
+    fn test_verify() {
+        let tx = test_transaction();
+        assert!(tx.verify_input(0));
+        assert!(tx.verify());
+
+        let mut tampered = tx.clone();
+        tampered.outputs[0] = UtxoOutput::new(U256::from(0xbbu32), 51);
+        assert!(!tampered.verify());
+    }
+
+    #[test]
+    fn test_signatures_are_bound_to_their_input() {
+        let mut tx = test_transaction();
+        tx.inputs.push(tx.inputs[0].clone());
+        tx.inputs[1] = UtxoInput::spend(OutPoint::new(U256::from(0xddu32), 0), test_pub_key());
+        tx.sign(&test_keypair());
+        assert!(tx.verify());
+
+        // Swapping the signatures of two inputs must invalidate both.
+        let first = tx.inputs[0].signature().copied();
+        let second = tx.inputs[1].signature().copied();
+        if let UtxoInput::Spend { signature, .. } = &mut tx.inputs[0] {
+            *signature = second;
+        }
+        if let UtxoInput::Spend { signature, .. } = &mut tx.inputs[1] {
+            *signature = first;
+        }
+        assert!(!tx.verify_input(0));
+        assert!(!tx.verify_input(1));
+    }
+
+    #[test]
+    fn test_unsigned_input_does_not_verify() {
+        let tx = Transaction::new(
+            vec![UtxoInput::spend(
+                OutPoint::new(U256::ONE, 0),
+                test_pub_key(),
+            )],
+            vec![],
+        );
+        assert!(!tx.verify());
+    }
+
+    #[test]
+    fn test_output_lock() {
+        let output = UtxoOutput::to_pub_key(&test_pub_key(), 10);
+        assert!(output.is_unlocked_by(&test_pub_key()));
+        assert!(!output.is_unlocked_by(&[0xaa; 32]));
+    }
+
+    #[test]
+    fn test_coinbase_encoding() {
+        let tx = Transaction::coinbase(300, 7, vec![UtxoOutput::new(U256::ONE, 50)]);
+        assert!(tx.is_coinbase());
+        assert!(tx.verify());
+        assert_eq!(tx.inputs[0].coinbase_height(), Some(300));
+        assert_eq!(tx.inputs[0].extra_nonce(), Some(7));
+
+        let bytes = tx.inputs[0].to_bytes();
+        let expected = [
+            "0000000000000000000000000000000000000000000000000000000000000000",
+            "ffffffff",
+            "fd2c01",
+            "0700000000000000",
+        ]
+        .concat();
+        assert_eq!(hex::encode(&bytes), expected);
+        assert_eq!(Transaction::from_bytes(&tx.to_bytes()), Ok(tx));
+    }
+
+    #[test]
+    fn test_coinbase_commits_to_height() {
+        let first = Transaction::coinbase(1, 0, vec![UtxoOutput::new(U256::ONE, 50)]);
+        let second = Transaction::coinbase(2, 0, vec![UtxoOutput::new(U256::ONE, 50)]);
+        assert_ne!(first.hash, second.hash);
+    }
+
+    #[test]
+    fn test_set_extra_nonce() {
+        let mut tx = Transaction::coinbase(1, 0, vec![UtxoOutput::new(U256::ONE, 50)]);
+        tx.set_extra_nonce(9);
+        assert_eq!(
+            tx,
+            Transaction::coinbase(1, 9, vec![UtxoOutput::new(U256::ONE, 50)])
+        );
+
+        let mut spend = test_transaction();
+        let hash = spend.hash;
+        spend.set_extra_nonce(9);
+        assert_eq!(spend.hash, hash);
+    }
+
+    #[test]
+    fn test_replaceable_transaction() {
+        let golden = golden_transaction();
+        assert!(!golden.is_replaceable());
+
+        let tx = Transaction::replaceable(golden.inputs.clone(), golden.outputs.clone());
+        assert!(tx.is_replaceable());
+        assert_ne!(tx.hash, golden.hash);
+        assert_eq!(tx.to_bytes()[4..], golden.to_bytes()[4..]);
+        assert_eq!(Transaction::from_bytes(&tx.to_bytes()), Ok(tx));
+    }
+
+    #[test]
+    fn test_rejects_unknown_version() {
+        let mut tx = test_transaction();
+        tx.version = TRANSACTION_VERSION + 1;
+        assert_eq!(
+            Transaction::from_bytes(&tx.to_bytes()),
+            Err(DecodeError::UnsupportedVersion(TRANSACTION_VERSION + 1))
+        );
+    }
+
+    #[test]
+    fn test_rejects_bad_signature_flag() {
+        let tx = test_transaction();
+        let mut bytes = tx.to_bytes();
+        // The flag directly follows the outpoint and public key of the input.
+        let flag = 4 + 1 + 36 + 32;
+        bytes[flag] = 2;
+        assert_eq!(
+            Transaction::from_bytes(&bytes),
+            Err(DecodeError::InvalidSignature)
+        );
     }
 }
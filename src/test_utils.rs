@@ -0,0 +1,78 @@
+//! Fixtures shared by the unit tests.
+
+use crate::{
+    Block, BlockHeader, BlockTemplateBuilder, Chain, ConsensusEngine, DifficultyAlgorithm, Hash,
+    SystemClock, TinyBlockchain, TinyBlockchainParams, Transaction, UtxoOutput, COINBASE_MATURITY,
+};
+use ethnum::U256;
+
+/// The easiest target, blocks are sealed on the first few nonces.
+pub const TEST_BITS: u32 = 0x207fffff;
+
+/// Params whose difficulty stays at `TEST_BITS`.
+pub fn test_params() -> TinyBlockchainParams {
+    TinyBlockchainParams {
+        blocks_in_epoch: 1_000_000,
+        init_difficulty: TEST_BITS,
+        target_spacing: 600,
+        max_adjustment: 4,
+        pow_limit: TEST_BITS,
+        difficulty: DifficultyAlgorithm::Epoch,
+        max_future_drift: 2 * 60 * 60,
+    }
+}
+
+/// A genesis block without transactions.
+pub fn test_genesis() -> Block {
+    genesis(vec![])
+}
+
+/// A blockchain on `test_genesis` with `test_params`.
+pub fn test_blockchain() -> TinyBlockchain {
+    blockchain(test_genesis())
+}
+
+/// A blockchain whose genesis coinbase pays `outputs`, mined past coinbase
+/// maturity so that they can be spent.
+pub fn mature_blockchain(outputs: Vec<UtxoOutput>) -> TinyBlockchain {
+    let mut blockchain = blockchain(genesis(vec![Transaction::coinbase(0, 0, outputs)]));
+    for _ in 0..COINBASE_MATURITY {
+        let block = mine_block(&blockchain, BlockTemplateBuilder::new(U256::ONE));
+        blockchain.add_block(block).unwrap();
+    }
+    blockchain
+}
+
+/// Builds the next block of `blockchain` from `builder` and seals it.
+pub fn mine_block(blockchain: &TinyBlockchain, builder: BlockTemplateBuilder) -> Block {
+    let mut block = builder.build(blockchain).block;
+    blockchain
+        .engine()
+        .seal(&mut block, &SystemClock)
+        .expect("Seal block");
+    block
+}
+
+fn genesis(transactions: Vec<Transaction>) -> Block {
+    let header = BlockHeader {
+        version: 1,
+        timestamp: 1_700_000_000,
+        prev: U256::ZERO,
+        merkle_root: U256::ZERO,
+        bits: TEST_BITS,
+        nonce: 0,
+        extra: Vec::new(),
+    };
+    let mut genesis = Block::new(0, U256::ZERO, header, transactions);
+    genesis.header.merkle_root = genesis.compute_merkle_root();
+    genesis.header_hash = genesis.header.hash();
+    genesis
+}
+
+fn blockchain(genesis: Block) -> TinyBlockchain {
+    let chain = Chain {
+        items: vec![genesis],
+        last_update: 0,
+    };
+    TinyBlockchain::new(chain, test_params())
+}
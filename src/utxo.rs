@@ -0,0 +1,470 @@
+use crate::{Block, OutPoint, Transaction, UtxoOutput};
+use ethnum::U256;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+/// An unspent output together with the data needed to validate its spend.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UtxoEntry {
+    pub output: UtxoOutput,
+    pub height: usize,
+    pub is_coinbase: bool,
+}
+
+/// The outputs spent by a block, in spend order, so that the block can be
+/// disconnected again during a reorganization.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockUndo {
+    pub spent: Vec<(OutPoint, UtxoEntry)>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum UtxoError {
+    /// The input refers to an output that doesn't exist or is already spent.
+    MissingInput(OutPoint),
+    /// Two inputs in the same block spend the same output.
+    DoubleSpend(OutPoint),
+    /// The transaction creates more value than its inputs provide.
+    Overspend {
+        txid: U256,
+        inputs: u64,
+        outputs: u64,
+    },
+    /// The transaction would overwrite outputs that are still unspent.
+    DuplicateTransaction(U256),
+    /// The undo data doesn't belong to the block being disconnected.
+    UndoMismatch(OutPoint),
+}
+
+impl std::fmt::Display for UtxoError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UtxoError::MissingInput(outpoint) => write!(
+                f,
+                "input {:#064x}:{} is missing or spent",
+                outpoint.txid, outpoint.vout
+            ),
+            UtxoError::DoubleSpend(outpoint) => write!(
+                f,
+                "output {:#064x}:{} is spent twice",
+                outpoint.txid, outpoint.vout
+            ),
+            UtxoError::Overspend {
+                txid,
+                inputs,
+                outputs,
+            } => write!(
+                f,
+                "transaction {:#064x} spends {} but creates {}",
+                txid, inputs, outputs
+            ),
+            UtxoError::DuplicateTransaction(txid) => {
+                write!(f, "transaction {:#064x} already has unspent outputs", txid)
+            }
+            UtxoError::UndoMismatch(outpoint) => write!(
+                f,
+                "undo data for {:#064x}:{} doesn't match the set",
+                outpoint.txid, outpoint.vout
+            ),
+        }
+    }
+}
+
+impl std::error::Error for UtxoError {}
+
+/// The set of all unspent transaction outputs, keyed by outpoint.
+#[derive(Clone, Debug, Default)]
+pub struct UtxoSet {
+    entries: HashMap<OutPoint, UtxoEntry>,
+    best_block: U256,
+}
+
+impl UtxoSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, outpoint: &OutPoint) -> Option<&UtxoEntry> {
+        self.entries.get(outpoint)
+    }
+
+    pub fn contains(&self, outpoint: &OutPoint) -> bool {
+        self.entries.contains_key(outpoint)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Hash of the last block applied to the set.
+    pub fn best_block(&self) -> U256 {
+        self.best_block
+    }
+
+    /// Sum of the values of the outputs spent by `tx`. Only outputs already
+    /// in the set are considered, so this is meant for loose transactions.
+    pub fn input_value(&self, tx: &Transaction) -> Result<u64, UtxoError> {
+        tx.inputs
+            .iter()
+            .filter_map(|input| input.prev_output())
+            .try_fold(0, |sum, outpoint| {
+                let entry = self
+                    .get(outpoint)
+                    .ok_or(UtxoError::MissingInput(*outpoint))?;
+                Ok(sum + entry.output.value())
+            })
+    }
+
+    /// Spends the inputs and adds the outputs of every transaction in the
+    /// block. Either the whole block is applied or, on error, the set is left
+    /// untouched.
+    pub fn apply_block(&mut self, block: &Block) -> Result<BlockUndo, UtxoError> {
+        let mut view = UtxoView::new(self);
+
+        for tx in &block.transactions {
+            view.apply_transaction(tx, block.height)?;
+        }
+
+        let changes = view.into_changes();
+        Ok(self.commit(changes, block.header_hash))
+    }
+
+    /// Writes changes staged in a `UtxoView` to the set.
+    pub fn commit(&mut self, changes: UtxoChanges, best_block: U256) -> BlockUndo {
+        for (outpoint, _) in &changes.spent {
+            self.entries.remove(outpoint);
+        }
+        for (outpoint, entry) in changes.created {
+            self.entries.insert(outpoint, entry);
+        }
+        self.best_block = best_block;
+
+        BlockUndo {
+            spent: changes.spent,
+        }
+    }
+
+    /// Reverts `apply_block` for the current tip using its undo data.
+    pub fn undo_block(&mut self, block: &Block, undo: &BlockUndo) -> Result<(), UtxoError> {
+        // Validate first so a wrong block or undo leaves the set untouched.
+        for (outpoint, _) in &undo.spent {
+            if self.entries.contains_key(outpoint) {
+                return Err(UtxoError::UndoMismatch(*outpoint));
+            }
+        }
+
+        for tx in block.transactions.iter().rev() {
+            for vout in 0..tx.outputs.len() {
+                self.entries.remove(&OutPoint::new(tx.hash, vout as u32));
+            }
+        }
+        for (outpoint, entry) in undo.spent.iter().rev() {
+            self.entries.insert(*outpoint, entry.clone());
+        }
+        self.best_block = block.header.prev;
+
+        Ok(())
+    }
+}
+
+/// Changes staged by a `UtxoView`, ready to be committed to the set.
+#[derive(Debug, Default)]
+pub struct UtxoChanges {
+    spent: Vec<(OutPoint, UtxoEntry)>,
+    created: Vec<(OutPoint, UtxoEntry)>,
+}
+
+/// Staged changes of a block on top of a `UtxoSet`, so that applying the
+/// block can fail halfway without corrupting the set.
+pub struct UtxoView<'a> {
+    base: &'a UtxoSet,
+    spent: Vec<(OutPoint, UtxoEntry)>,
+    spent_base: HashSet<OutPoint>,
+    created: HashMap<OutPoint, UtxoEntry>,
+    spent_created: HashSet<OutPoint>,
+}
+
+impl<'a> UtxoView<'a> {
+    pub fn new(base: &'a UtxoSet) -> Self {
+        UtxoView {
+            base,
+            spent: Vec::new(),
+            spent_base: HashSet::new(),
+            created: HashMap::new(),
+            spent_created: HashSet::new(),
+        }
+    }
+
+    /// Marks `outpoint` as spent and returns the entry it refers to. Outputs
+    /// created earlier in the view can be spent as well.
+    pub fn spend(&mut self, outpoint: &OutPoint) -> Result<UtxoEntry, UtxoError> {
+        if self.spent_base.contains(outpoint) || self.spent_created.contains(outpoint) {
+            return Err(UtxoError::DoubleSpend(*outpoint));
+        }
+
+        if let Some(entry) = self.created.get(outpoint) {
+            self.spent_created.insert(*outpoint);
+            return Ok(entry.clone());
+        }
+
+        let entry = self
+            .base
+            .get(outpoint)
+            .ok_or(UtxoError::MissingInput(*outpoint))?;
+        self.spent_base.insert(*outpoint);
+        self.spent.push((*outpoint, entry.clone()));
+
+        Ok(entry.clone())
+    }
+
+    /// Adds the outputs of `tx`, confirmed at `height`, to the view.
+    pub fn add_outputs(&mut self, tx: &Transaction, height: usize) -> Result<(), UtxoError> {
+        let is_coinbase = tx.is_coinbase();
+
+        for (vout, output) in tx.outputs.iter().enumerate() {
+            let outpoint = OutPoint::new(tx.hash, vout as u32);
+            if self.base.contains(&outpoint) || self.created.contains_key(&outpoint) {
+                return Err(UtxoError::DuplicateTransaction(tx.hash));
+            }
+
+            self.created.insert(
+                outpoint,
+                UtxoEntry {
+                    output: output.clone(),
+                    height,
+                    is_coinbase,
+                },
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Spends the inputs and adds the outputs of `tx`, returning the fee it
+    /// pays. A coinbase pays no fee.
+    pub fn apply_transaction(&mut self, tx: &Transaction, height: usize) -> Result<u64, UtxoError> {
+        let mut input_value = 0u64;
+        for input in &tx.inputs {
+            if let Some(outpoint) = input.prev_output() {
+                input_value += self.spend(outpoint)?.output.value();
+            }
+        }
+
+        let output_value: u64 = tx.outputs.iter().map(|o| o.value()).sum();
+        if !tx.is_coinbase() && output_value > input_value {
+            return Err(UtxoError::Overspend {
+                txid: tx.hash,
+                inputs: input_value,
+                outputs: output_value,
+            });
+        }
+
+        self.add_outputs(tx, height)?;
+
+        Ok(input_value.saturating_sub(output_value))
+    }
+
+    pub fn into_changes(self) -> UtxoChanges {
+        let UtxoView {
+            spent,
+            created,
+            spent_created,
+            ..
+        } = self;
+        let created = created
+            .into_iter()
+            .filter(|(outpoint, _)| !spent_created.contains(outpoint))
+            .collect();
+
+        UtxoChanges { spent, created }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{BlockHeader, Hash, UtxoInput};
+
+    fn tx(inputs: Vec<OutPoint>, values: &[u64], tag: u32) -> Transaction {
+        let outputs = values
+            .iter()
+            .map(|value| UtxoOutput::new(U256::from(tag), *value))
+            .collect();
+
+        if inputs.is_empty() {
+            return Transaction::coinbase(tag as u64, 0, outputs);
+        }
+
+        let inputs = inputs
+            .into_iter()
+            .map(|outpoint| UtxoInput::spend(outpoint, [0; 32]))
+            .collect();
+        Transaction::new(inputs, outputs)
+    }
+
+    fn block(height: usize, prev: U256, transactions: Vec<Transaction>) -> Block {
+        let header = BlockHeader {
+            version: 1,
+            timestamp: height as u64,
+            prev,
+            merkle_root: U256::ZERO,
+            bits: 0x207fffff,
+            nonce: 0,
+            extra: Vec::new(),
+        };
+        let mut block = Block::new(height, header.hash(), header, transactions);
+        block.header.merkle_root = block.compute_merkle_root();
+        block.header_hash = block.header.hash();
+        block
+    }
+
+    fn funded_set() -> (UtxoSet, Transaction, Block) {
+        let coinbase = tx(vec![], &[50, 25], 1);
+        let genesis = block(0, U256::ZERO, vec![coinbase.clone()]);
+        let mut set = UtxoSet::new();
+        set.apply_block(&genesis).unwrap();
+        (set, coinbase, genesis)
+    }
+
+    #[test]
+    fn test_apply_coinbase() {
+        let (set, coinbase, genesis) = funded_set();
+        assert_eq!(set.len(), 2);
+        assert_eq!(set.best_block(), genesis.header_hash);
+        let entry = set.get(&OutPoint::new(coinbase.hash, 0)).unwrap();
+        assert_eq!(entry.output.value(), 50);
+        assert!(entry.is_coinbase);
+        assert_eq!(entry.height, 0);
+    }
+
+    #[test]
+    fn test_apply_and_undo_spend() {
+        let (mut set, coinbase, genesis) = funded_set();
+        let spend = tx(vec![OutPoint::new(coinbase.hash, 0)], &[30, 20], 2);
+        let block = block(
+            1,
+            genesis.header_hash,
+            vec![tx(vec![], &[50], 3), spend.clone()],
+        );
+
+        let undo = set.apply_block(&block).unwrap();
+        assert_eq!(undo.spent.len(), 1);
+        assert!(!set.contains(&OutPoint::new(coinbase.hash, 0)));
+        assert!(set.contains(&OutPoint::new(spend.hash, 1)));
+        assert_eq!(set.len(), 4);
+
+        set.undo_block(&block, &undo).unwrap();
+        assert_eq!(set.len(), 2);
+        assert!(set.contains(&OutPoint::new(coinbase.hash, 0)));
+        assert!(!set.contains(&OutPoint::new(spend.hash, 0)));
+        assert_eq!(set.best_block(), genesis.header_hash);
+    }
+
+    #[test]
+    fn test_spend_output_created_in_same_block() {
+        let (mut set, coinbase, genesis) = funded_set();
+        let parent = tx(vec![OutPoint::new(coinbase.hash, 0)], &[40], 2);
+        let child = tx(vec![OutPoint::new(parent.hash, 0)], &[35], 3);
+        let block = block(1, genesis.header_hash, vec![parent.clone(), child.clone()]);
+
+        let undo = set.apply_block(&block).unwrap();
+        assert!(!set.contains(&OutPoint::new(parent.hash, 0)));
+        assert!(set.contains(&OutPoint::new(child.hash, 0)));
+
+        set.undo_block(&block, &undo).unwrap();
+        assert!(set.contains(&OutPoint::new(coinbase.hash, 0)));
+        assert!(!set.contains(&OutPoint::new(child.hash, 0)));
+        assert_eq!(set.len(), 2);
+    }
+
+    #[test]
+    fn test_rejects_double_spend_in_block() {
+        let (mut set, coinbase, genesis) = funded_set();
+        let outpoint = OutPoint::new(coinbase.hash, 0);
+        let first = tx(vec![outpoint], &[10], 2);
+        let second = tx(vec![outpoint], &[20], 3);
+        let block = block(1, genesis.header_hash, vec![first.clone(), second]);
+
+        assert_eq!(
+            set.apply_block(&block),
+            Err(UtxoError::DoubleSpend(outpoint))
+        );
+        // The failed block must not leave partial changes behind.
+        assert!(set.contains(&outpoint));
+        assert!(!set.contains(&OutPoint::new(first.hash, 0)));
+        assert_eq!(set.best_block(), genesis.header_hash);
+    }
+
+    #[test]
+    fn test_rejects_spent_output() {
+        let (mut set, coinbase, genesis) = funded_set();
+        let outpoint = OutPoint::new(coinbase.hash, 0);
+        let first = block(1, genesis.header_hash, vec![tx(vec![outpoint], &[10], 2)]);
+        set.apply_block(&first).unwrap();
+
+        let second = block(2, first.header_hash, vec![tx(vec![outpoint], &[10], 3)]);
+        assert_eq!(
+            set.apply_block(&second),
+            Err(UtxoError::MissingInput(outpoint))
+        );
+    }
+
+    #[test]
+    fn test_rejects_overspend() {
+        let (mut set, coinbase, genesis) = funded_set();
+        let spend = tx(vec![OutPoint::new(coinbase.hash, 1)], &[26], 2);
+        let block = block(1, genesis.header_hash, vec![spend.clone()]);
+
+        assert_eq!(
+            set.apply_block(&block),
+            Err(UtxoError::Overspend {
+                txid: spend.hash,
+                inputs: 25,
+                outputs: 26,
+            })
+        );
+        assert_eq!(set.len(), 2);
+    }
+
+    #[test]
+    fn test_rejects_duplicate_transaction() {
+        let (mut set, coinbase, genesis) = funded_set();
+        let block = block(1, genesis.header_hash, vec![coinbase.clone()]);
+        assert_eq!(
+            set.apply_block(&block),
+            Err(UtxoError::DuplicateTransaction(coinbase.hash))
+        );
+    }
+
+    #[test]
+    fn test_undo_rejects_wrong_undo_data() {
+        let (mut set, coinbase, genesis) = funded_set();
+        let entry = set.get(&OutPoint::new(coinbase.hash, 0)).unwrap().clone();
+        let undo = BlockUndo {
+            spent: vec![(OutPoint::new(coinbase.hash, 0), entry)],
+        };
+        assert_eq!(
+            set.undo_block(&genesis, &undo),
+            Err(UtxoError::UndoMismatch(OutPoint::new(coinbase.hash, 0)))
+        );
+        assert_eq!(set.len(), 2);
+    }
+
+    #[test]
+    fn test_input_value() {
+        let (set, coinbase, _) = funded_set();
+        let spend = tx(
+            vec![
+                OutPoint::new(coinbase.hash, 0),
+                OutPoint::new(coinbase.hash, 1),
+            ],
+            &[70],
+            2,
+        );
+        assert_eq!(set.input_value(&spend), Ok(75));
+    }
+}
@@ -0,0 +1,926 @@
+// Block and transaction validation.
+//
+// `check_transaction` and `check_block` only look at the data itself and can
+// run before a block's parent is known. `connect_block` checks the block
+// against the chain it extends and the UTXO set, and applies it on success.
+use crate::{
+    Block, BlockUndo, Chain, ConsensusEngine, Encode, Hash, OutPoint, TinyBlockchainParams,
+    Transaction, UtxoError, UtxoInput, UtxoSet, UtxoView,
+};
+use ethnum::U256;
+use std::collections::HashSet;
+
+/// Number of base units in one coin.
+pub const COIN: u64 = 100_000_000;
+/// No amount, nor the sum of the outputs of a transaction, may exceed this.
+pub const MAX_MONEY: u64 = 21_000_000 * COIN;
+/// Maximum size of an encoded block in bytes.
+pub const MAX_BLOCK_SIZE: usize = 1_000_000;
+/// Blocks that must be built on top of a coinbase before it can be spent.
+pub const COINBASE_MATURITY: usize = 100;
+/// Reward of the first blocks, halved every `SUBSIDY_HALVING_INTERVAL`.
+pub const INITIAL_SUBSIDY: u64 = 50 * COIN;
+pub const SUBSIDY_HALVING_INTERVAL: usize = 210_000;
+/// Blocks whose median timestamp a new block must be later than.
+pub const MEDIAN_TIME_SPAN: usize = 11;
+
+/// The newly created coins a coinbase at `height` may claim on top of fees.
+pub fn block_subsidy(height: usize) -> u64 {
+    let halvings = height / SUBSIDY_HALVING_INTERVAL;
+
+    if halvings >= 64 {
+        return 0;
+    }
+
+    INITIAL_SUBSIDY >> halvings
+}
+
+/// The reason a transaction or block was rejected.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ValidationError {
+    NoInputs(U256),
+    NoOutputs(U256),
+    TransactionTooLarge {
+        txid: U256,
+        size: usize,
+    },
+    OutputValueTooLarge {
+        txid: U256,
+        value: u64,
+    },
+    DuplicateInput {
+        txid: U256,
+        outpoint: OutPoint,
+    },
+    /// A coinbase input appears in a transaction that isn't a coinbase.
+    MisplacedCoinbaseInput(U256),
+    InvalidSignature {
+        txid: U256,
+        input: usize,
+    },
+    /// The key revealed by an input doesn't own the output it spends.
+    WrongUnlockKey {
+        txid: U256,
+        input: usize,
+    },
+    PrematureCoinbaseSpend {
+        txid: U256,
+        outpoint: OutPoint,
+    },
+    BadHeaderHash {
+        expected: U256,
+        found: U256,
+    },
+    HighHash(U256),
+    NoTransactions,
+    FirstTransactionNotCoinbase,
+    MultipleCoinbase(U256),
+    DuplicateTransaction(U256),
+    BadMerkleRoot {
+        expected: U256,
+        found: U256,
+    },
+    BlockTooLarge(usize),
+    /// The block doesn't build on the current tip.
+    PrevBlockMismatch {
+        expected: U256,
+        found: U256,
+    },
+    BadHeight {
+        expected: usize,
+        found: usize,
+    },
+    BadCoinbaseHeight {
+        expected: usize,
+        found: u64,
+    },
+    BadDifficulty {
+        expected: u32,
+        found: u32,
+    },
+    /// The bits encode a target easier than the proof of work limit.
+    TargetAboveLimit {
+        limit: u32,
+        found: u32,
+    },
+    /// The engine specific seal of the header is malformed or doesn't verify.
+    BadSeal(U256),
+    /// The block is sealed by a key that isn't a validator.
+    UnauthorizedSigner([u8; 32]),
+    /// The validator sealed one of the most recent blocks already.
+    RecentlySigned([u8; 32]),
+    /// The timestamp isn't after the median time past of its parent.
+    TimestampTooEarly {
+        min: u64,
+        found: u64,
+    },
+    /// The timestamp is too far ahead of network adjusted time.
+    TimestampTooLate {
+        max: u64,
+        found: u64,
+    },
+    ExcessiveCoinbase {
+        max: u64,
+        found: u64,
+    },
+    Utxo(UtxoError),
+}
+
+impl std::fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ValidationError::NoInputs(txid) => {
+                write!(f, "transaction {:#064x} has no inputs", txid)
+            }
+            ValidationError::NoOutputs(txid) => {
+                write!(f, "transaction {:#064x} has no outputs", txid)
+            }
+            ValidationError::TransactionTooLarge { txid, size } => {
+                write!(f, "transaction {:#064x} is {} bytes", txid, size)
+            }
+            ValidationError::OutputValueTooLarge { txid, value } => write!(
+                f,
+                "transaction {:#064x} outputs {} which is above the money supply",
+                txid, value
+            ),
+            ValidationError::DuplicateInput { txid, outpoint } => write!(
+                f,
+                "transaction {:#064x} spends {:#064x}:{} twice",
+                txid, outpoint.txid, outpoint.vout
+            ),
+            ValidationError::MisplacedCoinbaseInput(txid) => {
+                write!(
+                    f,
+                    "transaction {:#064x} has a misplaced coinbase input",
+                    txid
+                )
+            }
+            ValidationError::InvalidSignature { txid, input } => write!(
+                f,
+                "transaction {:#064x} has an invalid signature on input {}",
+                txid, input
+            ),
+            ValidationError::WrongUnlockKey { txid, input } => write!(
+                f,
+                "transaction {:#064x} input {} is signed by a key that doesn't own the output",
+                txid, input
+            ),
+            ValidationError::PrematureCoinbaseSpend { txid, outpoint } => write!(
+                f,
+                "transaction {:#064x} spends immature coinbase {:#064x}:{}",
+                txid, outpoint.txid, outpoint.vout
+            ),
+            ValidationError::BadHeaderHash { expected, found } => write!(
+                f,
+                "header hash is {:#064x} but the block claims {:#064x}",
+                expected, found
+            ),
+            ValidationError::HighHash(hash) => {
+                write!(f, "header hash {:#064x} doesn't meet the target", hash)
+            }
+            ValidationError::NoTransactions => write!(f, "block has no transactions"),
+            ValidationError::FirstTransactionNotCoinbase => {
+                write!(f, "first transaction is not a coinbase")
+            }
+            ValidationError::MultipleCoinbase(txid) => {
+                write!(f, "transaction {:#064x} is a second coinbase", txid)
+            }
+            ValidationError::DuplicateTransaction(txid) => {
+                write!(f, "transaction {:#064x} is included twice", txid)
+            }
+            ValidationError::BadMerkleRoot { expected, found } => write!(
+                f,
+                "merkle root is {:#064x} but the header commits to {:#064x}",
+                expected, found
+            ),
+            ValidationError::BlockTooLarge(size) => write!(f, "block is {} bytes", size),
+            ValidationError::PrevBlockMismatch { expected, found } => write!(
+                f,
+                "block builds on {:#064x} instead of the tip {:#064x}",
+                found, expected
+            ),
+            ValidationError::BadHeight { expected, found } => {
+                write!(f, "block height is {} instead of {}", found, expected)
+            }
+            ValidationError::BadCoinbaseHeight { expected, found } => write!(
+                f,
+                "coinbase commits to height {} instead of {}",
+                found, expected
+            ),
+            ValidationError::BadDifficulty { expected, found } => write!(
+                f,
+                "block bits are {:#010x} instead of {:#010x}",
+                found, expected
+            ),
+            ValidationError::TargetAboveLimit { limit, found } => write!(
+                f,
+                "block bits {:#010x} are easier than the limit {:#010x}",
+                found, limit
+            ),
+            ValidationError::BadSeal(hash) => write!(f, "block {:#064x} has a bad seal", hash),
+            ValidationError::UnauthorizedSigner(key) => {
+                write!(f, "block signed by unauthorized key {}", hex::encode(key))
+            }
+            ValidationError::RecentlySigned(key) => write!(
+                f,
+                "validator {} signed one of the recent blocks",
+                hex::encode(key)
+            ),
+            ValidationError::TimestampTooEarly { min, found } => {
+                write!(f, "block timestamp {} is earlier than {}", found, min)
+            }
+            ValidationError::TimestampTooLate { max, found } => {
+                write!(f, "block timestamp {} is later than {}", found, max)
+            }
+            ValidationError::ExcessiveCoinbase { max, found } => {
+                write!(
+                    f,
+                    "coinbase claims {} but at most {} is allowed",
+                    found, max
+                )
+            }
+            ValidationError::Utxo(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+impl From<UtxoError> for ValidationError {
+    fn from(err: UtxoError) -> Self {
+        ValidationError::Utxo(err)
+    }
+}
+
+/// Checks the rules a transaction must follow regardless of the chain state.
+pub fn check_transaction(tx: &Transaction) -> Result<(), ValidationError> {
+    if tx.inputs.is_empty() {
+        return Err(ValidationError::NoInputs(tx.hash));
+    }
+
+    if tx.outputs.is_empty() {
+        return Err(ValidationError::NoOutputs(tx.hash));
+    }
+
+    let size = tx.to_bytes().len();
+    if size > MAX_BLOCK_SIZE {
+        return Err(ValidationError::TransactionTooLarge {
+            txid: tx.hash,
+            size,
+        });
+    }
+
+    let mut total = 0u64;
+    for output in &tx.outputs {
+        total = total.saturating_add(output.value());
+        if output.value() > MAX_MONEY || total > MAX_MONEY {
+            return Err(ValidationError::OutputValueTooLarge {
+                txid: tx.hash,
+                value: total,
+            });
+        }
+    }
+
+    if tx.is_coinbase() {
+        return Ok(());
+    }
+
+    if tx.inputs.iter().any(|input| input.is_coinbase()) {
+        return Err(ValidationError::MisplacedCoinbaseInput(tx.hash));
+    }
+
+    let mut spent = HashSet::new();
+    for (index, input) in tx.inputs.iter().enumerate() {
+        let outpoint = input.prev_output().expect("Coinbase inputs to be rejected");
+
+        if !spent.insert(*outpoint) {
+            return Err(ValidationError::DuplicateInput {
+                txid: tx.hash,
+                outpoint: *outpoint,
+            });
+        }
+
+        if !tx.verify_input(index) {
+            return Err(ValidationError::InvalidSignature {
+                txid: tx.hash,
+                input: index,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks the rules a block must follow regardless of the chain it extends:
+/// the engine's seal, structure, merkle root, size and every transaction.
+pub fn check_block<E: ConsensusEngine>(block: &Block, engine: &E) -> Result<(), ValidationError> {
+    let header_hash = block.header.hash();
+    if block.header_hash != header_hash {
+        return Err(ValidationError::BadHeaderHash {
+            expected: header_hash,
+            found: block.header_hash,
+        });
+    }
+
+    engine.verify_seal(block)?;
+
+    let Some(coinbase) = block.transactions.first() else {
+        return Err(ValidationError::NoTransactions);
+    };
+
+    if !coinbase.is_coinbase() {
+        return Err(ValidationError::FirstTransactionNotCoinbase);
+    }
+
+    let mut txids = HashSet::new();
+    for (index, tx) in block.transactions.iter().enumerate() {
+        if index > 0 && tx.is_coinbase() {
+            return Err(ValidationError::MultipleCoinbase(tx.hash));
+        }
+
+        if !txids.insert(tx.hash) {
+            return Err(ValidationError::DuplicateTransaction(tx.hash));
+        }
+
+        check_transaction(tx)?;
+    }
+
+    let merkle_root = block.compute_merkle_root();
+    if block.header.merkle_root != merkle_root {
+        return Err(ValidationError::BadMerkleRoot {
+            expected: merkle_root,
+            found: block.header.merkle_root,
+        });
+    }
+
+    let size = block.to_bytes().len();
+    if size > MAX_BLOCK_SIZE {
+        return Err(ValidationError::BlockTooLarge(size));
+    }
+
+    Ok(())
+}
+
+/// Checks `block` against the tip of `chain` and the UTXO set, then applies
+/// it to the set. On error the set is left untouched.
+pub fn connect_block<E: ConsensusEngine>(
+    block: &Block,
+    chain: &Chain,
+    params: &TinyBlockchainParams,
+    engine: &E,
+    utxo: &mut UtxoSet,
+) -> Result<BlockUndo, ValidationError> {
+    check_block(block, engine)?;
+    check_contextual_header(block, chain, params, engine)?;
+
+    let mut view = UtxoView::new(utxo);
+    let mut fees = 0u64;
+
+    for tx in block.transactions.iter().skip(1) {
+        let mut input_value = 0u64;
+
+        for (index, input) in tx.inputs.iter().enumerate() {
+            let UtxoInput::Spend {
+                prev_output,
+                pub_key,
+                ..
+            } = input
+            else {
+                return Err(ValidationError::MisplacedCoinbaseInput(tx.hash));
+            };
+
+            let entry = view.spend(prev_output)?;
+
+            if !entry.output.is_unlocked_by(pub_key) {
+                return Err(ValidationError::WrongUnlockKey {
+                    txid: tx.hash,
+                    input: index,
+                });
+            }
+
+            if entry.is_coinbase && block.height.saturating_sub(entry.height) < COINBASE_MATURITY {
+                return Err(ValidationError::PrematureCoinbaseSpend {
+                    txid: tx.hash,
+                    outpoint: *prev_output,
+                });
+            }
+
+            input_value += entry.output.value();
+        }
+
+        let output_value: u64 = tx.outputs.iter().map(|output| output.value()).sum();
+        if output_value > input_value {
+            return Err(UtxoError::Overspend {
+                txid: tx.hash,
+                inputs: input_value,
+                outputs: output_value,
+            }
+            .into());
+        }
+
+        fees += input_value - output_value;
+        view.add_outputs(tx, block.height)?;
+    }
+
+    let coinbase = &block.transactions[0];
+    let claimed: u64 = coinbase.outputs.iter().map(|output| output.value()).sum();
+    let max = block_subsidy(block.height) + fees;
+    if claimed > max {
+        return Err(ValidationError::ExcessiveCoinbase {
+            max,
+            found: claimed,
+        });
+    }
+
+    let coinbase_height = coinbase.inputs[0].coinbase_height().unwrap_or_default();
+    if coinbase_height != block.height as u64 {
+        return Err(ValidationError::BadCoinbaseHeight {
+            expected: block.height,
+            found: coinbase_height,
+        });
+    }
+
+    view.add_outputs(coinbase, block.height)?;
+
+    let changes = view.into_changes();
+    Ok(utxo.commit(changes, block.header_hash))
+}
+
+fn check_contextual_header<E: ConsensusEngine>(
+    block: &Block,
+    chain: &Chain,
+    params: &TinyBlockchainParams,
+    engine: &E,
+) -> Result<(), ValidationError> {
+    let Some(prev_block) = chain.previous_block() else {
+        return Err(ValidationError::PrevBlockMismatch {
+            expected: U256::ZERO,
+            found: block.header.prev,
+        });
+    };
+
+    if block.header.prev != prev_block.header_hash {
+        return Err(ValidationError::PrevBlockMismatch {
+            expected: prev_block.header_hash,
+            found: block.header.prev,
+        });
+    }
+
+    if block.height != prev_block.height + 1 {
+        return Err(ValidationError::BadHeight {
+            expected: prev_block.height + 1,
+            found: block.height,
+        });
+    }
+
+    engine.verify_header(block, chain, params)?;
+
+    let min = chain.median_time_past() + 1;
+    if block.header.timestamp < min {
+        return Err(ValidationError::TimestampTooEarly {
+            min,
+            found: block.header.timestamp,
+        });
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::{test_params, TEST_BITS};
+    use crate::{check_proof, next_bits, pow, BlockHeader, FixedClock, ProofOfWork, UtxoOutput};
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    fn keypair() -> Ed25519KeyPair {
+        Ed25519KeyPair::from_seed_unchecked(&[1u8; 32]).unwrap()
+    }
+
+    fn pub_key() -> [u8; 32] {
+        keypair().public_key().as_ref().try_into().unwrap()
+    }
+
+    fn coinbase(height: usize, value: u64) -> Transaction {
+        Transaction::coinbase(
+            height as u64,
+            0,
+            vec![UtxoOutput::to_pub_key(&pub_key(), value)],
+        )
+    }
+
+    fn seal(mut block: Block) -> Block {
+        block.header.merkle_root = block.compute_merkle_root();
+        let proof = pow(&block.header, &FixedClock(block.header.timestamp));
+        block.header.nonce = proof.nonce;
+        block.header.timestamp = proof.timestamp;
+        block.header_hash = proof.hash;
+        block
+    }
+
+    fn next_block(chain: &Chain, transactions: Vec<Transaction>) -> Block {
+        let prev = chain.previous_block().unwrap();
+        let header = BlockHeader {
+            version: 1,
+            timestamp: prev.header.timestamp + 600,
+            prev: prev.header_hash,
+            merkle_root: U256::ZERO,
+            bits: next_bits(chain, &test_params()),
+            nonce: 0,
+            extra: Vec::new(),
+        };
+        seal(Block::new(
+            prev.height + 1,
+            U256::ZERO,
+            header,
+            transactions,
+        ))
+    }
+
+    fn genesis() -> Block {
+        let header = BlockHeader {
+            version: 1,
+            timestamp: 1_700_000_000,
+            prev: U256::ZERO,
+            merkle_root: U256::ZERO,
+            bits: TEST_BITS,
+            nonce: 0,
+            extra: Vec::new(),
+        };
+        seal(Block::new(0, U256::ZERO, header, vec![coinbase(0, 0)]))
+    }
+
+    /// A chain whose first coinbase has matured.
+    fn mature_chain() -> (Chain, UtxoSet) {
+        let mut chain = Chain {
+            items: vec![genesis()],
+            last_update: 0,
+        };
+        let mut utxo = UtxoSet::new();
+        utxo.apply_block(&chain.items[0]).unwrap();
+
+        for height in 1..=COINBASE_MATURITY + 1 {
+            let block = next_block(&chain, vec![coinbase(height, INITIAL_SUBSIDY)]);
+            connect_block(&block, &chain, &test_params(), &ProofOfWork, &mut utxo).unwrap();
+            chain.add_block(block);
+        }
+
+        (chain, utxo)
+    }
+
+    fn spend(outpoint: OutPoint, value: u64) -> Transaction {
+        let mut tx = Transaction::new(
+            vec![UtxoInput::spend(outpoint, pub_key())],
+            vec![UtxoOutput::to_pub_key(&pub_key(), value)],
+        );
+        tx.sign(&keypair());
+        tx
+    }
+
+    fn first_reward(chain: &Chain) -> OutPoint {
+        OutPoint::new(chain.items[1].transactions[0].hash, 0)
+    }
+
+    #[test]
+    fn test_block_subsidy() {
+        assert_eq!(block_subsidy(0), 50 * COIN);
+        assert_eq!(block_subsidy(SUBSIDY_HALVING_INTERVAL), 25 * COIN);
+        assert_eq!(block_subsidy(64 * SUBSIDY_HALVING_INTERVAL), 0);
+    }
+
+    #[test]
+    fn test_check_transaction() {
+        let tx = spend(OutPoint::new(U256::ONE, 0), 10);
+        assert_eq!(check_transaction(&tx), Ok(()));
+
+        let mut unsigned = tx.clone();
+        unsigned.inputs[0] = UtxoInput::spend(OutPoint::new(U256::ONE, 0), pub_key());
+        assert_eq!(
+            check_transaction(&unsigned),
+            Err(ValidationError::InvalidSignature {
+                txid: tx.hash,
+                input: 0
+            })
+        );
+
+        let no_outputs = Transaction::new(tx.inputs.clone(), vec![]);
+        assert_eq!(
+            check_transaction(&no_outputs),
+            Err(ValidationError::NoOutputs(no_outputs.hash))
+        );
+
+        let too_much = coinbase(1, MAX_MONEY + 1);
+        assert!(matches!(
+            check_transaction(&too_much),
+            Err(ValidationError::OutputValueTooLarge { .. })
+        ));
+    }
+
+    #[test]
+    fn test_check_transaction_duplicate_input() {
+        let outpoint = OutPoint::new(U256::ONE, 0);
+        let mut tx = Transaction::new(
+            vec![
+                UtxoInput::spend(outpoint, pub_key()),
+                UtxoInput::spend(outpoint, pub_key()),
+            ],
+            vec![UtxoOutput::to_pub_key(&pub_key(), 1)],
+        );
+        tx.sign(&keypair());
+        assert_eq!(
+            check_transaction(&tx),
+            Err(ValidationError::DuplicateInput {
+                txid: tx.hash,
+                outpoint
+            })
+        );
+    }
+
+    #[test]
+    fn test_check_transaction_misplaced_coinbase_input() {
+        let mut tx = spend(OutPoint::new(U256::ONE, 0), 10);
+        tx.inputs.push(UtxoInput::coinbase(1, 0));
+        tx.hash = tx.hash();
+        assert_eq!(
+            check_transaction(&tx),
+            Err(ValidationError::MisplacedCoinbaseInput(tx.hash))
+        );
+    }
+
+    #[test]
+    fn test_check_block_structure() {
+        let (chain, _) = mature_chain();
+
+        let empty = next_block(&chain, vec![]);
+        assert_eq!(
+            check_block(&empty, &ProofOfWork),
+            Err(ValidationError::NoTransactions)
+        );
+
+        let tx = spend(first_reward(&chain), 10);
+        let no_coinbase = next_block(&chain, vec![tx.clone()]);
+        assert_eq!(
+            check_block(&no_coinbase, &ProofOfWork),
+            Err(ValidationError::FirstTransactionNotCoinbase)
+        );
+
+        let second = coinbase(chain.len() + 1, 1);
+        let two_coinbases = next_block(&chain, vec![coinbase(chain.len(), 1), second.clone()]);
+        assert_eq!(
+            check_block(&two_coinbases, &ProofOfWork),
+            Err(ValidationError::MultipleCoinbase(second.hash))
+        );
+
+        let duplicate = next_block(
+            &chain,
+            vec![coinbase(chain.len(), 1), tx.clone(), tx.clone()],
+        );
+        assert_eq!(
+            check_block(&duplicate, &ProofOfWork),
+            Err(ValidationError::DuplicateTransaction(tx.hash))
+        );
+    }
+
+    #[test]
+    fn test_check_block_merkle_root() {
+        let (chain, _) = mature_chain();
+        let mut block = next_block(&chain, vec![coinbase(chain.len(), 1)]);
+        let expected = block.header.merkle_root;
+        block.header.merkle_root += 1;
+        let block = seal_keep_root(block);
+
+        assert_eq!(
+            check_block(&block, &ProofOfWork),
+            Err(ValidationError::BadMerkleRoot {
+                expected,
+                found: expected + 1
+            })
+        );
+    }
+
+    fn seal_keep_root(mut block: Block) -> Block {
+        let proof = pow(&block.header, &FixedClock(block.header.timestamp));
+        block.header.nonce = proof.nonce;
+        block.header_hash = proof.hash;
+        block
+    }
+
+    #[test]
+    fn test_check_block_header_hash_and_pow() {
+        let (chain, _) = mature_chain();
+        let mut block = next_block(&chain, vec![coinbase(chain.len(), 1)]);
+        block.header_hash += 1;
+        assert!(matches!(
+            check_block(&block, &ProofOfWork),
+            Err(ValidationError::BadHeaderHash { .. })
+        ));
+
+        let mut block = next_block(&chain, vec![coinbase(chain.len(), 1)]);
+        // Find a nonce that fails the target to prove the check isn't vacuous.
+        block.header.bits = 0x1f00ffff;
+        while check_proof(&block.header) {
+            block.header.nonce += 1;
+        }
+        block.header_hash = block.header.hash();
+        assert_eq!(
+            check_block(&block, &ProofOfWork),
+            Err(ValidationError::HighHash(block.header_hash))
+        );
+    }
+
+    #[test]
+    fn test_connect_block_spend() {
+        let (mut chain, mut utxo) = mature_chain();
+        let height = chain.len();
+        let tx = spend(first_reward(&chain), INITIAL_SUBSIDY - 1_000);
+        let block = next_block(
+            &chain,
+            vec![coinbase(height, INITIAL_SUBSIDY + 1_000), tx.clone()],
+        );
+
+        let undo = connect_block(&block, &chain, &test_params(), &ProofOfWork, &mut utxo).unwrap();
+        assert_eq!(undo.spent.len(), 1);
+        assert!(utxo.contains(&OutPoint::new(tx.hash, 0)));
+        assert!(!utxo.contains(&first_reward(&chain)));
+        chain.add_block(block);
+    }
+
+    #[test]
+    fn test_connect_block_rejects_excessive_coinbase() {
+        let (chain, mut utxo) = mature_chain();
+        let height = chain.len();
+        let tx = spend(first_reward(&chain), INITIAL_SUBSIDY - 1_000);
+        let block = next_block(&chain, vec![coinbase(height, INITIAL_SUBSIDY + 1_001), tx]);
+
+        assert_eq!(
+            connect_block(&block, &chain, &test_params(), &ProofOfWork, &mut utxo),
+            Err(ValidationError::ExcessiveCoinbase {
+                max: INITIAL_SUBSIDY + 1_000,
+                found: INITIAL_SUBSIDY + 1_001,
+            })
+        );
+        assert!(utxo.contains(&first_reward(&chain)));
+    }
+
+    #[test]
+    fn test_connect_block_rejects_immature_coinbase_spend() {
+        let (chain, mut utxo) = mature_chain();
+        let height = chain.len();
+        let immature = OutPoint::new(chain.items[height - 1].transactions[0].hash, 0);
+        let tx = spend(immature, 1);
+        let block = next_block(&chain, vec![coinbase(height, 1), tx.clone()]);
+
+        assert_eq!(
+            connect_block(&block, &chain, &test_params(), &ProofOfWork, &mut utxo),
+            Err(ValidationError::PrematureCoinbaseSpend {
+                txid: tx.hash,
+                outpoint: immature
+            })
+        );
+    }
+
+    #[test]
+    fn test_connect_block_rejects_wrong_key() {
+        let (chain, mut utxo) = mature_chain();
+        let height = chain.len();
+        let other = Ed25519KeyPair::from_seed_unchecked(&[2u8; 32]).unwrap();
+        let mut tx = Transaction::new(
+            vec![UtxoInput::spend(
+                first_reward(&chain),
+                other.public_key().as_ref().try_into().unwrap(),
+            )],
+            vec![UtxoOutput::to_pub_key(&pub_key(), 1)],
+        );
+        tx.sign(&other);
+        let block = next_block(&chain, vec![coinbase(height, 1), tx.clone()]);
+
+        assert_eq!(
+            connect_block(&block, &chain, &test_params(), &ProofOfWork, &mut utxo),
+            Err(ValidationError::WrongUnlockKey {
+                txid: tx.hash,
+                input: 0
+            })
+        );
+    }
+
+    #[test]
+    fn test_connect_block_rejects_double_spend() {
+        let (chain, mut utxo) = mature_chain();
+        let height = chain.len();
+        let first = spend(first_reward(&chain), 1);
+        let second = spend(first_reward(&chain), 2);
+        let block = next_block(&chain, vec![coinbase(height, 1), first, second]);
+
+        assert_eq!(
+            connect_block(&block, &chain, &test_params(), &ProofOfWork, &mut utxo),
+            Err(ValidationError::Utxo(UtxoError::DoubleSpend(first_reward(
+                &chain
+            ))))
+        );
+    }
+
+    #[test]
+    fn test_connect_block_rejects_missing_input() {
+        let (chain, mut utxo) = mature_chain();
+        let height = chain.len();
+        let missing = OutPoint::new(U256::ONE, 0);
+        let block = next_block(&chain, vec![coinbase(height, 1), spend(missing, 1)]);
+
+        assert_eq!(
+            connect_block(&block, &chain, &test_params(), &ProofOfWork, &mut utxo),
+            Err(ValidationError::Utxo(UtxoError::MissingInput(missing)))
+        );
+    }
+
+    #[test]
+    fn test_connect_block_contextual_header() {
+        let (chain, mut utxo) = mature_chain();
+        let height = chain.len();
+
+        let mut block = next_block(&chain, vec![coinbase(height, 1)]);
+        block.height += 1;
+        assert_eq!(
+            connect_block(&block, &chain, &test_params(), &ProofOfWork, &mut utxo),
+            Err(ValidationError::BadHeight {
+                expected: height,
+                found: height + 1
+            })
+        );
+
+        let block = next_block(&chain, vec![coinbase(height + 1, 1)]);
+        assert_eq!(
+            connect_block(&block, &chain, &test_params(), &ProofOfWork, &mut utxo),
+            Err(ValidationError::BadCoinbaseHeight {
+                expected: height,
+                found: height as u64 + 1
+            })
+        );
+
+        let mut block = next_block(&chain, vec![coinbase(height, 1)]);
+        block.header.bits = 0x2000ffff;
+        let block = seal(block);
+        assert_eq!(
+            connect_block(&block, &chain, &test_params(), &ProofOfWork, &mut utxo),
+            Err(ValidationError::BadDifficulty {
+                expected: TEST_BITS,
+                found: 0x2000ffff
+            })
+        );
+
+        let mut block = next_block(&chain, vec![coinbase(height, 1)]);
+        block.header.prev += 1;
+        let block = seal(block);
+        assert!(matches!(
+            connect_block(&block, &chain, &test_params(), &ProofOfWork, &mut utxo),
+            Err(ValidationError::PrevBlockMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn test_connect_block_timestamp_rules() {
+        let (chain, utxo) = mature_chain();
+        let height = chain.len();
+        let prev_timestamp = chain.previous_block().unwrap().header.timestamp;
+        let median_time_past = chain.median_time_past();
+        assert_eq!(median_time_past, prev_timestamp - 5 * 600);
+
+        let with_timestamp = |timestamp| {
+            let mut block = next_block(&chain, vec![coinbase(height, 1)]);
+            block.header.timestamp = timestamp;
+            let block = seal(block);
+            connect_block(
+                &block,
+                &chain,
+                &test_params(),
+                &ProofOfWork,
+                &mut utxo.clone(),
+            )
+        };
+
+        assert_eq!(
+            with_timestamp(median_time_past),
+            Err(ValidationError::TimestampTooEarly {
+                min: median_time_past + 1,
+                found: median_time_past
+            })
+        );
+        // Earlier than the parent is fine as long as it's after the median.
+        assert!(with_timestamp(median_time_past + 1).is_ok());
+
+        // How far ahead of the clock a block may be is checked when it is
+        // first seen, a block in the chain stays valid whatever the time.
+        assert!(with_timestamp(prev_timestamp + 1_000 * 600).is_ok());
+    }
+
+    #[test]
+    fn test_error_display() {
+        let err = ValidationError::BadDifficulty {
+            expected: 0x1d00ffff,
+            found: 0x1d00fffe,
+        };
+        assert_eq!(
+            err.to_string(),
+            "block bits are 0x1d00fffe instead of 0x1d00ffff"
+        );
+    }
+}